@@ -0,0 +1,897 @@
+//! An asynchronous (tokio) API for channels, circuits and streams, enabled with the `async` feature.
+//!
+//! Each `AsyncChannel` runs a reader task that demultiplexes incoming cells by circuit ID and a writer task that
+//! serialises outgoing cells. Each `AsyncCircuit` runs a task that decrypts its relay cells and hands them to the
+//! `AsyncTorStream` they belong to. Circuit builds can be cancelled by dropping the future (for example with
+//! `tokio::time::timeout`), in which case the half-built circuit is destroyed.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{SocketAddr, IpAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Weak, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use native_tls::Protocol;
+use ring::rand::SecureRandom;
+use torserde::NLengthVector;
+
+use crate::cells::{TorCell, Command, Relay, RelayCell, LinkSpecifier, BeginFlags, EndReason, DestroyReason, CSRNG};
+use crate::cellcrypto::{CellCrypto, CircuitCrypto};
+use crate::channel::{CircuitIdAllocator, versions_cell, negotiate_version, netinfo_cell};
+use crate::circuit::{SendMeTracker, circuit_sendme, stream_sendme, addr_and_port, CIRCUIT_SENDME_INCREMENT, STREAM_SENDME_INCREMENT, CIRCUIT_WINDOW_START, STREAM_WINDOW_START};
+use crate::custom_crypto::{kdf_tor, constant_time_eq};
+use crate::ntor::{NtorClient, NTOR_HANDSHAKE_TYPE, NTOR_KEY_MATERIAL_LENGTH};
+use crate::error::{ErrorKind, Result, Teardown};
+
+async fn read_cell<R: AsyncRead + Unpin>(reader: & mut R, version: u32) -> Result<TorCell> {
+    let header_length = if version < 4 { 3 } else { 5 };
+
+    let mut buffer = vec![0u8; header_length + 2];
+
+    reader.read_exact(& mut buffer[..header_length]).await?;
+
+    let mut filled = header_length;
+
+    let length = match TorCell::expected_length(&buffer[..filled], version) {
+        Some(length) => length,
+        None => {
+            reader.read_exact(& mut buffer[filled..filled + 2]).await?;
+            filled += 2;
+            TorCell::expected_length(&buffer[..filled], version).unwrap()
+        }
+    };
+
+    buffer.resize(length, 0);
+
+    reader.read_exact(& mut buffer[filled..]).await?;
+
+    Ok(TorCell::from_stream(buffer.as_slice(), version)?)
+}
+
+async fn write_cell<W: AsyncWrite + Unpin>(writer: & mut W, cell: TorCell, version: u32) -> Result<()> {
+    let mut buffer = Vec::with_capacity(514);
+
+    cell.into_stream(& mut buffer, version)?;
+
+    writer.write_all(&buffer).await?;
+
+    Ok(())
+}
+
+type CircuitMap = Arc<Mutex<HashMap<u32, mpsc::UnboundedSender<TorCell>>>>;
+
+struct ChannelShared {
+    version: u32,
+    outgoing: mpsc::UnboundedSender<TorCell>,
+    circuits: CircuitMap,
+    allocator: Mutex<CircuitIdAllocator>,
+    reader: JoinHandle<()>,
+}
+
+impl ChannelShared {
+    fn send(& self, cell: TorCell) -> Result<()> {
+        self.outgoing.send(cell).map_err(|_| ErrorKind::ChannelClosed)
+    }
+
+    fn register_circuit(& self) -> (u32, mpsc::UnboundedReceiver<TorCell>) {
+        let mut circuits = self.circuits.lock().unwrap();
+
+        let circuit_id = self.allocator.lock().unwrap().allocate(|id| circuits.contains_key(&id));
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        circuits.insert(circuit_id, sender);
+
+        (circuit_id, receiver)
+    }
+}
+
+impl Drop for ChannelShared {
+    fn drop(& mut self) {
+        self.reader.abort();
+    }
+}
+
+///An open link to a relay, shared by every circuit built through it
+pub struct AsyncChannel {
+    shared: Arc<ChannelShared>,
+}
+
+impl AsyncChannel {
+    ///Connect to a relay over TLS and perform the link handshake
+    pub async fn connect(address: SocketAddr) -> Result<Self> {
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_hostnames(true)
+            .danger_accept_invalid_certs(true)
+            .use_sni(false)
+            .min_protocol_version(Some(Protocol::Tlsv12))
+            .build()?;
+
+        let connector = tokio_native_tls::TlsConnector::from(connector);
+
+        let tcp = TcpStream::connect(address).await?;
+
+        let tls = connector.connect("", tcp).await?;
+
+        Self::handshake(tls, address.ip()).await
+    }
+
+    ///Perform the link handshake over an already connected stream then start the channel's tasks
+    pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(mut stream: S, peer: IpAddr) -> Result<Self> {
+        write_cell(& mut stream, versions_cell(), 3).await?;
+
+        let version = negotiate_version(&read_cell(& mut stream, 3).await?)?;
+
+        loop {
+            let cell = read_cell(& mut stream, version).await?;
+
+            match cell.get_command() {
+                Command::NetInfo { .. } => break,
+                Command::Certs { .. } | Command::AuthChallenge { .. } | Command::Padding => {},
+                _ => return Err(ErrorKind::UnexpectedCell(format!("{:?}", cell))),
+            }
+        }
+
+        write_cell(& mut stream, netinfo_cell(peer), version).await?;
+
+        let (read_half, write_half) = tokio::io::split(stream);
+
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+
+        let circuits: CircuitMap = Arc::new(Mutex::new(HashMap::new()));
+
+        tokio::spawn(Self::writer(write_half, version, outgoing_receiver));
+
+        let reader = tokio::spawn(Self::reactor(read_half, version, circuits.clone()));
+
+        Ok(Self {
+            shared: Arc::new(ChannelShared {
+                version,
+                outgoing,
+                circuits,
+                allocator: Mutex::new(CircuitIdAllocator::new(version)),
+                reader,
+            })
+        })
+    }
+
+    async fn writer<W: AsyncWrite + Unpin>(mut writer: W, version: u32, mut outgoing: mpsc::UnboundedReceiver<TorCell>) {
+        while let Some(cell) = outgoing.recv().await {
+            if write_cell(& mut writer, cell, version).await.is_err() {
+                break;
+            }
+        }
+
+        let _ = writer.shutdown().await;
+    }
+
+    ///Read cells forever, handing each to the queue of the circuit it belongs to
+    async fn reactor<R: AsyncRead + Unpin>(mut reader: R, version: u32, circuits: CircuitMap) {
+        loop {
+            let cell = match read_cell(& mut reader, version).await {
+                Ok(cell) => cell,
+                Err(ErrorKind::Torserde(torserde::ErrorKind::DiscardedCell(_))) => continue,
+                Err(_) => break,
+            };
+
+            let circuit_id = cell.get_circuit_id();
+
+            match cell.get_command() {
+                Command::Padding => {},
+                Command::Destroy { .. } => {
+                    if let Some(sender) = circuits.lock().unwrap().remove(&circuit_id) {
+                        let _ = sender.send(cell);
+                    }
+                },
+                _ => {
+                    let sender = circuits.lock().unwrap().get(&circuit_id).cloned();
+
+                    if let Some(sender) = sender {
+                        let _ = sender.send(cell);
+                    }
+                },
+            }
+        }
+
+        //Dropping the senders tells every circuit that the channel has closed
+        circuits.lock().unwrap().clear();
+    }
+
+    pub fn link_version(& self) -> u32 {
+        self.shared.version
+    }
+
+    ///The number of circuits currently registered on this channel
+    pub fn circuit_count(& self) -> usize {
+        self.shared.circuits.lock().unwrap().len()
+    }
+
+    ///Create a one hop circuit with CREATE_FAST
+    pub async fn create_fast(& self) -> Result<AsyncCircuit> {
+        let (circuit_id, mut incoming) = self.shared.register_circuit();
+
+        let guard = PendingCircuit::new(&self.shared, circuit_id);
+
+        let mut onion_skin = [0u8; 20];
+
+        CSRNG.fill(& mut onion_skin).unwrap();
+
+        self.shared.send(TorCell::new(circuit_id, Command::CreateFast { onion_skin }))?;
+
+        let reply = incoming.recv().await.ok_or(ErrorKind::ChannelClosed)?;
+
+        let handshake_data = match reply.get_command() {
+            Command::CreatedFast { handshake_data } => *handshake_data,
+            _ => return Err(ErrorKind::UnexpectedCell(format!("{:?}", reply))),
+        };
+
+        let mut shared_secret = Vec::from(onion_skin);
+
+        shared_secret.extend_from_slice(&handshake_data[0..20]);
+
+        let materials = kdf_tor(&shared_secret);
+
+        if !constant_time_eq(&materials[0..20], &handshake_data[20..40]) {
+            return Err(ErrorKind::HandshakeFailed);
+        }
+
+        let mut crypto = CircuitCrypto::new();
+
+        crypto.add_hop(CellCrypto::from(&materials[20..92].try_into().unwrap()).with_nonce(&materials[0..20]));
+
+        guard.disarm();
+
+        Ok(AsyncCircuit::start(self.shared.clone(), circuit_id, crypto, incoming))
+    }
+
+    ///Create a one hop circuit with CREATE2 and the ntor handshake
+    pub async fn create_ntor(& self, node_id: &[u8; 20], onion_key: &[u8; 32]) -> Result<AsyncCircuit> {
+        let (circuit_id, mut incoming) = self.shared.register_circuit();
+
+        let guard = PendingCircuit::new(&self.shared, circuit_id);
+
+        let ntor = NtorClient::new(node_id, onion_key);
+
+        self.shared.send(TorCell::new(circuit_id, Command::Create2 { handshake_type: NTOR_HANDSHAKE_TYPE, onion_skin: NLengthVector::from(ntor.onion_skin()) }))?;
+
+        let reply = incoming.recv().await.ok_or(ErrorKind::ChannelClosed)?;
+
+        let materials = match reply.get_command() {
+            Command::Created2 { handshake_data } => ntor.complete(&handshake_data.0, NTOR_KEY_MATERIAL_LENGTH).ok_or(ErrorKind::HandshakeFailed)?,
+            _ => return Err(ErrorKind::UnexpectedCell(format!("{:?}", reply))),
+        };
+
+        let mut crypto = CircuitCrypto::new();
+
+        crypto.add_hop(CellCrypto::from(&materials[0..72].try_into().unwrap()).with_nonce(&materials[72..92]));
+
+        guard.disarm();
+
+        Ok(AsyncCircuit::start(self.shared.clone(), circuit_id, crypto, incoming))
+    }
+}
+
+///Destroys a circuit whose build future was dropped before it completed
+struct PendingCircuit<'a> {
+    channel: &'a ChannelShared,
+    circuit_id: u32,
+    armed: bool,
+}
+
+impl<'a> PendingCircuit<'a> {
+    fn new(channel: &'a ChannelShared, circuit_id: u32) -> Self {
+        Self {
+            channel,
+            circuit_id,
+            armed: true,
+        }
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<'a> Drop for PendingCircuit<'a> {
+    fn drop(& mut self) {
+        if self.armed {
+            self.channel.circuits.lock().unwrap().remove(&self.circuit_id);
+
+            let _ = self.channel.send(TorCell::new(self.circuit_id, Command::Destroy { reason: DestroyReason::None }));
+        }
+    }
+}
+
+///The DATA cells we may still send to each hop and on each stream, and the writers waiting for a SENDME to open them
+#[derive(Default)]
+struct PackageWindows {
+    circuit: HashMap<usize, u32>,
+    streams: HashMap<u16, u32>,
+    waiting: Vec<Waker>,
+}
+
+impl PackageWindows {
+    fn wake(& mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+struct CircuitShared {
+    channel: Arc<ChannelShared>,
+    circuit_id: u32,
+    crypto: Mutex<CircuitCrypto>,
+    ///Each open stream's hop and queue
+    streams: Mutex<HashMap<u16, (usize, mpsc::UnboundedSender<RelayCell>)>>,
+    ///Streams whose hop was truncated away, and why
+    failed: Mutex<HashMap<u16, Teardown>>,
+    control: tokio::sync::Mutex<mpsc::UnboundedReceiver<RelayCell>>,
+    next_stream_id: Mutex<u16>,
+    closed: Mutex<Option<Teardown>>,
+    truncated: Mutex<Option<DestroyReason>>,
+    windows: Mutex<PackageWindows>,
+}
+
+impl CircuitShared {
+    ///Encrypt and queue a relay cell for `hop`. The crypto lock is held until the cell is queued so cells leave in the order they were encrypted
+    fn send_relay(& self, hop: usize, relay: RelayCell, early: bool) -> Result<()> {
+        if let Some(teardown) = *self.closed.lock().unwrap() {
+            return Err(teardown.into());
+        }
+
+        let mut crypto = self.crypto.lock().unwrap();
+
+        if hop >= crypto.hop_count() {
+            return Err(ErrorKind::CircuitTruncated(self.truncated.lock().unwrap().unwrap_or(DestroyReason::None)));
+        }
+
+        let contents = crypto.encrypt(hop, relay)?;
+
+        let command = if early {
+            Command::RelayEarly { contents }
+        } else {
+            Command::Relay { contents }
+        };
+
+        self.channel.send(TorCell::new(self.circuit_id, command))
+    }
+
+    fn last_hop(& self) -> usize {
+        self.crypto.lock().unwrap().hop_count() - 1
+    }
+
+    fn close(& self, teardown: Teardown) {
+        {
+            let mut closed = self.closed.lock().unwrap();
+
+            //A circuit we closed ourselves stays closed for that reason
+            if closed.is_none() {
+                *closed = Some(teardown);
+            }
+        }
+
+        //Dropping the senders ends every stream on the circuit
+        self.streams.lock().unwrap().clear();
+
+        self.windows.lock().unwrap().wake();
+    }
+
+    ///Send DESTROY unless the circuit is already closed, then fail everything still using it
+    fn destroy(& self, reason: DestroyReason) {
+        if self.closed.lock().unwrap().is_some() {
+            return;
+        }
+
+        self.close(Teardown::Closed);
+
+        self.channel.circuits.lock().unwrap().remove(&self.circuit_id);
+
+        let _ = self.channel.send(TorCell::new(self.circuit_id, Command::Destroy { reason }));
+    }
+
+    ///Drop the hops after `hop` and fail the streams attached to them
+    fn truncate(& self, hop: usize, reason: DestroyReason) {
+        self.crypto.lock().unwrap().truncate(hop + 1);
+
+        *self.truncated.lock().unwrap() = Some(reason);
+
+        let mut streams = self.streams.lock().unwrap();
+        let mut failed = self.failed.lock().unwrap();
+
+        let cut: Vec<u16> = streams.iter().filter(|(_, (stream_hop, _))| *stream_hop > hop).map(|(stream_id, _)| *stream_id).collect();
+
+        for stream_id in cut {
+            streams.remove(&stream_id);
+            failed.insert(stream_id, Teardown::Truncated(reason));
+        }
+
+        let mut windows = self.windows.lock().unwrap();
+
+        //A hop extended in place of the ones dropped starts with a full window
+        windows.circuit.retain(|window_hop, _| *window_hop <= hop);
+        windows.wake();
+    }
+
+    ///Take one DATA cell from the windows of `hop` and `stream_id`, or register to be woken when a SENDME opens them
+    fn poll_take_windows(& self, hop: usize, stream_id: u16, cx: & mut Context<'_>) -> Poll<()> {
+        let mut windows = self.windows.lock().unwrap();
+
+        let circuit = *windows.circuit.entry(hop).or_insert(CIRCUIT_WINDOW_START);
+        let stream = *windows.streams.entry(stream_id).or_insert(STREAM_WINDOW_START);
+
+        if circuit == 0 || stream == 0 {
+            if !windows.waiting.iter().any(|waker| waker.will_wake(cx.waker())) {
+                windows.waiting.push(cx.waker().clone());
+            }
+
+            return Poll::Pending;
+        }
+
+        windows.circuit.insert(hop, circuit - 1);
+        windows.streams.insert(stream_id, stream - 1);
+
+        Poll::Ready(())
+    }
+
+    ///Open the window a SENDME from `hop` is for, waking the writers waiting on it
+    fn sendme_received(& self, hop: usize, stream_id: u16) {
+        let mut windows = self.windows.lock().unwrap();
+
+        if stream_id == 0 {
+            *windows.circuit.entry(hop).or_insert(CIRCUIT_WINDOW_START) += CIRCUIT_SENDME_INCREMENT;
+        } else if let Some(window) = windows.streams.get_mut(&stream_id) {
+            *window += STREAM_SENDME_INCREMENT;
+        }
+
+        windows.wake();
+    }
+
+    ///Why a stream's queue was closed, or `None` if it simply ended
+    fn stream_teardown(& self, stream_id: u16) -> Option<Teardown> {
+        self.failed.lock().unwrap().get(&stream_id).copied().or(*self.closed.lock().unwrap())
+    }
+
+    fn forget_stream(& self, stream_id: u16) {
+        self.streams.lock().unwrap().remove(&stream_id);
+        self.failed.lock().unwrap().remove(&stream_id);
+        self.windows.lock().unwrap().streams.remove(&stream_id);
+    }
+}
+
+impl Drop for CircuitShared {
+    fn drop(& mut self) {
+        self.destroy(DestroyReason::None);
+    }
+}
+
+///A circuit built through an `AsyncChannel`. The circuit is destroyed once it and all of its streams are dropped
+pub struct AsyncCircuit {
+    shared: Arc<CircuitShared>,
+}
+
+impl AsyncCircuit {
+    fn start(channel: Arc<ChannelShared>, circuit_id: u32, crypto: CircuitCrypto, incoming: mpsc::UnboundedReceiver<TorCell>) -> Self {
+        let (control_sender, control) = mpsc::unbounded_channel();
+
+        let shared = Arc::new(CircuitShared {
+            channel,
+            circuit_id,
+            crypto: Mutex::new(crypto),
+            streams: Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashMap::new()),
+            control: tokio::sync::Mutex::new(control),
+            next_stream_id: Mutex::new(1),
+            closed: Mutex::new(None),
+            truncated: Mutex::new(None),
+            windows: Mutex::new(PackageWindows::default()),
+        });
+
+        tokio::spawn(Self::reactor(Arc::downgrade(&shared), incoming, control_sender));
+
+        Self {
+            shared,
+        }
+    }
+
+    ///Decrypt each relay cell for this circuit and pass it to its stream, sending SENDMEs as DATA cells arrive and
+    ///opening the package windows as SENDMEs do. The task only holds the circuit while it handles a cell, and ends once
+    ///the circuit and its streams are dropped
+    async fn reactor(circuit: Weak<CircuitShared>, mut incoming: mpsc::UnboundedReceiver<TorCell>, control: mpsc::UnboundedSender<RelayCell>) {
+        let mut sendme = SendMeTracker::new();
+
+        let teardown = loop {
+            let cell = match incoming.recv().await {
+                Some(cell) => cell,
+                None => break Teardown::Destroyed(DestroyReason::OrConnClosed),
+            };
+
+            let shared = match circuit.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+
+            let contents = match cell.into_command() {
+                Command::Relay { contents } => contents,
+                Command::Destroy { reason } => break Teardown::Destroyed(reason),
+                _ => continue,
+            };
+
+            let decrypted = shared.crypto.lock().unwrap().decrypt(&contents);
+
+            let (hop, messages) = match decrypted {
+                Ok(decrypted) => decrypted,
+                Err(_) => {
+                    //A cell no hop recognises means the circuit can't be trusted any more
+                    shared.channel.circuits.lock().unwrap().remove(&shared.circuit_id);
+
+                    let _ = shared.channel.send(TorCell::new(shared.circuit_id, Command::Destroy { reason: DestroyReason::Protocol }));
+
+                    break Teardown::Destroyed(DestroyReason::Protocol);
+                },
+            };
+
+            for relay in messages {
+                let stream_id = relay.get_stream_id();
+
+                if relay.get_command() == 2 {
+                    let (circuit_due, stream_due) = sendme.data_received(stream_id);
+
+                    if circuit_due {
+                        let cell = circuit_sendme(&shared.crypto.lock().unwrap(), hop);
+                        let _ = shared.send_relay(hop, cell, false);
+                    }
+
+                    if stream_due {
+                        let _ = shared.send_relay(hop, stream_sendme(stream_id), false);
+                    }
+                }
+
+                if relay.get_command() == 5 {
+                    shared.sendme_received(hop, stream_id);
+                } else if relay.get_command() == 9 {
+                    let reason = match relay.get_payload() {
+                        Ok(Some(Relay::Truncated { reason })) => reason,
+                        _ => DestroyReason::None,
+                    };
+
+                    shared.truncate(hop, reason);
+
+                    let _ = control.send(RelayCell::new(0, Relay::Truncated { reason }));
+                } else if stream_id == 0 {
+                    let _ = control.send(relay);
+                } else {
+                    let sender = shared.streams.lock().unwrap().get(&stream_id).map(|(_, sender)| sender.clone());
+
+                    if let Some(sender) = sender {
+                        let _ = sender.send(relay);
+                    }
+                }
+            }
+        };
+
+        if let Some(shared) = circuit.upgrade() {
+            shared.close(teardown);
+        }
+    }
+
+    pub fn circuit_id(& self) -> u32 {
+        self.shared.circuit_id
+    }
+
+    pub fn hop_count(& self) -> usize {
+        self.shared.crypto.lock().unwrap().hop_count()
+    }
+
+    pub fn is_closed(& self) -> bool {
+        self.shared.closed.lock().unwrap().is_some()
+    }
+
+    ///Why the circuit closed, if it has
+    pub fn teardown(& self) -> Option<Teardown> {
+        *self.shared.closed.lock().unwrap()
+    }
+
+    ///Close the circuit with DESTROY. Its streams fail with `ErrorKind::CircuitClosed`
+    pub fn destroy(& self) {
+        self.shared.destroy(DestroyReason::None);
+    }
+
+    ///Send RELAY_TRUNCATE to hop number `hops - 1`, keeping the first `hops` hops. Streams attached to later hops fail with `ErrorKind::CircuitTruncated`.
+    ///Truncating to zero hops destroys the circuit
+    pub async fn truncate(& self, hops: usize) -> Result<()> {
+        if hops == 0 {
+            self.destroy();
+            return Ok(());
+        }
+
+        if hops >= self.hop_count() {
+            return Ok(());
+        }
+
+        let mut control = self.shared.control.lock().await;
+
+        while control.try_recv().is_ok() {}
+
+        self.shared.send_relay(hops - 1, RelayCell::new(0, Relay::Truncate), false)?;
+
+        loop {
+            let reply = control.recv().await.ok_or_else(|| self.closed_error())?;
+
+            if let Some(Relay::Truncated { .. }) = reply.get_payload()? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn closed_error(& self) -> ErrorKind {
+        self.teardown().unwrap_or(Teardown::Closed).into()
+    }
+
+    ///Extend the circuit by one hop with EXTEND2 and the ntor handshake. If this future is dropped before it completes the circuit is destroyed, since the reply could otherwise arrive later
+    pub async fn extend_ntor(& self, link_specifiers: Vec<LinkSpecifier>, node_id: &[u8; 20], onion_key: &[u8; 32]) -> Result<()> {
+        let mut control = self.shared.control.lock().await;
+
+        let guard = PendingExtend { circuit: &self.shared, armed: true };
+
+        //Throw away control cells nobody asked for, such as a TRUNCATED the relay sent on its own
+        while control.try_recv().is_ok() {}
+
+        let ntor = NtorClient::new(node_id, onion_key);
+
+        let extend = Relay::Extend2 {
+            link_specifiers: NLengthVector::from(link_specifiers),
+            htype: NTOR_HANDSHAKE_TYPE,
+            handshake_data: NLengthVector::from(ntor.onion_skin()),
+        };
+
+        self.shared.send_relay(self.shared.last_hop(), RelayCell::new(0, extend), true)?;
+
+        let reply = control.recv().await.ok_or_else(|| self.closed_error())?;
+
+        let materials = match reply.get_payload()? {
+            Some(Relay::Extended2 { handshake_data }) => ntor.complete(&handshake_data.0, NTOR_KEY_MATERIAL_LENGTH).ok_or(ErrorKind::HandshakeFailed)?,
+            Some(Relay::Truncated { reason }) => {
+                //The relay reported the failure itself, so the circuit is still usable
+                guard.disarm();
+
+                return Err(ErrorKind::CircuitTruncated(reason));
+            },
+            payload => return Err(ErrorKind::UnexpectedCell(format!("{:?}", payload))),
+        };
+
+        self.shared.crypto.lock().unwrap().add_hop(CellCrypto::from(&materials[0..72].try_into().unwrap()).with_nonce(&materials[72..92]));
+
+        guard.disarm();
+
+        Ok(())
+    }
+
+    fn open_stream(& self, hop: usize) -> Result<(u16, mpsc::UnboundedReceiver<RelayCell>)> {
+        if self.is_closed() {
+            return Err(self.closed_error());
+        }
+
+        let mut streams = self.shared.streams.lock().unwrap();
+        let mut next = self.shared.next_stream_id.lock().unwrap();
+
+        let stream_id = loop {
+            let candidate = *next;
+
+            *next = next.wrapping_add(1);
+
+            if candidate != 0 && !streams.contains_key(&candidate) {
+                break candidate;
+            }
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        streams.insert(stream_id, (hop, sender));
+
+        Ok((stream_id, receiver))
+    }
+
+    async fn await_connected(& self, hop: usize, stream_id: u16, mut incoming: mpsc::UnboundedReceiver<RelayCell>) -> Result<AsyncTorStream> {
+        let stream = loop {
+            let reply = match incoming.recv().await {
+                Some(reply) => reply,
+                None => {
+                    let teardown = self.shared.stream_teardown(stream_id).unwrap_or(Teardown::Closed);
+
+                    self.shared.forget_stream(stream_id);
+
+                    return Err(teardown.into());
+                }
+            };
+
+            match reply.get_command() {
+                4 => break AsyncTorStream::new(self.shared.clone(), hop, stream_id, incoming),
+                3 => {
+                    self.shared.forget_stream(stream_id);
+
+                    return match reply.get_payload()? {
+                        Some(Relay::End { end_reason }) => Err(ErrorKind::StreamRefused(end_reason)),
+                        _ => Err(ErrorKind::StreamRefused(EndReason::Misc)),
+                    };
+                },
+                _ => {},
+            }
+        };
+
+        Ok(stream)
+    }
+
+    ///Open a stream from the last hop to `address:port`
+    pub async fn begin(& self, address: &str, port: u16) -> Result<AsyncTorStream> {
+        self.begin_with_flags(address, port, BeginFlags::empty()).await
+    }
+
+    ///Open a stream from the last hop to `address:port`, telling the exit which address families we can use
+    pub async fn begin_with_flags(& self, address: &str, port: u16, flags: BeginFlags) -> Result<AsyncTorStream> {
+        let hop = self.shared.last_hop();
+
+        let (stream_id, incoming) = self.open_stream(hop)?;
+
+        self.shared.send_relay(hop, RelayCell::new(stream_id, Relay::Begin { addr_and_port: addr_and_port(address, port), flags }), false)?;
+
+        self.await_connected(hop, stream_id, incoming).await
+    }
+
+    ///Open a stream to the directory port of the last hop
+    pub async fn begin_dir(& self) -> Result<AsyncTorStream> {
+        let hop = self.shared.last_hop();
+
+        let (stream_id, incoming) = self.open_stream(hop)?;
+
+        self.shared.send_relay(hop, RelayCell::new(stream_id, Relay::BeginDir), false)?;
+
+        self.await_connected(hop, stream_id, incoming).await
+    }
+}
+
+///Destroys a circuit whose extend future was dropped before it completed
+struct PendingExtend<'a> {
+    circuit: &'a CircuitShared,
+    armed: bool,
+}
+
+impl<'a> PendingExtend<'a> {
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<'a> Drop for PendingExtend<'a> {
+    fn drop(& mut self) {
+        if self.armed {
+            self.circuit.destroy(DestroyReason::None);
+        }
+    }
+}
+
+///A stream over a circuit, implementing tokio's `AsyncRead` and `AsyncWrite`
+pub struct AsyncTorStream {
+    circuit: Arc<CircuitShared>,
+    hop: usize,
+    stream_id: u16,
+    incoming: mpsc::UnboundedReceiver<RelayCell>,
+    buffer: Vec<u8>,
+    offset: usize,
+    finished: bool,
+    ended: bool,
+}
+
+impl AsyncTorStream {
+    fn new(circuit: Arc<CircuitShared>, hop: usize, stream_id: u16, incoming: mpsc::UnboundedReceiver<RelayCell>) -> Self {
+        Self {
+            circuit,
+            hop,
+            stream_id,
+            incoming,
+            buffer: Vec::new(),
+            offset: 0,
+            finished: false,
+            ended: false,
+        }
+    }
+
+    pub fn stream_id(& self) -> u16 {
+        self.stream_id
+    }
+
+    fn end(& mut self) {
+        if !self.ended {
+            self.ended = true;
+
+            let _ = self.circuit.send_relay(self.hop, RelayCell::new(self.stream_id, Relay::End { end_reason: EndReason::Done }), false);
+        }
+    }
+}
+
+impl AsyncRead for AsyncTorStream {
+    fn poll_read(mut self: Pin<& mut Self>, cx: & mut Context<'_>, buf: & mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.offset < self.buffer.len() {
+                let length = std::cmp::min(buf.remaining(), self.buffer.len() - self.offset);
+
+                buf.put_slice(&self.buffer[self.offset..self.offset + length]);
+
+                self.offset += length;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            match self.incoming.poll_recv(cx) {
+                Poll::Ready(Some(relay)) => match relay.get_command() {
+                    2 => {
+                        self.buffer = relay.into_data();
+                        self.offset = 0;
+                    },
+                    3 => {
+                        self.finished = true;
+                        self.ended = true;
+                    },
+                    _ => {},
+                },
+                Poll::Ready(None) => {
+                    if let Some(teardown) = self.circuit.stream_teardown(self.stream_id) {
+                        return Poll::Ready(Err(ErrorKind::from(teardown).into()));
+                    }
+
+                    self.finished = true;
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncTorStream {
+    fn poll_write(self: Pin<& mut Self>, cx: & mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        if self.ended {
+            return Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe)));
+        }
+
+        if let Some(teardown) = self.circuit.stream_teardown(self.stream_id) {
+            return Poll::Ready(Err(ErrorKind::from(teardown).into()));
+        }
+
+        if self.circuit.poll_take_windows(self.hop, self.stream_id, cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let length = std::cmp::min(buf.len(), 498);
+
+        self.circuit.send_relay(self.hop, RelayCell::new_data(self.stream_id, &buf[..length]), false)?;
+
+        Poll::Ready(Ok(length))
+    }
+
+    fn poll_flush(self: Pin<& mut Self>, _cx: & mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<& mut Self>, _cx: & mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.end();
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for AsyncTorStream {
+    fn drop(& mut self) {
+        self.end();
+
+        self.circuit.forget_stream(self.stream_id);
+    }
+}
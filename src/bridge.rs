@@ -0,0 +1,519 @@
+//! Bridges, the unlisted relays clients in censored networks use as their first hop.
+//!
+//! Bridges are configured with Tor's `Bridge [transport] addr:port [fingerprint] [k=v ...]` lines. Since bridges are not
+//! in the consensus, a client fetches each bridge's own descriptor from it over BEGIN_DIR to learn its keys, then builds
+//! every circuit through one of them. Whether each bridge could be reached is remembered, so later circuits try the ones
+//! that worked first and only retry failed ones after a backoff.
+//!
+//! Bridges with a transport are reached through the pluggable transport a `TransportManager` provides. obfs4 is built
+//! in, and is used for obfs4 bridges unless a `TransportManager` has a plugin for it.
+//!
+//! A descriptor is only used if its `router-signature` verifies with its `signing-key`, and the SHA-1 digest of that key
+//! is the fingerprint the bridge line names, if it names one. The link handshake's certificates aren't checked, so it's
+//! the ntor handshake that shows a bridge is that relay, by proving it holds the onion key its signed descriptor lists.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, IpAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use sha1::{Digest, Sha1};
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use rsa::pkcs1::DecodeRsaPublicKey;
+
+use crate::cells::LinkSpecifier;
+use crate::channel::Channel;
+use crate::circuit::Circuit;
+use crate::netdoc::{self, Item};
+use crate::obfs4;
+use crate::onion::{self, RelayInfo};
+use crate::pt::TransportManager;
+use crate::error::{ErrorKind, Result};
+
+///Where a bridge serves its own descriptor
+pub const BRIDGE_DESCRIPTOR_PATH: &str = "/tor/server/authority";
+
+///How long to wait before retrying a bridge after its first failure, in seconds. Each further failure doubles it
+const RETRY_INITIAL: i64 = 60;
+
+///The longest wait before retrying a bridge, in seconds
+const RETRY_MAX: i64 = 60 * 60;
+
+fn invalid(reason: &str) -> ErrorKind {
+    ErrorKind::InvalidDocument(format!("bad bridge line: {}", reason))
+}
+
+///Whether `address` is one a bridge line could name, which excludes unspecified and multicast addresses
+fn is_bridge_address(address: &SocketAddr) -> bool {
+    let ip: IpAddr = address.ip();
+
+    !ip.is_unspecified() && !ip.is_multicast() && address.port() != 0
+}
+
+///Parse a 40 character hex fingerprint, optionally prefixed with `$`
+pub fn parse_fingerprint(text: &str) -> Option<[u8; 20]> {
+    let text = text.strip_prefix('$').unwrap_or(text);
+
+    if text.len() != 40 || !text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let bytes: Vec<u8> = (0..40).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect();
+
+    bytes.as_slice().try_into().ok()
+}
+
+pub fn encode_fingerprint(fingerprint: &[u8; 20]) -> String {
+    fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+///A bridge as configured by a `Bridge` line
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeLine {
+    ///The pluggable transport to reach the bridge with, or `None` to connect directly
+    pub transport: Option<String>,
+    pub address: SocketAddr,
+    ///The SHA1 digest of the bridge's RSA identity key, if known
+    pub fingerprint: Option<[u8; 20]>,
+    ///`key=value` arguments for the transport
+    pub args: Vec<(String, String)>,
+}
+
+impl FromStr for BridgeLine {
+    type Err = ErrorKind;
+
+    ///Parse a `Bridge` line, with or without the `Bridge` keyword
+    fn from_str(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace().peekable();
+
+        if words.peek() == Some(&"Bridge") {
+            words.next();
+        }
+
+        let first = words.next().ok_or_else(|| invalid("missing address"))?;
+
+        let (transport, address) = match first.parse::<SocketAddr>() {
+            Ok(address) => (None, address),
+            Err(_) => {
+                let address = words.next().ok_or_else(|| invalid("missing address"))?;
+
+                (Some(String::from(first)), address.parse().map_err(|_| invalid(&format!("{} is not an address", address)))?)
+            },
+        };
+
+        if transport.as_ref().map(|transport| !transport.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')).unwrap_or(false) {
+            return Err(invalid(&format!("{} is not an address or transport name", first)));
+        }
+
+        if !is_bridge_address(&address) {
+            return Err(invalid(&format!("{} can't be a bridge", address)));
+        }
+
+        let mut fingerprint = None;
+        let mut args = Vec::new();
+
+        for (index, word) in words.enumerate() {
+            match word.split_once('=') {
+                Some((key, value)) => args.push((String::from(key), String::from(value))),
+                None if index == 0 => fingerprint = Some(parse_fingerprint(word).ok_or_else(|| invalid(&format!("{} is not a fingerprint", word)))?),
+                None => return Err(invalid(&format!("unexpected {}", word))),
+            }
+        }
+
+        Ok(Self {
+            transport,
+            address,
+            fingerprint,
+            args,
+        })
+    }
+}
+
+impl fmt::Display for BridgeLine {
+    ///The line without the `Bridge` keyword, as Tor's `Bridge` option takes it
+    fn fmt(& self, f: & mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(transport) = &self.transport {
+            write!(f, "{} ", transport)?;
+        }
+
+        write!(f, "{}", self.address)?;
+
+        if let Some(fingerprint) = &self.fingerprint {
+            write!(f, " {}", encode_fingerprint(fingerprint))?;
+        }
+
+        for (key, value) in &self.args {
+            write!(f, " {}={}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+///Whether `signature`, the `router-signature` object, is the PKCS#1 v1.5 signature with `signing_key` of the SHA-1 digest
+///of the descriptor from the start of its `router` line to the end of its `router-signature` line
+fn verify_router_signature(document: &str, signing_key: &[u8], signature: &[u8]) -> bool {
+    let (start, end) = match (document.find("router "), document.find("\nrouter-signature\n")) {
+        (Some(start), Some(end)) if start < end => (start, end + "\nrouter-signature\n".len()),
+        _ => return false,
+    };
+
+    let key = match RsaPublicKey::from_pkcs1_der(signing_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+
+    key.verify(Pkcs1v15Sign::new_unprefixed(), &Sha1::digest(&document.as_bytes()[start..end]), signature).is_ok()
+}
+
+///Parse the descriptor a bridge at `address` served for itself. Its fingerprint must be the digest of its signing key,
+///which must have signed it, and if the bridge line names a `fingerprint` it must be that one
+pub fn parse_bridge_descriptor(document: &str, address: SocketAddr, fingerprint: Option<[u8; 20]>) -> Result<RelayInfo> {
+    let items = netdoc::parse_items(document)?;
+
+    let invalid = |reason: &str| ErrorKind::InvalidDocument(format!("bad bridge descriptor: {}", reason));
+
+    match items.first() {
+        Some(item) if item.keyword == "router" => {},
+        _ => return Err(invalid("not a server descriptor")),
+    }
+
+    let signing_key = netdoc::find(&items, "signing-key")?.object()?;
+
+    let node_id: [u8; 20] = Sha1::digest(signing_key).into();
+
+    if parse_fingerprint(&netdoc::find(&items, "fingerprint")?.arguments.concat()) != Some(node_id) {
+        return Err(invalid("fingerprint is not the digest of the signing key"));
+    }
+
+    if !verify_router_signature(document, signing_key, netdoc::find(&items, "router-signature")?.object()?) {
+        return Err(invalid("router-signature does not verify with the signing key"));
+    }
+
+    if let Some(fingerprint) = fingerprint.filter(|fingerprint| *fingerprint != node_id) {
+        return Err(ErrorKind::BridgeMismatch(format!("{} is {}, not {}", address, encode_fingerprint(&node_id), encode_fingerprint(&fingerprint))));
+    }
+
+    let decode_key = |item: &Item| -> Result<[u8; 32]> {
+        netdoc::decode_base64(item.argument(0)?)?.as_slice().try_into().map_err(|_| invalid(&format!("bad {}", item.keyword)))
+    };
+
+    let ntor_onion_key = decode_key(netdoc::find(&items, "ntor-onion-key")?)?;
+
+    let ed25519_id = match items.iter().find(|item| item.keyword == "master-key-ed25519") {
+        Some(item) => Some(decode_key(item)?),
+        None => None,
+    };
+
+    let mut link_specifiers = vec![match address {
+        SocketAddr::V4(address) => LinkSpecifier::ipv4(address),
+        SocketAddr::V6(address) => LinkSpecifier::ipv6(address),
+    }];
+
+    link_specifiers.push(LinkSpecifier::legacy_id(node_id));
+    link_specifiers.extend(ed25519_id.map(LinkSpecifier::ed25519_id));
+
+    Ok(RelayInfo {
+        node_id,
+        ed25519_id,
+        ntor_onion_key,
+        link_specifiers,
+    })
+}
+
+///Whether we could reach a bridge the last time we tried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reachability {
+    ///We haven't tried it yet
+    Unknown,
+    Reachable,
+    ///The last attempt failed. Contains how many attempts in a row have failed
+    Unreachable(u32),
+}
+
+struct BridgeEntry {
+    line: BridgeLine,
+    descriptor: Option<RelayInfo>,
+    reachability: Reachability,
+    ///Unix time of the last attempt, successful or not
+    last_attempt: Option<i64>,
+    channel: Option<Arc<Channel>>,
+}
+
+impl BridgeEntry {
+    ///Whether the backoff after the bridge's failures is over at unix time `now`
+    fn ready(& self, now: i64) -> bool {
+        match (self.reachability, self.last_attempt) {
+            (Reachability::Unreachable(failures), Some(last_attempt)) => {
+                let backoff = RETRY_INITIAL.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(RETRY_MAX);
+
+                now >= last_attempt + backoff
+            },
+            _ => true,
+        }
+    }
+}
+
+///The configured bridges and what we know of them
+pub struct Bridges {
+    entries: Mutex<Vec<BridgeEntry>>,
+    transports: Option<Arc<TransportManager>>,
+}
+
+impl Bridges {
+    ///Bridges without pluggable transports, so only direct bridges can be used
+    pub fn new(lines: Vec<BridgeLine>) -> Self {
+        Self {
+            entries: Mutex::new(lines.into_iter().map(|line| BridgeEntry {
+                line,
+                descriptor: None,
+                reachability: Reachability::Unknown,
+                last_attempt: None,
+                channel: None,
+            }).collect()),
+            transports: None,
+        }
+    }
+
+    ///Bridges that reach those with a transport through the proxies of `transports`
+    pub fn with_transports(lines: Vec<BridgeLine>, transports: Arc<TransportManager>) -> Self {
+        Self {
+            transports: Some(transports),
+            ..Self::new(lines)
+        }
+    }
+
+    ///The bridges in the `Bridge` lines of a torrc-style configuration, ignoring every other option
+    pub fn from_config(config: &str) -> Result<Self> {
+        let lines = config.lines()
+            .map(|line| line.trim())
+            .filter(|line| line.split_whitespace().next() == Some("Bridge"))
+            .map(|line| line.parse())
+            .collect::<Result<Vec<BridgeLine>>>()?;
+
+        Ok(Self::new(lines))
+    }
+
+    pub fn lines(& self) -> Vec<BridgeLine> {
+        self.entries.lock().unwrap().iter().map(|entry| entry.line.clone()).collect()
+    }
+
+    pub fn reachability(& self, address: &SocketAddr) -> Option<Reachability> {
+        self.entries.lock().unwrap().iter().find(|entry| entry.line.address == *address).map(|entry| entry.reachability)
+    }
+
+    ///The relay a bridge's descriptor described, once it has been fetched
+    pub fn descriptor(& self, address: &SocketAddr) -> Option<RelayInfo> {
+        self.entries.lock().unwrap().iter().find(|entry| entry.line.address == *address).and_then(|entry| entry.descriptor.clone())
+    }
+
+    ///Fetch the descriptor of every bridge we don't have one for and whose backoff is over, returning how many bridges
+    ///we have descriptors for
+    pub fn fetch_descriptors(& self) -> usize {
+        let count = self.entries.lock().unwrap().len();
+
+        for index in 0..count {
+            let due = {
+                let entries = self.entries.lock().unwrap();
+
+                entries[index].descriptor.is_none() && entries[index].ready(chrono::Utc::now().timestamp())
+            };
+
+            if due {
+                let _ = self.fetch_descriptor(index);
+            }
+        }
+
+        self.entries.lock().unwrap().iter().filter(|entry| entry.descriptor.is_some()).count()
+    }
+
+    ///Build a circuit through a bridge and on to each relay of `path`. Bridges that were reachable are tried first and
+    ///unreachable ones are skipped until their backoff is over
+    pub fn build_circuit(& self, path: &[RelayInfo]) -> Result<Circuit> {
+        let now = chrono::Utc::now().timestamp();
+
+        let mut candidates: Vec<(usize, Reachability)> = self.entries.lock().unwrap().iter().enumerate()
+            .filter(|(_, entry)| entry.ready(now))
+            .map(|(index, entry)| (index, entry.reachability))
+            .collect();
+
+        candidates.sort_by_key(|(_, reachability)| match reachability {
+            Reachability::Reachable => 0,
+            Reachability::Unknown => 1,
+            Reachability::Unreachable(_) => 2,
+        });
+
+        let mut last_error = ErrorKind::NoUsableBridge;
+
+        for (index, _) in candidates {
+            let first_hop = self.entries.lock().unwrap()[index].descriptor.clone();
+
+            let first_hop = match first_hop {
+                Some(relay) => relay,
+                None => match self.fetch_descriptor(index) {
+                    Ok(relay) => relay,
+                    Err(error) => {
+                        last_error = error;
+                        continue;
+                    },
+                },
+            };
+
+            let circuit = match self.channel(index).and_then(|channel| channel.create_ntor(&first_hop.node_id, &first_hop.ntor_onion_key)) {
+                Ok(circuit) => circuit,
+                Err(error) => {
+                    self.record(index, false);
+                    last_error = error;
+                    continue;
+                },
+            };
+
+            self.record(index, true);
+
+            //The bridge did its part, so a failure further along is not held against it
+            for relay in path {
+                circuit.extend_ntor(relay.link_specifiers.clone(), &relay.node_id, &relay.ntor_onion_key)?;
+            }
+
+            return Ok(circuit);
+        }
+
+        Err(last_error)
+    }
+
+    ///Write what we know of each bridge's reachability to `path`, one `bridge <address> <state> <failures> <last attempt>`
+    ///line per bridge
+    pub fn save_state(& self, path: &Path) -> Result<()> {
+        let mut state = String::new();
+
+        for entry in self.entries.lock().unwrap().iter() {
+            let (name, failures) = match entry.reachability {
+                Reachability::Unknown => ("unknown", 0),
+                Reachability::Reachable => ("reachable", 0),
+                Reachability::Unreachable(failures) => ("unreachable", failures),
+            };
+
+            state.push_str(&format!("bridge {} {} {} {}\n", entry.line.address, name, failures, entry.last_attempt.unwrap_or(0)));
+        }
+
+        fs::write(path, state)?;
+
+        Ok(())
+    }
+
+    ///Restore the reachability saved by `save_state` for the bridges we still have. Other lines are ignored
+    pub fn load_state(& self, path: &Path) -> Result<()> {
+        let state = fs::read_to_string(path)?;
+
+        let mut entries = self.entries.lock().unwrap();
+
+        for line in state.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            let (address, reachability, last_attempt) = match words.as_slice() {
+                ["bridge", address, state, failures, last_attempt] => {
+                    let reachability = match (*state, failures.parse::<u32>()) {
+                        ("reachable", _) => Reachability::Reachable,
+                        ("unreachable", Ok(failures)) => Reachability::Unreachable(failures),
+                        _ => Reachability::Unknown,
+                    };
+
+                    (address.parse::<SocketAddr>(), reachability, last_attempt.parse::<i64>().ok().filter(|time| *time > 0))
+                },
+                _ => continue,
+            };
+
+            if let Some(entry) = address.ok().and_then(|address| entries.iter_mut().find(|entry| entry.line.address == address)) {
+                entry.reachability = reachability;
+                entry.last_attempt = last_attempt;
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Fetch the descriptor of the bridge at `index` and check it is for the relay the bridge line names
+    fn fetch_descriptor(& self, index: usize) -> Result<RelayInfo> {
+        let line = self.entries.lock().unwrap()[index].line.clone();
+
+        let channel = match self.channel(index) {
+            Ok(channel) => channel,
+            Err(error) => {
+                self.record(index, false);
+                return Err(error);
+            },
+        };
+
+        let fetched = channel.create_fast()
+            .and_then(|circuit| onion::http_get(& mut circuit.begin_dir()?, BRIDGE_DESCRIPTOR_PATH))
+            .and_then(|body| parse_bridge_descriptor(&String::from_utf8_lossy(&body), line.address, line.fingerprint));
+
+        let relay = match fetched {
+            Ok(relay) => relay,
+            Err(error) => {
+                self.record(index, false);
+                return Err(error);
+            },
+        };
+
+        self.entries.lock().unwrap()[index].descriptor = Some(relay.clone());
+
+        self.record(index, true);
+
+        Ok(relay)
+    }
+
+    ///The open channel to the bridge at `index`, connecting if there isn't one
+    fn channel(& self, index: usize) -> Result<Arc<Channel>> {
+        let line = {
+            let entries = self.entries.lock().unwrap();
+
+            match &entries[index].channel {
+                Some(channel) if !channel.is_closed() => return Ok(channel.clone()),
+                _ => entries[index].line.clone(),
+            }
+        };
+
+        let channel = match &line.transport {
+            None => Arc::new(Channel::connect(line.address)?),
+            Some(transport) => match &self.transports {
+                Some(transports) if transports.supports(transport) => {
+                    let stream = transports.connect(transport, line.address, &line.args)?;
+
+                    Arc::new(Channel::connect_over(stream, line.address.ip())?)
+                },
+                _ if transport == obfs4::TRANSPORT_NAME => {
+                    let stream = obfs4::dial(line.address, &obfs4::ClientArgs::from_args(&line.args)?)?;
+
+                    Arc::new(Channel::connect_over(stream, line.address.ip())?)
+                },
+                _ => return Err(ErrorKind::UnsupportedTransport(transport.clone())),
+            },
+        };
+
+        self.entries.lock().unwrap()[index].channel = Some(channel.clone());
+
+        Ok(channel)
+    }
+
+    fn record(& self, index: usize, reached: bool) {
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = & mut entries[index];
+
+        entry.last_attempt = Some(chrono::Utc::now().timestamp());
+
+        entry.reachability = match (reached, entry.reachability) {
+            (true, _) => Reachability::Reachable,
+            (false, Reachability::Unreachable(failures)) => Reachability::Unreachable(failures + 1),
+            (false, _) => Reachability::Unreachable(1),
+        };
+
+        if !reached {
+            entry.channel = None;
+        }
+    }
+}
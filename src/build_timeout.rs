@@ -0,0 +1,474 @@
+//! Learning how long to wait for a circuit to build, as Tor's circuit build timeout (CBT) does.
+//!
+//! The time each circuit takes to build is kept, up to the last 1000. Once there are enough, they are fitted to a
+//! Pareto distribution: the scale `Xm` is the mean of the most common 10ms bins, and the shape `alpha` is the maximum
+//! likelihood estimate with abandoned circuits counted as censored at the longest build seen. Circuits that take longer
+//! than the 80th percentile are not used, but they are kept building until the 99th percentile so their times can still
+//! be measured. Only those that haven't finished by then are abandoned.
+//!
+//! If nothing at all comes back from the network while circuits time out, the network is probably down rather than
+//! slow, so those timeouts aren't learned from and the timeout goes back to its initial value. If nearly all recent
+//! circuits time out after their first hop, the network has probably changed and everything learned is thrown away.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::channel::Channel;
+use crate::circuit::Circuit;
+use crate::error::{ErrorKind, Result};
+use crate::onion::RelayInfo;
+
+///How many build times are kept
+pub const CBT_NCIRCUITS_TO_OBSERVE: usize = 1000;
+
+///The width of the histogram bins, in milliseconds
+pub const CBT_BIN_WIDTH: u32 = 10;
+
+///How many of the most common bins `Xm` is taken from
+pub const CBT_NUM_XM_MODES: usize = 10;
+
+///How many build times are needed before the timeout is learned
+pub const CBT_MIN_CIRCUITS_TO_OBSERVE: usize = 100;
+
+///The quantile circuits time out at
+pub const CBT_QUANTILE_CUTOFF: f64 = 0.8;
+
+///The quantile circuits are abandoned at
+pub const CBT_CLOSE_QUANTILE: f64 = 0.99;
+
+///The timeout before anything has been learned, in milliseconds
+pub const CBT_TIMEOUT_INITIAL_VALUE: u32 = 60 * 1000;
+
+///The shortest timeout that may be learned, in milliseconds
+pub const CBT_TIMEOUT_MIN_VALUE: u32 = 10;
+
+///The longest build time that is kept, and the longest the timeout may grow to, in milliseconds. Longer times are
+///clamped to it so the histogram stays small
+pub const CBT_BUILD_TIME_MAX: u32 = 10 * CBT_TIMEOUT_INITIAL_VALUE;
+
+///How many recent circuits are watched for timeouts after the first hop
+pub const CBT_RECENT_CIRCUITS: usize = 20;
+
+///How many of the recent circuits may time out after their first hop before everything learned is reset
+pub const CBT_MAX_RECENT_TIMEOUT_COUNT: usize = 18;
+
+///How many timeouts in a row with no sign of the network mean it's down
+pub const CBT_NETWORK_NONLIVE_TIMEOUT_COUNT: u32 = 3;
+
+///The name of the file `save` and `load` use in the state directory
+pub const CBT_STATE_FILE: &str = "cbt_state";
+
+///How long a circuit took to build, or that it was abandoned at the close timeout
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BuildTime {
+    Completed(u32),
+    Abandoned,
+}
+
+#[derive(Debug)]
+struct BuildTimesState {
+    times: VecDeque<BuildTime>,
+    timeout_ms: f64,
+    close_ms: f64,
+    ///The fitted `Xm` and `alpha`, once there have been enough builds
+    fit: Option<(f64, f64)>,
+    ///Whether each recent circuit timed out after its first hop
+    recent: VecDeque<bool>,
+    last_live: Option<Instant>,
+    nonlive_timeouts: u32,
+}
+
+impl BuildTimesState {
+    fn new() -> Self {
+        Self {
+            times: VecDeque::with_capacity(CBT_NCIRCUITS_TO_OBSERVE),
+            timeout_ms: CBT_TIMEOUT_INITIAL_VALUE as f64,
+            close_ms: CBT_TIMEOUT_INITIAL_VALUE as f64,
+            fit: None,
+            recent: VecDeque::with_capacity(CBT_RECENT_CIRCUITS),
+            last_live: None,
+            nonlive_timeouts: 0,
+        }
+    }
+
+    fn add(& mut self, time: BuildTime) {
+        if self.times.len() == CBT_NCIRCUITS_TO_OBSERVE {
+            self.times.pop_front();
+        }
+
+        self.times.push_back(time);
+
+        self.recompute();
+    }
+
+    fn completed(& self) -> impl Iterator<Item = u32> + '_ {
+        self.times.iter().filter_map(|time| match time {
+            BuildTime::Completed(ms) => Some(*ms),
+            BuildTime::Abandoned => None,
+        })
+    }
+
+    ///Completed builds in each bin, indexed by bin
+    fn histogram(& self) -> Vec<u32> {
+        let mut bins = Vec::new();
+
+        for ms in self.completed() {
+            let bin = (ms / CBT_BIN_WIDTH) as usize;
+
+            if bins.len() <= bin {
+                bins.resize(bin + 1, 0);
+            }
+
+            bins[bin] += 1;
+        }
+
+        bins
+    }
+
+    ///The mean of the midpoints of the most common bins, weighted by their counts
+    fn xm(& self) -> Option<f64> {
+        let histogram = self.histogram();
+
+        let mut modes: Vec<usize> = (0..histogram.len()).filter(|bin| histogram[*bin] > 0).collect();
+
+        //The most common first, and the faster of equally common bins
+        modes.sort_by(|a, b| histogram[*b].cmp(&histogram[*a]).then(a.cmp(b)));
+        modes.truncate(CBT_NUM_XM_MODES);
+
+        let count: u32 = modes.iter().map(|bin| histogram[*bin]).sum();
+
+        if count == 0 {
+            return None;
+        }
+
+        let total: f64 = modes.iter().map(|bin| bin_midpoint(*bin) as f64 * histogram[*bin] as f64).sum();
+
+        Some(total / count as f64)
+    }
+
+    ///Fit the Pareto distribution and set the timeouts from it, if there are enough build times
+    fn recompute(& mut self) {
+        if self.times.len() < CBT_MIN_CIRCUITS_TO_OBSERVE {
+            return;
+        }
+
+        let xm = match self.xm() {
+            Some(xm) => xm,
+            None => return,
+        };
+
+        let max_time = self.completed().max().unwrap_or(0) as f64;
+
+        let abandoned = self.times.iter().filter(|time| **time == BuildTime::Abandoned).count() as f64;
+
+        //Times below Xm are treated as Xm, which makes the samples a little more Pareto-like
+        let mut sum: f64 = self.completed().map(|ms| (ms as f64).max(xm).ln()).sum();
+
+        let completed = self.times.len() as f64 - abandoned;
+
+        sum += abandoned * max_time.max(xm).ln();
+        sum -= (completed + abandoned) * xm.ln();
+
+        if sum <= 0.0 || completed == 0.0 {
+            return;
+        }
+
+        let alpha = completed / sum;
+
+        self.fit = Some((xm, alpha));
+
+        self.timeout_ms = pareto_quantile(xm, alpha, CBT_QUANTILE_CUTOFF).max(CBT_TIMEOUT_MIN_VALUE as f64);
+        self.close_ms = pareto_quantile(xm, alpha, CBT_CLOSE_QUANTILE).max(self.timeout_ms);
+    }
+
+    ///Forget every build time and start again from `timeout_ms`
+    fn reset(& mut self, timeout_ms: f64) {
+        self.times.clear();
+        self.recent.clear();
+        self.fit = None;
+        self.timeout_ms = timeout_ms;
+        self.close_ms = timeout_ms;
+    }
+
+    ///Record whether a circuit timed out after its first hop, resetting if nearly all recent ones did
+    fn record_recent(& mut self, timed_out_after_first_hop: bool) {
+        if self.recent.len() == CBT_RECENT_CIRCUITS {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(timed_out_after_first_hop);
+
+        if self.recent.iter().filter(|timed_out| **timed_out).count() >= CBT_MAX_RECENT_TIMEOUT_COUNT {
+            let timeout_ms = (self.timeout_ms * 2.0).max(CBT_TIMEOUT_INITIAL_VALUE as f64).min(CBT_BUILD_TIME_MAX as f64);
+
+            self.reset(timeout_ms);
+        }
+    }
+}
+
+///Append `count` of `time` to `times`, keeping only the last `CBT_NCIRCUITS_TO_OBSERVE` however large `count` is
+fn add_times(times: & mut VecDeque<BuildTime>, time: BuildTime, count: usize) {
+    let count = count.min(CBT_NCIRCUITS_TO_OBSERVE);
+
+    let excess = (times.len() + count).saturating_sub(CBT_NCIRCUITS_TO_OBSERVE);
+
+    times.drain(..excess);
+    times.resize(times.len() + count, time);
+}
+
+///The middle of a histogram bin, in milliseconds
+fn bin_midpoint(bin: usize) -> u32 {
+    bin as u32 * CBT_BIN_WIDTH + CBT_BIN_WIDTH / 2
+}
+
+///The time below which `quantile` of builds finish
+fn pareto_quantile(xm: f64, alpha: f64, quantile: f64) -> f64 {
+    xm / (1.0 - quantile).powf(1.0 / alpha)
+}
+
+///Learned circuit build timeouts, shared by everything that builds circuits
+#[derive(Debug)]
+pub struct CircuitBuildTimes {
+    ///Shared with the threads that keep building circuits past the timeout
+    state: Arc<Mutex<BuildTimesState>>,
+}
+
+impl Default for CircuitBuildTimes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CircuitBuildTimes {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BuildTimesState::new())),
+        }
+    }
+
+    ///How long a circuit may take to build before we stop waiting to use it
+    pub fn timeout(& self) -> Duration {
+        Duration::from_millis(self.state.lock().unwrap().timeout_ms as u64)
+    }
+
+    ///How long a circuit is kept building, to measure it, before it is abandoned
+    pub fn close_timeout(& self) -> Duration {
+        Duration::from_millis(self.state.lock().unwrap().close_ms as u64)
+    }
+
+    ///The fitted `Xm` in milliseconds and `alpha`, once enough circuits have been built
+    pub fn pareto(& self) -> Option<(f64, f64)> {
+        self.state.lock().unwrap().fit
+    }
+
+    ///How many build times are kept, abandoned ones included
+    pub fn len(& self) -> usize {
+        self.state.lock().unwrap().times.len()
+    }
+
+    pub fn is_empty(& self) -> bool {
+        self.len() == 0
+    }
+
+    ///Record a circuit that finished building in `time`, whether or not it timed out first
+    pub fn add_time(& self, time: Duration) {
+        let ms = time.as_millis().min(CBT_BUILD_TIME_MAX as u128) as u32;
+
+        self.state.lock().unwrap().add(BuildTime::Completed(ms));
+    }
+
+    ///Record a circuit abandoned at the close timeout
+    pub fn add_abandoned(& self) {
+        self.state.lock().unwrap().add(BuildTime::Abandoned);
+    }
+
+    ///Something arrived from the network, so it isn't down
+    pub fn network_is_live(& self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.last_live = Some(Instant::now());
+
+        if state.nonlive_timeouts >= CBT_NETWORK_NONLIVE_TIMEOUT_COUNT {
+            state.recompute();
+        }
+
+        state.nonlive_timeouts = 0;
+    }
+
+    ///Whether the network has seemed down since the last sign of it
+    pub fn network_is_down(& self) -> bool {
+        self.state.lock().unwrap().nonlive_timeouts >= CBT_NETWORK_NONLIVE_TIMEOUT_COUNT
+    }
+
+    ///A circuit started at `started` timed out. Returns whether the network was live, and so whether the timeout says
+    ///anything about how long circuits take. If nothing has arrived from the network since `started`, the timeout counts
+    ///towards the network being down, which puts the timeout back to its initial value
+    pub fn network_timeout(& self, started: Instant, after_first_hop: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let live = state.last_live.map(|last_live| last_live >= started).unwrap_or(false);
+
+        if !live {
+            state.nonlive_timeouts += 1;
+
+            if state.nonlive_timeouts == CBT_NETWORK_NONLIVE_TIMEOUT_COUNT {
+                state.timeout_ms = CBT_TIMEOUT_INITIAL_VALUE as f64;
+                state.close_ms = CBT_TIMEOUT_INITIAL_VALUE as f64;
+            }
+
+            return false;
+        }
+
+        state.record_recent(after_first_hop);
+
+        true
+    }
+
+    ///A circuit finished building within the timeout
+    pub fn network_succeeded(& self) {
+        self.state.lock().unwrap().record_recent(false);
+    }
+
+    ///Build a circuit from `first_hop`, the relay at the other end of `channel`, through `path`. A circuit that isn't
+    ///built by `timeout` fails with `ErrorKind::Timeout` then, but is left building in the background until
+    ///`close_timeout` so that its time can be learned from
+    pub fn build(& self, channel: &Arc<Channel>, first_hop: &RelayInfo, path: &[RelayInfo]) -> Result<Circuit> {
+        let started = Instant::now();
+        let timeout = self.timeout();
+
+        let (sender, receiver) = mpsc::channel();
+
+        let times = CircuitBuildTimes { state: self.state.clone() };
+        let channel = channel.clone();
+        let first_hop = first_hop.clone();
+        let path = path.to_vec();
+
+        thread::spawn(move || {
+            let _ = sender.send(times.build_until(&channel, &first_hop, &path, started, timeout));
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => Err(ErrorKind::Timeout),
+        }
+    }
+
+    ///Build the circuit for `build`, giving up at `close_timeout` and recording how long it took
+    fn build_until(& self, channel: &Channel, first_hop: &RelayInfo, path: &[RelayInfo], started: Instant, timeout: Duration) -> Result<Circuit> {
+        let deadline = Some(started + self.close_timeout());
+
+        let abandon = |after_first_hop: bool, error: ErrorKind| {
+            if let ErrorKind::Timeout = error {
+                if self.network_timeout(started, after_first_hop) {
+                    self.add_abandoned();
+                }
+            }
+
+            error
+        };
+
+        let circuit = channel.create_ntor_until(&first_hop.node_id, &first_hop.ntor_onion_key, deadline).map_err(|error| abandon(false, error))?;
+
+        self.network_is_live();
+
+        for relay in path {
+            circuit.extend_ntor_until(relay.link_specifiers.clone(), &relay.node_id, &relay.ntor_onion_key, deadline).map_err(|error| abandon(true, error))?;
+
+            self.network_is_live();
+        }
+
+        let elapsed = started.elapsed();
+
+        self.add_time(elapsed);
+
+        if elapsed > timeout {
+            self.network_timeout(started, !path.is_empty());
+
+            return Err(ErrorKind::Timeout);
+        }
+
+        self.network_succeeded();
+
+        Ok(circuit)
+    }
+
+    ///The build times as Tor keeps them in its state file: `TotalBuildTimes`, `CircuitBuildAbandonedCount` and a
+    ///`CircuitBuildTimeBin <midpoint>,<count>` line for each bin that isn't empty
+    pub fn to_state(& self) -> String {
+        let state = self.state.lock().unwrap();
+
+        let abandoned = state.times.iter().filter(|time| **time == BuildTime::Abandoned).count();
+
+        let mut text = format!("TotalBuildTimes {}\nCircuitBuildAbandonedCount {}\n", state.times.len(), abandoned);
+
+        for (bin, count) in state.histogram().into_iter().enumerate().filter(|(_, count)| *count > 0) {
+            text.push_str(&format!("CircuitBuildTimeBin {},{}\n", bin_midpoint(bin), count));
+        }
+
+        text
+    }
+
+    ///Build times from `to_state`, each completed build taking the midpoint of its bin. Other lines are ignored
+    pub fn from_state(text: &str) -> Result<Self> {
+        let invalid = |line: &str| ErrorKind::InvalidDocument(format!("bad build time state line {}", line));
+
+        let mut times = VecDeque::with_capacity(CBT_NCIRCUITS_TO_OBSERVE);
+
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+
+            match (words.next(), words.next()) {
+                (Some("CircuitBuildAbandonedCount"), Some(count)) => {
+                    let count = count.parse::<usize>().map_err(|_| invalid(line))?;
+
+                    add_times(& mut times, BuildTime::Abandoned, count);
+                },
+                (Some("CircuitBuildTimeBin"), Some(bin)) => {
+                    let (ms, count) = bin.split_once(',').ok_or_else(|| invalid(line))?;
+
+                    let ms = ms.parse::<u32>().map_err(|_| invalid(line))?;
+                    let count = count.parse::<usize>().map_err(|_| invalid(line))?;
+
+                    if ms > CBT_BUILD_TIME_MAX {
+                        return Err(invalid(line));
+                    }
+
+                    add_times(& mut times, BuildTime::Completed(ms), count);
+                },
+                _ => {},
+            }
+        }
+
+        let mut state = BuildTimesState::new();
+
+        state.times = times;
+        state.recompute();
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    ///Write the build times to `cbt_state` in `directory`
+    pub fn save(& self, directory: &Path) -> Result<()> {
+        fs::create_dir_all(directory)?;
+
+        fs::write(directory.join(CBT_STATE_FILE), self.to_state())?;
+
+        Ok(())
+    }
+
+    ///The build times saved in `directory`, or none if nothing has been saved there
+    pub fn load(directory: &Path) -> Result<Self> {
+        match fs::read_to_string(directory.join(CBT_STATE_FILE)) {
+            Ok(text) => Self::from_state(&text),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
@@ -1,21 +1,153 @@
 
 use sha1::Sha1;
 use sha1::Digest;
+use sha3::Sha3_256;
 use std::io::Write;
 
 use ctr::cipher::{NewCipher, StreamCipher};
 use std::convert::TryInto;
 use crate::cells::{RelayCell, Encrypted};
+use crate::ntor::NTOR_KEY_MATERIAL_LENGTH;
+use crate::relay_format::{self, RelayCellFormat, Reassembler, V1_DATA_LEN};
 use torserde::TorSerde;
 
 type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+///The running digest of a hop. Onion service hops use SHA3-256 where other hops use SHA1
+#[derive(Clone)]
+enum RunningDigest {
+    Sha1(Sha1),
+    Sha3(Sha3_256),
+}
+
+impl RunningDigest {
+    fn update(& mut self, data: &[u8]) {
+        match self {
+            RunningDigest::Sha1(digest) => Digest::update(digest, data),
+            RunningDigest::Sha3(digest) => Digest::update(digest, data),
+        }
+    }
+
+    ///The digest of everything so far, leaving the running digest untouched
+    fn current(& self) -> Vec<u8> {
+        match self {
+            RunningDigest::Sha1(digest) => digest.clone().finalize().to_vec(),
+            RunningDigest::Sha3(digest) => digest.clone().finalize().to_vec(),
+        }
+    }
+}
+
+impl Write for RunningDigest {
+    fn write(& mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(& mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+///The stream cipher of a hop. Onion service hops use AES-256 where other hops use AES-128
+enum KeyStream {
+    Aes128(Aes128Ctr),
+    Aes256(Aes256Ctr),
+}
+
+impl KeyStream {
+    fn apply_keystream(& mut self, data: & mut [u8]) {
+        match self {
+            KeyStream::Aes128(cipher) => cipher.apply_keystream(data),
+            KeyStream::Aes256(cipher) => cipher.apply_keystream(data),
+        }
+    }
+}
+
+///The relay crypto of one hop of a circuit, as the client sees it, chosen when the hop's handshake is made. Only tor1
+///(`CellCrypto`) implements it for now: CGO (`cgo::CgoCrypto`) tweaks every cell with its command, which isn't passed here
+pub trait RelayCrypto: Send {
+    ///Lay out a relay cell addressed to this hop in format v0, mark it as ours and apply this hop's layer of forward encryption
+    fn originate(& mut self, relay: RelayCell) -> torserde::Result<[u8; 509]>;
+
+    ///Mark a body whose first 16 bytes are left for the relay crypto, as in format v1, as ours and apply this hop's layer
+    ///of forward encryption
+    fn originate_body(& mut self, payload: & mut [u8; 509]);
+
+    ///Apply this hop's layer of forward encryption to a cell for a later hop
+    fn encrypt_layer(& mut self, payload: & mut [u8; 509]);
+
+    ///Remove this hop's layer of backward encryption
+    fn decrypt_layer(& mut self, payload: & mut [u8; 509]);
+
+    ///Check whether a decrypted cell originated at this hop
+    fn recognise(& mut self, payload: &[u8; 509]) -> bool;
+
+    ///Read the relay cell out of a format v0 cell this hop recognised
+    fn open(& self, payload: &[u8; 509]) -> torserde::Result<RelayCell>;
+
+    ///The most data a format v0 DATA cell to or from this hop carries
+    fn max_data_len(& self) -> usize;
+
+    ///What authenticated (version 1) SENDMEs to this hop echo back
+    fn backward_digest(& self) -> [u8; 20];
+
+    ///KH from the handshake that created this hop
+    fn nonce(& self) -> Option<[u8; 20]>;
+}
+
+///Which relay crypto a hop uses. Both ends have to agree on it before the hop's handshake. CGO isn't offered until the
+///ntor v3 handshake and congestion control it is negotiated alongside are implemented
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RelayCryptoKind {
+    ///AES-128-CTR with a running SHA-1 digest, as every relay supports
+    #[default]
+    Tor1,
+}
+
+///How a hop protects and lays out its relay cells. Both ends settle it before the hop's handshake, as Tor does in the
+///ntor v3 handshake, which isn't implemented here
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HopProtocol {
+    pub crypto: RelayCryptoKind,
+    pub format: RelayCellFormat,
+}
+
+///The default format for `crypto`: v0 for tor1
+impl From<RelayCryptoKind> for HopProtocol {
+    fn from(crypto: RelayCryptoKind) -> Self {
+        let format = match crypto {
+            RelayCryptoKind::Tor1 => RelayCellFormat::V0,
+        };
+
+        Self { crypto, format }
+    }
+}
+
+impl RelayCryptoKind {
+    ///The length of the ntor key material the hop's keys and KH are taken from
+    pub fn key_material_length(& self) -> usize {
+        match self {
+            RelayCryptoKind::Tor1 => NTOR_KEY_MATERIAL_LENGTH,
+        }
+    }
+
+    ///The crypto for a hop from `key_material_length` bytes of ntor key material: the keys, then KH
+    pub fn hop(& self, materials: &[u8]) -> Box<dyn RelayCrypto> {
+        match self {
+            RelayCryptoKind::Tor1 => Box::new(CellCrypto::from(&materials[0..72].try_into().unwrap()).with_nonce(&materials[72..92])),
+        }
+    }
+}
 
 //An object to handle the forward encryption, backward decryption and verification of relay cells
 pub struct CellCrypto {
-    forward_digest: Sha1,
-    backward_digest: Sha1,
-    forward_encryptor: Aes128Ctr,
-    backward_decryptor: Aes128Ctr,
+    forward_digest: RunningDigest,
+    backward_digest: RunningDigest,
+    forward_encryptor: KeyStream,
+    backward_decryptor: KeyStream,
+    ///KH, the nonce from the handshake that onion service cells such as ESTABLISH_INTRO are bound to
+    nonce: Option<[u8; 20]>,
 }
 
 impl From<&[u8; 72]> for CellCrypto {
@@ -42,24 +174,63 @@ impl CellCrypto {
         let forward_encryptor = Aes128Ctr::new(f_key.into(), iv.as_ref().into());
         let backward_decryptor = Aes128Ctr::new(b_key.into(), iv.as_ref().into());
 
-        println!("fkey: {:?}", f_key);
+        Self {
+            forward_digest: RunningDigest::Sha1(forward_digest),
+            backward_digest: RunningDigest::Sha1(backward_digest),
+            forward_encryptor: KeyStream::Aes128(forward_encryptor),
+            backward_decryptor: KeyStream::Aes128(backward_decryptor),
+            nonce: None,
+        }
+
+    }
+
+    ///The crypto for the virtual hop to an onion service, from the 128 bytes Df | Db | Kf | Kb of the hs-ntor handshake
+    ///(rend-spec-v3 section 4.2.1), which uses SHA3-256 digests and AES-256
+    pub fn onion_service(key_materials: &[u8; 128]) -> Self {
+        let mut forward_digest = Sha3_256::new();
+        let mut backward_digest = Sha3_256::new();
+
+        Digest::update(& mut forward_digest, &key_materials[0..32]);
+        Digest::update(& mut backward_digest, &key_materials[32..64]);
+
+        let iv = 0u128.to_be_bytes();
+
+        let forward_key: &[u8; 32] = (&key_materials[64..96]).try_into().unwrap();
+        let backward_key: &[u8; 32] = (&key_materials[96..128]).try_into().unwrap();
 
         Self {
-            forward_digest,
-            backward_digest,
-            forward_encryptor,
-            backward_decryptor,
+            forward_digest: RunningDigest::Sha3(forward_digest),
+            backward_digest: RunningDigest::Sha3(backward_digest),
+            forward_encryptor: KeyStream::Aes256(Aes256Ctr::new(forward_key.into(), iv.as_ref().into())),
+            backward_decryptor: KeyStream::Aes256(Aes256Ctr::new(backward_key.into(), iv.as_ref().into())),
+            nonce: None,
         }
+    }
+
+    ///Keep KH from the handshake that created this hop
+    pub fn with_nonce(mut self, nonce: &[u8]) -> Self {
+        self.nonce = nonce.try_into().ok();
+        self
+    }
+
+    pub fn nonce(& self) -> Option<[u8; 20]> {
+        self.nonce
+    }
+
+    ///Put the running forward digest of a body into its digest field, which must be zero
+    fn seal(& mut self, payload: & mut [u8; 509]) {
+        self.forward_digest.update(payload.as_ref());
+
+        let digest = self.forward_digest.current();
 
+        payload[5..9].copy_from_slice(&digest[0..4]);
     }
 
     pub fn set_forward_digest(& mut self, relay: & mut RelayCell) -> torserde::Result<()> {
 
         relay.bin_serialise_into(& mut self.forward_digest)?;
 
-        let clone = self.forward_digest.clone();
-
-        let digest = clone.finalize();
+        let digest = self.forward_digest.current();
 
         relay.set_digest(u32::from_be_bytes(digest[0..4].try_into().unwrap()));
 
@@ -74,11 +245,7 @@ impl CellCrypto {
 
         relay.bin_serialise_into(& mut self.backward_digest)?;
 
-        let clone = self.backward_digest.clone();
-
-        let calculated_digest = u32::from_be_bytes((&clone.finalize()[0..4]).try_into().unwrap());
-
-        println!("calculated: {}", calculated_digest);
+        let calculated_digest = u32::from_be_bytes((&self.backward_digest.current()[0..4]).try_into().unwrap());
 
         if sent_digest != calculated_digest {
             return Err(torserde::ErrorKind::BadDigest(sent_digest, calculated_digest));
@@ -107,4 +274,210 @@ impl CellCrypto {
 
         RelayCell::bin_deserialise_from(array.as_ref())
     }
-}
\ No newline at end of file
+
+    ///The running backward digest, which authenticated (version 1) SENDMEs echo back to the hop. SHA3-256 digests are truncated to 20 bytes
+    pub fn backward_digest(& self) -> [u8; 20] {
+        (&self.backward_digest.current()[..20]).try_into().unwrap()
+    }
+
+    ///Apply this hop's layer of forward encryption to a serialised relay cell
+    pub fn encrypt_layer(& mut self, payload: & mut [u8; 509]) {
+        self.forward_encryptor.apply_keystream(payload.as_mut());
+    }
+
+    ///Remove this hop's layer of backward encryption from a serialised relay cell
+    pub fn decrypt_layer(& mut self, payload: & mut [u8; 509]) {
+        self.backward_decryptor.apply_keystream(payload.as_mut());
+    }
+
+    ///Check whether a decrypted payload originated at this hop. The backward digest is only updated if it did
+    pub fn recognise(& mut self, payload: &[u8; 509]) -> bool {
+        if payload[1] != 0 || payload[2] != 0 {
+            return false;
+        }
+
+        let mut zeroed = *payload;
+
+        zeroed[5..9].copy_from_slice(&[0u8; 4]);
+
+        let mut digest = self.backward_digest.clone();
+
+        digest.update(zeroed.as_ref());
+
+        if digest.current()[0..4] == payload[5..9] {
+            self.backward_digest = digest;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl RelayCrypto for CellCrypto {
+    fn originate(& mut self, relay: RelayCell) -> torserde::Result<[u8; 509]> {
+        let mut array = [0u8; 509];
+
+        relay.bin_serialise_into(array.as_mut())?;
+
+        self.originate_body(& mut array);
+
+        Ok(array)
+    }
+
+    ///In format v1 the recognized and digest fields stay where they are in v0, inside the 16 bytes left for the relay crypto
+    fn originate_body(& mut self, payload: & mut [u8; 509]) {
+        self.seal(payload);
+
+        CellCrypto::encrypt_layer(self, payload);
+    }
+
+    fn encrypt_layer(& mut self, payload: & mut [u8; 509]) {
+        CellCrypto::encrypt_layer(self, payload);
+    }
+
+    fn decrypt_layer(& mut self, payload: & mut [u8; 509]) {
+        CellCrypto::decrypt_layer(self, payload);
+    }
+
+    fn recognise(& mut self, payload: &[u8; 509]) -> bool {
+        CellCrypto::recognise(self, payload)
+    }
+
+    fn open(& self, payload: &[u8; 509]) -> torserde::Result<RelayCell> {
+        RelayCell::bin_deserialise_from(payload.as_ref())
+    }
+
+    fn max_data_len(& self) -> usize {
+        498
+    }
+
+    fn backward_digest(& self) -> [u8; 20] {
+        CellCrypto::backward_digest(self)
+    }
+
+    fn nonce(& self) -> Option<[u8; 20]> {
+        CellCrypto::nonce(self)
+    }
+}
+
+///One hop of a circuit: its relay crypto, its relay cell format and any message it has only sent part of
+struct Hop {
+    crypto: Box<dyn RelayCrypto>,
+    format: RelayCellFormat,
+    reassembler: Reassembler,
+}
+
+///The relay crypto for every hop of a circuit, in order from the first hop
+#[derive(Default)]
+pub struct CircuitCrypto {
+    hops: Vec<Hop>,
+}
+
+impl CircuitCrypto {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_hop(& mut self, hop: impl RelayCrypto + 'static) {
+        self.add_boxed_hop(Box::new(hop));
+    }
+
+    ///Add a hop whose crypto was chosen at run time, as `RelayCryptoKind::hop` does
+    pub fn add_boxed_hop(& mut self, hop: Box<dyn RelayCrypto>) {
+        self.add_hop_with_format(hop, RelayCellFormat::V0);
+    }
+
+    ///Add a hop whose cells are laid out in `format`
+    pub fn add_hop_with_format(& mut self, hop: Box<dyn RelayCrypto>, format: RelayCellFormat) {
+        self.hops.push(Hop { crypto: hop, format, reassembler: Reassembler::new() });
+    }
+
+    pub fn hop_count(& self) -> usize {
+        self.hops.len()
+    }
+
+    ///Forget every hop after the first `hops`, after the circuit has been truncated
+    pub fn truncate(& mut self, hops: usize) {
+        self.hops.truncate(hops);
+    }
+
+    pub fn backward_digest(& self, hop: usize) -> [u8; 20] {
+        self.hops[hop].crypto.backward_digest()
+    }
+
+    pub fn nonce(& self, hop: usize) -> Option<[u8; 20]> {
+        self.hops[hop].crypto.nonce()
+    }
+
+    ///The most data a DATA message to or from `hop` carries in one cell
+    pub fn max_data_len(& self, hop: usize) -> usize {
+        match self.hops[hop].format {
+            RelayCellFormat::V0 => self.hops[hop].crypto.max_data_len(),
+            RelayCellFormat::V1 => V1_DATA_LEN,
+        }
+    }
+
+    ///Mark a cell as addressed to `hop` and encrypt it once for every hop up to and including it. A message only fits
+    ///in one cell in format v0, so use `encrypt_messages` for hops that may use v1
+    pub fn encrypt(& mut self, hop: usize, relay: RelayCell) -> torserde::Result<Encrypted> {
+        let array = self.hops[hop].crypto.originate(relay)?;
+
+        Ok(self.wrap(hop, array))
+    }
+
+    ///Encrypt `messages` for `hop` in as many cells as its format needs: one each in v0, packed and fragmented in v1
+    pub fn encrypt_messages(& mut self, hop: usize, messages: Vec<RelayCell>) -> torserde::Result<Vec<Encrypted>> {
+        let bodies = match self.hops[hop].format {
+            RelayCellFormat::V0 => {
+                let mut bodies = Vec::with_capacity(messages.len());
+
+                for relay in messages {
+                    bodies.push(self.hops[hop].crypto.originate(relay)?);
+                }
+
+                bodies
+            },
+            RelayCellFormat::V1 => {
+                let mut bodies = relay_format::pack(messages)?;
+
+                for body in bodies.iter_mut() {
+                    self.hops[hop].crypto.originate_body(body);
+                }
+
+                bodies
+            },
+        };
+
+        Ok(bodies.into_iter().map(|array| self.wrap(hop, array)).collect())
+    }
+
+    ///Add the layers of the hops before `hop` to a cell `hop` has encrypted
+    fn wrap(& mut self, hop: usize, mut array: [u8; 509]) -> Encrypted {
+        for layer in self.hops[..hop].iter_mut().rev() {
+            layer.crypto.encrypt_layer(& mut array);
+        }
+
+        Encrypted(array)
+    }
+
+    ///Peel layers off a cell until a hop recognises it, returning the index of that hop and the messages the cell
+    ///completes. A v1 cell may complete none, when it carries part of a longer message, or several
+    pub fn decrypt(& mut self, relay: & Encrypted) -> torserde::Result<(usize, Vec<RelayCell>)> {
+        let mut array = relay.0;
+
+        for (index, layer) in self.hops.iter_mut().enumerate() {
+            layer.crypto.decrypt_layer(& mut array);
+
+            if layer.crypto.recognise(&array) {
+                let messages = match layer.format {
+                    RelayCellFormat::V0 => vec![layer.crypto.open(&array)?],
+                    RelayCellFormat::V1 => layer.reassembler.unpack(&array)?,
+                };
+
+                return Ok((index, messages));
+            }
+        }
+
+        Err(torserde::ErrorKind::BadDigest(u32::from_be_bytes(array[5..9].try_into().unwrap()), 0))
+    }
+}
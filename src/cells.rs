@@ -1,5 +1,6 @@
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::ops::BitOr;
 use chrono::{DateTime, Local};
 
 use torserde_macros::Torserde;
@@ -12,8 +13,11 @@ use ring::rand::SecureRandom;
 
 use crate::misc::UnpackedCell;
 
+///The link protocol versions we can speak, sent in our VERSIONS cell
+pub const SUPPORTED_LINK_VERSIONS: [u16; 3] = [3, 4, 5];
+
 lazy_static!{
-    static ref CSRNG: ring::rand::SystemRandom = ring::rand::SystemRandom::new();
+    pub(crate) static ref CSRNG: ring::rand::SystemRandom = ring::rand::SystemRandom::new();
 }
 
 #[derive(Debug, Torserde)]
@@ -28,6 +32,74 @@ pub struct LinkSpecifier {
     lspec: NLengthVector<u8, 1>
 }
 
+impl LinkSpecifier {
+    pub fn new(ltype: u8, lspec: Vec<u8>) -> Self {
+        Self {
+            ltype,
+            lspec: NLengthVector::from(lspec),
+        }
+    }
+
+    pub fn ipv4(address: SocketAddrV4) -> Self {
+        let mut lspec = Vec::from(address.ip().octets());
+        lspec.extend_from_slice(&address.port().to_be_bytes());
+        Self::new(0, lspec)
+    }
+
+    pub fn ipv6(address: SocketAddrV6) -> Self {
+        let mut lspec = Vec::from(address.ip().octets());
+        lspec.extend_from_slice(&address.port().to_be_bytes());
+        Self::new(1, lspec)
+    }
+
+    ///The SHA1 hash of the relay's RSA identity key
+    pub fn legacy_id(fingerprint: [u8; 20]) -> Self {
+        Self::new(2, Vec::from(fingerprint))
+    }
+
+    pub fn ed25519_id(identity: [u8; 32]) -> Self {
+        Self::new(3, Vec::from(identity))
+    }
+
+    pub fn get_type(& self) -> u8 {
+        self.ltype
+    }
+
+    pub fn get_spec(& self) -> &[u8] {
+        &self.lspec.0
+    }
+}
+
+impl Clone for LinkSpecifier {
+    fn clone(& self) -> Self {
+        Self::new(self.ltype, self.lspec.0.clone())
+    }
+}
+
+///An extension in the onion service cells (ESTABLISH_INTRO, INTRODUCE1, INTRODUCE_ACK and so on)
+#[derive(Debug, Torserde)]
+pub struct HsExtension {
+    ext_type: u8,
+    data: NLengthVector<u8, 1>,
+}
+
+impl HsExtension {
+    pub fn new(ext_type: u8, data: Vec<u8>) -> Self {
+        Self {
+            ext_type,
+            data: NLengthVector::from(data),
+        }
+    }
+
+    pub fn get_type(& self) -> u8 {
+        self.ext_type
+    }
+
+    pub fn get_data(& self) -> &[u8] {
+        &self.data.0
+    }
+}
+
 #[derive(Debug, Torserde)]
 #[repr(u8)]
 pub enum SendMePayload {
@@ -39,6 +111,143 @@ pub enum SendMePayload {
 #[derive(Debug, Torserde)]
 pub struct Encrypted(pub [u8; 509]);
 
+///The FLAGS of a BEGIN cell, saying which address families the exit may connect with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BeginFlags(u32);
+
+impl BeginFlags {
+    ///We can use an IPv6 address if the exit resolves the hostname to one, and the exit may connect over IPv6
+    pub const IPV6_OK: BeginFlags = BeginFlags(1);
+    ///We can't use IPv4 addresses, so the exit must not connect over IPv4
+    pub const IPV4_NOT_OK: BeginFlags = BeginFlags(2);
+    ///If the hostname resolves to both, connect over IPv6
+    pub const IPV6_PREFERRED: BeginFlags = BeginFlags(4);
+
+    pub fn empty() -> Self {
+        BeginFlags(0)
+    }
+
+    ///Flags from their wire value. Bits we don't know are kept, since later versions of Tor may define them
+    pub fn from_bits(bits: u32) -> Self {
+        BeginFlags(bits)
+    }
+
+    pub fn bits(& self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(& self, flags: BeginFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for BeginFlags {
+    type Output = BeginFlags;
+
+    fn bitor(self, other: BeginFlags) -> BeginFlags {
+        BeginFlags(self.0 | other.0)
+    }
+}
+
+impl TorSerde for BeginFlags {
+    fn bin_serialise_into<W: Write>(&self, stream: W) -> torserde::Result<u32> {
+        self.0.bin_serialise_into(stream)
+    }
+
+    fn bin_deserialise_from<R: Read>(stream: R) -> torserde::Result<Self> {
+        Ok(BeginFlags(u32::bin_deserialise_from(stream)?))
+    }
+
+    fn serialised_length(&self) -> u32 {
+        4
+    }
+}
+
+///The body of a CONNECTED cell: the address the exit connected to and how long it may be cached for, or nothing for
+///streams that have no address, like those from BEGIN_DIR or to onion services
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectedAddress {
+    None,
+    Ipv4{ ip: Ipv4Addr, ttl: u32 },
+    ///Sent as four zero bytes, address type 6 and the address, so that clients expecting IPv4 see 0.0.0.0
+    Ipv6{ ip: Ipv6Addr, ttl: u32 },
+}
+
+impl ConnectedAddress {
+    pub fn ip(& self) -> Option<IpAddr> {
+        match *self {
+            ConnectedAddress::None => None,
+            ConnectedAddress::Ipv4 { ip, .. } => Some(IpAddr::V4(ip)),
+            ConnectedAddress::Ipv6 { ip, .. } => Some(IpAddr::V6(ip)),
+        }
+    }
+
+    pub fn ttl(& self) -> Option<u32> {
+        match *self {
+            ConnectedAddress::None => None,
+            ConnectedAddress::Ipv4 { ttl, .. } | ConnectedAddress::Ipv6 { ttl, .. } => Some(ttl),
+        }
+    }
+}
+
+impl TorSerde for ConnectedAddress {
+    fn bin_serialise_into<W: Write>(&self, mut stream: W) -> torserde::Result<u32> {
+        match self {
+            ConnectedAddress::None => {},
+            ConnectedAddress::Ipv4 { ip, ttl } => {
+                ip.bin_serialise_into(stream.borrow_mut())?;
+                ttl.bin_serialise_into(stream.borrow_mut())?;
+            },
+            ConnectedAddress::Ipv6 { ip, ttl } => {
+                Ipv4Addr::UNSPECIFIED.bin_serialise_into(stream.borrow_mut())?;
+                6u8.bin_serialise_into(stream.borrow_mut())?;
+                ip.bin_serialise_into(stream.borrow_mut())?;
+                ttl.bin_serialise_into(stream.borrow_mut())?;
+            },
+        }
+
+        Ok(self.serialised_length())
+    }
+
+    ///The body runs to the end of the cell, so its length says which form it takes
+    fn bin_deserialise_from<R: Read>(mut stream: R) -> torserde::Result<Self> {
+        let mut body = Vec::new();
+
+        stream.read_to_end(& mut body)?;
+
+        let malformed = || torserde::ErrorKind::StdIo(std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed CONNECTED body"));
+
+        match body.len() {
+            0 => Ok(ConnectedAddress::None),
+            8 if body[..4] != [0; 4] => {
+                let mut body = &body[..];
+
+                Ok(ConnectedAddress::Ipv4 {
+                    ip: Ipv4Addr::bin_deserialise_from(& mut body)?,
+                    ttl: u32::bin_deserialise_from(& mut body)?,
+                })
+            },
+            25 if body[..5] == [0, 0, 0, 0, 6] => {
+                let mut body = &body[5..];
+
+                Ok(ConnectedAddress::Ipv6 {
+                    ip: Ipv6Addr::bin_deserialise_from(& mut body)?,
+                    ttl: u32::bin_deserialise_from(& mut body)?,
+                })
+            },
+            _ => Err(malformed()),
+        }
+    }
+
+    fn serialised_length(&self) -> u32 {
+        match self {
+            ConnectedAddress::None => 0,
+            ConnectedAddress::Ipv4 { .. } => 8,
+            ConnectedAddress::Ipv6 { .. } => 25,
+        }
+    }
+}
+
 #[derive(Debug, Torserde)]
 #[repr(u8)]
 pub enum EndReason {
@@ -58,7 +267,7 @@ pub enum EndReason {
     NotDirectory = 14,
 }
 
-#[derive(Debug, Torserde)]
+#[derive(Debug, Torserde, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum DestroyReason {
     None = 0,
@@ -80,22 +289,34 @@ pub enum DestroyReason {
 #[derive(Debug, Torserde)]
 #[repr(u8)]
 pub enum Relay {
-    Begin{ addr_and_port: String, flags: u32 } = 1, //Done
+    Begin{ addr_and_port: String, flags: BeginFlags } = 1, //Done
     Data{ data: [u8; 498] } = 2, //DOne - Be careful with this as moving [u8; 498] is expensive
     End{ end_reason: EndReason } = 3, //Done
-    Connected { ip: Ipv4Addr, ttl: u32 } = 4, //Done
+    Connected { address: ConnectedAddress } = 4, //Done
     SendMe { payload: SendMePayload } = 5, //Done
 
+    Truncate = 8,
     Truncated{ reason: DestroyReason } = 9, //Done
+    Drop = 10,
 
     BeginDir = 13,
     Extend2{ link_specifiers: NLengthVector<LinkSpecifier, 1>, htype: u16, handshake_data: NLengthVector<u8, 2> } = 14, //What is a link specifier?
     Extended2{ handshake_data: NLengthVector<u8, 2> } = 15, //Done
+    ConfluxLink{ version: u8, nonce: [u8; 32], last_seqno_sent: u64, last_seqno_recv: u64, desired_ux: u8 } = 19,
+    ConfluxLinked{ version: u8, nonce: [u8; 32], last_seqno_sent: u64, last_seqno_recv: u64, desired_ux: u8 } = 20,
+    ConfluxLinkedAck = 21,
+    ConfluxSwitch{ relative_seq: u32 } = 22, //How far the sequence number moved on while this leg was idle
+    EstablishIntro = 32, //Signed over the whole body, so it is built and parsed by `onion_service::EstablishIntro`
     EstablishRendezvous{ rendezvous_cookie: [u8; 20] } = 33, //Done
-    Introduce1 = 34, //Need to do this one
-    // Rendezvous2{ handshake_data: & 'a [u8] } = 37, // Length?
+    Introduce1 = 34, //The body runs to the end of the cell, so it is built and parsed by `onion::Introduce1`
+    Introduce2 = 35, //The same body as `Introduce1`
+    Rendezvous1{ rendezvous_cookie: [u8; 20], handshake_info: [u8; 64] } = 36,
+    Rendezvous2{ handshake_info: [u8; 64] } = 37, //SERVER_PK | AUTH of the hs-ntor handshake
+    IntroEstablished{ extensions: NLengthVector<HsExtension, 1> } = 38,
     RendezvousEstablished = 39,
-    IntroduceAck = 40,
+    IntroduceAck{ status: u16, extensions: NLengthVector<HsExtension, 1> } = 40,
+    PaddingNegotiate{ version: u8, command: u8, machine_type: u8, echo_request: u8, machine_ctr: u32 } = 41,
+    PaddingNegotiated{ version: u8, command: u8, response: u8, machine_type: u8, machine_ctr: u32 } = 42,
 }
 
 #[derive(Debug, Torserde)]
@@ -103,8 +324,8 @@ pub enum Relay {
 pub enum Command {
     /* Fixed length commands */
     Padding = 0,
-    Create{ onion_skin: [u8; 20] } = 1, //Done
-    //Created = 2,
+    Create{ onion_skin: [u8; 186] } = 1, //TAP, built by `tap::TapClient`
+    Created{ handshake_data: [u8; 148] } = 2,
     Relay{ contents: Encrypted } = 3, //Figure out padding and encryption
     Destroy{ reason: DestroyReason } = 4, //Done
     CreateFast{ onion_skin: [u8; 20] } = 5, //Done
@@ -113,14 +334,15 @@ pub enum Command {
     RelayEarly{ contents: Encrypted } = 9, //Figure out padding and encryption
     Create2{ handshake_type: u16, onion_skin: NLengthVector<u8, 2> } = 10, //Done
     Created2{ handshake_data: NLengthVector<u8, 2> } = 11, //Done
+    PaddingNegotiate{ version: u8, command: u8, ito_low_ms: u16, ito_high_ms: u16 } = 12,
 
     /* Variable length commands */
     Versions{ version_list: VersionsVector } = 7, //Done
-    //VPadding = 128,
+    VPadding{ padding: NLengthVector<u8, 2> } = 128,
     Certs{ length: u16, certs: NLengthVector<Cert, 1> } = 129, //Done
     AuthChallenge{ length: u16, challenge: [u8; 32], methods: NLengthVector<u16, 2> } = 130, //Done
-    //Authenticate = 131,
-    //Authorize = 132,
+    Authenticate{ length: u16, auth_type: u16, authentication: NLengthVector<u8, 2> } = 131, //The body of auth method 3 is built and parsed by `link_auth::Auth0003`
+    Authorize{ payload: NLengthVector<u8, 2> } = 132,
 }
 
 #[derive(Debug)]
@@ -197,26 +419,51 @@ impl TorSerde for RelayCell {
 impl RelayCell {
 
     pub fn new(stream_id: u16, contents: Relay) -> Self {
+        let unpacked = Self::get_vector(contents).unwrap();
+
+        Self::new_raw(unpacked.command(), stream_id, unpacked.data())
+    }
+
+    ///Create a relay cell from a command byte and a raw body. Used for bodies that `Relay` cannot represent, like short `Data` payloads
+    ///Bodies over 498 bytes only fit relay cell format v1, which fragments them
+    pub fn new_raw(command: u8, stream_id: u16, data: Vec<u8>) -> Self {
         let recognised = 0;
         let digest = 0;
-        let unpacked = Self::get_vector(contents).unwrap();
 
-        let mut padding: Vec<_> = (0..509-11-unpacked.data_length()).into_iter().map(|_| 0u8).collect();
+        let mut padding: Vec<_> = (0..498usize.saturating_sub(data.len())).into_iter().map(|_| 0u8).collect();
 
         CSRNG.fill(& mut padding).unwrap();
 
         let padding = Some(padding);
 
         Self {
-            command: unpacked.command(),
+            command,
             recognised,
             stream_id,
             digest,
-            data: NLengthVector::<u8, 2>::from(unpacked.data()),
+            data: NLengthVector::<u8, 2>::from(data),
             padding,
         }
     }
 
+    ///Create a `Relay::Data` cell containing at most 498 bytes of `data`
+    pub fn new_data(stream_id: u16, data: &[u8]) -> Self {
+        Self::new_raw(2, stream_id, Vec::from(data))
+    }
+
+    pub fn get_command(& self) -> u8 {
+        self.command
+    }
+
+    pub fn get_stream_id(& self) -> u16 {
+        self.stream_id
+    }
+
+    ///Get the raw body of the cell, without interpreting it as a `Relay`
+    pub fn into_data(self) -> Vec<u8> {
+        self.data.0
+    }
+
     pub fn set_digest(& mut self, digest: u32) {
         self.digest = digest;
     }
@@ -235,7 +482,8 @@ impl RelayCell {
 
     pub fn get_payload(self) -> torserde::Result<Option<Relay>> {
 
-        if self.data.0.is_empty() {
+        //An empty CONNECTED still says the stream is open, so it is the one empty body that is parsed
+        if self.data.0.is_empty() && self.command != 4 {
             Ok(None)
         } else {
             let unpacked = UnpackedCell::new(self.command, Some(self.data.0));
@@ -257,6 +505,9 @@ impl Command {
             Command::Versions { version_list } => { Some((version_list.0.len()*2) as u32) }
             Command::Certs { length, certs: _ } => { Some(*length as u32) }
             Command::AuthChallenge { length, challenge: _, methods: _ } => { Some(*length as u32) }
+            Command::VPadding { padding } => { Some(padding.0.len() as u32) }
+            Command::Authenticate { length, auth_type: _, authentication: _ } => { Some(*length as u32) }
+            Command::Authorize { payload } => { Some(payload.0.len() as u32) }
             _ => { None }
         }
 
@@ -293,6 +544,29 @@ impl<'a> TorCell {
         &self.payload
     }
 
+    pub fn get_circuit_id(& self) -> u32 {
+        self.circuit_id
+    }
+
+    pub fn into_command(self) -> Command {
+        self.payload
+    }
+
+    ///Given the start of a serialised cell, get the total length of the cell in bytes, or None if there are not yet enough bytes to tell
+    pub fn expected_length(buffer: &[u8], version: u32) -> Option<usize> {
+        let circuit_id_length = if version < 4 { 2 } else { 4 };
+
+        let command = *buffer.get(circuit_id_length)?;
+
+        if Command::is_var_command(command) {
+            let length = buffer.get(circuit_id_length + 1..circuit_id_length + 3)?;
+
+            Some(circuit_id_length + 3 + u16::from_be_bytes([length[0], length[1]]) as usize)
+        } else {
+            Some(circuit_id_length + 1 + 509)
+        }
+    }
+
     ///Keep trying `from_stream` until the result is not a Err(DiscardedCell)
     pub fn try_from_stream<R: Read>(mut stream: R, version: u32) -> torserde::Result<Self> {
         todo!()
@@ -375,4 +649,42 @@ impl<'a> TorCell {
 
     }
 
-}
\ No newline at end of file
+}
+///Buffers bytes from a stream until a whole cell is available.
+///
+///Unlike `TorCell::from_stream` this tolerates streams with a read timeout, so a single thread can interleave reading cells with other work
+#[derive(Debug, Default)]
+pub struct CellReader {
+    buffer: Vec<u8>,
+}
+
+impl CellReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Read a cell from the stream if one is available. Returns `Ok(None)` if the stream timed out before a whole cell arrived
+    pub fn poll<R: Read>(& mut self, mut stream: R, version: u32) -> torserde::Result<Option<TorCell>> {
+        loop {
+            if let Some(length) = TorCell::expected_length(&self.buffer, version) {
+                if self.buffer.len() >= length {
+                    let cell = TorCell::from_stream(&self.buffer[..length], version);
+
+                    self.buffer.drain(..length);
+
+                    return cell.map(Some);
+                }
+            }
+
+            let mut chunk = [0u8; 514];
+
+            match stream.read(& mut chunk) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
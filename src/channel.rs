@@ -0,0 +1,511 @@
+//! A blocking channel: a single link to a relay carrying cells for many circuits.
+//!
+//! The channel owns the transport on a reactor thread which reads cells continuously and hands each to the queue of the
+//! circuit it belongs to, while writing any cells queued by circuits in between reads. PADDING cells are dropped and
+//! DESTROY cells close the circuit's queue, so circuits only see cells that are meant for them. When nothing crosses the
+//! channel for a while the reactor sends PADDING of its own, as set by `channel_padding`.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, IpAddr, Ipv4Addr};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, RecvTimeoutError};
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use native_tls::{TlsConnector, TlsStream, Protocol};
+use chrono::Local;
+use ring::rand::SecureRandom;
+use torserde::{NLengthVector, VersionsVector};
+
+use crate::cells::{TorCell, Command, CellReader, DestroyReason, CSRNG, SUPPORTED_LINK_VERSIONS};
+use crate::cellcrypto::{CellCrypto, CircuitCrypto, HopProtocol};
+use crate::channel_padding::{PaddingConfig, PaddingTimer, PADDING_NEGOTIATE_LINK_VERSION};
+use crate::circuit_padding::{Clock, SystemClock};
+use crate::circuit::Circuit;
+use crate::custom_crypto::{kdf_tor, constant_time_eq};
+use crate::ntor::{NtorClient, NTOR_HANDSHAKE_TYPE};
+#[cfg(feature = "legacy-tap")]
+use crate::tap::{TapClient, RsaPublicKey};
+use crate::error::{ErrorKind, Result};
+
+///How long the reactor waits for a cell before writing queued cells
+const REACTOR_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+///A stream that a channel can run over. The reactor needs a read timeout so it can write between reads
+pub trait ChannelTransport: Read + Write + Send + 'static {
+    fn set_poll_timeout(& self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl ChannelTransport for TcpStream {
+    fn set_poll_timeout(& self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+impl<S: ChannelTransport> ChannelTransport for TlsStream<S> {
+    fn set_poll_timeout(& self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.get_ref().set_poll_timeout(timeout)
+    }
+}
+
+///Hands out circuit IDs that are not in use on a channel.
+///
+///From link protocol 4 the initiator of the channel must set the high bit of the 4 byte ID. Before that IDs are 2 bytes
+pub struct CircuitIdAllocator {
+    high_bit: u32,
+    mask: u32,
+    next: u32,
+}
+
+impl CircuitIdAllocator {
+    pub fn new(version: u32) -> Self {
+        let mut start = [0u8; 4];
+
+        CSRNG.fill(& mut start).unwrap();
+
+        let (high_bit, mask) = if version < 4 { (0, 0xffff) } else { (0x80000000, 0x7fffffff) };
+
+        Self {
+            high_bit,
+            mask,
+            next: u32::from_be_bytes(start),
+        }
+    }
+
+    pub fn allocate<F: Fn(u32) -> bool>(& mut self, in_use: F) -> u32 {
+        loop {
+            let candidate = self.high_bit | (self.next & self.mask);
+
+            self.next = self.next.wrapping_add(1);
+
+            if candidate & self.mask != 0 && !in_use(candidate) {
+                return candidate;
+            }
+        }
+    }
+}
+
+pub fn versions_cell() -> TorCell {
+    TorCell::new(0, Command::Versions { version_list: VersionsVector::from(Vec::from(SUPPORTED_LINK_VERSIONS)) })
+}
+
+///Choose the highest link protocol version offered in the relay's VERSIONS cell that we also support
+pub fn negotiate_version(versions: &TorCell) -> Result<u32> {
+    match versions.get_command() {
+        Command::Versions { version_list } => version_list.0.iter()
+            .filter(|v| SUPPORTED_LINK_VERSIONS.contains(v))
+            .max()
+            .map(|v| *v as u32)
+            .ok_or(ErrorKind::NoCommonVersion),
+        _ => Err(ErrorKind::UnexpectedCell(format!("{:?}", versions))),
+    }
+}
+
+pub fn netinfo_cell(peer: IpAddr) -> TorCell {
+    TorCell::new(0, Command::NetInfo {
+        timestamp: Local::now(),
+        other_ip: peer,
+        this_ips: NLengthVector::from(vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)]) })
+}
+
+pub(crate) struct ChannelShared {
+    version: u32,
+    outgoing: Mutex<Sender<TorCell>>,
+    circuits: Mutex<HashMap<u32, Sender<TorCell>>>,
+    allocator: Mutex<CircuitIdAllocator>,
+    closed: AtomicBool,
+    padding: Mutex<LinkPadding>,
+}
+
+///How the reactor pads a channel
+struct LinkPadding {
+    ///The idle timeouts to pad with, if the channel pads
+    timeouts: Option<(u16, u16)>,
+    ///Whether `set_padding` chose the timeouts. If not, the channel starts padding with the defaults once it has a circuit
+    configured: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl ChannelShared {
+    pub(crate) fn send(& self, cell: TorCell) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ErrorKind::ChannelClosed);
+        }
+
+        self.outgoing.lock().unwrap().send(cell).map_err(|_| ErrorKind::ChannelClosed)
+    }
+
+    fn register_circuit(& self) -> Result<(u32, Receiver<TorCell>)> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ErrorKind::ChannelClosed);
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+
+        let circuit_id = self.allocator.lock().unwrap().allocate(|id| circuits.contains_key(&id));
+
+        let (sender, receiver) = channel();
+
+        circuits.insert(circuit_id, sender);
+
+        let mut padding = self.padding.lock().unwrap();
+
+        if !padding.configured {
+            padding.timeouts = PaddingConfig::default().timeouts();
+        }
+
+        Ok((circuit_id, receiver))
+    }
+
+    fn now(& self) -> Instant {
+        self.padding.lock().unwrap().clock.now()
+    }
+
+    pub(crate) fn unregister_circuit(& self, circuit_id: u32) {
+        self.circuits.lock().unwrap().remove(&circuit_id);
+    }
+
+    ///Queue a PADDING cell for a circuit, which ignores it, to wake a thread blocked reading the circuit's queue
+    pub(crate) fn wake_circuit(& self, circuit_id: u32) {
+        if let Some(sender) = self.circuits.lock().unwrap().get(&circuit_id) {
+            let _ = sender.send(TorCell::new(circuit_id, Command::Padding));
+        }
+    }
+
+    fn dispatch(& self, cell: TorCell) {
+        let circuit_id = cell.get_circuit_id();
+
+        match cell.get_command() {
+            Command::Padding | Command::VPadding { .. } => {},
+            Command::Destroy { .. } => {
+                if let Some(sender) = self.circuits.lock().unwrap().remove(&circuit_id) {
+                    let _ = sender.send(cell);
+                }
+            },
+            _ => {
+                if let Some(sender) = self.circuits.lock().unwrap().get(&circuit_id) {
+                    let _ = sender.send(cell);
+                }
+            },
+        }
+    }
+
+    fn close(& self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        //Dropping the senders tells every circuit that the channel has closed
+        self.circuits.lock().unwrap().clear();
+    }
+}
+
+///An open link to a relay, shared by every circuit built through it
+pub struct Channel {
+    shared: Arc<ChannelShared>,
+}
+
+impl Channel {
+    ///Connect to a relay over TLS and perform the link handshake
+    pub fn connect(address: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+
+        Self::connect_over(stream, address.ip())
+    }
+
+    ///Perform TLS and the link handshake over a connection that already reaches the relay at `peer`, such as one
+    ///through a pluggable transport
+    pub fn connect_over<S: ChannelTransport>(stream: S, peer: IpAddr) -> Result<Self> {
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_hostnames(true)
+            .danger_accept_invalid_certs(true)
+            .use_sni(false)
+            .min_protocol_version(Some(Protocol::Tlsv12))
+            .build()?;
+
+        let stream = connector.connect("", stream).map_err(|e| match e {
+            native_tls::HandshakeError::Failure(e) => ErrorKind::Tls(e),
+            native_tls::HandshakeError::WouldBlock(_) => ErrorKind::HandshakeFailed,
+        })?;
+
+        Self::handshake(stream, peer)
+    }
+
+    ///Perform the link handshake over an already connected transport then start the reactor thread
+    pub fn handshake<T: ChannelTransport>(mut transport: T, peer: IpAddr) -> Result<Self> {
+        transport.set_poll_timeout(None)?;
+
+        versions_cell().into_stream(& mut transport, 3)?;
+
+        let version = negotiate_version(&TorCell::from_stream(& mut transport, 3)?)?;
+
+        loop {
+            let cell = TorCell::from_stream(& mut transport, version)?;
+
+            match cell.get_command() {
+                Command::NetInfo { .. } => break,
+                Command::Certs { .. } | Command::AuthChallenge { .. } | Command::Padding | Command::VPadding { .. } | Command::Authorize { .. } => {},
+                _ => return Err(ErrorKind::UnexpectedCell(format!("{:?}", cell))),
+            }
+        }
+
+        netinfo_cell(peer).into_stream(& mut transport, version)?;
+
+        transport.set_poll_timeout(Some(REACTOR_POLL_INTERVAL))?;
+
+        let (outgoing, outgoing_receiver) = channel();
+
+        let shared = Arc::new(ChannelShared {
+            version,
+            outgoing: Mutex::new(outgoing),
+            circuits: Mutex::new(HashMap::new()),
+            allocator: Mutex::new(CircuitIdAllocator::new(version)),
+            closed: AtomicBool::new(false),
+            padding: Mutex::new(LinkPadding {
+                timeouts: None,
+                configured: false,
+                clock: Arc::new(SystemClock),
+            }),
+        });
+
+        let reactor_shared = Arc::downgrade(&shared);
+
+        thread::spawn(move || Self::reactor(transport, reactor_shared, version, outgoing_receiver));
+
+        Ok(Self {
+            shared,
+        })
+    }
+
+    ///Read and dispatch cells, writing queued cells between reads, until the transport closes or the channel and all its circuits are dropped.
+    ///PADDING is sent whenever the channel has been idle for the padding timeout
+    fn reactor<T: ChannelTransport>(mut transport: T, shared: Weak<ChannelShared>, version: u32, outgoing: Receiver<TorCell>) {
+        let mut reader = CellReader::new();
+        let mut padding = PaddingTimer::default();
+
+        loop {
+            let polled = reader.poll(& mut transport, version);
+
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => {
+                    //Still send what the last circuits queued as they were dropped, such as their DESTROY cells
+                    while let Ok(cell) = outgoing.try_recv() {
+                        if cell.into_stream(& mut transport, version).is_err() {
+                            break;
+                        }
+                    }
+
+                    return;
+                },
+            };
+
+            match polled {
+                Ok(Some(cell)) => {
+                    padding.activity(shared.now());
+                    shared.dispatch(cell);
+                },
+                Ok(None) => {},
+                Err(torserde::ErrorKind::DiscardedCell(_)) => {},
+                Err(_) => return shared.close(),
+            }
+
+            loop {
+                match outgoing.try_recv() {
+                    Ok(cell) => {
+                        if cell.into_stream(& mut transport, version).is_err() {
+                            return shared.close();
+                        }
+
+                        padding.activity(shared.now());
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return shared.close(),
+                }
+            }
+
+            let timeouts = shared.padding.lock().unwrap().timeouts;
+
+            if padding.due(shared.now(), timeouts) {
+                if TorCell::new(0, Command::Padding).into_stream(& mut transport, version).is_err() {
+                    return shared.close();
+                }
+
+                padding.activity(shared.now());
+            }
+        }
+    }
+
+    pub fn link_version(& self) -> u32 {
+        self.shared.version
+    }
+
+    pub fn is_closed(& self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    ///Pad the channel as `config` asks, telling the relay with PADDING_NEGOTIATE if the mode isn't `Normal`. Channels
+    ///that aren't told otherwise pad with the default consensus parameters once they have a circuit. Fails with
+    ///`NoCommonVersion` if the mode needs PADDING_NEGOTIATE and the link protocol is older than version 5
+    pub fn set_padding(& self, config: &PaddingConfig) -> Result<()> {
+        if let Some(command) = config.negotiate_command() {
+            if self.shared.version < PADDING_NEGOTIATE_LINK_VERSION {
+                return Err(ErrorKind::NoCommonVersion);
+            }
+
+            self.shared.send(TorCell::new(0, command))?;
+        }
+
+        let mut padding = self.shared.padding.lock().unwrap();
+
+        padding.timeouts = config.timeouts();
+        padding.configured = true;
+
+        Ok(())
+    }
+
+    ///The idle timeouts the channel pads with, or None while it doesn't pad
+    pub fn padding(& self) -> Option<(u16, u16)> {
+        self.shared.padding.lock().unwrap().timeouts
+    }
+
+    ///Time the channel's padding with `clock` rather than the real clock
+    pub(crate) fn set_padding_clock(& self, clock: Arc<dyn Clock>) {
+        self.shared.padding.lock().unwrap().clock = clock;
+    }
+
+    ///Send a VPADDING cell of `length` random bytes, which the relay throws away
+    pub fn send_vpadding(& self, length: u16) -> Result<()> {
+        let mut padding = vec![0u8; length as usize];
+
+        CSRNG.fill(& mut padding).unwrap();
+
+        self.shared.send(TorCell::new(0, Command::VPadding { padding: NLengthVector::from(padding) }))
+    }
+
+    ///The number of circuits currently registered on this channel
+    pub fn circuit_count(& self) -> usize {
+        self.shared.circuits.lock().unwrap().len()
+    }
+
+    ///Wait for the answer to a CREATE cell, destroying the circuit if it hasn't come by `deadline`
+    fn created(& self, circuit_id: u32, incoming: &Receiver<TorCell>, deadline: Option<Instant>) -> Result<TorCell> {
+        let reply = match deadline {
+            Some(deadline) => incoming.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => incoming.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match reply {
+            Ok(cell) => Ok(cell),
+            Err(RecvTimeoutError::Timeout) => Err(self.failed(circuit_id, ErrorKind::Timeout)),
+            Err(RecvTimeoutError::Disconnected) => {
+                self.shared.unregister_circuit(circuit_id);
+                Err(ErrorKind::ChannelClosed)
+            }
+        }
+    }
+
+    fn failed(& self, circuit_id: u32, kind: ErrorKind) -> ErrorKind {
+        self.shared.unregister_circuit(circuit_id);
+
+        let _ = self.shared.send(TorCell::new(circuit_id, Command::Destroy { reason: DestroyReason::None }));
+
+        kind
+    }
+
+    ///Create a one hop circuit with CREATE_FAST
+    pub fn create_fast(& self) -> Result<Circuit> {
+        let (circuit_id, incoming) = self.shared.register_circuit()?;
+
+        let mut onion_skin = [0u8; 20];
+
+        CSRNG.fill(& mut onion_skin).unwrap();
+
+        self.shared.send(TorCell::new(circuit_id, Command::CreateFast { onion_skin }))?;
+
+        let reply = self.created(circuit_id, &incoming, None)?;
+
+        let handshake_data = match reply.get_command() {
+            Command::CreatedFast { handshake_data } => *handshake_data,
+            _ => return Err(self.failed(circuit_id, ErrorKind::UnexpectedCell(format!("{:?}", reply)))),
+        };
+
+        let mut shared_secret = Vec::from(onion_skin);
+
+        shared_secret.extend_from_slice(&handshake_data[0..20]);
+
+        let materials = kdf_tor(&shared_secret);
+
+        if !constant_time_eq(&materials[0..20], &handshake_data[20..40]) {
+            return Err(self.failed(circuit_id, ErrorKind::HandshakeFailed));
+        }
+
+        let mut crypto = CircuitCrypto::new();
+
+        crypto.add_hop(CellCrypto::from(&materials[20..92].try_into().unwrap()).with_nonce(&materials[0..20]));
+
+        Ok(Circuit::new(self.shared.clone(), circuit_id, crypto, incoming))
+    }
+
+    ///Create a one hop circuit with CREATE and the legacy TAP handshake, for relays that don't speak ntor
+    #[cfg(feature = "legacy-tap")]
+    pub fn create_tap(& self, onion_key: &RsaPublicKey) -> Result<Circuit> {
+        let (circuit_id, incoming) = self.shared.register_circuit()?;
+
+        let tap = TapClient::new();
+
+        self.shared.send(TorCell::new(circuit_id, Command::Create { onion_skin: tap.onion_skin(onion_key) }))?;
+
+        let reply = self.created(circuit_id, &incoming, None)?;
+
+        let materials = match reply.get_command() {
+            Command::Created { handshake_data } => match tap.complete(handshake_data) {
+                Some(materials) => materials,
+                None => return Err(self.failed(circuit_id, ErrorKind::HandshakeFailed)),
+            },
+            _ => return Err(self.failed(circuit_id, ErrorKind::UnexpectedCell(format!("{:?}", reply)))),
+        };
+
+        let mut crypto = CircuitCrypto::new();
+
+        crypto.add_hop(CellCrypto::from(&materials[20..92].try_into().unwrap()).with_nonce(&materials[0..20]));
+
+        Ok(Circuit::new(self.shared.clone(), circuit_id, crypto, incoming))
+    }
+
+    ///Create a one hop circuit with CREATE2 and the ntor handshake
+    pub fn create_ntor(& self, node_id: &[u8; 20], onion_key: &[u8; 32]) -> Result<Circuit> {
+        self.create_ntor_until(node_id, onion_key, None)
+    }
+
+    ///`create_ntor`, destroying the circuit and failing with `ErrorKind::Timeout` if CREATED2 hasn't arrived by `deadline`
+    pub fn create_ntor_until(& self, node_id: &[u8; 20], onion_key: &[u8; 32], deadline: Option<Instant>) -> Result<Circuit> {
+        self.create_ntor_with(node_id, onion_key, HopProtocol::default(), deadline)
+    }
+
+    ///`create_ntor_until`, with the hop using the relay crypto and cell format in `protocol`
+    pub fn create_ntor_with(& self, node_id: &[u8; 20], onion_key: &[u8; 32], protocol: HopProtocol, deadline: Option<Instant>) -> Result<Circuit> {
+        let (circuit_id, incoming) = self.shared.register_circuit()?;
+
+        let ntor = NtorClient::new(node_id, onion_key);
+
+        self.shared.send(TorCell::new(circuit_id, Command::Create2 { handshake_type: NTOR_HANDSHAKE_TYPE, onion_skin: NLengthVector::from(ntor.onion_skin()) }))?;
+
+        let reply = self.created(circuit_id, &incoming, deadline)?;
+
+        let materials = match reply.get_command() {
+            Command::Created2 { handshake_data } => match ntor.complete(&handshake_data.0, protocol.crypto.key_material_length()) {
+                Some(materials) => materials,
+                None => return Err(self.failed(circuit_id, ErrorKind::HandshakeFailed)),
+            },
+            _ => return Err(self.failed(circuit_id, ErrorKind::UnexpectedCell(format!("{:?}", reply)))),
+        };
+
+        let mut hops = CircuitCrypto::new();
+
+        hops.add_hop_with_format(protocol.crypto.hop(&materials), protocol.format);
+
+        Ok(Circuit::new(self.shared.clone(), circuit_id, hops, incoming))
+    }
+}
@@ -0,0 +1,184 @@
+//! Connection-level padding from proposal 251, which keeps a channel from ever going quiet for long.
+//!
+//! Whenever no cell has crossed a channel in either direction for a while, a PADDING cell is sent. The wait is the
+//! larger of two uniform draws between `nf_ito_low` and `nf_ito_high` milliseconds, redrawn after every cell, so that
+//! netflow records of the connection look like those of a Tor client. Clients that want less padding send the relay a
+//! PADDING_NEGOTIATE with the reduced timeouts and use them themselves, and clients that want none tell the relay to
+//! stop. PADDING_NEGOTIATE needs link protocol 5.
+//!
+//! As in Tor, a channel only starts padding once it carries a circuit, unless it is told how to pad before then.
+
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::cells::Command;
+
+///The only PADDING_NEGOTIATE version
+pub const PADDING_NEGOTIATE_VERSION: u8 = 0;
+
+///The first link protocol version with PADDING_NEGOTIATE
+pub const PADDING_NEGOTIATE_LINK_VERSION: u32 = 5;
+
+///PADDING_NEGOTIATE command asking the relay to stop padding
+pub const PADDING_COMMAND_STOP: u8 = 1;
+
+///PADDING_NEGOTIATE command asking the relay to pad with the given timeouts
+pub const PADDING_COMMAND_START: u8 = 2;
+
+///The largest timeout a consensus parameter may set, in milliseconds
+const MAX_TIMEOUT_MS: u16 = 60000;
+
+///How much padding a client asks for, as with Tor's `ConnectionPadding` and `ReducedConnectionPadding` options
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaddingMode {
+    ///Pad with the consensus timeouts, leaving the relay to do the same
+    Normal,
+    ///Pad with the longer reduced timeouts and ask the relay to use them too
+    Reduced,
+    ///Don't pad, and ask the relay not to either
+    Disabled,
+}
+
+///The idle timeouts from the consensus `params` line, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaddingParams {
+    pub ito_low_ms: u16,
+    pub ito_high_ms: u16,
+    pub ito_low_reduced_ms: u16,
+    pub ito_high_reduced_ms: u16,
+}
+
+impl Default for PaddingParams {
+    ///Tor's defaults for when the consensus doesn't set them
+    fn default() -> Self {
+        Self {
+            ito_low_ms: 1500,
+            ito_high_ms: 9500,
+            ito_low_reduced_ms: 9000,
+            ito_high_reduced_ms: 14000,
+        }
+    }
+}
+
+impl PaddingParams {
+    ///Read the `nf_ito_*` parameters from a consensus `params` line, with or without its keyword. Missing or malformed
+    ///values keep their defaults, values are capped at 60 seconds and a high timeout is never below its low one
+    pub fn from_consensus(params: &str) -> Self {
+        let mut padding = Self::default();
+
+        for (key, value) in params.split_whitespace().filter_map(|word| word.split_once('=')) {
+            let value = match value.parse::<i64>() {
+                Ok(value) => value.clamp(0, MAX_TIMEOUT_MS as i64) as u16,
+                Err(_) => continue,
+            };
+
+            match key {
+                "nf_ito_low" => padding.ito_low_ms = value,
+                "nf_ito_high" => padding.ito_high_ms = value,
+                "nf_ito_low_reduced" => padding.ito_low_reduced_ms = value,
+                "nf_ito_high_reduced" => padding.ito_high_reduced_ms = value,
+                _ => {},
+            }
+        }
+
+        padding.ito_high_ms = padding.ito_high_ms.max(padding.ito_low_ms);
+        padding.ito_high_reduced_ms = padding.ito_high_reduced_ms.max(padding.ito_low_reduced_ms);
+
+        padding
+    }
+
+    ///The low and high timeouts to pad with in `mode`, or `None` if we shouldn't pad. A high timeout of 0 turns padding
+    ///off, as it does in Tor
+    pub fn timeouts(& self, mode: PaddingMode) -> Option<(u16, u16)> {
+        let (low, high) = match mode {
+            PaddingMode::Normal => (self.ito_low_ms, self.ito_high_ms),
+            PaddingMode::Reduced => (self.ito_low_reduced_ms, self.ito_high_reduced_ms),
+            PaddingMode::Disabled => return None,
+        };
+
+        if high == 0 {
+            None
+        } else {
+            Some((low, high))
+        }
+    }
+}
+
+///A client's padding preference and the consensus it applies to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaddingConfig {
+    pub mode: PaddingMode,
+    pub params: PaddingParams,
+}
+
+impl Default for PaddingConfig {
+    fn default() -> Self {
+        Self {
+            mode: PaddingMode::Normal,
+            params: PaddingParams::default(),
+        }
+    }
+}
+
+impl PaddingConfig {
+    pub fn timeouts(& self) -> Option<(u16, u16)> {
+        self.params.timeouts(self.mode)
+    }
+
+    ///The PADDING_NEGOTIATE to send the relay, if the mode needs one
+    pub fn negotiate_command(& self) -> Option<Command> {
+        let (command, (ito_low_ms, ito_high_ms)) = match self.mode {
+            PaddingMode::Normal => return None,
+            PaddingMode::Reduced => (PADDING_COMMAND_START, self.timeouts().unwrap_or((0, 0))),
+            PaddingMode::Disabled => (PADDING_COMMAND_STOP, (0, 0)),
+        };
+
+        Some(Command::PaddingNegotiate {
+            version: PADDING_NEGOTIATE_VERSION,
+            command,
+            ito_low_ms,
+            ito_high_ms,
+        })
+    }
+}
+
+///How long to wait for a cell before padding: the larger of two uniform draws between `low` and `high` milliseconds,
+///which favours longer waits
+pub fn sample_timeout(low: u16, high: u16) -> Duration {
+    let mut rng = rand::thread_rng();
+
+    let first = rng.gen_range(low..=high);
+    let second = rng.gen_range(low..=high);
+
+    Duration::from_millis(first.max(second) as u64)
+}
+
+///When a channel's reactor next has to send PADDING, pushed back by every cell that crosses the channel
+#[derive(Debug, Default)]
+pub(crate) struct PaddingTimer {
+    timeouts: Option<(u16, u16)>,
+    ///When the last cell crossed the channel
+    last_activity: Option<Instant>,
+    deadline: Option<Instant>,
+}
+
+impl PaddingTimer {
+    ///A cell was sent or received at `now`
+    pub(crate) fn activity(& mut self, now: Instant) {
+        self.last_activity = Some(now);
+        self.deadline = self.timeouts.map(|(low, high)| now + sample_timeout(low, high));
+    }
+
+    ///Whether a PADDING cell is due at `now` under `timeouts`. A change of timeouts draws a new wait from the last cell
+    pub(crate) fn due(& mut self, now: Instant, timeouts: Option<(u16, u16)>) -> bool {
+        if timeouts != self.timeouts {
+            let since = self.last_activity.unwrap_or(now);
+
+            self.timeouts = timeouts;
+            self.deadline = timeouts.map(|(low, high)| since + sample_timeout(low, high));
+        }
+
+        self.deadline.map(|deadline| now >= deadline).unwrap_or(false)
+    }
+}
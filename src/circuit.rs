@@ -0,0 +1,1294 @@
+//! Blocking circuits and streams built on top of a `Channel`.
+//!
+//! A circuit pulls cells from the queue its channel fills. Whichever thread is waiting for a cell takes the queue,
+//! decrypts what arrives and files each relay cell under the stream it belongs to, waking any other threads waiting on
+//! the circuit. Stream 0 holds the circuit's own control cells, such as EXTENDED2, along with BEGIN cells for streams
+//! the other end wants to open, as an onion service receives on a rendezvous circuit.
+//!
+//! DESTROY, or the channel closing, fails every stream with the `DestroyReason`. TRUNCATED drops the hops after the one
+//! that sent it and fails only the streams attached to those hops.
+//!
+//! A circuit may run a padding machine from `circuit_padding`, which is fed every cell the circuit sends and receives
+//! and sends RELAY_DROP cells from a thread of its own. DROP and PADDING_NEGOTIATED cells never reach the streams.
+//!
+//! A circuit linked into a `conflux::ConfluxSet` hands the stream cells it sends to the set, which picks the leg they go
+//! on, and the stream cells it receives to the set, which files them on the first leg once they are in order.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Weak, Mutex, MutexGuard, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use torserde::NLengthVector;
+
+use crate::cells::{TorCell, Command, Relay, RelayCell, LinkSpecifier, BeginFlags, ConnectedAddress, SendMePayload, EndReason, DestroyReason};
+use crate::cellcrypto::{CellCrypto, CircuitCrypto, HopProtocol};
+use crate::channel::ChannelShared;
+use crate::conflux::{ConfluxLeg, is_multiplexed};
+use crate::circuit_padding::{PaddingMachine, PaddingRuntime, PaddingEvent, Clock, SystemClock, CIRCPAD_VERSION, CIRCPAD_COMMAND_START, CIRCPAD_COMMAND_STOP, CIRCPAD_RESPONSE_OK};
+use crate::ntor::{NtorClient, NTOR_HANDSHAKE_TYPE};
+use crate::error::{ErrorKind, Result, Teardown};
+
+///Number of DATA cells received on a circuit before we send a circuit-level SENDME
+pub const CIRCUIT_SENDME_INCREMENT: u32 = 100;
+
+///Number of DATA cells received on a stream before we send a stream-level SENDME
+pub const STREAM_SENDME_INCREMENT: u32 = 50;
+
+///Number of DATA cells we may send to a hop before it has to answer with a circuit-level SENDME
+pub const CIRCUIT_WINDOW_START: u32 = 1000;
+
+///Number of DATA cells we may send on a stream before the other end has to answer with a stream-level SENDME
+pub const STREAM_WINDOW_START: u32 = 500;
+
+///Counts delivered DATA cells to decide when SENDMEs are due
+#[derive(Debug, Default)]
+pub struct SendMeTracker {
+    circuit_delivered: u32,
+    stream_delivered: HashMap<u16, u32>,
+}
+
+impl SendMeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Record a DATA cell on `stream_id`. Returns whether a circuit-level and a stream-level SENDME are now due
+    pub fn data_received(& mut self, stream_id: u16) -> (bool, bool) {
+        self.circuit_delivered += 1;
+
+        let delivered = self.stream_delivered.entry(stream_id).or_insert(0);
+
+        *delivered += 1;
+
+        (self.circuit_delivered % CIRCUIT_SENDME_INCREMENT == 0, *delivered % STREAM_SENDME_INCREMENT == 0)
+    }
+
+    pub fn stream_closed(& mut self, stream_id: u16) {
+        self.stream_delivered.remove(&stream_id);
+    }
+}
+
+///The circuit-level SENDME, authenticated with the digest of the hop it is sent to
+pub fn circuit_sendme(crypto: &CircuitCrypto, hop: usize) -> RelayCell {
+    RelayCell::new(0, Relay::SendMe { payload: SendMePayload::Authenticated { length: 20, digest: crypto.backward_digest(hop) } })
+}
+
+pub fn stream_sendme(stream_id: u16) -> RelayCell {
+    RelayCell::new_raw(5, stream_id, vec![])
+}
+
+///The ADDRPORT of a BEGIN cell, with IPv6 addresses in brackets
+pub fn addr_and_port(address: &str, port: u16) -> String {
+    if address.contains(':') && !address.starts_with('[') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+struct Incoming {
+    cells: Receiver<TorCell>,
+    sendme: SendMeTracker,
+}
+
+///Cells waiting for a stream, the hop the stream is attached to and the DATA cells we may still send on it
+struct StreamQueue {
+    hop: usize,
+    cells: VecDeque<RelayCell>,
+    failed: Option<Teardown>,
+    window: u32,
+}
+
+impl StreamQueue {
+    fn new(hop: usize) -> Self {
+        Self {
+            hop,
+            cells: VecDeque::new(),
+            failed: None,
+            window: STREAM_WINDOW_START,
+        }
+    }
+}
+
+///The DATA cells sent to a hop, and when each circuit SENDME the hop owes us for them fell due
+#[derive(Default)]
+struct SendMeTimer {
+    sent: u64,
+    due: VecDeque<Instant>,
+}
+
+struct Inbox {
+    pending: HashMap<u16, StreamQueue>,
+    ///The DATA cells we may still send to each hop, for hops we have sent DATA to
+    windows: HashMap<usize, u32>,
+    ///Times the circuit SENDMEs from each hop we have sent DATA to, giving its round trip
+    sendme_timers: HashMap<usize, SendMeTimer>,
+    closed: Option<Teardown>,
+    truncated: Option<DestroyReason>,
+}
+
+impl Inbox {
+    ///Queue a cell for its stream. BEGIN for a stream we don't know goes to stream 0, a SENDME opens the stream's window
+    ///and anything else for a stream we don't know is dropped
+    fn file(& mut self, relay: RelayCell) {
+        let stream_id = if relay.get_command() == 1 && !self.pending.contains_key(&relay.get_stream_id()) {
+            0
+        } else {
+            relay.get_stream_id()
+        };
+
+        if let Some(queue) = self.pending.get_mut(&stream_id) {
+            if relay.get_command() == 5 && stream_id != 0 {
+                queue.window += STREAM_SENDME_INCREMENT;
+            } else {
+                queue.cells.push_back(relay);
+            }
+        }
+    }
+
+    ///Take `cells` DATA cells from the window of `hop`, if there is room for them
+    fn take_circuit_window(& mut self, hop: usize, cells: u32) -> bool {
+        let window = self.windows.entry(hop).or_insert(CIRCUIT_WINDOW_START);
+
+        if *window < cells {
+            return false;
+        }
+
+        *window -= cells;
+
+        //The hop answers the cell that completes each increment with a SENDME
+        let timer = self.sendme_timers.entry(hop).or_default();
+
+        let before = timer.sent / CIRCUIT_SENDME_INCREMENT as u64;
+
+        timer.sent += cells as u64;
+
+        let now = Instant::now();
+
+        for _ in before..timer.sent / CIRCUIT_SENDME_INCREMENT as u64 {
+            timer.due.push_back(now);
+        }
+
+        true
+    }
+
+    ///Open the window of `hop` for a circuit SENDME, returning how long it took to arrive after it fell due
+    fn circuit_sendme(& mut self, hop: usize) -> Option<Duration> {
+        *self.windows.entry(hop).or_insert(CIRCUIT_WINDOW_START) += CIRCUIT_SENDME_INCREMENT;
+
+        self.sendme_timers.get_mut(&hop)?.due.pop_front().map(|due| due.elapsed())
+    }
+}
+
+///The padding machine running on a circuit
+struct ActivePadding {
+    runtime: PaddingRuntime,
+    ///The hop the machine pads to, counting from 0
+    hop: usize,
+    ///The counter the machine was negotiated with
+    counter: u32,
+}
+
+#[derive(Default)]
+struct CircuitPadding {
+    active: Option<ActivePadding>,
+    ///The counter of the last machine negotiated, so the relay can tell machines apart
+    counter: u32,
+    ///Whether the circuit has closed, so there is nothing left to pad
+    closed: bool,
+}
+
+///The padding machine running on a circuit, shared with the thread sending its padding so that thread can sleep
+///without keeping the circuit alive
+#[derive(Default)]
+struct PaddingControl {
+    state: Mutex<CircuitPadding>,
+    ///Notified whenever the machine may have something new to do
+    changed: Condvar,
+}
+
+impl PaddingControl {
+    fn wake(& self) {
+        let _padding = self.state.lock().unwrap();
+
+        self.changed.notify_all();
+    }
+
+    fn close(& self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+///Relay commands that are padding, or about padding, and so aren't events for the padding machine
+fn is_padding_command(command: u8) -> bool {
+    command == 10 || command == 41 || command == 42
+}
+
+///What became of a cell taken from the channel's queue
+enum Received {
+    Relay(RelayCell),
+    CircuitSendMe(usize),
+    Truncated(usize, DestroyReason),
+    Closed(Teardown),
+    Nothing,
+}
+
+pub(crate) struct CircuitInner {
+    channel: Arc<ChannelShared>,
+    circuit_id: u32,
+    crypto: Mutex<CircuitCrypto>,
+    incoming: Mutex<Incoming>,
+    inbox: Mutex<Inbox>,
+    arrived: Condvar,
+    next_stream_id: Mutex<u16>,
+    padding: Arc<PaddingControl>,
+    conflux: Mutex<Option<ConfluxLeg>>,
+}
+
+impl CircuitInner {
+    ///Encrypt and queue a relay cell for `hop`. Stream cells for the last hop of a conflux leg go on whichever leg the set chooses
+    fn send_relay(& self, hop: usize, relay: RelayCell, early: bool) -> Result<()> {
+        if is_multiplexed(relay.get_command()) {
+            if let Some(leg) = self.conflux_leg() {
+                if hop == self.last_hop() {
+                    return leg.set.send(relay);
+                }
+            }
+        }
+
+        self.send_relay_here(hop, relay, early)
+    }
+
+    ///Send a relay cell to the last hop of this circuit, even if it is a conflux leg
+    pub(crate) fn send_unsequenced(& self, relay: RelayCell) -> Result<()> {
+        self.send_relay_here(self.last_hop(), relay, false)
+    }
+
+    fn send_relay_here(& self, hop: usize, relay: RelayCell, early: bool) -> Result<()> {
+        self.send_relays_here(hop, vec![relay], early)
+    }
+
+    ///Encrypt and queue relay cells for `hop`, packed together if its cell format allows. The crypto lock is held until
+    ///the cells are queued so cells leave in the order they were encrypted
+    fn send_relays_here(& self, hop: usize, relays: Vec<RelayCell>, early: bool) -> Result<()> {
+        let data = relays.iter().filter(|relay| relay.get_command() == 2).count() as u32;
+
+        //DATA waits for the hop's window to open, while nothing else counts against it
+        self.wait_until(None, |inbox| {
+            if let Some(teardown) = inbox.closed {
+                return Some(Err(teardown.into()));
+            }
+
+            if data > 0 && !inbox.take_circuit_window(hop, data) {
+                return None;
+            }
+
+            Some(Ok(()))
+        })?;
+
+        self.encrypt_and_send(hop, relays, early)
+    }
+
+    ///Take room for `cells` DATA cells in the window of the last hop without waiting for it. `false` if there isn't any
+    pub(crate) fn try_take_window(& self, cells: u32) -> Result<bool> {
+        let hop = self.last_hop();
+
+        let mut inbox = self.inbox.lock().unwrap();
+
+        if let Some(teardown) = inbox.closed {
+            return Err(teardown.into());
+        }
+
+        Ok(cells == 0 || inbox.take_circuit_window(hop, cells))
+    }
+
+    ///Wait until the window of the last hop has room for `cells` DATA cells, without taking it
+    pub(crate) fn wait_for_window(& self, cells: u32) -> Result<()> {
+        let hop = self.last_hop();
+
+        self.wait_until(None, |inbox| {
+            if let Some(teardown) = inbox.closed {
+                return Some(Err(teardown.into()));
+            }
+
+            match inbox.windows.get(&hop).copied().unwrap_or(CIRCUIT_WINDOW_START) >= cells {
+                true => Some(Ok(())),
+                false => None,
+            }
+        })
+    }
+
+    ///Send a relay cell to the last hop once `try_take_window` has made room for it
+    pub(crate) fn send_reserved(& self, relay: RelayCell) -> Result<()> {
+        self.encrypt_and_send(self.last_hop(), vec![relay], false)
+    }
+
+    fn encrypt_and_send(& self, hop: usize, relays: Vec<RelayCell>, early: bool) -> Result<()> {
+        let truncated = self.inbox.lock().unwrap().truncated.unwrap_or(DestroyReason::None);
+
+        let padding = relays.iter().all(|relay| is_padding_command(relay.get_command()));
+
+        let mut crypto = self.crypto.lock().unwrap();
+
+        if hop >= crypto.hop_count() {
+            return Err(ErrorKind::CircuitTruncated(truncated));
+        }
+
+        for contents in crypto.encrypt_messages(hop, relays)? {
+            let command = if early {
+                Command::RelayEarly { contents }
+            } else {
+                Command::Relay { contents }
+            };
+
+            self.channel.send(TorCell::new(self.circuit_id, command))?;
+        }
+
+        if !padding {
+            self.padding_event(PaddingEvent::NonPaddingSent);
+        }
+
+        Ok(())
+    }
+
+    fn padding_event(& self, event: PaddingEvent) {
+        if let Some(active) = self.padding.state.lock().unwrap().active.as_mut() {
+            active.runtime.event(event);
+            self.padding.changed.notify_all();
+        }
+    }
+
+    ///Negotiate `machine` with its target hop and start a thread to send its padding, stopping any machine already running
+    fn start_padding(self: &Arc<Self>, machine: PaddingMachine, clock: Arc<dyn Clock>) -> Result<()> {
+        self.stop_padding();
+
+        let hop = machine.target_hop.clamp(1, self.crypto.lock().unwrap().hop_count()) - 1;
+
+        let counter = self.padding.state.lock().unwrap().counter.wrapping_add(1);
+
+        self.send_relay(hop, RelayCell::new(0, Relay::PaddingNegotiate {
+            version: CIRCPAD_VERSION,
+            command: CIRCPAD_COMMAND_START,
+            machine_type: machine.machine_type,
+            echo_request: 0,
+            machine_ctr: counter,
+        }), false)?;
+
+        let runtime = PaddingRuntime::new(machine, clock);
+
+        let control = Arc::downgrade(&self.padding);
+
+        runtime.on_clock_advance(Box::new(move || {
+            if let Some(control) = control.upgrade() {
+                control.wake();
+            }
+        }));
+
+        let mut padding = self.padding.state.lock().unwrap();
+
+        padding.counter = counter;
+        padding.active = Some(ActivePadding {
+            runtime,
+            hop,
+            counter,
+        });
+
+        let circuit = Arc::downgrade(self);
+        let control = self.padding.clone();
+
+        thread::spawn(move || Self::pad(circuit, control, counter));
+
+        Ok(())
+    }
+
+    ///Stop the running machine, telling the relay if the machine asks us to
+    fn stop_padding(& self) {
+        let stopped = self.padding.state.lock().unwrap().active.take();
+
+        self.padding.changed.notify_all();
+
+        if let Some(stopped) = stopped {
+            self.send_padding_stop(&stopped);
+        }
+    }
+
+    fn send_padding_stop(& self, stopped: &ActivePadding) {
+        if stopped.runtime.machine().negotiate_end {
+            let _ = self.send_relay(stopped.hop, RelayCell::new(0, Relay::PaddingNegotiate {
+                version: CIRCPAD_VERSION,
+                command: CIRCPAD_COMMAND_STOP,
+                machine_type: stopped.runtime.machine().machine_type,
+                echo_request: 0,
+                machine_ctr: stopped.counter,
+            }), false);
+        }
+    }
+
+    ///Send the padding of the machine negotiated with `counter` until it ends, is replaced or the circuit closes. In between
+    ///the thread sleeps until padding is due, or until an event, the machine's clock or the circuit closing wakes it
+    fn pad(circuit: Weak<Self>, control: Arc<PaddingControl>, counter: u32) {
+        loop {
+            let inner = match circuit.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+
+            let (due, hop, ended) = {
+                let mut padding = control.state.lock().unwrap();
+
+                if padding.closed {
+                    return;
+                }
+
+                let active = match padding.active.as_mut() {
+                    Some(active) if active.counter == counter => active,
+                    _ => return,
+                };
+
+                let due = active.runtime.poll();
+                let hop = active.hop;
+
+                let ended = if active.runtime.is_ended() {
+                    padding.active.take()
+                } else {
+                    None
+                };
+
+                (due, hop, ended)
+            };
+
+            if due && inner.send_relay(hop, RelayCell::new(0, Relay::Drop), false).is_err() {
+                return;
+            }
+
+            if let Some(ended) = ended {
+                inner.send_padding_stop(&ended);
+                return;
+            }
+
+            drop(inner);
+
+            let padding = control.state.lock().unwrap();
+
+            let wait = match padding.active.as_ref() {
+                Some(active) if active.counter == counter && !padding.closed => active.runtime.real_wait(),
+                _ => return,
+            };
+
+            //Whatever changes the machine takes the lock first, so nothing can happen between checking and waiting
+            match wait {
+                Some(wait) => drop(control.changed.wait_timeout(padding, wait).unwrap()),
+                None => drop(control.changed.wait(padding).unwrap()),
+            }
+        }
+    }
+
+    ///The relay's answer to PADDING_NEGOTIATE. A machine it refused is stopped
+    fn padding_negotiated(& self, relay: RelayCell) {
+        if let Ok(Some(Relay::PaddingNegotiated { command, response, machine_ctr, .. })) = relay.get_payload() {
+            let mut padding = self.padding.state.lock().unwrap();
+
+            let refused = matches!(padding.active.as_ref(), Some(active) if active.counter == machine_ctr);
+
+            if refused && command == CIRCPAD_COMMAND_START && response != CIRCPAD_RESPONSE_OK {
+                padding.active = None;
+                self.padding.changed.notify_all();
+            }
+        }
+    }
+
+    fn last_hop(& self) -> usize {
+        self.crypto.lock().unwrap().hop_count() - 1
+    }
+
+    ///The most data a DATA cell to `hop` carries, or the most any hop takes if the circuit was truncated before it
+    fn max_data_len(& self, hop: usize) -> usize {
+        let crypto = self.crypto.lock().unwrap();
+
+        crypto.max_data_len(hop.min(crypto.hop_count().saturating_sub(1)))
+    }
+
+    pub(crate) fn is_closed(& self) -> bool {
+        self.inbox.lock().unwrap().closed.is_some()
+    }
+
+    fn conflux_leg(& self) -> Option<ConfluxLeg> {
+        self.conflux.lock().unwrap().clone()
+    }
+
+    fn process(& self, cell: TorCell, sendme: & mut SendMeTracker) -> Vec<Received> {
+        let contents = match cell.into_command() {
+            Command::Relay { contents } => contents,
+            Command::Destroy { reason } => return vec![Received::Closed(Teardown::Destroyed(reason))],
+            _ => return Vec::new(),
+        };
+
+        let decrypted = self.crypto.lock().unwrap().decrypt(&contents);
+
+        let (hop, messages) = match decrypted {
+            Ok(decrypted) => decrypted,
+            Err(_) => {
+                //A cell no hop recognises means the circuit can't be trusted any more
+                self.channel.unregister_circuit(self.circuit_id);
+
+                let _ = self.channel.send(TorCell::new(self.circuit_id, Command::Destroy { reason: DestroyReason::Protocol }));
+
+                return vec![Received::Closed(Teardown::Destroyed(DestroyReason::Protocol))];
+            },
+        };
+
+        messages.into_iter().map(|relay| self.process_message(hop, relay, sendme)).collect()
+    }
+
+    ///Deal with one relay message from `hop`, of the one or more a cell may carry
+    fn process_message(& self, hop: usize, relay: RelayCell, sendme: & mut SendMeTracker) -> Received {
+        if !is_padding_command(relay.get_command()) {
+            self.padding_event(PaddingEvent::NonPaddingReceived);
+        }
+
+        let received = match relay.get_command() {
+            10 => {
+                self.padding_event(PaddingEvent::PaddingReceived);
+
+                Received::Nothing
+            },
+            5 if relay.get_stream_id() == 0 => Received::CircuitSendMe(hop),
+            41 => Received::Nothing,
+            42 => {
+                self.padding_negotiated(relay);
+
+                Received::Nothing
+            },
+            2 => {
+                let (circuit_due, stream_due) = sendme.data_received(relay.get_stream_id());
+
+                let mut sendmes = Vec::new();
+
+                if circuit_due {
+                    sendmes.push(circuit_sendme(&self.crypto.lock().unwrap(), hop));
+                }
+
+                if stream_due {
+                    sendmes.push(stream_sendme(relay.get_stream_id()));
+                }
+
+                //SENDMEs are never sequenced by a conflux set, and in format v1 both fit in one cell
+                if !sendmes.is_empty() {
+                    let _ = self.send_relays_here(hop, sendmes, false);
+                }
+
+                Received::Relay(relay)
+            },
+            9 => {
+                //Drop the hops now so no later cell is decrypted with them
+                self.crypto.lock().unwrap().truncate(hop + 1);
+
+                let reason = match relay.get_payload() {
+                    Ok(Some(Relay::Truncated { reason })) => reason,
+                    _ => DestroyReason::None,
+                };
+
+                Received::Truncated(hop, reason)
+            },
+            _ => Received::Relay(relay),
+        };
+
+        match received {
+            Received::Relay(relay) if is_multiplexed(relay.get_command()) || relay.get_command() == 22 => {
+                match self.conflux_leg() {
+                    Some(leg) if hop == self.last_hop() => {
+                        leg.set.receive(leg.index, relay);
+
+                        Received::Nothing
+                    },
+                    _ => Received::Relay(relay),
+                }
+            },
+            received => received,
+        }
+    }
+
+    ///Wait for the next relay cell on `stream_id`, taking cells from the channel's queue if no other thread is
+    fn receive(& self, stream_id: u16) -> Result<RelayCell> {
+        self.receive_until(stream_id, None)
+    }
+
+    ///`receive`, giving up with `ErrorKind::Timeout` at `deadline`
+    fn receive_until(& self, stream_id: u16, deadline: Option<Instant>) -> Result<RelayCell> {
+        self.wait_until(deadline, |inbox| {
+            match inbox.pending.get_mut(&stream_id) {
+                Some(queue) => {
+                    if let Some(cell) = queue.cells.pop_front() {
+                        return Some(Ok(cell));
+                    }
+
+                    if let Some(teardown) = queue.failed {
+                        return Some(Err(teardown.into()));
+                    }
+                },
+                None => return Some(Err(ErrorKind::StreamRefused(EndReason::Done))),
+            }
+
+            inbox.closed.map(|teardown| Err(teardown.into()))
+        })
+    }
+
+    ///Wait for room in the window of `stream_id`, then take one DATA cell from it
+    fn take_stream_window(& self, stream_id: u16) -> Result<()> {
+        self.wait_until(None, |inbox| {
+            if let Some(teardown) = inbox.closed {
+                return Some(Err(teardown.into()));
+            }
+
+            match inbox.pending.get_mut(&stream_id) {
+                Some(StreamQueue { failed: Some(teardown), .. }) => Some(Err((*teardown).into())),
+                Some(queue) if queue.window > 0 => {
+                    queue.window -= 1;
+
+                    Some(Ok(()))
+                },
+                Some(_) => None,
+                None => Some(Err(ErrorKind::StreamRefused(EndReason::Done))),
+            }
+        })
+    }
+
+    ///Wait until `ready` finds what the caller is after in the inbox, taking cells from the channel's queue if no other
+    ///thread is. Gives up with `ErrorKind::Timeout` at `deadline`
+    fn wait_until<T>(& self, deadline: Option<Instant>, mut ready: impl FnMut(& mut Inbox) -> Option<Result<T>>) -> Result<T> {
+        let mut inbox = self.inbox.lock().unwrap();
+
+        loop {
+            if let Some(result) = ready(& mut inbox) {
+                return result;
+            }
+
+            let remaining = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if remaining > Duration::from_millis(0) => Some(remaining),
+                    _ => return Err(ErrorKind::Timeout),
+                },
+                None => None,
+            };
+
+            match self.incoming.try_lock() {
+                Ok(mut incoming) => {
+                    drop(inbox);
+
+                    let cell = match remaining {
+                        Some(remaining) => incoming.cells.recv_timeout(remaining),
+                        None => incoming.cells.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+
+                    let received = match cell {
+                        Ok(cell) => {
+                            let Incoming { cells: _, sendme } = &mut *incoming;
+                            self.process(cell, sendme)
+                        },
+                        Err(RecvTimeoutError::Timeout) => Vec::new(),
+                        Err(RecvTimeoutError::Disconnected) => vec![Received::Closed(Teardown::Destroyed(DestroyReason::OrConnClosed))],
+                    };
+
+                    drop(incoming);
+
+                    inbox = self.inbox.lock().unwrap();
+
+                    for received in received {
+                        match received {
+                            Received::Relay(relay) => inbox.file(relay),
+                            Received::CircuitSendMe(hop) => {
+                                if let Some(rtt) = inbox.circuit_sendme(hop) {
+                                    self.sendme_rtt(hop, rtt);
+                                }
+                            },
+                            Received::Truncated(hop, reason) => {
+                                inbox.truncated = Some(reason);
+
+                                //A hop extended in place of the ones dropped starts with a full window
+                                inbox.windows.retain(|window_hop, _| *window_hop <= hop);
+                                inbox.sendme_timers.retain(|timer_hop, _| *timer_hop <= hop);
+
+                                for queue in inbox.pending.values_mut().filter(|queue| queue.hop > hop) {
+                                    queue.failed = Some(Teardown::Truncated(reason));
+                                }
+
+                                if let Some(control) = inbox.pending.get_mut(&0) {
+                                    control.cells.push_back(RelayCell::new(0, Relay::Truncated { reason }));
+                                }
+                            },
+                            Received::Closed(teardown) => {
+                                //A circuit we closed ourselves stays closed for that reason
+                                if inbox.closed.is_none() {
+                                    inbox.closed = Some(teardown);
+                                }
+                            },
+                            Received::Nothing => {},
+                        }
+                    }
+
+                    //Wake everyone, either because their cell arrived or so one of them can take the queue
+                    self.arrived.notify_all();
+
+                    if inbox.closed.is_some() {
+                        self.padding.close();
+                    }
+                },
+                Err(_) => {
+                    inbox = match remaining {
+                        Some(remaining) => self.arrived.wait_timeout(inbox, remaining).unwrap().0,
+                        None => self.arrived.wait(inbox).unwrap(),
+                    };
+                },
+            }
+        }
+    }
+
+    ///A circuit SENDME from `hop` took `rtt` to arrive. The last hop of a conflux leg tells its set
+    fn sendme_rtt(& self, hop: usize, rtt: Duration) {
+        if let Some(leg) = self.conflux_leg() {
+            if hop == self.last_hop() {
+                leg.set.update_rtt(leg.index, rtt);
+            }
+        }
+    }
+
+    ///Queue a cell another leg of our conflux set received, waking the threads waiting on the circuit
+    pub(crate) fn file(& self, relay: RelayCell) {
+        let mut inbox = self.inbox.lock().unwrap();
+
+        inbox.file(relay);
+
+        self.wake(inbox);
+    }
+
+    ///Wake every thread waiting on the circuit so it looks at `inbox` again. A thread reading the channel's queue holds
+    ///`incoming` and only wakes when a cell arrives, so it is sent a PADDING cell
+    fn wake(& self, inbox: MutexGuard<Inbox>) {
+        if self.incoming.try_lock().is_err() {
+            self.channel.wake_circuit(self.circuit_id);
+        }
+
+        drop(inbox);
+
+        self.arrived.notify_all();
+    }
+
+    ///Take cells from a conflux leg's queue, so cells for streams on other legs are put in order, until the leg closes
+    ///or its set is dropped. Control cells nobody else reads are thrown away
+    fn drive_conflux(& self, leg: ConfluxLeg) {
+        let _ = self.wait_until(None, |inbox| {
+            if let Some(control) = inbox.pending.get_mut(&0) {
+                control.cells.clear();
+            }
+
+            if leg.set.is_released() {
+                return Some(Ok(()));
+            }
+
+            inbox.closed.map(|teardown| Err(teardown.into()))
+        });
+    }
+
+    ///Throw away control cells nobody asked for, such as a TRUNCATED the relay sent on its own, before making a request
+    fn clear_control(& self) {
+        if let Some(control) = self.inbox.lock().unwrap().pending.get_mut(&0) {
+            control.cells.clear();
+        }
+    }
+
+    ///Start filing cells for a stream the other end opened
+    fn accept_stream(& self, hop: usize, stream_id: u16) -> Result<()> {
+        let mut inbox = self.inbox.lock().unwrap();
+
+        if let Some(teardown) = inbox.closed {
+            return Err(teardown.into());
+        }
+
+        if stream_id == 0 || inbox.pending.contains_key(&stream_id) {
+            return Err(ErrorKind::UnexpectedCell(format!("BEGIN for stream {}", stream_id)));
+        }
+
+        inbox.pending.insert(stream_id, StreamQueue::new(hop));
+
+        Ok(())
+    }
+
+    fn open_stream(& self, hop: usize) -> Result<u16> {
+        let mut inbox = self.inbox.lock().unwrap();
+
+        if let Some(teardown) = inbox.closed {
+            return Err(teardown.into());
+        }
+
+        let mut next = self.next_stream_id.lock().unwrap();
+
+        let stream_id = loop {
+            let candidate = *next;
+
+            *next = next.wrapping_add(1);
+
+            if candidate != 0 && !inbox.pending.contains_key(&candidate) {
+                break candidate;
+            }
+        };
+
+        inbox.pending.insert(stream_id, StreamQueue::new(hop));
+
+        Ok(stream_id)
+    }
+
+    fn close_stream(& self, stream_id: u16) {
+        self.inbox.lock().unwrap().pending.remove(&stream_id);
+
+        //Another thread may be blocked waiting for cells, in which case the count is simply left behind
+        if let Ok(mut incoming) = self.incoming.try_lock() {
+            incoming.sendme.stream_closed(stream_id);
+        }
+    }
+
+    ///Send DESTROY unless the circuit is already closed, then fail everything still using it
+    fn destroy(& self) {
+        let mut inbox = self.inbox.lock().unwrap();
+
+        if inbox.closed.is_some() {
+            return;
+        }
+
+        inbox.closed = Some(Teardown::Closed);
+
+        let _ = self.channel.send(TorCell::new(self.circuit_id, Command::Destroy { reason: DestroyReason::None }));
+
+        //Unregistering drops the queue's sender, which wakes a thread blocked waiting for cells
+        self.channel.unregister_circuit(self.circuit_id);
+
+        self.arrived.notify_all();
+        self.padding.close();
+    }
+}
+
+impl Drop for CircuitInner {
+    fn drop(& mut self) {
+        self.destroy();
+    }
+}
+
+///A circuit built through a `Channel`. The circuit is destroyed once it and all of its streams are dropped
+pub struct Circuit {
+    inner: Arc<CircuitInner>,
+}
+
+impl Circuit {
+    pub(crate) fn new(channel: Arc<ChannelShared>, circuit_id: u32, crypto: CircuitCrypto, cells: Receiver<TorCell>) -> Self {
+        let mut pending = HashMap::new();
+
+        pending.insert(0, StreamQueue::new(0));
+
+        Self {
+            inner: Arc::new(CircuitInner {
+                channel,
+                circuit_id,
+                crypto: Mutex::new(crypto),
+                incoming: Mutex::new(Incoming { cells, sendme: SendMeTracker::new() }),
+                inbox: Mutex::new(Inbox { pending, windows: HashMap::new(), sendme_timers: HashMap::new(), closed: None, truncated: None }),
+                arrived: Condvar::new(),
+                next_stream_id: Mutex::new(1),
+                padding: Arc::new(PaddingControl::default()),
+                conflux: Mutex::new(None),
+            })
+        }
+    }
+
+    pub fn circuit_id(& self) -> u32 {
+        self.inner.circuit_id
+    }
+
+    pub fn hop_count(& self) -> usize {
+        self.inner.crypto.lock().unwrap().hop_count()
+    }
+
+    pub fn is_closed(& self) -> bool {
+        self.inner.inbox.lock().unwrap().closed.is_some()
+    }
+
+    ///Run the padding machine `machine` on the circuit, replacing any machine already running. The relay at the machine's
+    ///target hop, or the last hop if the circuit is shorter, is asked to run its side with PADDING_NEGOTIATE
+    pub fn start_padding(& self, machine: PaddingMachine) -> Result<()> {
+        self.inner.start_padding(machine, Arc::new(SystemClock))
+    }
+
+    ///Run `machine` as `start_padding` does, timing it with `clock` rather than the real clock
+    pub(crate) fn start_padding_with_clock(& self, machine: PaddingMachine, clock: Arc<dyn Clock>) -> Result<()> {
+        self.inner.start_padding(machine, clock)
+    }
+
+    ///Stop the running padding machine, if there is one
+    pub fn stop_padding(& self) {
+        self.inner.stop_padding();
+    }
+
+    ///The name of the padding machine running on the circuit, if any
+    pub fn padding_machine(& self) -> Option<&'static str> {
+        self.inner.padding.state.lock().unwrap().active.as_ref().map(|active| active.runtime.machine().name)
+    }
+
+    ///Why the circuit closed, if it has
+    pub fn teardown(& self) -> Option<Teardown> {
+        self.inner.inbox.lock().unwrap().closed
+    }
+
+    ///Close the circuit with DESTROY. Its streams fail with `ErrorKind::CircuitClosed`
+    pub fn destroy(& self) {
+        self.inner.destroy();
+    }
+
+    ///Send RELAY_TRUNCATE to hop number `hops - 1`, keeping the first `hops` hops. Streams attached to later hops fail with `ErrorKind::CircuitTruncated`.
+    ///Truncating to zero hops destroys the circuit
+    pub fn truncate(& self, hops: usize) -> Result<()> {
+        if hops == 0 {
+            self.destroy();
+            return Ok(());
+        }
+
+        if hops >= self.hop_count() {
+            return Ok(());
+        }
+
+        self.inner.clear_control();
+
+        self.inner.send_relay(hops - 1, RelayCell::new(0, Relay::Truncate), false)?;
+
+        loop {
+            let reply = self.inner.receive(0)?;
+
+            if let Some(Relay::Truncated { .. }) = reply.get_payload()? {
+                return Ok(());
+            }
+        }
+    }
+
+    ///Extend the circuit by one hop with EXTEND2 and the ntor handshake
+    pub fn extend_ntor(& self, link_specifiers: Vec<LinkSpecifier>, node_id: &[u8; 20], onion_key: &[u8; 32]) -> Result<()> {
+        self.extend_ntor_until(link_specifiers, node_id, onion_key, None)
+    }
+
+    ///`extend_ntor`, failing with `ErrorKind::Timeout` if EXTENDED2 hasn't arrived by `deadline`. The circuit can't be
+    ///used after that, since the hop may still answer
+    pub fn extend_ntor_until(& self, link_specifiers: Vec<LinkSpecifier>, node_id: &[u8; 20], onion_key: &[u8; 32], deadline: Option<Instant>) -> Result<()> {
+        self.extend_ntor_with(link_specifiers, node_id, onion_key, HopProtocol::default(), deadline)
+    }
+
+    ///`extend_ntor_until`, with the new hop using the relay crypto and cell format in `protocol`
+    pub fn extend_ntor_with(& self, link_specifiers: Vec<LinkSpecifier>, node_id: &[u8; 20], onion_key: &[u8; 32], protocol: HopProtocol, deadline: Option<Instant>) -> Result<()> {
+        let ntor = NtorClient::new(node_id, onion_key);
+
+        let extend = Relay::Extend2 {
+            link_specifiers: NLengthVector::from(link_specifiers),
+            htype: NTOR_HANDSHAKE_TYPE,
+            handshake_data: NLengthVector::from(ntor.onion_skin()),
+        };
+
+        self.inner.clear_control();
+
+        self.inner.send_relay(self.inner.last_hop(), RelayCell::new(0, extend), true)?;
+
+        loop {
+            let reply = self.inner.receive_until(0, deadline)?;
+
+            match reply.get_payload()? {
+                Some(Relay::Extended2 { handshake_data }) => {
+                    let materials = ntor.complete(&handshake_data.0, protocol.crypto.key_material_length()).ok_or(ErrorKind::HandshakeFailed)?;
+
+                    self.inner.crypto.lock().unwrap().add_hop_with_format(protocol.crypto.hop(&materials), protocol.format);
+
+                    return Ok(());
+                },
+                Some(Relay::Truncated { reason }) => return Err(ErrorKind::CircuitTruncated(reason)),
+                Some(Relay::SendMe { .. }) => {},
+                payload => return Err(ErrorKind::UnexpectedCell(format!("{:?}", payload))),
+            }
+        }
+    }
+
+    ///Send a control cell (stream 0) to the last hop, dropping control cells nobody asked for first
+    pub(crate) fn send_control(& self, relay: RelayCell) -> Result<()> {
+        self.inner.clear_control();
+
+        self.inner.send_relay(self.inner.last_hop(), relay, false)
+    }
+
+    ///Wait for the next control cell (stream 0) from any hop
+    pub(crate) fn receive_control(& self) -> Result<RelayCell> {
+        self.inner.receive(0)
+    }
+
+    ///`receive_control`, failing with `ErrorKind::Timeout` if nothing arrives within `timeout`
+    pub(crate) fn receive_control_timeout(& self, timeout: Duration) -> Result<RelayCell> {
+        self.inner.receive_until(0, Some(Instant::now() + timeout))
+    }
+
+    pub(crate) fn downgrade(& self) -> Weak<CircuitInner> {
+        Arc::downgrade(&self.inner)
+    }
+
+    ///Hand the circuit's stream cells to its conflux set, starting a thread that takes cells from its queue until the set
+    ///is released
+    pub(crate) fn join_conflux(& self, leg: ConfluxLeg) {
+        *self.inner.conflux.lock().unwrap() = Some(leg.clone());
+
+        let circuit = self.inner.clone();
+
+        thread::spawn(move || circuit.drive_conflux(leg));
+    }
+
+    ///Wake every thread waiting on the circuit, such as the one driving its conflux leg
+    pub(crate) fn wake(& self) {
+        self.inner.wake(self.inner.inbox.lock().unwrap());
+    }
+
+    ///Add a hop whose keys came from a handshake carried in other cells, such as the onion service at a rendezvous point
+    pub(crate) fn add_virtual_hop(& self, crypto: CellCrypto) {
+        self.inner.crypto.lock().unwrap().add_hop(crypto);
+    }
+
+    ///KH from the handshake with the last hop
+    pub(crate) fn last_hop_nonce(& self) -> Option<[u8; 20]> {
+        let crypto = self.inner.crypto.lock().unwrap();
+
+        crypto.nonce(crypto.hop_count() - 1)
+    }
+
+    ///Answer a BEGIN from the other end of the circuit with CONNECTED, returning the stream and the requested `address:port`
+    pub fn accept(& self, begin: RelayCell) -> Result<(TorStream, String)> {
+        let hop = self.inner.last_hop();
+        let stream_id = begin.get_stream_id();
+
+        let target = match begin.get_payload()? {
+            Some(Relay::Begin { addr_and_port, .. }) => addr_and_port,
+            payload => return Err(ErrorKind::UnexpectedCell(format!("{:?}", payload))),
+        };
+
+        self.inner.accept_stream(hop, stream_id)?;
+
+        self.inner.send_relay(hop, RelayCell::new(stream_id, Relay::Connected { address: ConnectedAddress::None }), false)?;
+
+        Ok((TorStream::new(self.inner.clone(), hop, stream_id, ConnectedAddress::None), target))
+    }
+
+    fn await_connected(& self, hop: usize, stream_id: u16) -> Result<TorStream> {
+        loop {
+            let reply = match self.inner.receive(stream_id) {
+                Ok(reply) => reply,
+                Err(kind) => {
+                    self.inner.close_stream(stream_id);
+                    return Err(kind);
+                }
+            };
+
+            match reply.get_command() {
+                4 => {
+                    let connected = match reply.get_payload() {
+                        Ok(Some(Relay::Connected { address })) => address,
+                        _ => ConnectedAddress::None,
+                    };
+
+                    return Ok(TorStream::new(self.inner.clone(), hop, stream_id, connected));
+                },
+                3 => {
+                    self.inner.close_stream(stream_id);
+
+                    return match reply.get_payload()? {
+                        Some(Relay::End { end_reason }) => Err(ErrorKind::StreamRefused(end_reason)),
+                        _ => Err(ErrorKind::StreamRefused(EndReason::Misc)),
+                    };
+                },
+                _ => {},
+            }
+        }
+    }
+
+    ///Open a stream from the last hop to `address:port`
+    pub fn begin(& self, address: &str, port: u16) -> Result<TorStream> {
+        self.begin_with_flags(address, port, BeginFlags::empty())
+    }
+
+    ///Open a stream from the last hop to `address:port`, telling the exit which address families we can use
+    pub fn begin_with_flags(& self, address: &str, port: u16, flags: BeginFlags) -> Result<TorStream> {
+        let hop = self.inner.last_hop();
+
+        let stream_id = self.inner.open_stream(hop)?;
+
+        self.inner.send_relay(hop, RelayCell::new(stream_id, Relay::Begin { addr_and_port: addr_and_port(address, port), flags }), false)?;
+
+        self.await_connected(hop, stream_id)
+    }
+
+    ///Open a stream to the directory port of the last hop
+    pub fn begin_dir(& self) -> Result<TorStream> {
+        let hop = self.inner.last_hop();
+
+        let stream_id = self.inner.open_stream(hop)?;
+
+        self.inner.send_relay(hop, RelayCell::new(stream_id, Relay::BeginDir), false)?;
+
+        self.await_connected(hop, stream_id)
+    }
+}
+
+///A stream over a circuit, implementing `Read` and `Write`
+pub struct TorStream {
+    circuit: Arc<CircuitInner>,
+    hop: usize,
+    stream_id: u16,
+    connected: ConnectedAddress,
+    buffer: Vec<u8>,
+    offset: usize,
+    finished: bool,
+    ended: bool,
+}
+
+impl TorStream {
+    fn new(circuit: Arc<CircuitInner>, hop: usize, stream_id: u16, connected: ConnectedAddress) -> Self {
+        Self {
+            circuit,
+            hop,
+            stream_id,
+            connected,
+            buffer: Vec::new(),
+            offset: 0,
+            finished: false,
+            ended: false,
+        }
+    }
+
+    pub fn stream_id(& self) -> u16 {
+        self.stream_id
+    }
+
+    ///The address the exit said it connected to, and for how long it may be cached
+    pub fn connected_address(& self) -> ConnectedAddress {
+        self.connected
+    }
+
+    ///A handle that writes to this stream from another thread, so a stream can be read and written at the same time
+    pub fn writer(& self) -> TorStreamWriter {
+        TorStreamWriter {
+            circuit: self.circuit.clone(),
+            hop: self.hop,
+            stream_id: self.stream_id,
+        }
+    }
+
+    ///Send RELAY_END to the exit. Anything already received can still be read
+    pub fn end(& mut self) {
+        if !self.ended {
+            self.ended = true;
+
+            let _ = self.circuit.send_relay(self.hop, RelayCell::new(self.stream_id, Relay::End { end_reason: EndReason::Done }), false);
+        }
+    }
+}
+
+impl Read for TorStream {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.offset < self.buffer.len() {
+                let length = std::cmp::min(buf.len(), self.buffer.len() - self.offset);
+
+                buf[..length].copy_from_slice(&self.buffer[self.offset..self.offset + length]);
+
+                self.offset += length;
+
+                return Ok(length);
+            }
+
+            if self.finished {
+                return Ok(0);
+            }
+
+            let relay = self.circuit.receive(self.stream_id)?;
+
+            match relay.get_command() {
+                2 => {
+                    self.buffer = relay.into_data();
+                    self.offset = 0;
+                },
+                3 => {
+                    self.finished = true;
+                    self.ended = true;
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+impl Write for TorStream {
+    fn write(& mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty DATA cell would still spend a cell of the stream and circuit windows
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.ended {
+            return Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        }
+
+        let length = std::cmp::min(buf.len(), self.circuit.max_data_len(self.hop));
+
+        self.circuit.take_stream_window(self.stream_id)?;
+
+        self.circuit.send_relay(self.hop, RelayCell::new_data(self.stream_id, &buf[..length]), false)?;
+
+        Ok(length)
+    }
+
+    fn flush(& mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TorStream {
+    fn drop(& mut self) {
+        self.end();
+
+        self.circuit.close_stream(self.stream_id);
+    }
+}
+
+///Writes to a `TorStream` from another thread. Unlike the stream itself, dropping the writer does not end the stream
+pub struct TorStreamWriter {
+    circuit: Arc<CircuitInner>,
+    hop: usize,
+    stream_id: u16,
+}
+
+impl TorStreamWriter {
+    ///Send RELAY_END, telling the other end we have nothing more to send
+    pub fn end(& mut self) {
+        let _ = self.circuit.send_relay(self.hop, RelayCell::new(self.stream_id, Relay::End { end_reason: EndReason::Done }), false);
+    }
+}
+
+impl Write for TorStreamWriter {
+    fn write(& mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let length = std::cmp::min(buf.len(), self.circuit.max_data_len(self.hop));
+
+        self.circuit.take_stream_window(self.stream_id)?;
+
+        self.circuit.send_relay(self.hop, RelayCell::new_data(self.stream_id, &buf[..length]), false)?;
+
+        Ok(length)
+    }
+
+    fn flush(& mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
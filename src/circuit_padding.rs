@@ -0,0 +1,523 @@
+//! Circuit-level padding machines from proposal 254.
+//!
+//! A machine is a list of states, each with a histogram of delays and a table of which events move it to which state.
+//! Entering a state draws a delay from its histogram, and a RELAY_DROP cell is sent to the machine's target hop if the
+//! delay runs out before anything else happens. Histograms may hold tokens for an "infinity" bin, which means waiting
+//! for the next event instead of padding, and may use up their tokens as padding is sent. A state can also limit how
+//! many padding cells it sends before moving on.
+//!
+//! Machines run against a `Clock` so their behaviour can be checked with a `VirtualClock` that only moves when told to.
+//! Circuits negotiate a machine with the relay at the target hop using PADDING_NEGOTIATE, and the relay answers with
+//! PADDING_NEGOTIATED.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+///The only circuit PADDING_NEGOTIATE version
+pub const CIRCPAD_VERSION: u8 = 0;
+
+///PADDING_NEGOTIATE command to stop a machine
+pub const CIRCPAD_COMMAND_STOP: u8 = 1;
+
+///PADDING_NEGOTIATE command to start a machine
+pub const CIRCPAD_COMMAND_START: u8 = 2;
+
+///PADDING_NEGOTIATED response when the relay did as asked
+pub const CIRCPAD_RESPONSE_OK: u8 = 1;
+
+///PADDING_NEGOTIATED response when the relay couldn't
+pub const CIRCPAD_RESPONSE_ERR: u8 = 2;
+
+///Machine number of the client introduction circuit machine
+pub const MACHINE_CLIENT_INTRO: u8 = 0;
+
+///Machine number of the client rendezvous circuit machine
+pub const MACHINE_CLIENT_RENDEZVOUS: u8 = 1;
+
+///The fewest and most padding cells the intro machine sends after INTRODUCE1
+const INTRO_PADDING_RANGE: (u32, u32) = (7, 10);
+
+///The state every machine starts in
+pub const STATE_START: usize = 0;
+
+///Called to wake whatever waits on a clock when it is moved forward by hand
+pub type ClockWake = Box<dyn Fn() + Send + Sync>;
+
+///A source of the current time for padding machines
+pub trait Clock: Send + Sync {
+    fn now(& self) -> Instant;
+
+    ///How long to wait in real time for `deadline`, or None if only `advance` brings it closer
+    fn real_wait(& self, deadline: Instant) -> Option<Duration> {
+        Some(deadline.saturating_duration_since(self.now()))
+    }
+
+    ///Call `wake` whenever the clock is moved forward by hand, which the real clock never is
+    fn on_advance(& self, _wake: ClockWake) {}
+}
+
+///The real clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(& self) -> Instant {
+        Instant::now()
+    }
+}
+
+///A clock that stands still until `advance` is called, shared between its clones
+#[derive(Clone)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Instant>>,
+    wakers: Arc<Mutex<Vec<ClockWake>>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+            wakers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    ///Move the clock forward, waking whatever is waiting on it
+    pub fn advance(& self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+
+        for wake in self.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(& self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn real_wait(& self, _deadline: Instant) -> Option<Duration> {
+        None
+    }
+
+    fn on_advance(& self, wake: ClockWake) {
+        self.wakers.lock().unwrap().push(wake);
+    }
+}
+
+///Something that happened on the circuit, which may move a machine to another state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaddingEvent {
+    NonPaddingReceived,
+    NonPaddingSent,
+    PaddingSent,
+    PaddingReceived,
+    ///The state's histogram drew its infinity bin
+    Infinity,
+    ///The state used up the tokens in its histogram
+    BinsEmpty,
+    ///The state sent as many padding cells as its length allows
+    LengthCount,
+}
+
+///Where an event takes a machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    ///Enter the state with this index, or draw a new delay if it is the current one
+    State(usize),
+    ///Stay in the current state without padding until the next event
+    Cancel,
+    ///Stop the machine
+    End,
+}
+
+///Delays to pad after. Bin `i` covers `edges[i]..edges[i + 1]` and holds `tokens[i]` tokens, and the infinity bin
+///holds `infinity` more. Bins are drawn in proportion to their tokens
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub edges: Vec<Duration>,
+    pub tokens: Vec<u32>,
+    pub infinity: u32,
+    ///Whether sending padding uses up a token from the bin its delay came from, and sending any other cell one from
+    ///the bin its gap falls in
+    pub remove_tokens: bool,
+}
+
+impl Histogram {
+    ///A histogram that always pads straight away
+    pub fn immediate() -> Self {
+        Self {
+            edges: vec![Duration::from_micros(0), Duration::from_micros(1)],
+            tokens: vec![1],
+            infinity: 0,
+            remove_tokens: false,
+        }
+    }
+
+    ///A histogram that never pads
+    pub fn never() -> Self {
+        Self {
+            edges: vec![],
+            tokens: vec![],
+            infinity: 1,
+            remove_tokens: false,
+        }
+    }
+
+    ///The bin `delay` falls in, if any
+    fn bin_of(& self, delay: Duration) -> Option<usize> {
+        (0..self.tokens.len()).find(|bin| delay >= self.edges[*bin] && delay < self.edges[*bin + 1])
+    }
+}
+
+///One state of a padding machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaddingState {
+    pub histogram: Histogram,
+    ///The fewest and most padding cells to send in this state, drawn uniformly on entering it
+    pub length: Option<(u32, u32)>,
+    pub transitions: Vec<(PaddingEvent, Transition)>,
+}
+
+impl PaddingState {
+    fn transition(& self, event: PaddingEvent) -> Option<Transition> {
+        self.transitions.iter().find(|(on, _)| *on == event).map(|(_, transition)| *transition)
+    }
+}
+
+///A padding machine, as both ends of a circuit know it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaddingMachine {
+    pub name: &'static str,
+    ///The number sent in PADDING_NEGOTIATE to name the machine
+    pub machine_type: u8,
+    ///The hop to pad to, counting the first hop as 1
+    pub target_hop: usize,
+    ///Whether to tell the relay with PADDING_NEGOTIATE when the machine ends
+    pub negotiate_end: bool,
+    pub states: Vec<PaddingState>,
+}
+
+impl PaddingMachine {
+    ///The client side of the machine that makes introduction circuits look like general ones. Once INTRODUCE1 is sent
+    ///it pads straight away until it has sent 7 to 10 cells, ending early if the intro point answers first
+    pub fn client_intro() -> Self {
+        Self {
+            name: "client_ip_circ",
+            machine_type: MACHINE_CLIENT_INTRO,
+            target_hop: 2,
+            negotiate_end: true,
+            states: vec![
+                PaddingState {
+                    histogram: Histogram::never(),
+                    length: None,
+                    transitions: vec![(PaddingEvent::NonPaddingSent, Transition::State(1))],
+                },
+                PaddingState {
+                    histogram: Histogram::immediate(),
+                    length: Some(INTRO_PADDING_RANGE),
+                    transitions: vec![
+                        (PaddingEvent::NonPaddingReceived, Transition::End),
+                        (PaddingEvent::LengthCount, Transition::End),
+                    ],
+                },
+            ],
+        }
+    }
+
+    ///The client side of the machine that makes rendezvous circuits look like general ones. It sends a single padding
+    ///cell after ESTABLISH_RENDEZVOUS, as a general circuit would carry one more cell at that point
+    pub fn client_rendezvous() -> Self {
+        Self {
+            name: "client_rp_circ",
+            machine_type: MACHINE_CLIENT_RENDEZVOUS,
+            target_hop: 2,
+            negotiate_end: true,
+            states: vec![
+                PaddingState {
+                    histogram: Histogram::never(),
+                    length: None,
+                    transitions: vec![(PaddingEvent::NonPaddingSent, Transition::State(1))],
+                },
+                PaddingState {
+                    histogram: Histogram::immediate(),
+                    length: None,
+                    transitions: vec![(PaddingEvent::PaddingSent, Transition::End)],
+                },
+            ],
+        }
+    }
+}
+
+///A running padding machine
+pub struct PaddingRuntime {
+    machine: PaddingMachine,
+    clock: Arc<dyn Clock>,
+    ///None once the machine has ended
+    state: Option<usize>,
+    tokens: Vec<u32>,
+    infinity: u32,
+    ///Padding cells left before `LengthCount`
+    length: Option<u32>,
+    ///When padding is due, and the bin its delay came from
+    scheduled: Option<(Instant, usize)>,
+    last_cell: Instant,
+    padding_sent: u64,
+    ///Bumped whenever a state is entered or cancelled, so `poll` knows whether an event already rescheduled
+    generation: u64,
+}
+
+impl PaddingRuntime {
+    pub fn new(machine: PaddingMachine, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+
+        let mut runtime = Self {
+            machine,
+            clock,
+            state: None,
+            tokens: vec![],
+            infinity: 0,
+            length: None,
+            scheduled: None,
+            last_cell: now,
+            padding_sent: 0,
+            generation: 0,
+        };
+
+        let mut events = VecDeque::new();
+
+        runtime.enter(STATE_START, & mut events);
+        runtime.run(events);
+
+        runtime
+    }
+
+    pub fn machine(& self) -> &PaddingMachine {
+        &self.machine
+    }
+
+    ///The index of the current state, or None if the machine has ended
+    pub fn state(& self) -> Option<usize> {
+        self.state
+    }
+
+    pub fn is_ended(& self) -> bool {
+        self.state.is_none()
+    }
+
+    ///When the next padding cell is due, if one is scheduled
+    pub fn deadline(& self) -> Option<Instant> {
+        self.scheduled.map(|(deadline, _)| deadline)
+    }
+
+    ///How long to wait in real time before padding is due, or None if it waits on an event or the clock being advanced
+    pub fn real_wait(& self) -> Option<Duration> {
+        let deadline = self.deadline()?;
+
+        if self.clock.now() >= deadline {
+            return Some(Duration::from_secs(0));
+        }
+
+        self.clock.real_wait(deadline)
+    }
+
+    ///Call `wake` whenever the machine's clock is moved forward by hand
+    pub fn on_clock_advance(& self, wake: ClockWake) {
+        self.clock.on_advance(wake);
+    }
+
+    ///The number of padding cells the machine has sent
+    pub fn padding_sent(& self) -> u64 {
+        self.padding_sent
+    }
+
+    ///Tell the machine a cell crossed the circuit. `PaddingSent` is raised by `poll` and shouldn't be passed here
+    pub fn event(& mut self, event: PaddingEvent) {
+        let now = self.clock.now();
+
+        let mut events = VecDeque::new();
+
+        events.push_back(event);
+
+        if event == PaddingEvent::NonPaddingSent {
+            let gap = now.saturating_duration_since(self.last_cell);
+
+            if let Some(state) = self.state {
+                let histogram = &self.machine.states[state].histogram;
+
+                if let Some(bin) = histogram.bin_of(gap).filter(|bin| histogram.remove_tokens && self.tokens[*bin] > 0) {
+                    self.tokens[bin] -= 1;
+
+                    if self.bins_empty() {
+                        events.push_back(PaddingEvent::BinsEmpty);
+                    }
+                }
+            }
+        }
+
+        if event != PaddingEvent::PaddingReceived {
+            self.last_cell = now;
+        }
+
+        self.run(events);
+    }
+
+    ///Whether a padding cell is due now. If it is, the caller must send it, since it is counted as sent
+    pub fn poll(& mut self) -> bool {
+        let now = self.clock.now();
+
+        let bin = match self.scheduled {
+            Some((deadline, bin)) if deadline <= now => bin,
+            _ => return false,
+        };
+
+        let state = match self.state {
+            Some(state) => state,
+            None => return false,
+        };
+
+        self.scheduled = None;
+        self.padding_sent += 1;
+        self.last_cell = now;
+
+        let mut events = VecDeque::new();
+
+        events.push_back(PaddingEvent::PaddingSent);
+
+        if let Some(length) = self.length.as_mut() {
+            *length = length.saturating_sub(1);
+
+            if *length == 0 {
+                events.push_back(PaddingEvent::LengthCount);
+            }
+        }
+
+        if self.machine.states[state].histogram.remove_tokens {
+            self.tokens[bin] = self.tokens[bin].saturating_sub(1);
+
+            if self.bins_empty() {
+                events.push_back(PaddingEvent::BinsEmpty);
+            }
+        }
+
+        let generation = self.generation;
+
+        self.run(events);
+
+        //A state with nothing to move it keeps padding
+        if self.state == Some(state) && self.generation == generation && self.scheduled.is_none() {
+            let mut events = VecDeque::new();
+
+            self.schedule(& mut events);
+            self.run(events);
+        }
+
+        true
+    }
+
+    fn bins_empty(& self) -> bool {
+        self.infinity == 0 && self.tokens.iter().all(|tokens| *tokens == 0)
+    }
+
+    ///Handle events in order, along with any they raise
+    fn run(& mut self, mut events: VecDeque<PaddingEvent>) {
+        while let Some(event) = events.pop_front() {
+            let state = match self.state {
+                Some(state) => state,
+                None => return,
+            };
+
+            match self.machine.states[state].transition(event) {
+                Some(Transition::State(next)) if next == state => {
+                    self.generation += 1;
+                    self.schedule(& mut events);
+                },
+                Some(Transition::State(next)) => self.enter(next, & mut events),
+                Some(Transition::Cancel) => {
+                    self.generation += 1;
+                    self.scheduled = None;
+                },
+                Some(Transition::End) => {
+                    self.state = None;
+                    self.scheduled = None;
+                },
+                None => {},
+            }
+        }
+    }
+
+    fn enter(& mut self, state: usize, events: & mut VecDeque<PaddingEvent>) {
+        let spec = &self.machine.states[state];
+
+        self.state = Some(state);
+        self.generation += 1;
+        self.tokens = spec.histogram.tokens.clone();
+        self.infinity = spec.histogram.infinity;
+        self.length = spec.length.map(|(low, high)| rand::thread_rng().gen_range(low..=high.max(low)));
+
+        if self.length == Some(0) {
+            self.scheduled = None;
+            events.push_back(PaddingEvent::LengthCount);
+            return;
+        }
+
+        self.schedule(events);
+    }
+
+    ///Draw a bin and schedule padding after a delay from it, unless the infinity bin comes up
+    fn schedule(& mut self, events: & mut VecDeque<PaddingEvent>) {
+        self.scheduled = None;
+
+        let state = match self.state {
+            Some(state) => state,
+            None => return,
+        };
+
+        let histogram = &self.machine.states[state].histogram;
+
+        let finite: u32 = self.tokens.iter().sum();
+        let total = finite + self.infinity;
+
+        if total == 0 {
+            events.push_back(PaddingEvent::BinsEmpty);
+            return;
+        }
+
+        let mut rng = rand::thread_rng();
+
+        let mut draw = rng.gen_range(0..total);
+
+        if draw >= finite {
+            events.push_back(PaddingEvent::Infinity);
+            return;
+        }
+
+        let bin = self.tokens.iter().position(|tokens| {
+            if draw < *tokens {
+                true
+            } else {
+                draw -= tokens;
+                false
+            }
+        }).unwrap();
+
+        let (low, high) = (histogram.edges[bin], histogram.edges[bin + 1]);
+
+        let delay = if high > low {
+            low + Duration::from_nanos(rng.gen_range(0..(high - low).as_nanos() as u64))
+        } else {
+            low
+        };
+
+        self.scheduled = Some((self.clock.now() + delay, bin));
+    }
+}
@@ -0,0 +1,327 @@
+//! A pool of circuits shared between streams.
+//!
+//! The pool keeps clean (never used) circuits built ahead of time for the exit ports it predicts from recent requests,
+//! hands out circuits whose exit allows the requested port, and stops handing out a circuit once it has been dirty for
+//! longer than `MaxCircuitDirtiness`. Circuits are only shared between streams with the same `IsolationKey`.
+//!
+//! The pool does not choose paths itself. Circuits come from a `CircuitBuilder`, which also answers whether a circuit's
+//! exit allows a port.
+//!
+//! The pool also owns the `DnsCache` for its streams, if one is configured, and flushes an isolation group's answers when
+//! one of that group's circuits is retired.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::circuit::Circuit;
+use crate::dns_cache::{DnsCache, DnsCacheConfig};
+use crate::error::Result;
+use crate::isolation::{IsolationKey, IsolationFlags, StreamRequest};
+
+///Builds circuits for a `CircuitPool`
+pub trait CircuitBuilder {
+    type Circuit: PooledCircuit;
+
+    ///Build a circuit whose exit allows every port in `ports`. An empty slice asks for any exit
+    fn build(& self, ports: &[u16]) -> Result<Self::Circuit>;
+
+    ///Whether the exit of `circuit` allows connections to `port`
+    fn exit_allows(& self, circuit: &Self::Circuit, port: u16) -> bool;
+}
+
+///A circuit the pool can manage
+pub trait PooledCircuit {
+    fn is_closed(& self) -> bool;
+}
+
+impl PooledCircuit for Circuit {
+    fn is_closed(& self) -> bool {
+        Circuit::is_closed(self)
+    }
+}
+
+///Settings for a `CircuitPool`. The defaults follow Tor's
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    ///The number of clean circuits to keep even when no port is predicted
+    pub clean_circuits: usize,
+    ///The number of clean circuits to keep for each predicted port
+    pub circuits_per_port: usize,
+    ///The most clean circuits to keep at once
+    pub max_clean_circuits: usize,
+    ///How long after its first use a circuit may be given to new streams (`MaxCircuitDirtiness`)
+    pub max_circuit_dirtiness: Duration,
+    ///How long a port stays predicted after it was last requested
+    pub predicted_port_lifetime: Duration,
+    ///Ports predicted before any requests are made
+    pub initial_ports: Vec<u16>,
+    ///Which resolved addresses to cache for the pool's streams. Off by default
+    pub dns_cache: DnsCacheConfig,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            clean_circuits: 1,
+            circuits_per_port: 2,
+            max_clean_circuits: 14,
+            max_circuit_dirtiness: Duration::from_secs(10 * 60),
+            predicted_port_lifetime: Duration::from_secs(60 * 60),
+            initial_ports: vec![80],
+            dns_cache: DnsCacheConfig::default(),
+        }
+    }
+}
+
+///Counters describing how the pool has performed
+#[derive(Debug, Clone, Default)]
+pub struct PoolMetrics {
+    pub builds_succeeded: u64,
+    pub builds_failed: u64,
+    ///Requests answered with a circuit that was already in the pool
+    pub circuits_reused: u64,
+    ///Requests that had to wait for a new circuit
+    pub built_on_demand: u64,
+    ///Circuits removed from the pool because they were too dirty or closed
+    pub circuits_retired: u64,
+    pub total_build_time: Duration,
+    pub max_build_time: Duration,
+}
+
+impl PoolMetrics {
+    ///The mean time of successful builds
+    pub fn mean_build_time(& self) -> Option<Duration> {
+        if self.builds_succeeded == 0 {
+            None
+        } else {
+            Some(self.total_build_time / self.builds_succeeded as u32)
+        }
+    }
+}
+
+struct PoolEntry<C> {
+    circuit: Arc<C>,
+    ///When the circuit was first handed out, and to which isolation group
+    dirty: Option<(Instant, IsolationKey)>,
+}
+
+struct PoolState<C> {
+    circuits: Vec<PoolEntry<C>>,
+    ///The last time each port was requested
+    ports: HashMap<u16, Instant>,
+    metrics: PoolMetrics,
+}
+
+pub struct CircuitPool<B: CircuitBuilder> {
+    builder: B,
+    config: PoolConfig,
+    state: Mutex<PoolState<B::Circuit>>,
+    dns_cache: DnsCache,
+}
+
+impl<B: CircuitBuilder> CircuitPool<B> {
+    pub fn new(builder: B, config: PoolConfig) -> Self {
+        let now = Instant::now();
+
+        let ports = config.initial_ports.iter().map(|port| (*port, now)).collect();
+
+        Self {
+            builder,
+            dns_cache: DnsCache::new(config.dns_cache),
+            config,
+            state: Mutex::new(PoolState {
+                circuits: Vec::new(),
+                ports,
+                metrics: PoolMetrics::default(),
+            }),
+        }
+    }
+
+    ///Get a circuit for a stream to `port`. A dirty circuit in the same isolation group is preferred, then a clean one,
+    ///and a new circuit is built only if neither allows the port
+    pub fn get(& self, port: u16, isolation: &IsolationKey) -> Result<Arc<B::Circuit>> {
+        let now = Instant::now();
+
+        {
+            let mut state = self.state.lock().unwrap();
+
+            state.ports.insert(port, now);
+
+            self.retire(& mut state, now);
+
+            let usable = |entry: &PoolEntry<B::Circuit>| self.builder.exit_allows(&entry.circuit, port);
+
+            let reused = state.circuits.iter().position(|entry| entry.dirty.as_ref().map(|(_, key)| key == isolation).unwrap_or(false) && usable(entry))
+                .or_else(|| state.circuits.iter().position(|entry| entry.dirty.is_none() && usable(entry)));
+
+            if let Some(index) = reused {
+                state.metrics.circuits_reused += 1;
+
+                let entry = & mut state.circuits[index];
+
+                entry.dirty.get_or_insert_with(|| (now, isolation.clone()));
+
+                return Ok(entry.circuit.clone());
+            }
+
+            state.metrics.built_on_demand += 1;
+        }
+
+        let circuit = Arc::new(self.build(&[port])?);
+
+        self.state.lock().unwrap().circuits.push(PoolEntry {
+            circuit: circuit.clone(),
+            dirty: Some((now, isolation.clone())),
+        });
+
+        Ok(circuit)
+    }
+
+    ///Get a circuit for `request`, isolated according to the `flags` of the front-end it came through
+    pub fn get_for(& self, request: &StreamRequest, flags: &IsolationFlags) -> Result<Arc<B::Circuit>> {
+        self.get(request.port, &flags.key(request))
+    }
+
+    ///Retire expired and closed circuits, then build clean circuits until every predicted port is covered. Call this
+    ///regularly, for example once a second, so requests rarely wait for a build
+    pub fn maintain(& self) {
+        loop {
+            let needed = {
+                let mut state = self.state.lock().unwrap();
+
+                self.retire(& mut state, Instant::now());
+
+                self.needed_ports(&state)
+            };
+
+            let ports = match needed {
+                Some(ports) => ports,
+                None => return,
+            };
+
+            //Stop this round on failure rather than retrying in a tight loop. The next call tries again
+            match self.build(&ports) {
+                Ok(circuit) => self.state.lock().unwrap().circuits.push(PoolEntry { circuit: Arc::new(circuit), dirty: None }),
+                Err(_) => return,
+            }
+        }
+    }
+
+    ///The ports for the next clean circuit to build, or `None` if there are enough clean circuits
+    fn needed_ports(& self, state: &PoolState<B::Circuit>) -> Option<Vec<u16>> {
+        let clean: Vec<&PoolEntry<B::Circuit>> = state.circuits.iter().filter(|entry| entry.dirty.is_none()).collect();
+
+        if clean.len() >= self.config.max_clean_circuits {
+            return None;
+        }
+
+        let mut ports = self.predicted(state);
+
+        ports.sort_unstable();
+
+        let uncovered: Vec<u16> = ports.into_iter()
+            .filter(|port| clean.iter().filter(|entry| self.builder.exit_allows(&entry.circuit, *port)).count() < self.config.circuits_per_port)
+            .collect();
+
+        if !uncovered.is_empty() {
+            Some(uncovered)
+        } else if clean.len() < self.config.clean_circuits {
+            Some(vec![])
+        } else {
+            None
+        }
+    }
+
+    fn predicted(& self, state: &PoolState<B::Circuit>) -> Vec<u16> {
+        let now = Instant::now();
+
+        state.ports.iter()
+            .filter(|(_, used)| now.duration_since(**used) < self.config.predicted_port_lifetime)
+            .map(|(port, _)| *port)
+            .collect()
+    }
+
+    fn retire(& self, state: & mut PoolState<B::Circuit>, now: Instant) {
+        let before = state.circuits.len();
+
+        let max_dirtiness = self.config.max_circuit_dirtiness;
+
+        let mut rotated = Vec::new();
+
+        state.circuits.retain(|entry| {
+            let expired = entry.dirty.as_ref().map(|(since, _)| now.duration_since(*since) >= max_dirtiness).unwrap_or(false);
+
+            let keep = !expired && !entry.circuit.is_closed();
+
+            if let (false, Some((_, isolation))) = (keep, &entry.dirty) {
+                rotated.push(isolation.clone());
+            }
+
+            keep
+        });
+
+        for isolation in &rotated {
+            self.dns_cache.flush(isolation);
+        }
+
+        state.metrics.circuits_retired += (before - state.circuits.len()) as u64;
+    }
+
+    fn build(& self, ports: &[u16]) -> Result<B::Circuit> {
+        let started = Instant::now();
+
+        let built = self.builder.build(ports);
+
+        let elapsed = started.elapsed();
+
+        let metrics = & mut self.state.lock().unwrap().metrics;
+
+        match &built {
+            Ok(_) => {
+                metrics.builds_succeeded += 1;
+                metrics.total_build_time += elapsed;
+                metrics.max_build_time = metrics.max_build_time.max(elapsed);
+            },
+            Err(_) => metrics.builds_failed += 1,
+        }
+
+        built
+    }
+
+    ///The ports requested within the last `predicted_port_lifetime`
+    pub fn predicted_ports(& self) -> Vec<u16> {
+        let mut ports = self.predicted(&self.state.lock().unwrap());
+
+        ports.sort_unstable();
+
+        ports
+    }
+
+    ///The number of circuits that have never been handed out
+    pub fn clean_count(& self) -> usize {
+        self.state.lock().unwrap().circuits.iter().filter(|entry| entry.dirty.is_none()).count()
+    }
+
+    ///The number of circuits in the pool, clean or dirty
+    pub fn len(& self) -> usize {
+        self.state.lock().unwrap().circuits.len()
+    }
+
+    pub fn is_empty(& self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn builder(& self) -> &B {
+        &self.builder
+    }
+
+    ///The resolved addresses cached for the pool's streams
+    pub fn dns_cache(& self) -> &DnsCache {
+        &self.dns_cache
+    }
+
+    pub fn metrics(& self) -> PoolMetrics {
+        self.state.lock().unwrap().metrics.clone()
+    }
+}
@@ -0,0 +1,210 @@
+//! Client authorization for version 3 onion services, also called restricted discovery (rend-spec-v3 section 2.5.1.2).
+//!
+//! A service that restricts discovery encrypts the inner layer of its descriptor with a descriptor cookie, and gives
+//! each authorized client a copy of the cookie encrypted to the client's x25519 key. Clients keep one private key per
+//! onion address in Tor's `ClientOnionAuthDir` format, one `<address>.auth_private` file per service:
+//!
+//! `<56 character address>:descriptor:x25519:<base32 private key>`
+//!
+//! Services keep the public keys of their clients in `authorized_clients/<name>.auth` files inside their
+//! `HiddenServiceDir`:
+//!
+//! `descriptor:x25519:<base32 public key>`
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use x25519_dalek::{StaticSecret, PublicKey};
+
+use crate::ntor::generate_secret;
+use crate::onion::OnionAddress;
+use crate::error::{ErrorKind, Result};
+
+const AUTH_TYPE: &str = "descriptor";
+const KEY_TYPE: &str = "x25519";
+
+const CLIENT_KEY_EXTENSION: &str = "auth_private";
+const SERVICE_KEY_EXTENSION: &str = "auth";
+
+///The directory in a `HiddenServiceDir` holding the `.auth` files of authorized clients
+pub const AUTHORIZED_CLIENTS_DIR: &str = "authorized_clients";
+
+fn encode_key(key: &[u8; 32]) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, key)
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; 32]> {
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, &encoded.trim().to_ascii_uppercase())
+        .and_then(|key| key.as_slice().try_into().ok())
+        .ok_or_else(|| ErrorKind::InvalidKeyFile(format!("{} is not a base32 x25519 key", encoded)))
+}
+
+///Check the `descriptor:x25519` fields and decode the key that follows them
+fn parse_key_fields<'a, I: Iterator<Item = &'a str>>(mut fields: I) -> Result<[u8; 32]> {
+    match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(AUTH_TYPE), Some(KEY_TYPE), Some(key), None) => decode_key(key),
+        _ => Err(ErrorKind::InvalidKeyFile(String::from("expected descriptor:x25519:<key>"))),
+    }
+}
+
+///A client's x25519 key for one onion service
+pub struct ClientAuthKey {
+    pub address: OnionAddress,
+    pub secret: StaticSecret,
+}
+
+impl ClientAuthKey {
+    ///A new key for `address`. Its public half goes to the service operator
+    pub fn generate(address: OnionAddress) -> Self {
+        Self {
+            address,
+            secret: generate_secret(),
+        }
+    }
+
+    pub fn public_key(& self) -> PublicKey {
+        PublicKey::from(&self.secret)
+    }
+
+    ///The line of the key's `.auth_private` file
+    pub fn to_line(& self) -> String {
+        let address = self.address.to_string();
+
+        format!("{}:{}:{}:{}", address.trim_end_matches(".onion"), AUTH_TYPE, KEY_TYPE, encode_key(&self.secret.to_bytes()))
+    }
+
+    ///The line of the `.auth` file the service needs to authorize this key
+    pub fn service_line(& self) -> String {
+        authorized_client_line(&self.public_key())
+    }
+}
+
+impl FromStr for ClientAuthKey {
+    type Err = ErrorKind;
+
+    ///Parse a line of an `.auth_private` file
+    fn from_str(line: &str) -> Result<Self> {
+        let mut fields = line.trim().split(':');
+
+        let address = fields.next().unwrap().parse::<OnionAddress>()?;
+
+        Ok(Self {
+            address,
+            secret: StaticSecret::from(parse_key_fields(fields)?),
+        })
+    }
+}
+
+///The client keys we hold, by the onion service they are for
+#[derive(Default)]
+pub struct ClientAuthStore {
+    keys: HashMap<OnionAddress, StaticSecret>,
+}
+
+impl ClientAuthStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Read every `.auth_private` file in a `ClientOnionAuthDir`. Files with other extensions are ignored
+    pub fn load(directory: &Path) -> Result<Self> {
+        let mut store = Self::new();
+
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+
+            if path.extension().and_then(|extension| extension.to_str()) != Some(CLIENT_KEY_EXTENSION) {
+                continue;
+            }
+
+            for line in fs::read_to_string(&path)?.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+                store.insert(line.parse()?);
+            }
+        }
+
+        Ok(store)
+    }
+
+    ///Write `key` to `<address>.auth_private` in `directory`, creating the directory if needed
+    pub fn save(directory: &Path, key: &ClientAuthKey) -> Result<()> {
+        fs::create_dir_all(directory)?;
+
+        let address = key.address.to_string();
+
+        fs::write(directory.join(format!("{}.{}", address.trim_end_matches(".onion"), CLIENT_KEY_EXTENSION)), format!("{}\n", key.to_line()))?;
+
+        Ok(())
+    }
+
+    ///Use `key` for its service, replacing any key we had for it
+    pub fn insert(& mut self, key: ClientAuthKey) {
+        self.keys.insert(key.address, key.secret);
+    }
+
+    pub fn remove(& mut self, address: &OnionAddress) -> Option<StaticSecret> {
+        self.keys.remove(address)
+    }
+
+    ///Our key for the service at `address`, if we have one
+    pub fn get(& self, address: &OnionAddress) -> Option<&StaticSecret> {
+        self.keys.get(address)
+    }
+
+    pub fn len(& self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(& self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+///The line of an `.auth` file authorizing `public_key`
+pub fn authorized_client_line(public_key: &PublicKey) -> String {
+    format!("{}:{}:{}", AUTH_TYPE, KEY_TYPE, encode_key(public_key.as_bytes()))
+}
+
+///Parse a line of an `.auth` file
+pub fn parse_authorized_client(line: &str) -> Result<PublicKey> {
+    Ok(PublicKey::from(parse_key_fields(line.trim().split(':'))?))
+}
+
+///Read the keys of every `.auth` file in the `authorized_clients` directory of a `HiddenServiceDir`. A service with no
+///such directory authorizes nobody, so anyone can read its descriptor
+pub fn load_authorized_clients(service_directory: &Path) -> Result<Vec<PublicKey>> {
+    let directory = service_directory.join(AUTHORIZED_CLIENTS_DIR);
+
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut clients = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+
+        if path.extension().and_then(|extension| extension.to_str()) != Some(SERVICE_KEY_EXTENSION) {
+            continue;
+        }
+
+        for line in fs::read_to_string(&path)?.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+            clients.push(parse_authorized_client(line)?);
+        }
+    }
+
+    Ok(clients)
+}
+
+///Write `authorized_clients/<name>.auth` for `public_key` in a `HiddenServiceDir`
+pub fn save_authorized_client(service_directory: &Path, name: &str, public_key: &PublicKey) -> Result<()> {
+    let directory = service_directory.join(AUTHORIZED_CLIENTS_DIR);
+
+    fs::create_dir_all(&directory)?;
+
+    fs::write(directory.join(format!("{}.{}", name, SERVICE_KEY_EXTENSION)), format!("{}\n", authorized_client_line(public_key)))?;
+
+    Ok(())
+}
@@ -0,0 +1,431 @@
+//! Conflux: streams spread over several circuits to the same exit (proposal 329).
+//!
+//! The legs of a set are linked by sending RELAY_CONFLUX_LINK with the same nonce on each of them, which the exit answers
+//! with RELAY_CONFLUX_LINKED. From then on the cells that belong to streams, such as BEGIN, DATA and END, are numbered
+//! across the whole set instead of per circuit. Numbers are never sent with a cell: each end counts the cells on every
+//! leg, and a RELAY_CONFLUX_SWITCH on a leg says how far the numbering moved on while that leg was idle. The receiving
+//! side holds back cells that arrive ahead of their turn and hands them to their streams in order.
+//!
+//! Streams are opened on the first leg and used like any other `TorStream`. Which leg each of their cells goes out on is
+//! up to the set's `Scheduler`, which goes by each leg's round trip: first the time from RELAY_CONFLUX_LINK to
+//! RELAY_CONFLUX_LINKED, then the time from each DATA cell that completes a SENDME increment to the circuit SENDME that
+//! answers it. While a set is linked, a thread for each leg takes its cells, so control cells sent to the legs by the
+//! exit are not seen by anything else. Those threads sleep until a cell arrives, another leg files one for them or the
+//! set is dropped.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak, Mutex};
+use std::time::{Duration, Instant};
+
+use ring::rand::SecureRandom;
+
+use crate::cells::{Relay, RelayCell, BeginFlags, CSRNG};
+use crate::circuit::{Circuit, CircuitInner, TorStream, CIRCUIT_SENDME_INCREMENT};
+use crate::error::{ErrorKind, Result};
+
+///The version of the RELAY_CONFLUX_LINK body we send
+pub const CONFLUX_VERSION: u8 = 1;
+
+///What a client asks the exit to optimise for in RELAY_CONFLUX_LINK
+pub const CONFLUX_UX_NO_OPINION: u8 = 0;
+pub const CONFLUX_UX_MIN_LATENCY: u8 = 1;
+pub const CONFLUX_UX_LOW_MEM_LATENCY: u8 = 2;
+pub const CONFLUX_UX_HIGH_THROUGHPUT: u8 = 3;
+pub const CONFLUX_UX_LOW_MEM_THROUGHPUT: u8 = 4;
+
+///How long each leg has to answer RELAY_CONFLUX_LINK
+const LINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+///Whether cells with relay command `command` are numbered across the set. Everything else belongs to the leg it arrives on
+pub fn is_multiplexed(command: u8) -> bool {
+    matches!(command, 1 | 2 | 3 | 4 | 11 | 12 | 43 | 44)
+}
+
+///Holds back items that arrive ahead of their sequence number. Numbering starts at 1
+#[derive(Debug)]
+pub struct ReorderQueue<T> {
+    delivered: u64,
+    waiting: BTreeMap<u64, T>,
+}
+
+impl<T> Default for ReorderQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ReorderQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            delivered: 0,
+            waiting: BTreeMap::new(),
+        }
+    }
+
+    ///Queue `item` as number `seq`. Numbers already delivered or already waiting are refused
+    pub fn push(& mut self, seq: u64, item: T) -> bool {
+        if seq <= self.delivered || self.waiting.contains_key(&seq) {
+            return false;
+        }
+
+        self.waiting.insert(seq, item);
+
+        true
+    }
+
+    ///The next item in order, if it has arrived
+    pub fn pop(& mut self) -> Option<T> {
+        let item = self.waiting.remove(&(self.delivered + 1))?;
+
+        self.delivered += 1;
+
+        Some(item)
+    }
+
+    ///The number of the last item handed out
+    pub fn delivered(& self) -> u64 {
+        self.delivered
+    }
+
+    ///The number of items held back
+    pub fn len(& self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(& self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LegSequence {
+    sent: u64,
+    received: u64,
+}
+
+///Counts the multiplexed cells sent and received on each leg of a set
+#[derive(Debug, Clone, Default)]
+pub struct Sequencer {
+    last_sent: u64,
+    legs: Vec<LegSequence>,
+}
+
+impl Sequencer {
+    pub fn new(legs: usize) -> Self {
+        Self {
+            last_sent: 0,
+            legs: vec![LegSequence::default(); legs],
+        }
+    }
+
+    ///Start counting for one more leg, returning its index
+    pub fn add_leg(& mut self) -> usize {
+        self.legs.push(LegSequence::default());
+
+        self.legs.len() - 1
+    }
+
+    ///Count a cell about to be sent on `leg`. If the last cell went out on another leg, returns the relative sequence
+    ///number of the RELAY_CONFLUX_SWITCH that has to go on `leg` first
+    pub fn send(& mut self, leg: usize) -> Option<u32> {
+        let sequence = &mut self.legs[leg];
+
+        let switch = if sequence.sent != self.last_sent {
+            Some((self.last_sent - sequence.sent) as u32)
+        } else {
+            None
+        };
+
+        self.last_sent += 1;
+        sequence.sent = self.last_sent;
+
+        switch
+    }
+
+    ///Apply a RELAY_CONFLUX_SWITCH received on `leg`
+    pub fn switch(& mut self, leg: usize, relative_seq: u32) {
+        self.legs[leg].received += relative_seq as u64;
+    }
+
+    ///Count a cell received on `leg`, returning its sequence number
+    pub fn receive(& mut self, leg: usize) -> u64 {
+        self.legs[leg].received += 1;
+
+        self.legs[leg].received
+    }
+
+    ///The sequence number of the last cell sent on any leg
+    pub fn last_sent(& self) -> u64 {
+        self.last_sent
+    }
+}
+
+///How a set chooses the leg for each cell it sends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheduler {
+    ///Every cell goes on the leg with the lowest round trip
+    MinRtt,
+    ///A leg keeps sending for at least a circuit SENDME increment of cells before the set moves to a faster one, so the
+    ///exit rarely has to hold cells back
+    LowMemory,
+}
+
+impl Scheduler {
+    ///The `desired_ux` to ask the exit for in RELAY_CONFLUX_LINK
+    pub fn desired_ux(& self) -> u8 {
+        match self {
+            Scheduler::MinRtt => CONFLUX_UX_MIN_LATENCY,
+            Scheduler::LowMemory => CONFLUX_UX_LOW_MEM_LATENCY,
+        }
+    }
+
+    ///The leg for the next cell. `rtts` holds the round trip of each leg, or `None` for legs that have closed, `current`
+    ///is the leg the last cell went on and `run` the number of cells it has sent in a row. `None` if every leg has closed
+    pub fn choose(& self, rtts: &[Option<Duration>], current: usize, run: u64) -> Option<usize> {
+        let current_open = matches!(rtts.get(current), Some(Some(_)));
+
+        if *self == Scheduler::LowMemory && current_open && run < CIRCUIT_SENDME_INCREMENT as u64 {
+            return Some(current);
+        }
+
+        let fastest = rtts.iter()
+            .enumerate()
+            .filter_map(|(leg, rtt)| rtt.map(|rtt| (leg, rtt)))
+            .min_by_key(|(_, rtt)| *rtt)?;
+
+        //Ties stay on the current leg rather than sending a SWITCH for nothing
+        if current_open && rtts[current] == Some(fastest.1) {
+            Some(current)
+        } else {
+            Some(fastest.0)
+        }
+    }
+}
+
+struct SetState {
+    sequencer: Sequencer,
+    queue: ReorderQueue<RelayCell>,
+    current: usize,
+    run: u64,
+    out_of_order: u64,
+}
+
+///The state the legs of a set share. Each leg's circuit holds the set, and the set only holds the legs weakly
+pub(crate) struct LinkedSet {
+    legs: Vec<Weak<CircuitInner>>,
+    ///The latest round trip of each leg. Only ever locked on its own
+    rtts: Mutex<Vec<Duration>>,
+    scheduler: Scheduler,
+    state: Mutex<SetState>,
+    ///Set once the `ConfluxSet` is dropped, which stops the threads driving the legs
+    released: AtomicBool,
+}
+
+impl LinkedSet {
+    ///Send a multiplexed cell on the leg the scheduler chooses, preceded by RELAY_CONFLUX_SWITCH if it isn't the last leg
+    ///used. The numbering is only held while nothing blocks: if the chosen leg's window is shut, the lock is let go while
+    ///waiting for it, so the leg's SENDME can still be received, and the choice is made again
+    pub(crate) fn send(& self, relay: RelayCell) -> Result<()> {
+        let data = (relay.get_command() == 2) as u32;
+
+        loop {
+            let mut state = self.state.lock().unwrap();
+
+            let open: Vec<_> = self.legs.iter().map(|leg| leg.upgrade().filter(|leg| !leg.is_closed())).collect();
+
+            let rtts: Vec<_> = open.iter().zip(self.rtts.lock().unwrap().iter()).map(|(leg, rtt)| leg.as_ref().map(|_| *rtt)).collect();
+
+            let leg = self.scheduler.choose(&rtts, state.current, state.run).ok_or(ErrorKind::CircuitClosed)?;
+
+            let circuit = open[leg].as_ref().unwrap();
+
+            match circuit.try_take_window(data) {
+                Ok(true) => {},
+                Ok(false) | Err(_) => {
+                    drop(state);
+
+                    //A leg that closes drops out of the next choice
+                    let _ = circuit.wait_for_window(data);
+
+                    continue;
+                },
+            }
+
+            state.chose(leg);
+
+            if let Some(relative_seq) = state.sequencer.send(leg) {
+                circuit.send_unsequenced(RelayCell::new(0, Relay::ConfluxSwitch { relative_seq }))?;
+            }
+
+            return circuit.send_reserved(relay);
+        }
+    }
+
+    ///A circuit SENDME on `leg` took `rtt` to arrive
+    pub(crate) fn update_rtt(& self, leg: usize, rtt: Duration) {
+        if let Some(leg_rtt) = self.rtts.lock().unwrap().get_mut(leg) {
+            *leg_rtt = rtt;
+        }
+    }
+
+    pub(crate) fn is_released(& self) -> bool {
+        self.released.load(Ordering::SeqCst)
+    }
+
+    ///Take a multiplexed cell or RELAY_CONFLUX_SWITCH that arrived on `leg`, filing every cell now in order on the first leg
+    pub(crate) fn receive(& self, leg: usize, relay: RelayCell) {
+        let mut state = self.state.lock().unwrap();
+
+        if relay.get_command() == 22 {
+            if let Ok(Some(Relay::ConfluxSwitch { relative_seq })) = relay.get_payload() {
+                state.sequencer.switch(leg, relative_seq);
+            }
+
+            return;
+        }
+
+        let seq = state.sequencer.receive(leg);
+
+        if seq != state.queue.delivered() + 1 {
+            state.out_of_order += 1;
+        }
+
+        state.queue.push(seq, relay);
+
+        //The lock is held while filing so cells released by two legs at once can't overtake each other
+        if let Some(primary) = self.legs[0].upgrade() {
+            while let Some(relay) = state.queue.pop() {
+                primary.file(relay);
+            }
+        }
+    }
+}
+
+impl SetState {
+    ///Count a cell going out on `leg` towards the scheduler's run
+    fn chose(& mut self, leg: usize) {
+        if leg == self.current {
+            self.run += 1;
+        } else {
+            self.current = leg;
+            self.run = 1;
+        }
+    }
+}
+
+///A leg's place in its set
+#[derive(Clone)]
+pub(crate) struct ConfluxLeg {
+    pub set: Arc<LinkedSet>,
+    pub index: usize,
+}
+
+///Circuits to the same exit linked into one conflux set. The legs are closed when the set is dropped, after which
+///streams opened on it carry on over the first leg alone
+pub struct ConfluxSet {
+    legs: Vec<Circuit>,
+    nonce: [u8; 32],
+    set: Arc<LinkedSet>,
+}
+
+impl ConfluxSet {
+    ///Link two circuits ending at the same exit, measuring each leg's round trip as it is linked
+    pub fn link(first: Circuit, second: Circuit, scheduler: Scheduler) -> Result<Self> {
+        let legs = vec![first, second];
+
+        let mut nonce = [0u8; 32];
+
+        CSRNG.fill(& mut nonce).unwrap();
+
+        let mut rtts = Vec::new();
+
+        for leg in legs.iter() {
+            let started = Instant::now();
+
+            leg.send_control(RelayCell::new(0, Relay::ConfluxLink {
+                version: CONFLUX_VERSION,
+                nonce,
+                last_seqno_sent: 0,
+                last_seqno_recv: 0,
+                desired_ux: scheduler.desired_ux(),
+            }))?;
+
+            loop {
+                match leg.receive_control_timeout(LINK_TIMEOUT)?.get_payload()? {
+                    Some(Relay::ConfluxLinked { nonce: linked, .. }) if linked == nonce => break,
+                    Some(Relay::ConfluxLinked { .. }) => return Err(ErrorKind::HandshakeFailed),
+                    Some(Relay::SendMe { .. }) => {},
+                    payload => return Err(ErrorKind::UnexpectedCell(format!("{:?}", payload))),
+                }
+            }
+
+            rtts.push(started.elapsed());
+
+            leg.send_control(RelayCell::new_raw(21, 0, vec![]))?;
+        }
+
+        let set = Arc::new(LinkedSet {
+            legs: legs.iter().map(|leg| leg.downgrade()).collect(),
+            rtts: Mutex::new(rtts),
+            scheduler,
+            state: Mutex::new(SetState {
+                sequencer: Sequencer::new(legs.len()),
+                queue: ReorderQueue::new(),
+                current: 0,
+                run: 0,
+                out_of_order: 0,
+            }),
+            released: AtomicBool::new(false),
+        });
+
+        for (index, leg) in legs.iter().enumerate() {
+            leg.join_conflux(ConfluxLeg { set: set.clone(), index });
+        }
+
+        Ok(Self { legs, nonce, set })
+    }
+
+    pub fn legs(& self) -> &[Circuit] {
+        &self.legs
+    }
+
+    pub fn nonce(& self) -> [u8; 32] {
+        self.nonce
+    }
+
+    pub fn scheduler(& self) -> Scheduler {
+        self.set.scheduler
+    }
+
+    ///The latest round trip of each leg: from RELAY_CONFLUX_LINK to RELAY_CONFLUX_LINKED until the leg's first circuit
+    ///SENDME, then from the DATA cell that completed a SENDME increment to its SENDME
+    pub fn rtts(& self) -> Vec<Duration> {
+        self.set.rtts.lock().unwrap().clone()
+    }
+
+    ///The number of cells that arrived ahead of their turn and had to be held back
+    pub fn out_of_order(& self) -> u64 {
+        self.set.state.lock().unwrap().out_of_order
+    }
+
+    ///Open a stream from the exit to `address:port`
+    pub fn begin(& self, address: &str, port: u16) -> Result<TorStream> {
+        self.legs[0].begin(address, port)
+    }
+
+    ///Open a stream from the exit to `address:port`, telling the exit which address families we can use
+    pub fn begin_with_flags(& self, address: &str, port: u16, flags: BeginFlags) -> Result<TorStream> {
+        self.legs[0].begin_with_flags(address, port, flags)
+    }
+}
+
+impl Drop for ConfluxSet {
+    fn drop(& mut self) {
+        self.set.released.store(true, Ordering::SeqCst);
+
+        for leg in self.legs.iter() {
+            leg.wake();
+        }
+    }
+}
@@ -2,7 +2,20 @@
 use sha1::Sha1;
 use sha1::Digest;
 use std::io::Write;
+use std::convert::TryFrom;
 use sha1::digest::Reset;
+use sha2::Sha256;
+use hmac::{Hmac, Mac, NewMac};
+use sha3::{Sha3_256, Shake256};
+use sha3::digest::{Update, ExtendableOutput, XofReader};
+use ctr::cipher::{NewCipher, StreamCipher};
+use poly1305::Poly1305;
+use poly1305::universal_hash::NewUniversalHash;
+use salsa20::XSalsa20;
+use blake2::VarBlake2b;
+use blake2::digest::VariableOutput;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
 
 pub fn kdf_tor(shared_secret: &[u8]) -> Vec<u8> {
     let mut hasher = Sha1::new();
@@ -22,4 +35,154 @@ pub fn kdf_tor(shared_secret: &[u8]) -> Vec<u8> {
     key_stuff.truncate(92);
 
     key_stuff
-}
\ No newline at end of file
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+
+    mac.update(message);
+
+    mac.finalize().into_bytes().into()
+}
+
+///The HKDF-SHA256 expansion used by ntor (tor-spec section 5.2.2), where `key_seed` is the already extracted pseudo-random key
+pub fn kdf_rfc5869(key_seed: &[u8], m_expand: &[u8], length: usize) -> Vec<u8> {
+    let mut key_stuff = Vec::with_capacity(length + 32);
+
+    let mut previous: Vec<u8> = Vec::new();
+
+    let mut i = 1u8;
+
+    while key_stuff.len() < length {
+        let mut message = previous.clone();
+        message.extend_from_slice(m_expand);
+        message.push(i);
+
+        previous = Vec::from(hmac_sha256(key_seed, &message));
+
+        key_stuff.extend_from_slice(&previous);
+
+        i += 1;
+    }
+
+    key_stuff.truncate(length);
+
+    key_stuff
+}
+
+///Compare two byte slices without leaking the position of the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn sha3_256(message: &[u8]) -> [u8; 32] {
+    Sha3_256::digest(message).into()
+}
+
+///SHAKE-256, the KDF of the v3 onion service protocol
+pub fn shake256(message: &[u8], length: usize) -> Vec<u8> {
+    let mut hasher = Shake256::default();
+
+    Update::update(& mut hasher, message);
+
+    let mut key_stuff = vec![0u8; length];
+
+    hasher.finalize_xof().read(& mut key_stuff);
+
+    key_stuff
+}
+
+///The onion service MAC (rend-spec-v3 section 0.3), SHA3-256(INT_8(key length) | key | message)
+pub fn hs_mac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut input = Vec::with_capacity(8 + key.len() + message.len());
+
+    input.extend_from_slice(&(key.len() as u64).to_be_bytes());
+    input.extend_from_slice(key);
+    input.extend_from_slice(message);
+
+    sha3_256(&input)
+}
+
+///Encrypt or decrypt `data` in place with AES-256 in counter mode
+pub fn aes256_ctr(key: &[u8; 32], iv: &[u8; 16], data: & mut [u8]) {
+    Aes256Ctr::new(key.into(), iv.into()).apply_keystream(data);
+}
+
+///Check an ed25519 signature, rejecting keys that are not curve points
+pub fn ed25519_verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let public_key = match ed25519_dalek::PublicKey::from_bytes(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+
+    let signature = match ed25519_dalek::Signature::try_from(&signature[..]) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    public_key.verify_strict(message, &signature).is_ok()
+}
+
+///Unkeyed BLAKE2b (RFC 7693) with a digest of `length` bytes, between 1 and 64
+pub fn blake2b(message: &[u8], length: usize) -> Vec<u8> {
+    let mut hasher = VarBlake2b::new(length).expect("BLAKE2b digests are 1 to 64 bytes");
+
+    hasher.update(message);
+
+    let mut digest = Vec::new();
+
+    hasher.finalize_variable(|result| digest.extend_from_slice(result));
+
+    digest
+}
+
+///The length of the Poly1305 tag NaCl's secretbox puts before the ciphertext
+pub const SECRETBOX_TAG_LENGTH: usize = 16;
+
+///Encrypt and authenticate `message` with NaCl's `crypto_secretbox` (XSalsa20 and Poly1305), returning the tag followed by
+///the ciphertext
+pub fn secretbox_seal(key: &[u8; 32], nonce: &[u8; 24], message: &[u8]) -> Vec<u8> {
+    let mut cipher = XSalsa20::new(key.into(), nonce.into());
+
+    //The first 32 bytes of keystream are the one-time Poly1305 key, and the message is encrypted with the rest
+    let mut mac_key = [0u8; 32];
+
+    cipher.apply_keystream(& mut mac_key);
+
+    let mut sealed = vec![0u8; SECRETBOX_TAG_LENGTH];
+
+    sealed.extend_from_slice(message);
+
+    cipher.apply_keystream(& mut sealed[SECRETBOX_TAG_LENGTH..]);
+
+    let tag = Poly1305::new((&mac_key).into()).compute_unpadded(&sealed[SECRETBOX_TAG_LENGTH..]);
+
+    sealed[..SECRETBOX_TAG_LENGTH].copy_from_slice(&tag.into_bytes());
+
+    sealed
+}
+
+///Check and decrypt a box made by `secretbox_seal`, returning `None` if it was altered or made with another key or nonce
+pub fn secretbox_open(key: &[u8; 32], nonce: &[u8; 24], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < SECRETBOX_TAG_LENGTH {
+        return None;
+    }
+
+    let mut cipher = XSalsa20::new(key.into(), nonce.into());
+
+    let mut mac_key = [0u8; 32];
+
+    cipher.apply_keystream(& mut mac_key);
+
+    let tag = Poly1305::new((&mac_key).into()).compute_unpadded(&sealed[SECRETBOX_TAG_LENGTH..]);
+
+    if !constant_time_eq(&tag.into_bytes(), &sealed[..SECRETBOX_TAG_LENGTH]) {
+        return None;
+    }
+
+    let mut message = sealed[SECRETBOX_TAG_LENGTH..].to_vec();
+
+    cipher.apply_keystream(& mut message);
+
+    Some(message)
+}
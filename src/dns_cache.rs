@@ -0,0 +1,180 @@
+//! A client-side cache of the addresses exits resolved hostnames to.
+//!
+//! Every CONNECTED to a hostname tells us the address the exit resolved it to and how long that answer may be kept.
+//! With the cache on, later streams to the same hostname ask the exit for the cached address instead, as Tor does with
+//! `CacheIPv4DNS` and `UseIPv4Cache`. Remembered answers link streams to each other and let a hostile exit feed us an
+//! address that outlives its circuit, so the cache is off by default, kept apart for each isolation group and flushed
+//! when that group's circuits are retired. TTLs are clipped to one of two values, as exits do, so they can't be used to
+//! tell DNS answers apart. A cached IPv6 address is only used for streams whose BEGIN flags allow IPv6, and a cached IPv4
+//! address only for streams that allow IPv4.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cells::{BeginFlags, ConnectedAddress};
+use crate::circuit::{Circuit, TorStream};
+use crate::error::Result;
+use crate::isolation::IsolationKey;
+
+///TTLs below this are raised to it, in seconds
+pub const MIN_DNS_TTL: u32 = 5 * 60;
+
+///Every TTL not below `MIN_DNS_TTL` becomes this, in seconds
+pub const MAX_DNS_TTL: u32 = 60 * 60;
+
+///Clip a TTL to one of Tor's two buckets
+pub fn clip_dns_ttl(ttl: u32) -> u32 {
+    if ttl < MIN_DNS_TTL {
+        MIN_DNS_TTL
+    } else {
+        MAX_DNS_TTL
+    }
+}
+
+///Which answers the cache keeps. Both families are off by default
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DnsCacheConfig {
+    pub cache_ipv4: bool,
+    pub cache_ipv6: bool,
+}
+
+impl DnsCacheConfig {
+    pub fn enabled(& self) -> bool {
+        self.cache_ipv4 || self.cache_ipv6
+    }
+
+    fn caches(& self, address: IpAddr) -> bool {
+        match address {
+            IpAddr::V4(_) => self.cache_ipv4,
+            IpAddr::V6(_) => self.cache_ipv6,
+        }
+    }
+}
+
+///Whether a stream with `flags` may connect to `address`
+fn flags_allow(flags: BeginFlags, address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(_) => !flags.contains(BeginFlags::IPV4_NOT_OK),
+        IpAddr::V6(_) => flags.contains(BeginFlags::IPV6_OK),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedAnswer {
+    address: IpAddr,
+    expires: Instant,
+}
+
+///Resolved addresses for each isolation group, with at most one answer of each family for every hostname
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    config: DnsCacheConfig,
+    groups: Mutex<HashMap<IsolationKey, HashMap<String, Vec<CachedAnswer>>>>,
+}
+
+impl DnsCache {
+    pub fn new(config: DnsCacheConfig) -> Self {
+        Self {
+            config,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(& self) -> &DnsCacheConfig {
+        &self.config
+    }
+
+    ///Remember the answer in a CONNECTED to `hostname`. Empty answers, answers for families the cache is off for and
+    ///streams to literal addresses are ignored
+    pub fn record(& self, isolation: &IsolationKey, hostname: &str, answer: &ConnectedAddress) {
+        let (address, ttl) = match (answer.ip(), answer.ttl()) {
+            (Some(address), Some(ttl)) => (address, ttl),
+            _ => return,
+        };
+
+        if !self.config.caches(address) || address.is_unspecified() || hostname.parse::<IpAddr>().is_ok() {
+            return;
+        }
+
+        let expires = Instant::now() + Duration::from_secs(clip_dns_ttl(ttl) as u64);
+
+        let mut groups = self.groups.lock().unwrap();
+
+        let answers = groups.entry(isolation.clone()).or_default().entry(hostname.to_ascii_lowercase()).or_default();
+
+        answers.retain(|answer| answer.address.is_ipv4() != address.is_ipv4());
+        answers.push(CachedAnswer { address, expires });
+    }
+
+    ///The cached address of `hostname` for streams in `isolation` that a stream with `flags` may connect to, if it
+    ///hasn't expired. IPv4 is picked over IPv6 unless the flags prefer IPv6
+    pub fn lookup(& self, isolation: &IsolationKey, hostname: &str, flags: BeginFlags) -> Option<IpAddr> {
+        let mut groups = self.groups.lock().unwrap();
+
+        let answers = groups.get_mut(isolation)?;
+
+        let hostname = hostname.to_ascii_lowercase();
+
+        let cached = answers.get_mut(&hostname)?;
+
+        let now = Instant::now();
+
+        cached.retain(|answer| answer.expires > now && self.config.caches(answer.address));
+
+        let prefer_ipv6 = flags.contains(BeginFlags::IPV6_PREFERRED);
+
+        let address = cached.iter()
+            .map(|answer| answer.address)
+            .filter(|address| flags_allow(flags, *address))
+            .min_by_key(|address| address.is_ipv6() != prefer_ipv6);
+
+        if cached.is_empty() {
+            answers.remove(&hostname);
+        }
+
+        address
+    }
+
+    ///The address to put in a BEGIN to `address` with `flags`: the cached answer the flags allow if there is one,
+    ///otherwise `address` unchanged
+    pub fn rewrite(& self, isolation: &IsolationKey, address: &str, flags: BeginFlags) -> String {
+        match self.lookup(isolation, address, flags) {
+            Some(cached) => cached.to_string(),
+            None => String::from(address),
+        }
+    }
+
+    ///Open a stream like `Circuit::begin_with_flags`, asking for the cached address of `address` that `flags` allow and
+    ///caching the answer the exit gives for it
+    pub fn begin(& self, circuit: &Circuit, isolation: &IsolationKey, address: &str, port: u16, flags: BeginFlags) -> Result<TorStream> {
+        let target = self.rewrite(isolation, address, flags);
+
+        let stream = circuit.begin_with_flags(&target, port, flags)?;
+
+        if target == address {
+            self.record(isolation, address, &stream.connected_address());
+        }
+
+        Ok(stream)
+    }
+
+    ///Forget every answer cached for `isolation`, because its circuits have been retired
+    pub fn flush(& self, isolation: &IsolationKey) {
+        self.groups.lock().unwrap().remove(isolation);
+    }
+
+    pub fn clear(& self) {
+        self.groups.lock().unwrap().clear();
+    }
+
+    ///The number of answers cached across all groups, expired or not
+    pub fn len(& self) -> usize {
+        self.groups.lock().unwrap().values().flat_map(|answers| answers.values()).map(|cached| cached.len()).sum()
+    }
+
+    pub fn is_empty(& self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,332 @@
+//! Elligator 2 for Curve25519, which maps curve25519 public keys to strings indistinguishable from random.
+//!
+//! A key's "representative" is a field element that the Elligator 2 map takes to the key's u-coordinate. Only about half
+//! of all points have one, so `generate_representable` draws keys until it finds one that does. Representatives are
+//! 254 bits and are sent with random top bits. Public keys also get a random low-order component, which disappears in
+//! the Diffie-Hellman since x25519 scalars are multiples of 8, so they are not all in the prime-order subgroup the way
+//! `scalar * basepoint` keys are.
+
+use std::convert::TryInto;
+
+use curve25519_dalek::constants::{ED25519_BASEPOINT_TABLE, EIGHT_TORSION};
+use curve25519_dalek::scalar::Scalar;
+use rand::RngCore;
+use x25519_dalek::StaticSecret;
+
+const MASK_51: u64 = (1 << 51) - 1;
+
+///The Montgomery `A` of Curve25519
+const CURVE_A: u64 = 486662;
+
+///p - 2, the exponent for inversion, little endian
+const P_MINUS_2: [u8; 32] = exponent(0xeb, 0x7f);
+
+///(p - 1) / 2, the exponent for the Legendre symbol
+const P_MINUS_1_OVER_2: [u8; 32] = exponent(0xf6, 0x3f);
+
+///(p + 3) / 8, the exponent for a candidate square root
+const P_PLUS_3_OVER_8: [u8; 32] = exponent(0xfe, 0x0f);
+
+///(p - 1) / 4, the exponent that takes 2 to a square root of -1
+const P_MINUS_1_OVER_4: [u8; 32] = exponent(0xfb, 0x1f);
+
+///Every exponent we need is all ones apart from its lowest and highest byte
+const fn exponent(low: u8, high: u8) -> [u8; 32] {
+    let mut bytes = [0xffu8; 32];
+
+    bytes[0] = low;
+    bytes[31] = high;
+
+    bytes
+}
+
+///An element of the field of integers modulo 2^255 - 19, in five 51 bit limbs
+#[derive(Clone, Copy, Debug)]
+struct FieldElement([u64; 5]);
+
+impl FieldElement {
+    const ZERO: Self = Self([0; 5]);
+    const ONE: Self = Self([1, 0, 0, 0, 0]);
+
+    fn from_u64(value: u64) -> Self {
+        Self([value & MASK_51, value >> 51, 0, 0, 0])
+    }
+
+    ///Read 32 little endian bytes, ignoring the top bit
+    fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let load = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        Self([
+            load(0) & MASK_51,
+            (load(6) >> 3) & MASK_51,
+            (load(12) >> 6) & MASK_51,
+            (load(19) >> 1) & MASK_51,
+            (load(24) >> 12) & MASK_51,
+        ])
+    }
+
+    ///The canonical little endian encoding, fully reduced modulo p
+    fn to_bytes(self) -> [u8; 32] {
+        let mut limbs = Self::carry(self.0);
+
+        //Work out whether the value is at least p by seeing if adding 19 carries out of the top limb
+        let mut q = (limbs[0] + 19) >> 51;
+
+        for limb in &limbs[1..] {
+            q = (limb + q) >> 51;
+        }
+
+        limbs[0] += 19 * q;
+
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK_51;
+        }
+
+        limbs[4] &= MASK_51;
+
+        let mut bytes = [0u8; 32];
+        let mut accumulator: u128 = 0;
+        let mut bits = 0;
+        let mut index = 0;
+
+        for limb in &limbs {
+            accumulator |= (*limb as u128) << bits;
+            bits += 51;
+
+            while bits >= 8 {
+                bytes[index] = accumulator as u8;
+                accumulator >>= 8;
+                bits -= 8;
+                index += 1;
+            }
+        }
+
+        bytes[index] = accumulator as u8;
+
+        bytes
+    }
+
+    ///Bring every limb back under 2^52 or so
+    fn carry(mut limbs: [u64; 5]) -> [u64; 5] {
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK_51;
+        }
+
+        limbs[0] += 19 * (limbs[4] >> 51);
+        limbs[4] &= MASK_51;
+
+        limbs[1] += limbs[0] >> 51;
+        limbs[0] &= MASK_51;
+
+        limbs
+    }
+
+    fn add(& self, other: & Self) -> Self {
+        let mut limbs = self.0;
+
+        for (limb, other) in limbs.iter_mut().zip(other.0.iter()) {
+            *limb += other;
+        }
+
+        Self(Self::carry(limbs))
+    }
+
+    fn sub(& self, other: & Self) -> Self {
+        //Add 16p first so no limb goes negative
+        const SIXTEEN_P: [u64; 5] = [36028797018963664, 36028797018963952, 36028797018963952, 36028797018963952, 36028797018963952];
+
+        let mut limbs = self.0;
+
+        for i in 0..5 {
+            limbs[i] = limbs[i] + SIXTEEN_P[i] - other.0[i];
+        }
+
+        Self(Self::carry(limbs))
+    }
+
+    fn negate(& self) -> Self {
+        Self::ZERO.sub(self)
+    }
+
+    fn mul(& self, other: & Self) -> Self {
+        let a = self.0.map(|limb| limb as u128);
+        let b = other.0.map(|limb| limb as u128);
+
+        //Limbs past the top wrap around multiplied by 19, since 2^255 = 19
+        let b19 = b.map(|limb| limb * 19);
+
+        let c = [
+            a[0] * b[0] + a[4] * b19[1] + a[3] * b19[2] + a[2] * b19[3] + a[1] * b19[4],
+            a[1] * b[0] + a[0] * b[1] + a[4] * b19[2] + a[3] * b19[3] + a[2] * b19[4],
+            a[2] * b[0] + a[1] * b[1] + a[0] * b[2] + a[4] * b19[3] + a[3] * b19[4],
+            a[3] * b[0] + a[2] * b[1] + a[1] * b[2] + a[0] * b[3] + a[4] * b19[4],
+            a[4] * b[0] + a[3] * b[1] + a[2] * b[2] + a[1] * b[3] + a[0] * b[4],
+        ];
+
+        let mut limbs = [0u64; 5];
+        let mut carry: u128 = 0;
+
+        for i in 0..5 {
+            let value = c[i] + carry;
+
+            limbs[i] = (value as u64) & MASK_51;
+            carry = value >> 51;
+        }
+
+        let low = limbs[0] as u128 + carry * 19;
+
+        limbs[0] = (low as u64) & MASK_51;
+        limbs[1] += (low >> 51) as u64;
+
+        Self(Self::carry(limbs))
+    }
+
+    fn square(& self) -> Self {
+        self.mul(self)
+    }
+
+    ///Raise to a little endian exponent
+    fn pow(& self, exponent: &[u8; 32]) -> Self {
+        let mut result = Self::ONE;
+
+        for byte in exponent.iter().rev() {
+            for bit in (0..8).rev() {
+                result = result.square();
+
+                if (byte >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+
+        result
+    }
+
+    fn invert(& self) -> Self {
+        self.pow(&P_MINUS_2)
+    }
+
+    fn is_zero(& self) -> bool {
+        self.to_bytes() == [0u8; 32]
+    }
+
+    fn equals(& self, other: & Self) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+
+    ///Whether the canonical encoding is odd, the usual meaning of a negative field element
+    fn is_negative(& self) -> bool {
+        self.to_bytes()[0] & 1 == 1
+    }
+
+    ///Whether the element is zero or a square
+    fn is_square(& self) -> bool {
+        let legendre = self.pow(&P_MINUS_1_OVER_2);
+
+        legendre.is_zero() || legendre.equals(&Self::ONE)
+    }
+
+    ///The non-negative square root, if there is one
+    fn sqrt(& self) -> Option<Self> {
+        let candidate = self.pow(&P_PLUS_3_OVER_8);
+
+        let root = if candidate.square().equals(self) {
+            candidate
+        } else if candidate.square().equals(&self.negate()) {
+            candidate.mul(&Self::from_u64(2).pow(&P_MINUS_1_OVER_4))
+        } else {
+            return None;
+        };
+
+        Some(if root.is_negative() { root.negate() } else { root })
+    }
+}
+
+///The u-coordinate the Elligator 2 map takes `representative` to. The top two bits of the representative are ignored
+pub fn representative_to_public(representative: &[u8; 32]) -> [u8; 32] {
+    let mut bytes = *representative;
+
+    bytes[31] &= 0x3f;
+
+    let r = FieldElement::from_bytes(&bytes);
+    let a = FieldElement::from_u64(CURVE_A);
+
+    //w = -A / (1 + 2r^2), which is never a division by zero since -1/2 is not a square
+    let w = a.negate().mul(&FieldElement::ONE.add(&r.square().add(&r.square())).invert());
+
+    //w is the point's u-coordinate if w^3 + Aw^2 + w is a square, otherwise -w - A is
+    let curve = w.square().mul(&w).add(&a.mul(&w.square())).add(&w);
+
+    if curve.is_square() {
+        w.to_bytes()
+    } else {
+        w.negate().sub(&a).to_bytes()
+    }
+}
+
+///A representative that maps to the u-coordinate `public`, if it has one. The top two bits are left clear
+pub fn public_to_representative(public: &[u8; 32]) -> Option<[u8; 32]> {
+    let u = FieldElement::from_bytes(public);
+    let a = FieldElement::from_u64(CURVE_A);
+
+    let u_plus_a = u.add(&a);
+
+    if u.is_zero() || u_plus_a.is_zero() {
+        return None;
+    }
+
+    //r = sqrt(-(u + A) / 2u) makes 1 + 2r^2 = -A/u, so the map's w is u itself
+    let r = u_plus_a.negate().mul(&u.add(&u).invert()).sqrt()?;
+
+    let representative = r.to_bytes();
+
+    if representative_to_public(&representative) != u.to_bytes() {
+        return None;
+    }
+
+    Some(representative)
+}
+
+///A curve25519 key pair whose public key has a representative
+pub struct RepresentableKeypair {
+    pub secret: StaticSecret,
+    pub public: [u8; 32],
+    ///The representative with random top bits, as it is sent
+    pub representative: [u8; 32],
+}
+
+///Generate key pairs until one has a representative, which takes two tries on average
+pub fn generate_representable() -> RepresentableKeypair {
+    let mut rng = rand::thread_rng();
+
+    loop {
+        let mut secret = [0u8; 32];
+
+        rng.fill_bytes(& mut secret);
+
+        //Clamped the same way x25519 clamps it, so the secret agrees with the public key we compute
+        let mut clamped = secret;
+
+        clamped[0] &= 248;
+        clamped[31] &= 127;
+        clamped[31] |= 64;
+
+        let torsion = EIGHT_TORSION[(rng.next_u32() & 7) as usize];
+
+        let point = &Scalar::from_bits(clamped) * &ED25519_BASEPOINT_TABLE + torsion;
+
+        let public = point.to_montgomery().to_bytes();
+
+        if let Some(mut representative) = public_to_representative(&public) {
+            representative[31] |= (rng.next_u32() as u8) & 0xc0;
+
+            return RepresentableKeypair {
+                secret: StaticSecret::from(secret),
+                public,
+                representative,
+            };
+        }
+    }
+}
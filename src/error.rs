@@ -0,0 +1,119 @@
+//Errors from the channel, circuit and stream layers, which wrap the cell-level errors from Torserde
+
+use crate::cells::{EndReason, DestroyReason};
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    ///A cell could not be serialised or deserialised
+    Torserde(torserde::ErrorKind),
+    StdIo(std::io::Error),
+    Tls(native_tls::Error),
+    ///The relay and client share no link protocol version, or none new enough for what was asked
+    NoCommonVersion,
+    ///The other side of a handshake could not be authenticated, or replied with something we did not expect
+    HandshakeFailed,
+    ///A cell arrived that makes no sense in the current state. Contains the debug representation of the cell
+    UnexpectedCell(String),
+    ///The channel this circuit or stream used has closed
+    ChannelClosed,
+    ///We closed the circuit this stream used
+    CircuitClosed,
+    ///The circuit was destroyed by a relay, or the channel under it closed (`DestroyReason::OrConnClosed`)
+    CircuitDestroyed(DestroyReason),
+    ///The circuit was truncated before the hop this stream or request used
+    CircuitTruncated(DestroyReason),
+    ///The exit ended the stream before or instead of connecting
+    StreamRefused(EndReason),
+    ///A directory document could not be parsed. Contains what was wrong with it
+    InvalidDocument(String),
+    ///An onion address was malformed or its checksum did not match
+    InvalidOnionAddress(String),
+    ///An onion service descriptor could not be fetched, verified or decrypted
+    DescriptorUnavailable(String),
+    ///The onion service restricts discovery and we have no client key for it
+    ClientAuthRequired,
+    ///The onion service restricts discovery and did not authorize our client key. Contains which check failed
+    ClientAuthRejected(String),
+    ///Every introduction point refused or failed to relay our introduction
+    IntroductionFailed,
+    ///A key file was missing its header or had the wrong length
+    InvalidKeyFile(String),
+    ///Nothing arrived in the time we were willing to wait
+    Timeout,
+    ///No configured bridge could be reached or had a descriptor we could use
+    NoUsableBridge,
+    ///A bridge's descriptor was for a different relay than its bridge line's fingerprint
+    BridgeMismatch(String),
+    ///A bridge line names a pluggable transport we can't use
+    UnsupportedTransport(String),
+    ///A pluggable transport could not be launched or could not reach the bridge. Contains why
+    TransportFailed(String),
+}
+
+pub type Result<T> = std::result::Result<T, ErrorKind>;
+
+///Why a circuit, or the part of it a stream used, can no longer be used. This is the error carried inside the
+///`std::io::Error` returned by streams, so callers can recover the `DestroyReason` with `get_ref` and `downcast_ref`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Teardown {
+    Closed,
+    Destroyed(DestroyReason),
+    Truncated(DestroyReason),
+}
+
+impl From<Teardown> for ErrorKind {
+    fn from(teardown: Teardown) -> Self {
+        match teardown {
+            Teardown::Closed => ErrorKind::CircuitClosed,
+            Teardown::Destroyed(reason) => ErrorKind::CircuitDestroyed(reason),
+            Teardown::Truncated(reason) => ErrorKind::CircuitTruncated(reason),
+        }
+    }
+}
+
+impl std::fmt::Display for Teardown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for Teardown {}
+
+impl From<torserde::ErrorKind> for ErrorKind {
+    fn from(kind: torserde::ErrorKind) -> Self {
+        ErrorKind::Torserde(kind)
+    }
+}
+
+impl From<std::io::Error> for ErrorKind {
+    fn from(error: std::io::Error) -> Self {
+        ErrorKind::StdIo(error)
+    }
+}
+
+impl From<native_tls::Error> for ErrorKind {
+    fn from(error: native_tls::Error) -> Self {
+        ErrorKind::Tls(error)
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ErrorKind {}
+
+impl From<ErrorKind> for std::io::Error {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::StdIo(error) => error,
+            ErrorKind::CircuitClosed => std::io::Error::new(std::io::ErrorKind::ConnectionAborted, Teardown::Closed),
+            ErrorKind::CircuitDestroyed(reason) => std::io::Error::new(std::io::ErrorKind::ConnectionAborted, Teardown::Destroyed(reason)),
+            ErrorKind::CircuitTruncated(reason) => std::io::Error::new(std::io::ErrorKind::ConnectionAborted, Teardown::Truncated(reason)),
+            ErrorKind::Timeout => std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"),
+            kind => std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", kind)),
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! Exit policies: which addresses and ports an exit will connect to.
+//!
+//! Torrc-style policies are `accept`, `reject`, `accept6` and `reject6` lines, checked in order with the first match
+//! deciding. Server descriptors carry the full IPv4 policy as `accept` and `reject` lines but only an `ipv6-policy`
+//! summary for IPv6, and microdescriptors only carry summaries, `p` for IPv4 and `p6` for IPv6. Summaries list the ports
+//! that are accepted (or rejected) for most addresses. Neither is the whole truth, so a policy also remembers the
+//! addresses an exit refused with `EndReason::ExitPolicy` until the TTL the exit gave runs out.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use crate::cells::EndReason;
+use crate::error::{ErrorKind, Result};
+use crate::netdoc::{self, Item};
+
+///The networks the `private` pattern stands for, as in Tor's `private_nets`
+const PRIVATE_NETWORKS: [(&str, u8); 11] = [
+    ("0.0.0.0", 8),
+    ("169.254.0.0", 16),
+    ("127.0.0.0", 8),
+    ("192.168.0.0", 16),
+    ("10.0.0.0", 8),
+    ("172.16.0.0", 12),
+    ("::", 8),
+    ("fc00::", 7),
+    ("fe80::", 10),
+    ("fec0::", 10),
+    ("::", 127),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PolicyAction {
+    Accept,
+    Reject,
+}
+
+///An inclusive range of ports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub low: u16,
+    pub high: u16,
+}
+
+impl PortRange {
+    pub const ALL: PortRange = PortRange { low: 1, high: 65535 };
+
+    pub fn contains(& self, port: u16) -> bool {
+        self.low <= port && port <= self.high
+    }
+
+    ///`*`, `port` or `low-high`
+    pub fn parse(range: &str) -> Result<Self> {
+        let invalid = || ErrorKind::InvalidDocument(format!("bad port range {}", range));
+
+        if range == "*" {
+            return Ok(Self::ALL);
+        }
+
+        let (low, high) = range.split_once('-').unwrap_or((range, range));
+
+        let low = low.parse::<u16>().map_err(|_| invalid())?;
+        let high = high.parse::<u16>().map_err(|_| invalid())?;
+
+        if low == 0 || high < low {
+            return Err(invalid());
+        }
+
+        Ok(Self { low, high })
+    }
+}
+
+///The address half of a policy rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressPattern {
+    ///`*`: every address
+    Any,
+    ///`*4`: every IPv4 address
+    AnyV4,
+    ///`*6`: every IPv6 address
+    AnyV6,
+    ///`private`: loopback, link local and the private ranges
+    Private,
+    ///An address and how many of its leading bits must match
+    Network(IpAddr, u8),
+}
+
+///Whether the first `bits` bits of `address` and `network` are equal. Addresses of different families never match
+fn in_network(address: IpAddr, network: IpAddr, bits: u8) -> bool {
+    let (address, network, width) = match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => (u32::from(address) as u128, u32::from(network) as u128, 32u32),
+        (IpAddr::V6(address), IpAddr::V6(network)) => (u128::from(address), u128::from(network), 128),
+        _ => return false,
+    };
+
+    if bits == 0 {
+        return true;
+    }
+
+    let shift = width - (bits as u32).min(width);
+
+    address >> shift == network >> shift
+}
+
+impl AddressPattern {
+    pub fn matches(& self, address: IpAddr) -> bool {
+        match *self {
+            AddressPattern::Any => true,
+            AddressPattern::AnyV4 => address.is_ipv4(),
+            AddressPattern::AnyV6 => address.is_ipv6(),
+            AddressPattern::Private => PRIVATE_NETWORKS.iter().any(|(network, bits)| in_network(address, network.parse().unwrap(), *bits)),
+            AddressPattern::Network(network, bits) => in_network(address, network, bits),
+        }
+    }
+
+    ///Whether the pattern matches every address of at least one family
+    fn is_wildcard(& self) -> bool {
+        match *self {
+            AddressPattern::Any | AddressPattern::AnyV4 | AddressPattern::AnyV6 => true,
+            AddressPattern::Network(_, bits) => bits == 0,
+            AddressPattern::Private => false,
+        }
+    }
+
+    ///`*`, `*4`, `*6`, `private`, `1.2.3.4`, `1.2.3.0/24`, `1.2.3.0/255.255.255.0`, `[::1]` or `[2001:db8::]/32`
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let invalid = || ErrorKind::InvalidDocument(format!("bad address pattern {}", pattern));
+
+        match pattern {
+            "*" => return Ok(AddressPattern::Any),
+            "*4" => return Ok(AddressPattern::AnyV4),
+            "*6" => return Ok(AddressPattern::AnyV6),
+            "private" => return Ok(AddressPattern::Private),
+            _ => {},
+        }
+
+        let (address, mask) = match pattern.split_once('/') {
+            Some((address, mask)) => (address, Some(mask)),
+            None => (pattern, None),
+        };
+
+        let address = match address.strip_prefix('[').and_then(|address| address.strip_suffix(']')) {
+            Some(address) => IpAddr::V6(address.parse::<Ipv6Addr>().map_err(|_| invalid())?),
+            None => IpAddr::V4(address.parse::<Ipv4Addr>().map_err(|_| invalid())?),
+        };
+
+        let width = if address.is_ipv4() { 32 } else { 128 };
+
+        let bits = match mask {
+            None => width,
+            Some(mask) => match mask.parse::<u8>() {
+                Ok(bits) if bits <= width => bits,
+                Ok(_) => return Err(invalid()),
+                //IPv4 masks may also be written as addresses, as long as their bits are contiguous
+                Err(_) if address.is_ipv4() => {
+                    let mask = u32::from(mask.parse::<Ipv4Addr>().map_err(|_| invalid())?);
+
+                    if mask.leading_ones() + mask.trailing_zeros() != 32 {
+                        return Err(invalid());
+                    }
+
+                    mask.leading_ones() as u8
+                },
+                Err(_) => return Err(invalid()),
+            },
+        };
+
+        Ok(AddressPattern::Network(address, bits))
+    }
+}
+
+///One line of a full exit policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    pub address: AddressPattern,
+    pub ports: PortRange,
+}
+
+impl PolicyRule {
+    pub fn matches(& self, address: IpAddr, port: u16) -> bool {
+        self.ports.contains(port) && self.address.matches(address)
+    }
+
+    ///A rule from its keyword and `pattern:ports` argument. Returns `None` for `accept6` and `reject6` rules with IPv4
+    ///addresses, which Tor ignores
+    pub fn parse(keyword: &str, argument: &str) -> Result<Option<Self>> {
+        let (action, ipv6_only) = match keyword {
+            "accept" => (PolicyAction::Accept, false),
+            "reject" => (PolicyAction::Reject, false),
+            "accept6" => (PolicyAction::Accept, true),
+            "reject6" => (PolicyAction::Reject, true),
+            _ => return Err(ErrorKind::InvalidDocument(format!("{} is not a policy rule", keyword))),
+        };
+
+        let (address, ports) = argument.rsplit_once(':').ok_or_else(|| ErrorKind::InvalidDocument(format!("policy rule {} has no ports", argument)))?;
+
+        let mut address = AddressPattern::parse(address)?;
+
+        if ipv6_only {
+            match address {
+                AddressPattern::Any => address = AddressPattern::AnyV6,
+                AddressPattern::AnyV4 | AddressPattern::Network(IpAddr::V4(_), _) => return Ok(None),
+                _ => {},
+            }
+        }
+
+        Ok(Some(Self {
+            action,
+            address,
+            ports: PortRange::parse(ports)?,
+        }))
+    }
+}
+
+///A microdescriptor's `p` or `p6` line: the ports accepted for most addresses, or the ports rejected for most
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicySummary {
+    pub action: PolicyAction,
+    pub ports: Vec<PortRange>,
+}
+
+impl PolicySummary {
+    ///What an exit without a summary allows
+    pub fn reject_all() -> Self {
+        Self {
+            action: PolicyAction::Reject,
+            ports: vec![PortRange::ALL],
+        }
+    }
+
+    pub fn allows_port(& self, port: u16) -> bool {
+        let listed = self.ports.iter().any(|range| range.contains(port));
+
+        listed == (self.action == PolicyAction::Accept)
+    }
+
+    ///The arguments of a `p` or `p6` line, such as `accept 80,443,6660-6669`
+    pub fn parse(item: &Item) -> Result<Self> {
+        let action = match item.argument(0)? {
+            "accept" => PolicyAction::Accept,
+            "reject" => PolicyAction::Reject,
+            action => return Err(ErrorKind::InvalidDocument(format!("bad {} action {}", item.keyword, action))),
+        };
+
+        let ports = item.argument(1)?.split(',').map(PortRange::parse).collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { action, ports })
+    }
+}
+
+///What an exit's policy was read from
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicySource {
+    ///Torrc-style rules for both address families, in order
+    Full(Vec<PolicyRule>),
+    ///The IPv4 rules of a server descriptor, in order, and its `ipv6-policy` summary
+    Descriptor { ipv4: Vec<PolicyRule>, ipv6: PolicySummary },
+    ///The summaries of a microdescriptor
+    Summary { ipv4: PolicySummary, ipv6: PolicySummary },
+}
+
+///What the first of `rules` to match `address:port` says, or accept if none does
+fn rules_allow(rules: &[PolicyRule], address: IpAddr, port: u16) -> bool {
+    match rules.iter().find(|rule| rule.matches(address, port)) {
+        Some(rule) => rule.action == PolicyAction::Accept,
+        None => true,
+    }
+}
+
+///Whether `rules` might accept `port` on some address, passing over rejections of particular addresses
+fn rules_allow_port(rules: &[PolicyRule], port: u16) -> bool {
+    for rule in rules.iter().filter(|rule| rule.ports.contains(port)) {
+        match rule.action {
+            PolicyAction::Accept => return true,
+            PolicyAction::Reject if rule.address.is_wildcard() => return false,
+            PolicyAction::Reject => {},
+        }
+    }
+
+    true
+}
+
+///An address and port an exit refused, and when we may try it again
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LearnedRejection {
+    ///`None` when the exit did not say which address it refused, which rules out the port for every address
+    address: Option<IpAddr>,
+    port: u16,
+    expires: Instant,
+}
+
+///An exit's policy, plus what it told us when it refused our streams
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitPolicy {
+    pub source: PolicySource,
+    learned: Vec<LearnedRejection>,
+}
+
+impl ExitPolicy {
+    pub fn new(source: PolicySource) -> Self {
+        Self {
+            source,
+            learned: Vec::new(),
+        }
+    }
+
+    ///Read a full policy from torrc-style policy lines. Other items are skipped. Addresses no rule matches are accepted,
+    ///as dir-spec says
+    pub fn parse(document: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+
+        for item in netdoc::parse_items(document)? {
+            if let "accept" | "reject" | "accept6" | "reject6" = item.keyword.as_str() {
+                rules.extend(PolicyRule::parse(&item.keyword, item.argument(0)?)?);
+            }
+        }
+
+        Ok(Self::new(PolicySource::Full(rules)))
+    }
+
+    ///The policy from a server descriptor's items. Its `accept` and `reject` lines only cover IPv4, while IPv6 addresses
+    ///follow the `ipv6-policy` summary, and a descriptor without one rejects them all
+    pub fn from_descriptor(items: &[Item]) -> Result<Self> {
+        let mut ipv4 = Vec::new();
+
+        for item in items {
+            if let "accept" | "reject" = item.keyword.as_str() {
+                ipv4.extend(PolicyRule::parse(&item.keyword, item.argument(0)?)?);
+            }
+        }
+
+        let ipv6 = match items.iter().find(|item| item.keyword == "ipv6-policy") {
+            Some(item) => PolicySummary::parse(item)?,
+            None => PolicySummary::reject_all(),
+        };
+
+        Ok(Self::new(PolicySource::Descriptor { ipv4, ipv6 }))
+    }
+
+    ///The summaries from a microdescriptor's `p` and `p6` items. A missing summary rejects everything
+    pub fn from_microdesc(items: &[Item]) -> Result<Self> {
+        let summary = |keyword: &str| match items.iter().find(|item| item.keyword == keyword) {
+            Some(item) => PolicySummary::parse(item),
+            None => Ok(PolicySummary::reject_all()),
+        };
+
+        Ok(Self::new(PolicySource::Summary {
+            ipv4: summary("p")?,
+            ipv6: summary("p6")?,
+        }))
+    }
+
+    ///Whether the exit would connect to `address:port`. Summaries only say what happens to most addresses, so they may
+    ///be wrong for some
+    pub fn allows(& self, address: IpAddr, port: u16) -> bool {
+        let now = Instant::now();
+
+        let refused = self.learned.iter().any(|rejection| {
+            rejection.port == port && rejection.expires > now && rejection.address.map(|refused| refused == address).unwrap_or(true)
+        });
+
+        if refused {
+            return false;
+        }
+
+        match &self.source {
+            PolicySource::Full(rules) => rules_allow(rules, address, port),
+            PolicySource::Descriptor { ipv4, ipv6 } => match address {
+                IpAddr::V4(_) => rules_allow(ipv4, address, port),
+                IpAddr::V6(_) => ipv6.allows_port(port),
+            },
+            PolicySource::Summary { ipv4, ipv6 } => match address {
+                IpAddr::V4(_) => ipv4.allows_port(port),
+                IpAddr::V6(_) => ipv6.allows_port(port),
+            },
+        }
+    }
+
+    ///Whether the exit might connect to `port` on an address we don't know yet, such as a hostname it will resolve.
+    ///As in Tor, rules for particular addresses that reject the port are passed over, while ones that accept it count
+    pub fn allows_port(& self, port: u16) -> bool {
+        let now = Instant::now();
+
+        if self.learned.iter().any(|rejection| rejection.port == port && rejection.address.is_none() && rejection.expires > now) {
+            return false;
+        }
+
+        match &self.source {
+            PolicySource::Full(rules) | PolicySource::Descriptor { ipv4: rules, .. } => rules_allow_port(rules, port),
+            PolicySource::Summary { ipv4, .. } => ipv4.allows_port(port),
+        }
+    }
+
+    ///Remember that the exit refused a stream to `port` with `end_reason`, so that we don't ask it again until the TTL
+    ///it gave runs out. Other reasons say nothing about the policy and are ignored
+    pub fn learn(& mut self, end_reason: &EndReason, port: u16) {
+        let (ip, ttl) = match end_reason {
+            EndReason::ExitPolicy { ip, ttl } => (*ip, *ttl),
+            _ => return,
+        };
+
+        let now = Instant::now();
+
+        self.learned.retain(|rejection| rejection.expires > now);
+
+        self.learned.push(LearnedRejection {
+            address: if ip.is_unspecified() { None } else { Some(IpAddr::V4(ip)) },
+            port,
+            expires: now + Duration::from_secs(ttl as u64),
+        });
+    }
+}
@@ -0,0 +1,705 @@
+//! Version 3 onion service descriptors (rend-spec-v3 section 2.4 and 2.5).
+//!
+//! A descriptor has a plaintext outer layer, a superencrypted middle layer keyed by the blinded public key, and an
+//! encrypted inner layer that lists the introduction points.
+//!
+//! The outer layer is signed by a descriptor signing key, which is certified by the blinded key. Each introduction point's
+//! keys are in turn certified by the descriptor signing key. When client authorization is in use the inner layer is also
+//! keyed by a descriptor cookie, which the middle layer carries encrypted to each authorized client's x25519 key.
+
+use std::convert::TryInto;
+use chrono::{DateTime, Utc};
+use ring::rand::SecureRandom;
+use x25519_dalek::{StaticSecret, PublicKey};
+use ed25519_dalek::ExpandedSecretKey;
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::scalar::Scalar;
+
+use crate::cells::{LinkSpecifier, CSRNG};
+use crate::custom_crypto::{hs_mac, shake256, aes256_ctr, constant_time_eq, ed25519_verify};
+use crate::netdoc::{self, Item};
+use crate::onion::{OnionAddress, TimePeriod};
+use crate::pow::PowParams;
+use crate::error::{ErrorKind, Result};
+
+const SUPERENCRYPTED_CONSTANT: &[u8] = b"hsdir-superencrypted-data";
+const ENCRYPTED_CONSTANT: &[u8] = b"hsdir-encrypted-data";
+
+///Prefixed to the outer layer before it is signed
+const SIGNATURE_PREFIX: &[u8] = b"Tor onion service descriptor sig v3";
+
+///The descriptor signing key, certified by the blinded key
+pub const CERT_TYPE_DESC_SIGNING: u8 = 8;
+
+///An introduction point's authentication key, certified by the descriptor signing key
+pub const CERT_TYPE_INTRO_AUTH: u8 = 9;
+
+///The ed25519 form of an introduction point's encryption key, certified by the descriptor signing key
+pub const CERT_TYPE_INTRO_ENC: u8 = 11;
+
+///How long HSDirs keep a descriptor we upload, in minutes
+pub const DESCRIPTOR_LIFETIME: u32 = 180;
+
+///How long the certificates in a descriptor we build are valid for, in hours
+pub const DESCRIPTOR_CERT_LIFETIME: u32 = 54;
+
+///The length of the descriptor cookie that keys the inner layer for authorized clients
+pub const DESCRIPTOR_COOKIE_LENGTH: usize = 16;
+
+///The number of `auth-client` lines in a descriptor is padded to a multiple of this
+const AUTH_CLIENT_MULTIPLE: usize = 16;
+
+fn unavailable(reason: &str) -> ErrorKind {
+    ErrorKind::DescriptorUnavailable(String::from(reason))
+}
+
+///An ed25519 key that can sign, held in expanded form since blinded keys have no seed
+pub struct Ed25519Keypair {
+    pub secret: ExpandedSecretKey,
+    pub public: ed25519_dalek::PublicKey,
+}
+
+impl Ed25519Keypair {
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+
+        CSRNG.fill(& mut seed).unwrap();
+
+        let secret = ed25519_dalek::SecretKey::from_bytes(&seed).unwrap();
+
+        Self::from_expanded(ExpandedSecretKey::from(&secret))
+    }
+
+    pub fn from_expanded(secret: ExpandedSecretKey) -> Self {
+        //`PublicKey::from` clamps the scalar first, which would give the wrong key for a blinded secret
+        let scalar = Scalar::from_bits(secret.to_bytes()[0..32].try_into().unwrap());
+
+        let public = ed25519_dalek::PublicKey::from_bytes((&scalar * &ED25519_BASEPOINT_TABLE).compress().as_bytes()).unwrap();
+
+        Self {
+            secret,
+            public,
+        }
+    }
+
+    pub fn public_bytes(& self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    pub fn sign(& self, message: &[u8]) -> [u8; 64] {
+        self.secret.sign(message, &self.public).to_bytes()
+    }
+
+    ///Certify `certified_key` with this key until `expiration` hours after the epoch
+    pub fn certify(& self, cert_type: u8, expiration: u32, certified_key: &[u8; 32]) -> Ed25519Cert {
+        Ed25519Cert::parse(&Ed25519Cert::encode(cert_type, expiration, certified_key, &self.secret, &self.public)).unwrap()
+    }
+}
+
+fn invalid(reason: &str) -> ErrorKind {
+    ErrorKind::InvalidDocument(String::from(reason))
+}
+
+///An Ed25519 certificate (cert-spec section 2.1)
+#[derive(Debug, Clone)]
+pub struct Ed25519Cert {
+    pub cert_type: u8,
+    ///Hours since the epoch
+    pub expiration: u32,
+    pub certified_key: [u8; 32],
+    ///The key that signed the certificate, from the signed-with-ed25519-key extension
+    pub signing_key: Option<[u8; 32]>,
+    ///Everything before the signature, which is what the signature covers
+    signed: Vec<u8>,
+    signature: [u8; 64],
+}
+
+impl Ed25519Cert {
+    pub fn parse(encoded: &[u8]) -> Result<Self> {
+        if encoded.len() < 104 || encoded[0] != 1 {
+            return Err(invalid("bad ed25519 certificate"));
+        }
+
+        let mut signing_key = None;
+
+        let mut offset = 40;
+
+        for _ in 0..encoded[39] {
+            if offset + 4 > encoded.len() {
+                return Err(invalid("truncated certificate extension"));
+            }
+
+            let length = u16::from_be_bytes(encoded[offset..offset + 2].try_into().unwrap()) as usize;
+            let ext_type = encoded[offset + 2];
+            let flags = encoded[offset + 3];
+
+            let data = encoded.get(offset + 4..offset + 4 + length).ok_or_else(|| invalid("truncated certificate extension"))?;
+
+            match ext_type {
+                4 if length == 32 => signing_key = Some(data.try_into().unwrap()),
+                //An unknown extension that affects validation means we cannot use the certificate
+                _ if flags & 1 == 1 => return Err(invalid("unknown critical certificate extension")),
+                _ => {},
+            }
+
+            offset += 4 + length;
+        }
+
+        if encoded.len() != offset + 64 {
+            return Err(invalid("bad ed25519 certificate length"));
+        }
+
+        Ok(Self {
+            cert_type: encoded[1],
+            expiration: u32::from_be_bytes(encoded[2..6].try_into().unwrap()),
+            certified_key: encoded[7..39].try_into().unwrap(),
+            signing_key,
+            signed: Vec::from(&encoded[..offset]),
+            signature: encoded[offset..].try_into().unwrap(),
+        })
+    }
+
+    ///Encode and sign a certificate for `certified_key`, which expires `expiration` hours after the epoch. The signing key
+    ///is named in a signed-with-ed25519-key extension
+    pub fn encode(cert_type: u8, expiration: u32, certified_key: &[u8; 32], signing_secret: &ExpandedSecretKey, signing_key: &ed25519_dalek::PublicKey) -> Vec<u8> {
+        let mut encoded = vec![1, cert_type];
+
+        encoded.extend_from_slice(&expiration.to_be_bytes());
+
+        //CERT_KEY_TYPE 1 is an ed25519 key
+        encoded.push(1);
+        encoded.extend_from_slice(certified_key);
+        encoded.push(1);
+        encoded.extend_from_slice(&32u16.to_be_bytes());
+        encoded.extend_from_slice(&[4, 0]);
+        encoded.extend_from_slice(signing_key.as_bytes());
+
+        let signature = signing_secret.sign(&encoded, signing_key);
+
+        encoded.extend_from_slice(&signature.to_bytes());
+
+        encoded
+    }
+
+    ///Whether `signing_key` signed this certificate. If the certificate names the key that signed it, it must be `signing_key`
+    pub fn verify(& self, signing_key: &[u8; 32]) -> bool {
+        if matches!(self.signing_key, Some(named) if &named != signing_key) {
+            return false;
+        }
+
+        ed25519_verify(signing_key, &self.signed, &self.signature)
+    }
+
+    pub fn expired_at(& self, time: DateTime<Utc>) -> bool {
+        (self.expiration as i64) * 3600 < time.timestamp()
+    }
+
+    pub fn to_bytes(& self) -> Vec<u8> {
+        let mut encoded = self.signed.clone();
+
+        encoded.extend_from_slice(&self.signature);
+
+        encoded
+    }
+}
+
+///The secret input of a layer, SECRET_DATA | subcredential | INT_8(revision counter), followed by the salt and constant
+fn layer_keys(secret_data: &[u8], subcredential: &[u8; 32], revision_counter: u64, salt: &[u8], constant: &[u8]) -> ([u8; 32], [u8; 16], [u8; 32]) {
+    let mut input = Vec::from(secret_data);
+
+    input.extend_from_slice(subcredential);
+    input.extend_from_slice(&revision_counter.to_be_bytes());
+    input.extend_from_slice(salt);
+    input.extend_from_slice(constant);
+
+    let keys = shake256(&input, 32 + 16 + 32);
+
+    (keys[0..32].try_into().unwrap(), keys[32..48].try_into().unwrap(), keys[48..80].try_into().unwrap())
+}
+
+fn layer_mac(mac_key: &[u8; 32], salt: &[u8], ciphertext: &[u8]) -> [u8; 32] {
+    let mut message = Vec::from(&(salt.len() as u64).to_be_bytes()[..]);
+
+    message.extend_from_slice(salt);
+    message.extend_from_slice(ciphertext);
+
+    hs_mac(mac_key, &message)
+}
+
+///Decrypt a layer, SALT | ENCRYPTED | MAC, checking its MAC
+pub fn decrypt_layer(blob: &[u8], secret_data: &[u8], subcredential: &[u8; 32], revision_counter: u64, constant: &[u8]) -> Result<Vec<u8>> {
+    if blob.len() < 16 + 32 {
+        return Err(invalid("encrypted layer too short"));
+    }
+
+    let (salt, rest) = blob.split_at(16);
+    let (ciphertext, mac) = rest.split_at(rest.len() - 32);
+
+    let (key, iv, mac_key) = layer_keys(secret_data, subcredential, revision_counter, salt, constant);
+
+    if !constant_time_eq(&layer_mac(&mac_key, salt, ciphertext), mac) {
+        return Err(invalid("layer MAC mismatch"));
+    }
+
+    let mut plaintext = Vec::from(ciphertext);
+
+    aes256_ctr(&key, &iv, & mut plaintext);
+
+    //Layers are padded with NULs
+    while plaintext.last() == Some(&0) {
+        plaintext.pop();
+    }
+
+    Ok(plaintext)
+}
+
+///Encrypt a layer with a fresh salt, padding the plaintext with NULs to a multiple of 10000 bytes as Tor does
+pub fn encrypt_layer(plaintext: &[u8], secret_data: &[u8], subcredential: &[u8; 32], revision_counter: u64, constant: &[u8]) -> Vec<u8> {
+    let mut salt = [0u8; 16];
+
+    CSRNG.fill(& mut salt).unwrap();
+
+    let (key, iv, mac_key) = layer_keys(secret_data, subcredential, revision_counter, &salt, constant);
+
+    let mut ciphertext = Vec::from(plaintext);
+
+    ciphertext.resize((plaintext.len() / 10000 + 1) * 10000, 0);
+
+    aes256_ctr(&key, &iv, & mut ciphertext);
+
+    let mut blob = Vec::from(salt);
+
+    blob.extend_from_slice(&ciphertext);
+    blob.extend_from_slice(&layer_mac(&mac_key, &salt, &ciphertext));
+
+    blob
+}
+
+///Encrypt the superencrypted (middle) layer
+pub fn encrypt_superencrypted(plaintext: &[u8], blinded_key: &[u8; 32], subcredential: &[u8; 32], revision_counter: u64) -> Vec<u8> {
+    encrypt_layer(plaintext, blinded_key, subcredential, revision_counter, SUPERENCRYPTED_CONSTANT)
+}
+
+///Encrypt the inner layer. The descriptor cookie is only present when client authorization is in use
+pub fn encrypt_inner(plaintext: &[u8], blinded_key: &[u8; 32], descriptor_cookie: Option<&[u8; DESCRIPTOR_COOKIE_LENGTH]>, subcredential: &[u8; 32], revision_counter: u64) -> Vec<u8> {
+    encrypt_layer(plaintext, &inner_secret(blinded_key, descriptor_cookie), subcredential, revision_counter, ENCRYPTED_CONSTANT)
+}
+
+fn inner_secret(blinded_key: &[u8; 32], descriptor_cookie: Option<&[u8; DESCRIPTOR_COOKIE_LENGTH]>) -> Vec<u8> {
+    let mut secret_data = Vec::from(*blinded_key);
+
+    if let Some(cookie) = descriptor_cookie {
+        secret_data.extend_from_slice(cookie);
+    }
+
+    secret_data
+}
+
+///CLIENT-ID and COOKIE-KEY, from the x25519 secret shared between the service's ephemeral key and a client's key
+fn client_auth_keys(subcredential: &[u8; 32], secret_seed: &[u8; 32]) -> ([u8; 8], [u8; 32]) {
+    let mut input = Vec::from(*subcredential);
+
+    input.extend_from_slice(secret_seed);
+
+    let keys = shake256(&input, 40);
+
+    (keys[0..8].try_into().unwrap(), keys[8..40].try_into().unwrap())
+}
+
+///An `auth-client` line of the middle layer: the descriptor cookie encrypted to one client
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthClient {
+    pub client_id: [u8; 8],
+    pub iv: [u8; 16],
+    pub encrypted_cookie: [u8; DESCRIPTOR_COOKIE_LENGTH],
+}
+
+impl AuthClient {
+    ///Encrypt `descriptor_cookie` to the client with x25519 key `client_key`. `ephemeral_secret` is the service's key for
+    ///this descriptor, whose public half goes in `desc-auth-ephemeral-key`
+    pub fn new(ephemeral_secret: &StaticSecret, client_key: &PublicKey, subcredential: &[u8; 32], descriptor_cookie: &[u8; DESCRIPTOR_COOKIE_LENGTH]) -> Self {
+        let (client_id, cookie_key) = client_auth_keys(subcredential, ephemeral_secret.diffie_hellman(client_key).as_bytes());
+
+        let mut iv = [0u8; 16];
+
+        CSRNG.fill(& mut iv).unwrap();
+
+        let mut encrypted_cookie = *descriptor_cookie;
+
+        aes256_ctr(&cookie_key, &iv, & mut encrypted_cookie);
+
+        Self {
+            client_id,
+            iv,
+            encrypted_cookie,
+        }
+    }
+
+    ///A line with random values, so that the number of authorized clients is hidden
+    pub fn fake() -> Self {
+        let mut fake = Self {
+            client_id: [0u8; 8],
+            iv: [0u8; 16],
+            encrypted_cookie: [0u8; DESCRIPTOR_COOKIE_LENGTH],
+        };
+
+        CSRNG.fill(& mut fake.client_id).unwrap();
+        CSRNG.fill(& mut fake.iv).unwrap();
+        CSRNG.fill(& mut fake.encrypted_cookie).unwrap();
+
+        fake
+    }
+
+    fn parse(item: &Item) -> Result<Self> {
+        let decode = |index: usize| netdoc::decode_base64(item.argument(index)?);
+
+        Ok(Self {
+            client_id: decode(0)?.as_slice().try_into().map_err(|_| invalid("bad auth-client id"))?,
+            iv: decode(1)?.as_slice().try_into().map_err(|_| invalid("bad auth-client iv"))?,
+            encrypted_cookie: decode(2)?.as_slice().try_into().map_err(|_| invalid("bad auth-client cookie"))?,
+        })
+    }
+
+    pub fn to_line(& self) -> String {
+        format!("auth-client {} {} {}", netdoc::encode_base64(&self.client_id), netdoc::encode_base64(&self.iv), netdoc::encode_base64(&self.encrypted_cookie))
+    }
+}
+
+///Find our `auth-client` line and decrypt the descriptor cookie from it
+pub fn find_descriptor_cookie(auth_clients: &[AuthClient], ephemeral_key: &PublicKey, client_secret: &StaticSecret, subcredential: &[u8; 32]) -> Option<[u8; DESCRIPTOR_COOKIE_LENGTH]> {
+    let (client_id, cookie_key) = client_auth_keys(subcredential, client_secret.diffie_hellman(ephemeral_key).as_bytes());
+
+    let entry = auth_clients.iter().find(|entry| constant_time_eq(&entry.client_id, &client_id))?;
+
+    let mut cookie = entry.encrypted_cookie;
+
+    aes256_ctr(&cookie_key, &entry.iv, & mut cookie);
+
+    Some(cookie)
+}
+
+///An introduction point from the inner layer of a descriptor
+#[derive(Debug, Clone)]
+pub struct IntroPoint {
+    ///How to reach the intro point's relay
+    pub link_specifiers: Vec<LinkSpecifier>,
+    ///The relay's ntor onion key, for extending a circuit to it
+    pub onion_key: [u8; 32],
+    ///The service's authentication key for this intro point, certified by the descriptor signing key
+    pub auth_key: [u8; 32],
+    pub auth_key_cert: Ed25519Cert,
+    ///The service's x25519 encryption key for INTRODUCE1
+    pub enc_key: [u8; 32],
+    pub enc_key_cert: Ed25519Cert,
+}
+
+impl IntroPoint {
+    ///The relay's RSA identity digest, from its legacy link specifier
+    pub fn node_id(& self) -> Option<[u8; 20]> {
+        self.link_specifiers.iter().find(|spec| spec.get_type() == 2).and_then(|spec| spec.get_spec().try_into().ok())
+    }
+
+    ///The relay's ed25519 identity, from its ed25519 link specifier
+    pub fn ed25519_id(& self) -> Option<[u8; 32]> {
+        self.link_specifiers.iter().find(|spec| spec.get_type() == 3).and_then(|spec| spec.get_spec().try_into().ok())
+    }
+
+    ///This introduction point's entry in the inner layer
+    pub fn to_entry(& self) -> String {
+        format!("introduction-point {}\nonion-key ntor {}\nauth-key\n{}\nenc-key ntor {}\nenc-key-cert\n{}\n",
+            netdoc::encode_base64(&encode_link_specifiers(&self.link_specifiers)),
+            netdoc::encode_base64(&self.onion_key),
+            netdoc::encode_object("ED25519 CERT", &self.auth_key_cert.to_bytes()),
+            netdoc::encode_base64(&self.enc_key),
+            netdoc::encode_object("ED25519 CERT", &self.enc_key_cert.to_bytes()))
+    }
+
+    ///Whether both of the intro point's certificates were signed by the descriptor signing key
+    pub fn verify(& self, signing_key: &[u8; 32]) -> bool {
+        self.auth_key_cert.cert_type == CERT_TYPE_INTRO_AUTH && self.auth_key_cert.verify(signing_key)
+            && self.enc_key_cert.cert_type == CERT_TYPE_INTRO_ENC && self.enc_key_cert.verify(signing_key)
+    }
+}
+
+fn decode_key(item: &Item, index: usize) -> Result<[u8; 32]> {
+    netdoc::decode_base64(item.argument(index)?)?.as_slice().try_into().map_err(|_| invalid("bad key length"))
+}
+
+///Parse NSPEC | (LSTYPE | LSLEN | LSPEC)*
+pub fn parse_link_specifiers(encoded: &[u8]) -> Result<Vec<LinkSpecifier>> {
+    let count = *encoded.first().ok_or_else(|| invalid("empty link specifiers"))?;
+
+    let mut specifiers = Vec::new();
+
+    let mut offset = 1;
+
+    for _ in 0..count {
+        let header = encoded.get(offset..offset + 2).ok_or_else(|| invalid("truncated link specifier"))?;
+        let spec = encoded.get(offset + 2..offset + 2 + header[1] as usize).ok_or_else(|| invalid("truncated link specifier"))?;
+
+        specifiers.push(LinkSpecifier::new(header[0], Vec::from(spec)));
+
+        offset += 2 + header[1] as usize;
+    }
+
+    Ok(specifiers)
+}
+
+pub fn encode_link_specifiers(specifiers: &[LinkSpecifier]) -> Vec<u8> {
+    let mut encoded = vec![specifiers.len() as u8];
+
+    for spec in specifiers {
+        encoded.push(spec.get_type());
+        encoded.push(spec.get_spec().len() as u8);
+        encoded.extend_from_slice(spec.get_spec());
+    }
+
+    encoded
+}
+
+///Parse the introduction points from the plaintext of the inner layer
+pub fn parse_intro_points(plaintext: &str) -> Result<Vec<IntroPoint>> {
+    let items = netdoc::parse_items(plaintext)?;
+
+    let mut intro_points = Vec::new();
+
+    //Each introduction point runs from its introduction-point item to the next
+    for (index, item) in items.iter().enumerate().filter(|(_, item)| item.keyword == "introduction-point") {
+        let section: Vec<&Item> = items[index + 1..].iter().take_while(|item| item.keyword != "introduction-point").collect();
+
+        let find = |keyword: &str, first: Option<&str>| section.iter().find(|item| item.keyword == keyword && (first.is_none() || item.arguments.first().map(|a| a.as_str()) == first)).copied()
+            .ok_or_else(|| invalid(&format!("introduction point missing {}", keyword)));
+
+        let auth_key_cert = Ed25519Cert::parse(find("auth-key", None)?.object()?)?;
+
+        intro_points.push(IntroPoint {
+            link_specifiers: parse_link_specifiers(&netdoc::decode_base64(item.argument(0)?)?)?,
+            onion_key: decode_key(find("onion-key", Some("ntor"))?, 1)?,
+            auth_key: auth_key_cert.certified_key,
+            auth_key_cert,
+            enc_key: decode_key(find("enc-key", Some("ntor"))?, 1)?,
+            enc_key_cert: Ed25519Cert::parse(find("enc-key-cert", None)?.object()?)?,
+        });
+    }
+
+    Ok(intro_points)
+}
+
+///What the inner layer of a descriptor tells a client about reaching the service
+#[derive(Debug)]
+pub struct InnerLayer {
+    pub intro_points: Vec<IntroPoint>,
+    ///The proof-of-work the service asks for, if it is under attack
+    pub pow_params: Option<PowParams>,
+}
+
+///Parse the plaintext of the inner layer
+pub fn parse_inner_layer(plaintext: &str) -> Result<InnerLayer> {
+    let items = netdoc::parse_items(plaintext)?;
+
+    let mut pow_params = None;
+
+    //A service may list several schemes, and we only know v1
+    for item in items.iter().filter(|item| item.keyword == "pow-params") {
+        if let Some(params) = PowParams::parse(item)? {
+            pow_params = Some(params);
+        }
+    }
+
+    Ok(InnerLayer {
+        intro_points: parse_intro_points(plaintext)?,
+        pow_params,
+    })
+}
+
+///The outer layer of a descriptor, as served by an HSDir
+#[derive(Debug, Clone)]
+pub struct HsDescriptor {
+    ///Minutes
+    pub lifetime: u32,
+    pub signing_key_cert: Ed25519Cert,
+    pub revision_counter: u64,
+    superencrypted: Vec<u8>,
+    ///The document up to the `signature` item, which is what the signature covers
+    signed: Vec<u8>,
+    signature: [u8; 64],
+}
+
+impl HsDescriptor {
+    pub fn parse(document: &str) -> Result<Self> {
+        let items = netdoc::parse_items(document)?;
+
+        match items.first() {
+            Some(item) if item.keyword == "hs-descriptor" && item.arguments.first().map(|v| v.as_str()) == Some("3") => {},
+            _ => return Err(invalid("not a version 3 descriptor")),
+        }
+
+        let number = |keyword: &str| -> Result<u64> {
+            netdoc::find(&items, keyword)?.argument(0)?.parse::<u64>().map_err(|_| invalid(&format!("bad {}", keyword)))
+        };
+
+        //The signature is the last item, and everything before it is signed, up to and including the newline
+        let signed_length = document.rfind("\nsignature ").ok_or_else(|| invalid("missing signature"))? + 1;
+
+        let signature = netdoc::decode_base64(netdoc::find(&items, "signature")?.argument(0)?)?;
+
+        Ok(Self {
+            lifetime: number("descriptor-lifetime")? as u32,
+            signing_key_cert: Ed25519Cert::parse(netdoc::find(&items, "descriptor-signing-key-cert")?.object()?)?,
+            revision_counter: number("revision-counter")?,
+            superencrypted: Vec::from(netdoc::find(&items, "superencrypted")?.object()?),
+            signed: Vec::from(&document.as_bytes()[..signed_length]),
+            signature: signature.as_slice().try_into().map_err(|_| invalid("bad signature length"))?,
+        })
+    }
+
+    ///Check that `blinded_key` certified the descriptor signing key, that the certificate has not expired at `time` and
+    ///that the descriptor signing key signed the document
+    pub fn verify(& self, blinded_key: &[u8; 32], time: DateTime<Utc>) -> Result<()> {
+        let cert = &self.signing_key_cert;
+
+        if cert.cert_type != CERT_TYPE_DESC_SIGNING || !cert.verify(blinded_key) {
+            return Err(unavailable("descriptor signing key is not certified by the blinded key"));
+        }
+
+        if cert.expired_at(time) {
+            return Err(unavailable("descriptor signing key certificate has expired"));
+        }
+
+        let mut signed = Vec::from(SIGNATURE_PREFIX);
+
+        signed.extend_from_slice(&self.signed);
+
+        if !ed25519_verify(&cert.certified_key, &signed, &self.signature) {
+            return Err(unavailable("bad descriptor signature"));
+        }
+
+        Ok(())
+    }
+
+    ///Decrypt both layers and return the introduction points whose certificates are valid. `client_secret` is our x25519
+    ///key if the service requires client authorization
+    pub fn decrypt(& self, blinded_key: &[u8; 32], subcredential: &[u8; 32], client_secret: Option<&StaticSecret>) -> Result<Vec<IntroPoint>> {
+        Ok(self.decrypt_inner(blinded_key, subcredential, client_secret)?.intro_points)
+    }
+
+    ///`decrypt`, also returning the rest of the inner layer
+    pub fn decrypt_inner(& self, blinded_key: &[u8; 32], subcredential: &[u8; 32], client_secret: Option<&StaticSecret>) -> Result<InnerLayer> {
+        let middle = decrypt_layer(&self.superencrypted, blinded_key, subcredential, self.revision_counter, SUPERENCRYPTED_CONSTANT)?;
+
+        let middle = String::from_utf8(middle).map_err(|_| invalid("superencrypted layer is not text"))?;
+
+        let items = netdoc::parse_items(&middle)?;
+
+        //Every descriptor has auth-client lines, so only failing to decrypt the inner layer shows a cookie was needed
+        let descriptor_cookie = match client_secret {
+            Some(client_secret) => {
+                let ephemeral_key: [u8; 32] = decode_key(netdoc::find(&items, "desc-auth-ephemeral-key")?, 0)?;
+
+                let auth_clients = items.iter()
+                    .filter(|item| item.keyword == "auth-client")
+                    .map(AuthClient::parse)
+                    .collect::<Result<Vec<AuthClient>>>()?;
+
+                find_descriptor_cookie(&auth_clients, &PublicKey::from(ephemeral_key), client_secret, subcredential)
+            },
+            None => None,
+        };
+
+        let encrypted = netdoc::find(&items, "encrypted")?.object()?;
+
+        let inner = decrypt_layer(encrypted, &inner_secret(blinded_key, descriptor_cookie.as_ref()), subcredential, self.revision_counter, ENCRYPTED_CONSTANT)
+            .map_err(|_| match (client_secret, descriptor_cookie) {
+                (None, _) => ErrorKind::ClientAuthRequired,
+                (Some(_), None) => ErrorKind::ClientAuthRejected(String::from("our client key is not authorized")),
+                (Some(_), Some(_)) => ErrorKind::ClientAuthRejected(String::from("the descriptor cookie for our client key is wrong")),
+            })?;
+
+        let inner = String::from_utf8(inner).map_err(|_| invalid("encrypted layer is not text"))?;
+
+        let signing_key = &self.signing_key_cert.certified_key;
+
+        let mut inner = parse_inner_layer(&inner)?;
+
+        inner.intro_points.retain(|intro_point| intro_point.verify(signing_key));
+
+        Ok(inner)
+    }
+}
+
+///Build and sign a descriptor listing `intro_points`, whose certificates must have been made by `signing`. The descriptor
+///signing key is certified by `blinded`, the service's blinded key for the time period `subcredential` belongs to.
+///If `authorized_clients` is not empty only those clients can decrypt the inner layer, and with `pow_params` clients are
+///asked for proof-of-work
+pub fn encode_descriptor(blinded: &Ed25519Keypair, signing: &Ed25519Keypair, subcredential: &[u8; 32], revision_counter: u64, intro_points: &[IntroPoint], authorized_clients: &[PublicKey], pow_params: Option<&PowParams>) -> String {
+    let expiration = (Utc::now().timestamp() / 3600) as u32 + DESCRIPTOR_CERT_LIFETIME;
+
+    let mut inner = String::from("create2-formats 2\n");
+
+    if let Some(params) = pow_params {
+        inner.push_str(&params.to_line());
+    }
+
+    for intro_point in intro_points {
+        inner.push_str(&intro_point.to_entry());
+    }
+
+    let ephemeral_secret = crate::ntor::generate_secret();
+
+    let descriptor_cookie = if authorized_clients.is_empty() {
+        None
+    } else {
+        let mut cookie = [0u8; DESCRIPTOR_COOKIE_LENGTH];
+
+        CSRNG.fill(& mut cookie).unwrap();
+
+        Some(cookie)
+    };
+
+    //Without client authorization the auth-client lines are all random, and with it they are padded with random lines
+    let mut auth_lines: Vec<String> = match &descriptor_cookie {
+        Some(cookie) => authorized_clients.iter().map(|client| AuthClient::new(&ephemeral_secret, client, subcredential, cookie).to_line()).collect(),
+        None => Vec::new(),
+    };
+
+    while auth_lines.is_empty() || auth_lines.len() % AUTH_CLIENT_MULTIPLE != 0 {
+        auth_lines.push(AuthClient::fake().to_line());
+    }
+
+    //Client IDs look random, so sorting hides which lines are real
+    auth_lines.sort();
+
+    let mut middle = format!("desc-auth-type x25519\ndesc-auth-ephemeral-key {}\n", netdoc::encode_base64(PublicKey::from(&ephemeral_secret).as_bytes()));
+
+    for line in auth_lines {
+        middle.push_str(&line);
+        middle.push('\n');
+    }
+
+    let blinded_key = blinded.public_bytes();
+
+    middle.push_str(&format!("encrypted\n{}\n", netdoc::encode_object("MESSAGE", &encrypt_inner(inner.as_bytes(), &blinded_key, descriptor_cookie.as_ref(), subcredential, revision_counter))));
+
+    let outer = format!("hs-descriptor 3\ndescriptor-lifetime {}\ndescriptor-signing-key-cert\n{}\nrevision-counter {}\nsuperencrypted\n{}\n",
+        DESCRIPTOR_LIFETIME,
+        netdoc::encode_object("ED25519 CERT", &blinded.certify(CERT_TYPE_DESC_SIGNING, expiration, &signing.public_bytes()).to_bytes()),
+        revision_counter,
+        netdoc::encode_object("MESSAGE", &encrypt_superencrypted(middle.as_bytes(), &blinded_key, subcredential, revision_counter)));
+
+    let mut signed = Vec::from(SIGNATURE_PREFIX);
+
+    signed.extend_from_slice(outer.as_bytes());
+
+    format!("{}signature {}\n", outer, netdoc::encode_base64(&signing.sign(&signed)))
+}
+
+///Parse, verify and decrypt the descriptor of the service at `address` for `period`, returning its introduction points
+pub fn decode(document: &str, address: &OnionAddress, period: TimePeriod, client_secret: Option<&StaticSecret>) -> Result<Vec<IntroPoint>> {
+    let blinded_key = address.blinded_key(period)?;
+
+    let descriptor = HsDescriptor::parse(document)?;
+
+    descriptor.verify(&blinded_key, Utc::now())?;
+
+    descriptor.decrypt(&blinded_key, &address.subcredential(period)?, client_secret)
+}
@@ -0,0 +1,197 @@
+//! The hs-ntor handshake used to introduce a client to an onion service (rend-spec-v3 section 5.1).
+//!
+//! The client encrypts the plaintext of INTRODUCE1 to the intro point's encryption key, and the service answers through
+//! the rendezvous point with SERVER_PK | AUTH in RENDEZVOUS1. Both sides then derive the keys of the virtual hop.
+
+use x25519_dalek::{StaticSecret, PublicKey};
+use std::convert::TryInto;
+
+use crate::custom_crypto::{hs_mac, shake256, aes256_ctr, constant_time_eq};
+use crate::ntor::generate_secret;
+
+const PROTOID: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1";
+const T_HSENC: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_key_extract";
+const T_HSVERIFY: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_verify";
+const T_HSMAC: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_mac";
+const M_HSEXPAND: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_key_expand";
+
+///The length of the key material for the virtual hop, Df | Db | Kf | Kb
+pub const HS_KEY_MATERIAL_LENGTH: usize = 128;
+
+fn exp(secret: &StaticSecret, public: &PublicKey) -> Option<[u8; 32]> {
+    let shared = *secret.diffie_hellman(public).as_bytes();
+
+    if shared.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(shared)
+    }
+}
+
+///ENC_KEY and MAC_KEY for the encrypted part of INTRODUCE1, the same on both sides
+fn intro_keys(xb: &[u8; 32], auth_key: &[u8; 32], x: &PublicKey, b: &PublicKey, subcredential: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut input = Vec::new();
+
+    input.extend_from_slice(xb);
+    input.extend_from_slice(auth_key);
+    input.extend_from_slice(x.as_bytes());
+    input.extend_from_slice(b.as_bytes());
+    input.extend_from_slice(PROTOID);
+    input.extend_from_slice(T_HSENC);
+    input.extend_from_slice(M_HSEXPAND);
+    input.extend_from_slice(subcredential);
+
+    let keys = shake256(&input, 64);
+
+    (keys[0..32].try_into().unwrap(), keys[32..64].try_into().unwrap())
+}
+
+///NTOR_KEY_SEED and AUTH_INPUT_MAC for the rendezvous, the same on both sides
+fn seed_and_auth(xy: &[u8; 32], xb: &[u8; 32], auth_key: &[u8; 32], b: &PublicKey, x: &PublicKey, y: &PublicKey) -> ([u8; 32], [u8; 32]) {
+    let mut secret_input = Vec::new();
+
+    secret_input.extend_from_slice(xy);
+    secret_input.extend_from_slice(xb);
+    secret_input.extend_from_slice(auth_key);
+    secret_input.extend_from_slice(b.as_bytes());
+    secret_input.extend_from_slice(x.as_bytes());
+    secret_input.extend_from_slice(y.as_bytes());
+    secret_input.extend_from_slice(PROTOID);
+
+    let key_seed = hs_mac(&secret_input, T_HSENC);
+    let verify = hs_mac(&secret_input, T_HSVERIFY);
+
+    let mut auth_input = Vec::from(verify);
+
+    auth_input.extend_from_slice(auth_key);
+    auth_input.extend_from_slice(b.as_bytes());
+    auth_input.extend_from_slice(y.as_bytes());
+    auth_input.extend_from_slice(x.as_bytes());
+    auth_input.extend_from_slice(PROTOID);
+    auth_input.extend_from_slice(b"Server");
+
+    (key_seed, hs_mac(&auth_input, T_HSMAC))
+}
+
+fn expand(key_seed: &[u8; 32]) -> [u8; HS_KEY_MATERIAL_LENGTH] {
+    let mut input = Vec::from(*key_seed);
+
+    input.extend_from_slice(M_HSEXPAND);
+
+    shake256(&input, HS_KEY_MATERIAL_LENGTH).as_slice().try_into().unwrap()
+}
+
+///Client side of hs-ntor, for one introduction attempt
+pub struct HsNtorClient {
+    auth_key: [u8; 32],
+    enc_key: PublicKey,
+    subcredential: [u8; 32],
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl HsNtorClient {
+    ///`auth_key` and `enc_key` are the intro point's keys from the descriptor
+    pub fn new(auth_key: &[u8; 32], enc_key: &[u8; 32], subcredential: &[u8; 32]) -> Self {
+        let secret = generate_secret();
+        let public = PublicKey::from(&secret);
+
+        Self {
+            auth_key: *auth_key,
+            enc_key: PublicKey::from(*enc_key),
+            subcredential: *subcredential,
+            secret,
+            public,
+        }
+    }
+
+    pub fn client_public(& self) -> [u8; 32] {
+        *self.public.as_bytes()
+    }
+
+    ///Encrypt the plaintext of INTRODUCE1 and MAC it along with the `header` of the cell that precedes it.
+    ///Returns CLIENT_PK | ENCRYPTED_DATA | MAC
+    pub fn encrypt(& self, header: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+        let xb = exp(&self.secret, &self.enc_key)?;
+
+        let (enc_key, mac_key) = intro_keys(&xb, &self.auth_key, &self.public, &self.enc_key, &self.subcredential);
+
+        let mut encrypted = Vec::from(*self.public.as_bytes());
+
+        let start = encrypted.len();
+
+        encrypted.extend_from_slice(plaintext);
+
+        aes256_ctr(&enc_key, &[0u8; 16], & mut encrypted[start..]);
+
+        let mut mac_input = Vec::from(header);
+
+        mac_input.extend_from_slice(&encrypted);
+
+        encrypted.extend_from_slice(&hs_mac(&mac_key, &mac_input));
+
+        Some(encrypted)
+    }
+
+    ///Verify the service's SERVER_PK | AUTH from RENDEZVOUS2 and derive the key material of the virtual hop
+    pub fn complete(& self, handshake_info: &[u8; 64]) -> Option<[u8; HS_KEY_MATERIAL_LENGTH]> {
+        let y: [u8; 32] = handshake_info[0..32].try_into().unwrap();
+        let y = PublicKey::from(y);
+
+        let xy = exp(&self.secret, &y)?;
+        let xb = exp(&self.secret, &self.enc_key)?;
+
+        let (key_seed, auth) = seed_and_auth(&xy, &xb, &self.auth_key, &self.enc_key, &self.public, &y);
+
+        if !constant_time_eq(&auth, &handshake_info[32..64]) {
+            return None;
+        }
+
+        Some(expand(&key_seed))
+    }
+}
+
+///Service side of hs-ntor. Checks the MAC of an INTRODUCE2 whose encrypted part is CLIENT_PK | ENCRYPTED_DATA | MAC and
+///whose earlier fields are `header`, decrypts it, and returns the plaintext, the reply for RENDEZVOUS1 and the key material
+pub fn service_handshake(auth_key: &[u8; 32], enc_secret: &StaticSecret, subcredential: &[u8; 32], header: &[u8], encrypted: &[u8]) -> Option<(Vec<u8>, [u8; 64], [u8; HS_KEY_MATERIAL_LENGTH])> {
+    if encrypted.len() < 64 {
+        return None;
+    }
+
+    let b = PublicKey::from(enc_secret);
+
+    let x: [u8; 32] = encrypted[0..32].try_into().unwrap();
+    let x = PublicKey::from(x);
+
+    let xb = exp(enc_secret, &x)?;
+
+    let (enc_key, mac_key) = intro_keys(&xb, auth_key, &x, &b, subcredential);
+
+    let (body, mac) = encrypted.split_at(encrypted.len() - 32);
+
+    let mut mac_input = Vec::from(header);
+
+    mac_input.extend_from_slice(body);
+
+    if !constant_time_eq(&hs_mac(&mac_key, &mac_input), mac) {
+        return None;
+    }
+
+    let mut plaintext = Vec::from(&body[32..]);
+
+    aes256_ctr(&enc_key, &[0u8; 16], & mut plaintext);
+
+    let secret = generate_secret();
+    let y = PublicKey::from(&secret);
+
+    let xy = exp(&secret, &x)?;
+
+    let (key_seed, auth) = seed_and_auth(&xy, &xb, auth_key, &b, &x, &y);
+
+    let mut reply = [0u8; 64];
+
+    reply[0..32].copy_from_slice(y.as_bytes());
+    reply[32..64].copy_from_slice(&auth);
+
+    Some((plaintext, reply, expand(&key_seed)))
+}
@@ -0,0 +1,158 @@
+//! Stream isolation: which streams may share a circuit.
+//!
+//! Every stream request carries the details Tor isolates on. A front-end, such as a SOCKS listener, holds an
+//! `IsolationFlags` saying which of those details matter and turns each request into an `IsolationKey`. Two streams may
+//! only share a circuit if their keys are equal. Like Tor's `SessionGroup`, the flags are part of the key, so streams
+//! from front-ends with different flags never share a circuit.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+///An explicit isolation group chosen by the caller. Streams only share a circuit if they carry the same token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IsolationToken(u64);
+
+impl IsolationToken {
+    ///A token that is not equal to any other token
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+
+        IsolationToken(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    ///The token shared by every stream that does not ask for isolation
+    pub fn no_isolation() -> Self {
+        IsolationToken(0)
+    }
+}
+
+impl Default for IsolationToken {
+    fn default() -> Self {
+        Self::no_isolation()
+    }
+}
+
+///Which details of a request isolate it from other streams. The defaults follow Tor's `SocksPort` defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IsolationFlags {
+    ///`IsolateSOCKSAuth`: streams with different SOCKS usernames or passwords, or with and without them, are isolated
+    pub socks_auth: bool,
+    ///`IsolateDestAddr`: streams to different destination addresses are isolated
+    pub dest_addr: bool,
+    ///`IsolateDestPort`: streams to different destination ports are isolated
+    pub dest_port: bool,
+    ///`IsolateClientAddr`: streams from different client addresses are isolated
+    pub client_addr: bool,
+}
+
+impl Default for IsolationFlags {
+    fn default() -> Self {
+        Self {
+            socks_auth: true,
+            dest_addr: false,
+            dest_port: false,
+            client_addr: true,
+        }
+    }
+}
+
+impl IsolationFlags {
+    ///Isolate on nothing but the request's token
+    pub fn none() -> Self {
+        Self {
+            socks_auth: false,
+            dest_addr: false,
+            dest_port: false,
+            client_addr: false,
+        }
+    }
+
+    ///The key of `request`, keeping only the details these flags isolate on
+    pub fn key(& self, request: &StreamRequest) -> IsolationKey {
+        IsolationKey {
+            flags: *self,
+            token: request.token,
+            socks_auth: if self.socks_auth { request.socks_auth.clone() } else { None },
+            dest_addr: if self.dest_addr { Some(request.address.to_ascii_lowercase()) } else { None },
+            dest_port: if self.dest_port { Some(request.port) } else { None },
+            client_addr: if self.client_addr { request.client_addr } else { None },
+        }
+    }
+}
+
+///A request to open a stream, with everything that can isolate it
+#[derive(Debug, Clone)]
+pub struct StreamRequest {
+    pub address: String,
+    pub port: u16,
+    ///The SOCKS username and password, if the client sent any
+    pub socks_auth: Option<(Vec<u8>, Vec<u8>)>,
+    ///The address of the application that made the request, for front-ends that accept connections
+    pub client_addr: Option<IpAddr>,
+    pub token: IsolationToken,
+}
+
+impl StreamRequest {
+    pub fn new(address: &str, port: u16) -> Self {
+        Self {
+            address: String::from(address),
+            port,
+            socks_auth: None,
+            client_addr: None,
+            token: IsolationToken::no_isolation(),
+        }
+    }
+
+    pub fn socks_auth(mut self, username: &[u8], password: &[u8]) -> Self {
+        self.socks_auth = Some((Vec::from(username), Vec::from(password)));
+        self
+    }
+
+    pub fn client_addr(mut self, address: IpAddr) -> Self {
+        self.client_addr = Some(address);
+        self
+    }
+
+    pub fn token(mut self, token: IsolationToken) -> Self {
+        self.token = token;
+        self
+    }
+}
+
+///The isolation group of a stream. Streams may share a circuit only if their keys are equal
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IsolationKey {
+    flags: IsolationFlags,
+    token: IsolationToken,
+    socks_auth: Option<(Vec<u8>, Vec<u8>)>,
+    dest_addr: Option<String>,
+    dest_port: Option<u16>,
+    client_addr: Option<IpAddr>,
+}
+
+impl IsolationKey {
+    ///The key for streams that only isolate by `token`
+    pub fn from_token(token: IsolationToken) -> Self {
+        Self {
+            flags: IsolationFlags::none(),
+            token,
+            socks_auth: None,
+            dest_addr: None,
+            dest_port: None,
+            client_addr: None,
+        }
+    }
+}
+
+impl Default for IsolationKey {
+    ///The key shared by every stream that does not ask for isolation
+    fn default() -> Self {
+        Self::from_token(IsolationToken::no_isolation())
+    }
+}
+
+impl From<IsolationToken> for IsolationKey {
+    fn from(token: IsolationToken) -> Self {
+        Self::from_token(token)
+    }
+}
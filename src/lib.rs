@@ -4,6 +4,38 @@ mod torpedo_tests;
 mod macro_tests;
 mod cells;
 mod cellcrypto;
+mod cgo;
 mod custom_crypto;
 mod directories;
 mod misc;
+mod ntor;
+#[cfg(feature = "legacy-tap")]
+mod tap;
+mod error;
+mod channel;
+mod channel_padding;
+mod link_auth;
+mod circuit;
+mod circuit_padding;
+mod circuit_pool;
+mod conflux;
+mod relay_format;
+mod build_timeout;
+mod dns_cache;
+mod exit_policy;
+mod isolation;
+mod netdoc;
+mod hs_ntor;
+mod hs_descriptor;
+mod onion;
+mod onion_service;
+mod client_auth;
+mod pow;
+mod bridge;
+mod pt;
+mod elligator;
+mod obfs4;
+#[cfg(feature = "async")]
+mod async_channel;
+#[cfg(test)]
+mod mock_relay;
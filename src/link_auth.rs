@@ -0,0 +1,129 @@
+//! The body of AUTHENTICATE for the Ed25519-SHA256-RFC5705 method, as laid out in tor-spec section 4.4.
+//!
+//! This is only the wire format: the body binds the identities of both ends, digests of everything each side sent during
+//! the handshake, the responder's TLS certificate and secrets exported from the TLS session, then is signed with the
+//! initiator's Ed25519 link authentication key.
+//!
+//! Relay authentication is only partly done. Channel setup never sends AUTHENTICATE, so Torpedo can't authenticate as a
+//! bridge or relay yet. That still needs the RFC 5705 exporter for TLSSECRETS, which native-tls doesn't expose, plus RSA
+//! and Ed25519 identity certificates in our own CERTS cell.
+
+use std::convert::TryInto;
+
+use ring::rand::SecureRandom;
+use torserde::NLengthVector;
+
+use crate::cells::{Command, CSRNG};
+use crate::custom_crypto::ed25519_verify;
+use crate::error::{ErrorKind, Result};
+use crate::hs_descriptor::Ed25519Keypair;
+
+///The AUTH_CHALLENGE method number of Ed25519-SHA256-RFC5705
+pub const AUTH_METHOD_ED25519_SHA256_RFC5705: u16 = 3;
+
+///The TYPE field that starts the body
+pub const AUTH0003_TYPE: &[u8; 8] = b"AUTH0003";
+
+///The label TLSSECRETS is exported from the TLS session with, using CID_ED as the context
+pub const AUTH0003_EXPORTER_LABEL: &str = "EXPORTER FOR TOR TLS CLIENT BINDING AUTH0003";
+
+///The length of the body, signature included
+pub const AUTH0003_LENGTH: usize = 8 + 32 * 8 + 24 + 64;
+
+///An AUTH0003 body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Auth0003 {
+    ///SHA256 of the initiator's RSA identity key, DER encoded
+    pub cid: [u8; 32],
+    ///SHA256 of the responder's RSA identity key, DER encoded
+    pub sid: [u8; 32],
+    ///The initiator's Ed25519 identity key
+    pub cid_ed: [u8; 32],
+    ///The responder's Ed25519 identity key, or zeros if it has none
+    pub sid_ed: [u8; 32],
+    ///SHA256 of every byte the responder sent on the channel so far
+    pub slog: [u8; 32],
+    ///SHA256 of every byte the initiator sent on the channel so far, up to this cell
+    pub clog: [u8; 32],
+    ///SHA256 of the responder's TLS link certificate, DER encoded
+    pub scert: [u8; 32],
+    ///32 bytes from the TLS exporter with `AUTH0003_EXPORTER_LABEL`
+    pub tls_secrets: [u8; 32],
+    pub rand: [u8; 24],
+    pub signature: [u8; 64],
+}
+
+impl Auth0003 {
+    ///Every field before the signature, which is what gets signed
+    pub fn signed_bytes(& self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(AUTH0003_LENGTH);
+
+        bytes.extend_from_slice(AUTH0003_TYPE);
+
+        for field in &[self.cid, self.sid, self.cid_ed, self.sid_ed, self.slog, self.clog, self.scert, self.tls_secrets] {
+            bytes.extend_from_slice(field);
+        }
+
+        bytes.extend_from_slice(&self.rand);
+
+        bytes
+    }
+
+    ///Fill in RAND and sign with `authentication_key`, the key certified by the initiator's type 6 certificate
+    pub fn sign(& mut self, authentication_key: &Ed25519Keypair) {
+        CSRNG.fill(& mut self.rand).unwrap();
+
+        self.signature = authentication_key.sign(&self.signed_bytes());
+    }
+
+    ///Whether the signature is good for `authentication_key`
+    pub fn verify(& self, authentication_key: &[u8; 32]) -> bool {
+        ed25519_verify(authentication_key, &self.signed_bytes(), &self.signature)
+    }
+
+    pub fn to_bytes(& self) -> Vec<u8> {
+        let mut bytes = self.signed_bytes();
+
+        bytes.extend_from_slice(&self.signature);
+
+        bytes
+    }
+
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        if body.len() != AUTH0003_LENGTH || &body[..8] != AUTH0003_TYPE {
+            return Err(ErrorKind::UnexpectedCell(String::from("malformed AUTH0003 body")));
+        }
+
+        let field = |index: usize| -> [u8; 32] { body[8 + index * 32..8 + (index + 1) * 32].try_into().unwrap() };
+
+        Ok(Self {
+            cid: field(0),
+            sid: field(1),
+            cid_ed: field(2),
+            sid_ed: field(3),
+            slog: field(4),
+            clog: field(5),
+            scert: field(6),
+            tls_secrets: field(7),
+            rand: body[264..288].try_into().unwrap(),
+            signature: body[288..352].try_into().unwrap(),
+        })
+    }
+
+    ///The AUTHENTICATE cell carrying this body
+    pub fn to_command(& self) -> Command {
+        Command::Authenticate {
+            length: (4 + AUTH0003_LENGTH) as u16,
+            auth_type: AUTH_METHOD_ED25519_SHA256_RFC5705,
+            authentication: NLengthVector::from(self.to_bytes()),
+        }
+    }
+
+    ///The body of an AUTHENTICATE cell, which must use this method
+    pub fn from_command(command: &Command) -> Result<Self> {
+        match command {
+            Command::Authenticate { auth_type, authentication, .. } if *auth_type == AUTH_METHOD_ED25519_SHA256_RFC5705 => Self::parse(&authentication.0),
+            _ => Err(ErrorKind::UnexpectedCell(format!("{:?}", command))),
+        }
+    }
+}
@@ -0,0 +1,1532 @@
+//! A minimal in-process relay so that protocol tests can run without touching the live network.
+//!
+//! The relay speaks link protocol 3 and 4 over plain TCP or TLS with a self-signed certificate, answers the
+//! VERSIONS/CERTS/AUTH_CHALLENGE/NETINFO exchange, accepts CREATE_FAST and CREATE2 (ntor) and relay cells encrypted with
+//! `CellCrypto` in relay cell format v0 or v1. Every hop of a circuit is simulated by the same relay, so EXTEND2 simply adds another layer of crypto.
+//! As an exit it only connects to loopback addresses.
+//!
+//! The relay can also play every part of an onion service connection: HSDir for descriptors posted and fetched over
+//! BEGIN_DIR, introduction point and rendezvous point. Connections share an `OnionRegistry` so cells can pass between the
+//! client's and the service's channels.
+//!
+//! Like a bridge, the relay serves its own descriptor over BEGIN_DIR, and it can also be an obfs4 bridge.
+//!
+//! As a conflux exit it links circuits on the same connection that send the same nonce. Each cell of data it relays to
+//! a linked set is split over two legs and the second half is sent first, so clients always have cells to put back in
+//! order.
+
+use std::net::{TcpListener, TcpStream, SocketAddr, IpAddr, Ipv4Addr, Shutdown};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::convert::TryInto;
+use std::time::Duration;
+use std::thread;
+
+use native_tls::{TlsAcceptor, Identity};
+use sha1::{Sha1, Digest};
+use ctr::cipher::{NewCipher, StreamCipher};
+use x25519_dalek::{StaticSecret, PublicKey};
+use ring::rand::SecureRandom;
+use chrono::{Local, Utc};
+use torserde::{TorSerde, NLengthVector, VersionsVector};
+use rsa::Pkcs1v15Sign;
+use rsa::pkcs1::EncodeRsaPublicKey;
+
+use crate::cells::{TorCell, Command, Relay, RelayCell, Encrypted, CellReader, DestroyReason, EndReason, BeginFlags, ConnectedAddress, SendMePayload, CSRNG};
+use crate::cellcrypto::{HopProtocol, RelayCryptoKind};
+use crate::circuit::{CIRCUIT_SENDME_INCREMENT, STREAM_SENDME_INCREMENT, CIRCUIT_WINDOW_START, STREAM_WINDOW_START};
+use crate::circuit_padding::{CIRCPAD_VERSION, CIRCPAD_RESPONSE_OK, CIRCPAD_RESPONSE_ERR};
+use crate::conflux::{ReorderQueue, Sequencer, is_multiplexed};
+use crate::custom_crypto::kdf_tor;
+#[cfg(feature = "legacy-tap")]
+use crate::tap::{self, RsaPublicKey, RsaPrivateKey};
+use crate::hs_descriptor::HsDescriptor;
+use crate::netdoc;
+use crate::obfs4::{self, IatMode, ServerKeys};
+use crate::ntor::{self, NTOR_HANDSHAKE_TYPE};
+use crate::relay_format::{self, RelayCellFormat, Reassembler, V1_DATA_LEN};
+use crate::onion::{Introduce1, INTRODUCE_ACK_SUCCESS};
+use crate::onion_service::{EstablishIntro, DosParams};
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+const SUPPORTED_VERSIONS: [u16; 3] = [3, 4, 5];
+
+///How long a connection waits for a cell before checking for data from its exit streams
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+///How long a test waits for the relay to receive something before giving up
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum MockTransport {
+    Plain,
+    Tls,
+    ///TLS inside obfs4, as an obfs4 bridge speaks. The client's half of obfs4 sets the IAT mode it writes with
+    Obfs4,
+}
+
+struct RelayIdentity {
+    node_id: [u8; 20],
+    onion_secret: StaticSecret,
+    ///The RSA identity key the relay signs its descriptor with. `node_id` is the digest of its public half's DER
+    signing_key: rsa::RsaPrivateKey,
+}
+
+///Status for an INTRODUCE1 naming an authentication key no circuit established
+const INTRODUCE_ACK_UNKNOWN: u16 = 1;
+
+///The modulus of every mock relay's RSA onion key for TAP. Generating RSA keys is slow, so they share a fixed one
+#[cfg(feature = "legacy-tap")]
+const TAP_ONION_KEY_MODULUS: &str = "c26e5f14752b03cd3a40570ab8fd6edb5d16092bdc609f6b4e5106afa6c79d99ba09fcac2719685f02eb819f02ead7fcce0fc3f4f2289392d1837d26ec0b29cd7fe4491b754c8426bf46da8030fbe09fa4fe8890ed95d9c680c3652cddb74b21c9d7c536af3b7636648e3766bcd4941f6b6f2b9f92db2067d3d27d9ddfe85001";
+
+#[cfg(feature = "legacy-tap")]
+const TAP_ONION_KEY_PRIVATE_EXPONENT: &str = "8cd032bc363f78f9617ec78b547ef79297d025c4464f14263b0a23b5832c7ecf9f152c91e19f5d1fb295adc2e3f2018b6e11acf1f18fc1914b7bd7602103d4b964e64b5e578aadf967ea52281c0f08ff0eaf2fcb41b41658fa1b1e4d860eabe8e3a8845b7db9c4961a3789d73d2191a25d2840d5f64fc2fb7b920323ad195801";
+
+///The private half of the TAP onion key every mock relay uses
+#[cfg(feature = "legacy-tap")]
+pub fn tap_onion_key() -> RsaPrivateKey {
+    let parse = |hex: &str| num_bigint::BigUint::parse_bytes(hex.as_bytes(), 16).unwrap();
+
+    RsaPrivateKey {
+        public: RsaPublicKey {
+            modulus: parse(TAP_ONION_KEY_MODULUS),
+            exponent: num_bigint::BigUint::from(65537u32),
+        },
+        private_exponent: parse(TAP_ONION_KEY_PRIVATE_EXPONENT),
+    }
+}
+
+///How long an obfs4 client has to finish its handshake. Clients that can't find our mark wait for us to hang up
+const OBFS4_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+///A circuit on any of the relay's connections
+#[derive(Clone)]
+struct CircuitHandle {
+    connection: u64,
+    circuit_id: u32,
+    sender: Sender<Event>,
+}
+
+impl CircuitHandle {
+    ///Queue an event for the circuit's connection, returning false if the connection has gone
+    fn send(& self, event: impl FnOnce(u32) -> Event) -> bool {
+        self.sender.send(event(self.circuit_id)).is_ok()
+    }
+}
+
+///Onion service state shared between the relay's connections
+#[derive(Default)]
+struct OnionRegistry {
+    ///Intro circuits by the authentication key they were established with
+    intro_points: Mutex<HashMap<[u8; 32], CircuitHandle>>,
+    ///Rendezvous circuits waiting for the service, by cookie
+    rendezvous: Mutex<HashMap<[u8; 20], CircuitHandle>>,
+    ///Descriptors by the base64 blinded key they are fetched with
+    descriptors: Mutex<HashMap<String, String>>,
+    ///The DoS parameters each ESTABLISH_INTRO asked for
+    dos_params: Mutex<Vec<DosParams>>,
+    next_connection: AtomicU64,
+}
+
+///Requests from a test to every connection the relay is serving
+#[derive(Default)]
+struct Controls {
+    disconnect: AtomicBool,
+    destroy: Mutex<Option<DestroyReason>>,
+    ///The highest link protocol version to negotiate, or 0 for any we support
+    max_link_version: AtomicU16,
+    ///PADDING and PADDING_NEGOTIATE cells received over every connection
+    link_padding: Mutex<LinkPaddingReceived>,
+    ///Notified whenever `link_padding` changes
+    link_padding_changed: Condvar,
+    ///RELAY_DROP and circuit PADDING_NEGOTIATE cells received on every circuit
+    circuit_padding: Mutex<CircuitPaddingReceived>,
+    ///Notified whenever `circuit_padding` changes
+    circuit_padding_changed: Condvar,
+    ///Whether to answer PADDING_NEGOTIATE with an error
+    refuse_machines: AtomicBool,
+    ///The lengths of every VPADDING cell received
+    vpadding: Mutex<Vec<usize>>,
+    ///The length of a VPADDING cell for the next connection to look for requests to send
+    send_vpadding: Mutex<Option<u16>>,
+    ///Whether hops created or extended with ntor use relay cell format v1
+    cell_format_v1: AtomicBool,
+    ///DATA cells received from clients on every circuit
+    data: AtomicU64,
+    ///Whether to hold back the SENDMEs owed to clients until this is cleared
+    hold_sendmes: AtomicBool,
+    ///DATA cells clients sent beyond their circuit or stream window
+    window_overruns: AtomicU64,
+    ///How long to wait before answering an EXTEND2
+    extend_delay: Mutex<Duration>,
+}
+
+///The link padding the relay has received
+#[derive(Default)]
+struct LinkPaddingReceived {
+    padding: u64,
+    ///The command, low and high timeouts of every PADDING_NEGOTIATE
+    negotiations: Vec<(u8, u16, u16)>,
+}
+
+///The circuit padding the relay has received
+#[derive(Default)]
+struct CircuitPaddingReceived {
+    drops: u64,
+    ///The command, machine type and counter of every PADDING_NEGOTIATE
+    machines: Vec<(u8, u8, u32)>,
+}
+
+impl Controls {
+    fn hop_protocol(& self) -> HopProtocol {
+        let crypto = RelayCryptoKind::Tor1;
+
+        let format = if self.cell_format_v1.load(Ordering::SeqCst) {
+            RelayCellFormat::V1
+        } else {
+            HopProtocol::from(crypto).format
+        };
+
+        HopProtocol { crypto, format }
+    }
+}
+
+pub struct MockRelay {
+    address: SocketAddr,
+    identity: Arc<RelayIdentity>,
+    controls: Arc<Controls>,
+    onion: Arc<OnionRegistry>,
+    obfs4: Option<Arc<ServerKeys>>,
+}
+
+impl MockRelay {
+    ///Start listening on an ephemeral localhost port. Each connection is served on its own thread
+    pub fn spawn(transport: MockTransport) -> Self {
+        let signing_key = rsa::RsaPrivateKey::new(& mut rand::rngs::OsRng, 1024).unwrap();
+
+        let identity = Arc::new(RelayIdentity {
+            node_id: Sha1::digest(signing_key.to_public_key().to_pkcs1_der().unwrap().as_bytes()).into(),
+            onion_secret: ntor::generate_secret(),
+            signing_key,
+        });
+
+        let obfs4 = match transport {
+            MockTransport::Obfs4 => Some(Arc::new(ServerKeys::generate(identity.node_id))),
+            _ => None,
+        };
+
+        let acceptor = match transport {
+            MockTransport::Plain => None,
+            MockTransport::Tls | MockTransport::Obfs4 => {
+                let certificate = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+
+                let identity = Identity::from_pkcs8(
+                    certificate.serialize_pem().unwrap().as_bytes(),
+                    certificate.serialize_private_key_pem().as_bytes()).unwrap();
+
+                Some(TlsAcceptor::new(identity).unwrap())
+            }
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let thread_identity = identity.clone();
+
+        let controls = Arc::new(Controls::default());
+        let thread_controls = controls.clone();
+
+        let onion = Arc::new(OnionRegistry::default());
+        let thread_onion = onion.clone();
+
+        let thread_obfs4 = obfs4.clone();
+
+        thread::spawn(move || {
+            for tcp in listener.incoming() {
+                let tcp = match tcp {
+                    Ok(tcp) => tcp,
+                    Err(_) => continue,
+                };
+
+                let identity = thread_identity.clone();
+                let acceptor = acceptor.clone();
+                let controls = thread_controls.clone();
+                let onion = thread_onion.clone();
+                let obfs4 = thread_obfs4.clone();
+
+                thread::spawn(move || {
+                    let _ = match (acceptor, obfs4) {
+                        (None, _) => {
+                            tcp.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+                            Connection::new(identity, controls, onion).serve(tcp)
+                        }
+                        (Some(acceptor), Some(keys)) => {
+                            tcp.set_read_timeout(Some(OBFS4_HANDSHAKE_TIMEOUT)).unwrap();
+
+                            let obfs4 = match obfs4::accept(tcp, &keys, IatMode::None) {
+                                Ok(obfs4) => obfs4,
+                                Err(_) => return,
+                            };
+
+                            let tls = match acceptor.accept(obfs4) {
+                                Ok(tls) => tls,
+                                Err(_) => return,
+                            };
+                            tls.get_ref().get_ref().set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+                            Connection::new(identity, controls, onion).serve(tls)
+                        }
+                        (Some(acceptor), None) => {
+                            let tls = match acceptor.accept(tcp) {
+                                Ok(tls) => tls,
+                                Err(_) => return,
+                            };
+                            tls.get_ref().set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+                            Connection::new(identity, controls, onion).serve(tls)
+                        }
+                    };
+                });
+            }
+        });
+
+        Self {
+            address,
+            identity,
+            controls,
+            onion,
+            obfs4,
+        }
+    }
+
+    ///Drop every open connection without sending DESTROY, as if the relay had gone away
+    pub fn disconnect_all(& self) {
+        self.controls.disconnect.store(true, Ordering::SeqCst);
+    }
+
+    ///Send DESTROY with `reason` for every circuit on the next connection to look for requests
+    pub fn destroy_circuits(& self, reason: DestroyReason) {
+        *self.controls.destroy.lock().unwrap() = Some(reason);
+    }
+
+    ///How many PADDING cells the relay has received
+    pub fn padding_received(& self) -> u64 {
+        self.controls.link_padding.lock().unwrap().padding
+    }
+
+    ///The command, low and high timeouts of each PADDING_NEGOTIATE the relay has received
+    pub fn padding_negotiations(& self) -> Vec<(u8, u16, u16)> {
+        self.controls.link_padding.lock().unwrap().negotiations.clone()
+    }
+
+    ///Wait until the relay has received at least `padding` PADDING cells and `negotiations` PADDING_NEGOTIATE cells,
+    ///giving up after a while
+    pub fn wait_for_link_padding(& self, padding: u64, negotiations: usize) {
+        let received = self.controls.link_padding.lock().unwrap();
+
+        let _ = self.controls.link_padding_changed.wait_timeout_while(received, WAIT_TIMEOUT, |received| {
+            received.padding < padding || received.negotiations.len() < negotiations
+        }).unwrap();
+    }
+
+    ///Only negotiate link protocol versions up to `version`, as an older relay would
+    pub fn limit_link_version(& self, version: u16) {
+        self.controls.max_link_version.store(version, Ordering::SeqCst);
+    }
+
+    ///How many RELAY_DROP cells the relay has received
+    pub fn drops_received(& self) -> u64 {
+        self.controls.circuit_padding.lock().unwrap().drops
+    }
+
+    ///The command, machine type and counter of each circuit PADDING_NEGOTIATE the relay has received
+    pub fn padding_machines(& self) -> Vec<(u8, u8, u32)> {
+        self.controls.circuit_padding.lock().unwrap().machines.clone()
+    }
+
+    ///Wait until the relay has received at least `drops` RELAY_DROP cells and `machines` circuit PADDING_NEGOTIATE
+    ///cells, giving up after a while
+    pub fn wait_for_circuit_padding(& self, drops: u64, machines: usize) {
+        let received = self.controls.circuit_padding.lock().unwrap();
+
+        let _ = self.controls.circuit_padding_changed.wait_timeout_while(received, WAIT_TIMEOUT, |received| {
+            received.drops < drops || received.machines.len() < machines
+        }).unwrap();
+    }
+
+    ///Answer circuit PADDING_NEGOTIATE with an error from now on, as a relay without the machine would
+    pub fn refuse_padding_machines(& self) {
+        self.controls.refuse_machines.store(true, Ordering::SeqCst);
+    }
+
+    ///The length of each VPADDING cell the relay has received
+    pub fn vpadding_received(& self) -> Vec<usize> {
+        self.controls.vpadding.lock().unwrap().clone()
+    }
+
+    ///Send a VPADDING cell of `length` bytes on the next connection to look for requests
+    pub fn send_vpadding(& self, length: u16) {
+        *self.controls.send_vpadding.lock().unwrap() = Some(length);
+    }
+
+    ///Lay out cells for hops created or extended with ntor from now on in `format`. Clients ask for it in the ntor v3
+    ///handshake, which the mock doesn't speak, so tests choose it for both ends
+    pub fn use_cell_format(& self, format: RelayCellFormat) {
+        self.controls.cell_format_v1.store(format == RelayCellFormat::V1, Ordering::SeqCst);
+    }
+
+    ///How many DATA cells the relay has received from clients
+    pub fn data_received(& self) -> u64 {
+        self.controls.data.load(Ordering::SeqCst)
+    }
+
+    ///Hold back the SENDMEs owed to clients while `hold` is set, sending them all once it is cleared
+    pub fn hold_sendmes(& self, hold: bool) {
+        self.controls.hold_sendmes.store(hold, Ordering::SeqCst);
+    }
+
+    ///Wait `delay` before answering each EXTEND2, as a slow relay further along the circuit would
+    pub fn delay_extends(& self, delay: Duration) {
+        *self.controls.extend_delay.lock().unwrap() = delay;
+    }
+
+    ///How many DATA cells clients sent beyond their window. Each one destroyed its circuit
+    pub fn window_overruns(& self) -> u64 {
+        self.controls.window_overruns.load(Ordering::SeqCst)
+    }
+
+    pub fn address(& self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn node_id(& self) -> [u8; 20] {
+        self.identity.node_id
+    }
+
+    ///The relay's curve25519 ntor onion key
+    pub fn onion_key(& self) -> [u8; 32] {
+        *PublicKey::from(&self.identity.onion_secret).as_bytes()
+    }
+
+    ///The `cert` argument of the relay's obfs4 bridge line, if it speaks obfs4
+    pub fn obfs4_cert(& self) -> Option<String> {
+        self.obfs4.as_ref().map(|keys| keys.cert())
+    }
+
+    ///The DoS parameters of every intro circuit established so far
+    pub fn intro_dos_params(& self) -> Vec<DosParams> {
+        self.onion.dos_params.lock().unwrap().clone()
+    }
+
+    ///How many onion service descriptors the relay is storing as an HSDir
+    pub fn descriptor_count(& self) -> usize {
+        self.onion.descriptors.lock().unwrap().len()
+    }
+}
+
+///Start a localhost TCP server that echoes back everything it receives, for use as an exit destination
+pub fn spawn_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(mut stream) = stream {
+                thread::spawn(move || {
+                    let mut reader = stream.try_clone().unwrap();
+                    let _ = std::io::copy(& mut reader, & mut stream);
+                });
+            }
+        }
+    });
+
+    address
+}
+
+///The relay's half of the tor1 relay crypto for a single hop, the mirror image of `CellCrypto`
+struct Tor1Layer {
+    forward_digest: Sha1,
+    backward_digest: Sha1,
+    forward_decryptor: Aes128Ctr,
+    backward_encryptor: Aes128Ctr,
+    ///KH from the handshake that created this hop
+    nonce: [u8; 20],
+}
+
+impl Tor1Layer {
+    fn new(key_materials: &[u8], nonce: &[u8]) -> Self {
+        let mut forward_digest = Sha1::new();
+        let mut backward_digest = Sha1::new();
+
+        forward_digest.update(&key_materials[0..20]);
+        backward_digest.update(&key_materials[20..40]);
+
+        let f_key: &[u8; 16] = key_materials[40..56].try_into().unwrap();
+        let b_key: &[u8; 16] = key_materials[56..72].try_into().unwrap();
+
+        let iv = 0u128.to_be_bytes();
+
+        Self {
+            forward_digest,
+            backward_digest,
+            forward_decryptor: Aes128Ctr::new(f_key.into(), iv.as_ref().into()),
+            backward_encryptor: Aes128Ctr::new(b_key.into(), iv.as_ref().into()),
+            nonce: nonce.try_into().unwrap(),
+        }
+    }
+
+    ///Remove this hop's layer of encryption from a cell travelling away from the client
+    fn peel(& mut self, payload: & mut [u8; 509]) {
+        self.forward_decryptor.apply_keystream(payload.as_mut());
+    }
+
+    ///Check whether a peeled cell is addressed to this hop. The running digest is only updated if it is
+    fn recognise(& mut self, payload: &[u8; 509]) -> bool {
+        if payload[1] != 0 || payload[2] != 0 {
+            return false;
+        }
+
+        let mut zeroed = *payload;
+
+        zeroed[5..9].copy_from_slice(&[0u8; 4]);
+
+        let mut digest = self.forward_digest.clone();
+
+        digest.update(zeroed.as_ref());
+
+        if digest.clone().finalize()[0..4] == payload[5..9] {
+            self.forward_digest = digest;
+            true
+        } else {
+            false
+        }
+    }
+
+    ///Set the digest of a cell originating at this hop then encrypt it
+    fn originate(& mut self, payload: & mut [u8; 509]) {
+        payload[5..9].copy_from_slice(&[0u8; 4]);
+
+        self.backward_digest.update(payload.as_ref());
+
+        let digest = self.backward_digest.clone().finalize();
+
+        payload[5..9].copy_from_slice(&digest[0..4]);
+
+        self.wrap(payload);
+    }
+
+    ///Add this hop's layer of encryption to a cell travelling towards the client
+    fn wrap(& mut self, payload: & mut [u8; 509]) {
+        self.backward_encryptor.apply_keystream(payload.as_mut());
+    }
+}
+
+///One hop of a circuit as the relay sees it: its crypto, its relay cell format and any message only partly received
+struct RelayLayer {
+    crypto: Tor1Layer,
+    format: RelayCellFormat,
+    reassembler: Reassembler,
+}
+
+impl RelayLayer {
+    fn new(key_materials: &[u8], nonce: &[u8]) -> Self {
+        Self::with_crypto(Tor1Layer::new(key_materials, nonce), RelayCellFormat::V0)
+    }
+
+    ///A hop from ntor key material, using the relay crypto and cell format the client chose for it
+    fn ntor(protocol: HopProtocol, materials: &[u8]) -> Self {
+        let crypto = match protocol.crypto {
+            RelayCryptoKind::Tor1 => Tor1Layer::new(&materials[0..72], &materials[72..92]),
+        };
+
+        Self::with_crypto(crypto, protocol.format)
+    }
+
+    fn with_crypto(crypto: Tor1Layer, format: RelayCellFormat) -> Self {
+        Self {
+            crypto,
+            format,
+            reassembler: Reassembler::new(),
+        }
+    }
+
+    fn nonce(& self) -> [u8; 20] {
+        self.crypto.nonce
+    }
+
+    fn max_data_len(& self) -> usize {
+        match self.format {
+            RelayCellFormat::V1 => V1_DATA_LEN,
+            RelayCellFormat::V0 => 498,
+        }
+    }
+
+    fn peel(& mut self, payload: & mut [u8; 509]) {
+        self.crypto.peel(payload);
+    }
+
+    fn recognise(& mut self, payload: &[u8; 509]) -> bool {
+        self.crypto.recognise(payload)
+    }
+
+    ///Read the relay messages a cell this hop recognised completes
+    fn open(& mut self, payload: &[u8; 509]) -> torserde::Result<Vec<RelayCell>> {
+        match self.format {
+            RelayCellFormat::V1 => self.reassembler.unpack(payload),
+            RelayCellFormat::V0 => Ok(vec![RelayCell::bin_deserialise_from(payload.as_ref())?]),
+        }
+    }
+
+    ///Lay out relay messages originating at this hop in as many cells as the format needs, then mark and encrypt them
+    fn originate(& mut self, relays: Vec<RelayCell>) -> torserde::Result<Vec<[u8; 509]>> {
+        let mut payloads = match self.format {
+            RelayCellFormat::V1 => relay_format::pack(relays)?,
+            RelayCellFormat::V0 => {
+                let mut payloads = Vec::new();
+
+                for relay in relays {
+                    let mut payload = [0u8; 509];
+
+                    relay.bin_serialise_into(payload.as_mut())?;
+
+                    payloads.push(payload);
+                }
+
+                payloads
+            },
+        };
+
+        for payload in payloads.iter_mut() {
+            self.crypto.originate(payload);
+        }
+
+        Ok(payloads)
+    }
+
+    fn wrap(& mut self, payload: & mut [u8; 509]) {
+        self.crypto.wrap(payload);
+    }
+}
+
+///Work for a connection from its exit streams or from other connections
+enum Event {
+    Data(u32, u16, Vec<u8>),
+    Closed(u32, u16),
+    ///Send a cell to the client as though it came from the last hop of the circuit
+    Relay(u32, RelayCell),
+    ///Pass cells the circuit's hops don't recognise on to another circuit
+    Splice(u32, CircuitHandle),
+    ///A cell from a spliced circuit, to wrap in every layer of this one
+    Forward(u32, Box<[u8; 509]>),
+}
+
+///A request arriving on a BEGIN_DIR stream
+struct DirStream {
+    hop: usize,
+    request: Vec<u8>,
+}
+
+struct ExitStream {
+    hop: usize,
+    target: TcpStream,
+}
+
+///A conflux set linked over this connection
+struct MockConflux {
+    ///The circuits of the set's legs. Streams are kept under the first
+    legs: Vec<u32>,
+    sequencer: Sequencer,
+    queue: ReorderQueue<RelayCell>,
+    ///The leg the next cell to the client goes on
+    next_leg: usize,
+}
+
+impl MockConflux {
+    fn new() -> Self {
+        Self {
+            legs: Vec::new(),
+            sequencer: Sequencer::new(0),
+            queue: ReorderQueue::new(),
+            next_leg: 0,
+        }
+    }
+}
+
+///The DATA cells a client may still send on a circuit hop or a stream, and how many it has sent
+struct DeliverWindow {
+    window: u32,
+    received: u32,
+}
+
+impl DeliverWindow {
+    fn new(window: u32) -> Self {
+        Self {
+            window,
+            received: 0,
+        }
+    }
+
+    ///Count a DATA cell, returning `false` if the window was already closed
+    fn receive(& mut self) -> bool {
+        if self.window == 0 {
+            return false;
+        }
+
+        self.window -= 1;
+        self.received += 1;
+
+        true
+    }
+}
+
+struct Connection {
+    connection: u64,
+    identity: Arc<RelayIdentity>,
+    controls: Arc<Controls>,
+    version: u32,
+    circuits: HashMap<u32, Vec<RelayLayer>>,
+    exits: HashMap<(u32, u16), ExitStream>,
+    dir_streams: HashMap<(u32, u16), DirStream>,
+    splices: HashMap<u32, CircuitHandle>,
+    ///Linked conflux sets by nonce, and the nonce of each leg's set
+    conflux: HashMap<[u8; 32], MockConflux>,
+    conflux_legs: HashMap<u32, [u8; 32]>,
+    ///Deliver windows for each circuit hop, and for each stream by the circuit its exit is attached to
+    circuit_windows: HashMap<(u32, usize), DeliverWindow>,
+    stream_windows: HashMap<(u32, u16), DeliverWindow>,
+    ///The circuit, hop and stream (0 for the circuit itself) of each SENDME held back by `Controls::hold_sendmes`
+    held_sendmes: Vec<(u32, usize, u16)>,
+    onion: Arc<OnionRegistry>,
+    event_sender: Sender<Event>,
+    event_receiver: Receiver<Event>,
+}
+
+impl Connection {
+    fn new(identity: Arc<RelayIdentity>, controls: Arc<Controls>, onion: Arc<OnionRegistry>) -> Self {
+        let (event_sender, event_receiver) = channel();
+
+        Self {
+            connection: onion.next_connection.fetch_add(1, Ordering::SeqCst),
+            identity,
+            controls,
+            version: 3,
+            circuits: HashMap::new(),
+            exits: HashMap::new(),
+            dir_streams: HashMap::new(),
+            splices: HashMap::new(),
+            conflux: HashMap::new(),
+            conflux_legs: HashMap::new(),
+            circuit_windows: HashMap::new(),
+            stream_windows: HashMap::new(),
+            held_sendmes: Vec::new(),
+            onion,
+            event_sender,
+            event_receiver,
+        }
+    }
+
+    fn serve<S: Read + Write>(mut self, mut stream: S) -> torserde::Result<()> {
+        let mut reader = CellReader::new();
+
+        let versions = loop {
+            if let Some(cell) = reader.poll(& mut stream, 3)? {
+                break cell;
+            }
+        };
+
+        let max_version = match self.controls.max_link_version.load(Ordering::SeqCst) {
+            0 => u16::MAX,
+            max_version => max_version,
+        };
+
+        let supported: Vec<u16> = SUPPORTED_VERSIONS.iter().copied().filter(|v| *v <= max_version).collect();
+
+        let version = match versions.get_command() {
+            Command::Versions { version_list } => version_list.0.iter().filter(|v| supported.contains(v)).max().copied(),
+            _ => None,
+        };
+
+        let version = match version {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+
+        TorCell::new(0, Command::Versions { version_list: VersionsVector::from(supported) }).into_stream(& mut stream, 3)?;
+
+        self.version = version as u32;
+
+        self.send(& mut stream, TorCell::new(0, Command::Certs { length: 1, certs: NLengthVector::from(vec![]) }))?;
+
+        let mut challenge = [0u8; 32];
+
+        CSRNG.fill(& mut challenge).unwrap();
+
+        self.send(& mut stream, TorCell::new(0, Command::AuthChallenge { length: 38, challenge, methods: NLengthVector::from(vec![1, 3]) }))?;
+
+        self.send(& mut stream, TorCell::new(0, Command::NetInfo {
+            timestamp: Local::now(),
+            other_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            this_ips: NLengthVector::from(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]) }))?;
+
+        loop {
+            if self.controls.disconnect.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            let destroy = self.controls.destroy.lock().unwrap().take();
+
+            if let Some(reason) = destroy {
+                let circuits: Vec<u32> = self.circuits.keys().copied().collect();
+
+                for circuit_id in circuits {
+                    self.destroy_circuit(circuit_id);
+                    self.send(& mut stream, TorCell::new(circuit_id, Command::Destroy { reason }))?;
+                }
+            }
+
+            let vpadding = self.controls.send_vpadding.lock().unwrap().take();
+
+            if let Some(length) = vpadding {
+                self.send(& mut stream, TorCell::new(0, Command::VPadding { padding: NLengthVector::from(vec![0u8; length as usize]) }))?;
+            }
+
+            if !self.controls.hold_sendmes.load(Ordering::SeqCst) {
+                for (circuit_id, hop, stream_id) in std::mem::take(& mut self.held_sendmes) {
+                    self.send_sendme(& mut stream, circuit_id, hop, stream_id)?;
+                }
+            }
+
+            match reader.poll(& mut stream, self.version) {
+                Ok(Some(cell)) => self.handle_cell(& mut stream, cell)?,
+                Ok(None) => {},
+                Err(torserde::ErrorKind::DiscardedCell(_)) => {},
+                Err(e) => return Err(e),
+            }
+
+            while let Ok(event) = self.event_receiver.try_recv() {
+                match event {
+                    Event::Data(circuit_id, stream_id, data) => {
+                        let nonce = self.conflux_legs.get(&circuit_id).copied();
+
+                        if let Some(exit) = self.exits.get(&(circuit_id, stream_id)) {
+                            let hop = exit.hop;
+
+                            match nonce {
+                                Some(nonce) if data.len() > 1 => {
+                                    let (first, second) = data.split_at(data.len() / 2);
+
+                                    self.send_conflux(& mut stream, nonce, vec![RelayCell::new_data(stream_id, first), RelayCell::new_data(stream_id, second)])?;
+                                },
+                                _ => self.send_data(& mut stream, circuit_id, hop, stream_id, &data)?,
+                            }
+                        }
+                    },
+                    Event::Closed(circuit_id, stream_id) => {
+                        if let Some(exit) = self.exits.remove(&(circuit_id, stream_id)) {
+                            self.send_relay(& mut stream, circuit_id, exit.hop, RelayCell::new(stream_id, Relay::End { end_reason: EndReason::Done }))?;
+                        }
+                    },
+                    Event::Relay(circuit_id, relay) => {
+                        if let Some(last) = self.circuits.get(&circuit_id).map(|hops| hops.len() - 1) {
+                            self.send_relay(& mut stream, circuit_id, last, relay)?;
+                        }
+                    },
+                    Event::Splice(circuit_id, other) => {
+                        self.splices.insert(circuit_id, other);
+                    },
+                    Event::Forward(circuit_id, mut payload) => {
+                        if let Some(hops) = self.circuits.get_mut(&circuit_id) {
+                            for layer in hops.iter_mut().rev() {
+                                layer.wrap(& mut payload);
+                            }
+
+                            self.send(& mut stream, TorCell::new(circuit_id, Command::Relay { contents: Encrypted(*payload) }))?;
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    fn handle(& self, circuit_id: u32) -> CircuitHandle {
+        CircuitHandle {
+            connection: self.connection,
+            circuit_id,
+            sender: self.event_sender.clone(),
+        }
+    }
+
+    fn send<S: Write>(& self, stream: S, cell: TorCell) -> torserde::Result<()> {
+        cell.into_stream(stream, self.version)
+    }
+
+    ///Send a relay cell to the client as though it originated at hop number `hop` of the circuit. Stream cells for a
+    ///conflux leg are numbered across its set instead
+    fn send_relay<S: Write>(& mut self, stream: S, circuit_id: u32, hop: usize, relay: RelayCell) -> torserde::Result<()> {
+        if is_multiplexed(relay.get_command()) {
+            if let Some(nonce) = self.conflux_legs.get(&circuit_id).copied() {
+                return self.send_conflux(stream, nonce, vec![relay]);
+            }
+        }
+
+        self.originate(stream, circuit_id, hop, vec![relay])
+    }
+
+    ///Number stream cells for a conflux set, putting each on the next leg in turn. The cells are written a leg at a
+    ///time starting from the last leg used, so the client receives later numbers first
+    fn send_conflux<S: Write>(& mut self, mut stream: S, nonce: [u8; 32], cells: Vec<RelayCell>) -> torserde::Result<()> {
+        let set = match self.conflux.get_mut(&nonce) {
+            Some(set) => set,
+            None => return Ok(()),
+        };
+
+        let mut batches = Vec::new();
+
+        for relay in cells {
+            let leg = set.next_leg;
+
+            set.next_leg = (leg + 1) % set.legs.len();
+
+            let mut batch = Vec::new();
+
+            if let Some(relative_seq) = set.sequencer.send(leg) {
+                batch.push(RelayCell::new(0, Relay::ConfluxSwitch { relative_seq }));
+            }
+
+            batch.push(relay);
+            batches.push((set.legs[leg], batch));
+        }
+
+        for (circuit_id, batch) in batches.into_iter().rev() {
+            let last = match self.circuits.get(&circuit_id) {
+                Some(hops) => hops.len() - 1,
+                None => continue,
+            };
+
+            self.originate(& mut stream, circuit_id, last, batch)?;
+        }
+
+        Ok(())
+    }
+
+    ///Send `data` in as many DATA cells as the hop's relay crypto and cell format need
+    fn send_data<S: Write>(& mut self, mut stream: S, circuit_id: u32, hop: usize, stream_id: u16, data: &[u8]) -> torserde::Result<()> {
+        let length = match self.circuits.get(&circuit_id) {
+            Some(hops) => hops[hop].max_data_len(),
+            None => return Ok(()),
+        };
+
+        if self.conflux_legs.contains_key(&circuit_id) {
+            for chunk in data.chunks(length) {
+                self.send_relay(& mut stream, circuit_id, hop, RelayCell::new_data(stream_id, chunk))?;
+            }
+
+            return Ok(());
+        }
+
+        let cells = data.chunks(length).map(|chunk| RelayCell::new_data(stream_id, chunk)).collect();
+
+        self.originate(stream, circuit_id, hop, cells)
+    }
+
+    ///Send relay cells to the client from hop number `hop`, packed together if the hop's cell format allows
+    fn originate<S: Write>(& mut self, mut stream: S, circuit_id: u32, hop: usize, relays: Vec<RelayCell>) -> torserde::Result<()> {
+        let hops = match self.circuits.get_mut(&circuit_id) {
+            Some(hops) => hops,
+            None => return Ok(()),
+        };
+
+        let mut payloads = hops[hop].originate(relays)?;
+
+        for payload in payloads.iter_mut() {
+            for layer in hops[..hop].iter_mut().rev() {
+                layer.wrap(payload);
+            }
+        }
+
+        for payload in payloads {
+            self.send(& mut stream, TorCell::new(circuit_id, Command::Relay { contents: Encrypted(payload) }))?;
+        }
+
+        Ok(())
+    }
+
+    ///Count a DATA cell from the client against the window of circuit hop `hop`, or of the stream if `stream_id` isn't 0,
+    ///owing the client a SENDME every increment. `false` if the client overran the window
+    fn deliver<S: Write>(& mut self, stream: S, circuit_id: u32, hop: usize, stream_id: u16) -> torserde::Result<bool> {
+        let (window, increment) = match stream_id {
+            0 => (self.circuit_windows.entry((circuit_id, hop)).or_insert_with(|| DeliverWindow::new(CIRCUIT_WINDOW_START)), CIRCUIT_SENDME_INCREMENT),
+            _ => (self.stream_windows.entry((circuit_id, stream_id)).or_insert_with(|| DeliverWindow::new(STREAM_WINDOW_START)), STREAM_SENDME_INCREMENT),
+        };
+
+        if !window.receive() {
+            self.controls.window_overruns.fetch_add(1, Ordering::SeqCst);
+
+            return Ok(false);
+        }
+
+        if window.received % increment != 0 {
+            return Ok(true);
+        }
+
+        if self.controls.hold_sendmes.load(Ordering::SeqCst) {
+            self.held_sendmes.push((circuit_id, hop, stream_id));
+        } else {
+            self.send_sendme(stream, circuit_id, hop, stream_id)?;
+        }
+
+        Ok(true)
+    }
+
+    ///Send the client a circuit-level SENDME from `hop`, or a stream-level one if `stream_id` isn't 0, opening the window
+    fn send_sendme<S: Write>(& mut self, stream: S, circuit_id: u32, hop: usize, stream_id: u16) -> torserde::Result<()> {
+        let (window, increment) = match stream_id {
+            0 => (self.circuit_windows.get_mut(&(circuit_id, hop)), CIRCUIT_SENDME_INCREMENT),
+            _ => (self.stream_windows.get_mut(&(circuit_id, stream_id)), STREAM_SENDME_INCREMENT),
+        };
+
+        let window = match window {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+
+        window.window += increment;
+
+        let sendme = match stream_id {
+            0 => RelayCell::new(0, Relay::SendMe { payload: SendMePayload::Ignore }),
+            _ => RelayCell::new_raw(5, stream_id, vec![]),
+        };
+
+        self.originate(stream, circuit_id, hop, vec![sendme])
+    }
+
+    fn destroy_circuit(& mut self, circuit_id: u32) {
+        self.circuits.remove(&circuit_id);
+        self.circuit_windows.retain(|(circuit, _), _| *circuit != circuit_id);
+        self.stream_windows.retain(|(circuit, _), _| *circuit != circuit_id);
+        self.splices.remove(&circuit_id);
+        self.conflux_legs.remove(&circuit_id);
+        self.dir_streams.retain(|(circuit, _), _| *circuit != circuit_id);
+        self.onion.intro_points.lock().unwrap().retain(|_, handle| handle.connection != self.connection || handle.circuit_id != circuit_id);
+
+        let streams: Vec<_> = self.exits.keys().filter(|(circuit, _)| *circuit == circuit_id).copied().collect();
+
+        for key in streams {
+            if let Some(exit) = self.exits.remove(&key) {
+                let _ = exit.target.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    fn handle_cell<S: Write>(& mut self, stream: S, cell: TorCell) -> torserde::Result<()> {
+        let circuit_id = cell.get_circuit_id();
+
+        match cell.into_command() {
+            Command::CreateFast { onion_skin } => {
+                let mut y = [0u8; 20];
+
+                CSRNG.fill(& mut y).unwrap();
+
+                let mut shared_secret = Vec::from(onion_skin);
+
+                shared_secret.extend_from_slice(&y);
+
+                let materials = kdf_tor(&shared_secret);
+
+                let mut handshake_data = [0u8; 40];
+
+                handshake_data[0..20].copy_from_slice(&y);
+                handshake_data[20..40].copy_from_slice(&materials[0..20]);
+
+                self.circuits.insert(circuit_id, vec![RelayLayer::new(&materials[20..92], &materials[0..20])]);
+
+                self.send(stream, TorCell::new(circuit_id, Command::CreatedFast { handshake_data }))
+            },
+            #[cfg(feature = "legacy-tap")]
+            Command::Create { onion_skin } => {
+                match tap::server_handshake(&tap_onion_key(), &onion_skin) {
+                    Some((handshake_data, materials)) => {
+                        self.circuits.insert(circuit_id, vec![RelayLayer::new(&materials[20..92], &materials[0..20])]);
+
+                        self.send(stream, TorCell::new(circuit_id, Command::Created { handshake_data }))
+                    },
+                    None => self.send(stream, TorCell::new(circuit_id, Command::Destroy { reason: DestroyReason::Protocol })),
+                }
+            },
+            Command::Create2 { handshake_type, onion_skin } => {
+                let protocol = self.controls.hop_protocol();
+
+                let reply = if handshake_type == NTOR_HANDSHAKE_TYPE {
+                    ntor::server_handshake(&self.identity.node_id, &self.identity.onion_secret, &onion_skin.0, protocol.crypto.key_material_length())
+                } else {
+                    None
+                };
+
+                match reply {
+                    Some((handshake_data, materials)) => {
+                        self.circuits.insert(circuit_id, vec![RelayLayer::ntor(protocol, &materials)]);
+
+                        self.send(stream, TorCell::new(circuit_id, Command::Created2 { handshake_data: NLengthVector::from(handshake_data) }))
+                    },
+                    None => self.send(stream, TorCell::new(circuit_id, Command::Destroy { reason: DestroyReason::Protocol })),
+                }
+            },
+            Command::Relay { contents } | Command::RelayEarly { contents } => {
+                self.handle_relay(stream, circuit_id, contents.0)
+            },
+            Command::Destroy { reason: _ } => {
+                self.destroy_circuit(circuit_id);
+                Ok(())
+            },
+            Command::VPadding { padding } => {
+                self.controls.vpadding.lock().unwrap().push(padding.0.len());
+                Ok(())
+            },
+            Command::Padding => {
+                self.controls.link_padding.lock().unwrap().padding += 1;
+                self.controls.link_padding_changed.notify_all();
+                Ok(())
+            },
+            Command::PaddingNegotiate { version: _, command, ito_low_ms, ito_high_ms } => {
+                self.controls.link_padding.lock().unwrap().negotiations.push((command, ito_low_ms, ito_high_ms));
+                self.controls.link_padding_changed.notify_all();
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_relay<S: Write>(& mut self, stream: S, circuit_id: u32, mut payload: [u8; 509]) -> torserde::Result<()> {
+        let (hop, hop_count) = {
+            let hops = match self.circuits.get_mut(&circuit_id) {
+                Some(hops) => hops,
+                None => return Ok(()),
+            };
+
+            let mut recognised_at = None;
+
+            for (index, layer) in hops.iter_mut().enumerate() {
+                layer.peel(& mut payload);
+
+                if layer.recognise(&payload) {
+                    recognised_at = Some(index);
+                    break;
+                }
+            }
+
+            (recognised_at, hops.len())
+        };
+
+        let hop = match hop {
+            Some(hop) => hop,
+            None if self.splices.contains_key(&circuit_id) => {
+                self.splices[&circuit_id].send(|other| Event::Forward(other, Box::new(payload)));
+
+                return Ok(());
+            },
+            None => {
+                self.destroy_circuit(circuit_id);
+                return self.send(stream, TorCell::new(circuit_id, Command::Destroy { reason: DestroyReason::Protocol }));
+            }
+        };
+
+        let relays = match self.circuits.get_mut(&circuit_id) {
+            Some(hops) => hops[hop].open(&payload)?,
+            None => return Ok(()),
+        };
+
+        let mut stream = stream;
+
+        for relay in relays {
+            if relay.get_command() == 2 {
+                self.controls.data.fetch_add(1, Ordering::SeqCst);
+
+                if !self.deliver(& mut stream, circuit_id, hop, 0)? {
+                    self.destroy_circuit(circuit_id);
+                    return self.send(stream, TorCell::new(circuit_id, Command::Destroy { reason: DestroyReason::Protocol }));
+                }
+            }
+
+            if hop + 1 == hop_count && (is_multiplexed(relay.get_command()) || relay.get_command() == 22) {
+                if let Some(nonce) = self.conflux_legs.get(&circuit_id).copied() {
+                    self.conflux_received(& mut stream, nonce, circuit_id, relay)?;
+
+                    continue;
+                }
+            }
+
+            self.handle_relay_cell(& mut stream, circuit_id, hop, hop_count, relay)?;
+        }
+
+        Ok(())
+    }
+
+    ///Number a stream cell that arrived on a conflux leg, handling every cell now in order as though it came on the first leg
+    fn conflux_received<S: Write>(& mut self, mut stream: S, nonce: [u8; 32], circuit_id: u32, relay: RelayCell) -> torserde::Result<()> {
+        let set = match self.conflux.get_mut(&nonce) {
+            Some(set) => set,
+            None => return Ok(()),
+        };
+
+        let leg = match set.legs.iter().position(|leg| *leg == circuit_id) {
+            Some(leg) => leg,
+            None => return Ok(()),
+        };
+
+        if relay.get_command() == 22 {
+            if let Some(Relay::ConfluxSwitch { relative_seq }) = relay.get_payload()? {
+                set.sequencer.switch(leg, relative_seq);
+            }
+
+            return Ok(());
+        }
+
+        let seq = set.sequencer.receive(leg);
+
+        set.queue.push(seq, relay);
+
+        let primary = set.legs[0];
+
+        let mut ready = Vec::new();
+
+        while let Some(relay) = set.queue.pop() {
+            ready.push(relay);
+        }
+
+        let hop_count = match self.circuits.get(&primary) {
+            Some(hops) => hops.len(),
+            None => return Ok(()),
+        };
+
+        for relay in ready {
+            self.handle_relay_cell(& mut stream, primary, hop_count - 1, hop_count, relay)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_relay_cell<S: Write>(& mut self, mut stream: S, circuit_id: u32, hop: usize, hop_count: usize, relay: RelayCell) -> torserde::Result<()> {
+        let stream_id = relay.get_stream_id();
+
+        match relay.get_command() {
+            1 => {
+                let (target, flags) = match relay.get_payload()? {
+                    Some(Relay::Begin { addr_and_port, flags }) => (addr_and_port, flags),
+                    _ => return Ok(()),
+                };
+
+                if hop + 1 != hop_count {
+                    return self.send_relay(stream, circuit_id, hop, RelayCell::new(stream_id, Relay::End { end_reason: EndReason::NotDirectory }));
+                }
+
+                self.begin(stream, circuit_id, hop, stream_id, &target, flags)
+            },
+            2 => {
+                if !self.deliver(& mut stream, circuit_id, hop, stream_id)? {
+                    self.destroy_circuit(circuit_id);
+                    return self.send(stream, TorCell::new(circuit_id, Command::Destroy { reason: DestroyReason::Protocol }));
+                }
+
+                if let Some(exit) = self.exits.get_mut(&(circuit_id, stream_id)) {
+                    let _ = exit.target.write_all(&relay.into_data());
+                } else if let Some(dir) = self.dir_streams.get_mut(&(circuit_id, stream_id)) {
+                    dir.request.extend_from_slice(&relay.into_data());
+
+                    return self.answer_directory(stream, circuit_id, stream_id);
+                }
+
+                Ok(())
+            },
+            3 => {
+                if let Some(exit) = self.exits.remove(&(circuit_id, stream_id)) {
+                    let _ = exit.target.shutdown(Shutdown::Both);
+                }
+
+                Ok(())
+            },
+            10 => {
+                self.controls.circuit_padding.lock().unwrap().drops += 1;
+                self.controls.circuit_padding_changed.notify_all();
+
+                Ok(())
+            },
+            41 => {
+                let (command, machine_type, machine_ctr) = match relay.get_payload()? {
+                    Some(Relay::PaddingNegotiate { version: _, command, machine_type, echo_request: _, machine_ctr }) => (command, machine_type, machine_ctr),
+                    _ => return Ok(()),
+                };
+
+                self.controls.circuit_padding.lock().unwrap().machines.push((command, machine_type, machine_ctr));
+                self.controls.circuit_padding_changed.notify_all();
+
+                let response = if self.controls.refuse_machines.load(Ordering::SeqCst) {
+                    CIRCPAD_RESPONSE_ERR
+                } else {
+                    CIRCPAD_RESPONSE_OK
+                };
+
+                self.send_relay(stream, circuit_id, hop, RelayCell::new(0, Relay::PaddingNegotiated { version: CIRCPAD_VERSION, command, response, machine_type, machine_ctr }))
+            },
+            8 => {
+                if let Some(hops) = self.circuits.get_mut(&circuit_id) {
+                    hops.truncate(hop + 1);
+                }
+
+                let cut: Vec<_> = self.exits.iter().filter(|((circuit, _), exit)| *circuit == circuit_id && exit.hop > hop).map(|(key, _)| *key).collect();
+
+                for key in cut {
+                    if let Some(exit) = self.exits.remove(&key) {
+                        let _ = exit.target.shutdown(Shutdown::Both);
+                    }
+                }
+
+                self.send_relay(stream, circuit_id, hop, RelayCell::new(0, Relay::Truncated { reason: DestroyReason::Requested }))
+            },
+            19 => {
+                if hop + 1 != hop_count {
+                    return Ok(());
+                }
+
+                if let Some(Relay::ConfluxLink { version, nonce, desired_ux, .. }) = relay.get_payload()? {
+                    let set = self.conflux.entry(nonce).or_insert_with(MockConflux::new);
+
+                    set.legs.push(circuit_id);
+                    set.sequencer.add_leg();
+
+                    self.conflux_legs.insert(circuit_id, nonce);
+
+                    return self.send_relay(stream, circuit_id, hop, RelayCell::new(0, Relay::ConfluxLinked { version, nonce, last_seqno_sent: 0, last_seqno_recv: 0, desired_ux }));
+                }
+
+                Ok(())
+            },
+            13 => {
+                self.dir_streams.insert((circuit_id, stream_id), DirStream { hop, request: Vec::new() });
+
+                self.send_relay(stream, circuit_id, hop, RelayCell::new(stream_id, Relay::Connected { address: ConnectedAddress::None }))
+            },
+            14 => {
+                let protocol = self.controls.hop_protocol();
+
+                let delay = *self.controls.extend_delay.lock().unwrap();
+
+                thread::sleep(delay);
+
+                let reply = match relay.get_payload()? {
+                    Some(Relay::Extend2 { link_specifiers: _, htype, handshake_data }) if htype == NTOR_HANDSHAKE_TYPE => {
+                        ntor::server_handshake(&self.identity.node_id, &self.identity.onion_secret, &handshake_data.0, protocol.crypto.key_material_length())
+                    },
+                    _ => None,
+                };
+
+                match reply {
+                    Some((handshake_data, materials)) => {
+                        self.send_relay(stream, circuit_id, hop, RelayCell::new(0, Relay::Extended2 { handshake_data: NLengthVector::from(handshake_data) }))?;
+
+                        if let Some(hops) = self.circuits.get_mut(&circuit_id) {
+                            hops.push(RelayLayer::ntor(protocol, &materials));
+                        }
+
+                        Ok(())
+                    },
+                    None => self.send_relay(stream, circuit_id, hop, RelayCell::new(0, Relay::Truncated { reason: DestroyReason::ConnectFailed })),
+                }
+            },
+            32 => {
+                let nonce = self.circuits[&circuit_id][hop].nonce();
+
+                match EstablishIntro::parse(&relay.into_data()) {
+                    Ok(establish) if establish.verify(&nonce) => {
+                        if let Some(params) = establish.dos_params() {
+                            self.onion.dos_params.lock().unwrap().push(params);
+                        }
+
+                        self.onion.intro_points.lock().unwrap().insert(establish.auth_key, self.handle(circuit_id));
+
+                        self.send_relay(stream, circuit_id, hop, RelayCell::new(0, Relay::IntroEstablished { extensions: NLengthVector::from(vec![]) }))
+                    },
+                    _ => {
+                        self.destroy_circuit(circuit_id);
+                        self.send(stream, TorCell::new(circuit_id, Command::Destroy { reason: DestroyReason::Protocol }))
+                    },
+                }
+            },
+            33 => {
+                if let Some(Relay::EstablishRendezvous { rendezvous_cookie }) = relay.get_payload()? {
+                    self.onion.rendezvous.lock().unwrap().insert(rendezvous_cookie, self.handle(circuit_id));
+                }
+
+                self.send_relay(stream, circuit_id, hop, RelayCell::new_raw(39, 0, vec![]))
+            },
+            34 => {
+                let body = relay.into_data();
+
+                let service = Introduce1::parse(&body).ok().and_then(|introduce| self.onion.intro_points.lock().unwrap().get(&introduce.auth_key).cloned());
+
+                let relayed = match service {
+                    Some(service) => service.send(|intro_circuit| Event::Relay(intro_circuit, RelayCell::new_raw(35, 0, body))),
+                    None => false,
+                };
+
+                let status = if relayed {
+                    INTRODUCE_ACK_SUCCESS
+                } else {
+                    INTRODUCE_ACK_UNKNOWN
+                };
+
+                self.send_relay(stream, circuit_id, hop, RelayCell::new(0, Relay::IntroduceAck { status, extensions: NLengthVector::from(vec![]) }))
+            },
+            36 => {
+                if let Some(Relay::Rendezvous1 { rendezvous_cookie, handshake_info }) = relay.get_payload()? {
+                    let client = self.onion.rendezvous.lock().unwrap().remove(&rendezvous_cookie);
+
+                    if let Some(client) = client {
+                        let service = self.handle(circuit_id);
+
+                        client.send(|client_circuit| Event::Relay(client_circuit, RelayCell::new(0, Relay::Rendezvous2 { handshake_info })));
+                        client.send(|client_circuit| Event::Splice(client_circuit, service));
+
+                        self.splices.insert(circuit_id, client);
+                    }
+                }
+
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    ///Answer the request on a BEGIN_DIR stream once all of it has arrived. Only onion service descriptors and the relay's
+    ///own descriptor are served
+    fn answer_directory<S: Write>(& mut self, stream: S, circuit_id: u32, stream_id: u16) -> torserde::Result<()> {
+        let dir = &self.dir_streams[&(circuit_id, stream_id)];
+
+        let split = match dir.request.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+
+        let head = String::from_utf8_lossy(&dir.request[..split]).to_string();
+
+        let length = head.lines()
+            .filter_map(|line| line.strip_prefix("Content-Length: "))
+            .next()
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+
+        if dir.request.len() < split + 4 + length {
+            return Ok(());
+        }
+
+        let hop = dir.hop;
+        let body = String::from_utf8_lossy(&dir.request[split + 4..split + 4 + length]).to_string();
+
+        self.dir_streams.remove(&(circuit_id, stream_id));
+
+        let mut words = head.split_whitespace();
+
+        let response = match (words.next(), words.next()) {
+            (Some("GET"), Some("/tor/server/authority")) => format!("HTTP/1.0 200 OK\r\n\r\n{}", self.server_descriptor()),
+            (Some("GET"), Some(path)) => match path.strip_prefix("/tor/hs/3/").and_then(|key| self.onion.descriptors.lock().unwrap().get(key).cloned()) {
+                Some(descriptor) => format!("HTTP/1.0 200 OK\r\n\r\n{}", descriptor),
+                None => String::from("HTTP/1.0 404 Not found\r\n\r\n"),
+            },
+            (Some("POST"), Some("/tor/hs/3/publish")) => {
+                let blinded_key = HsDescriptor::parse(&body).ok()
+                    .and_then(|descriptor| descriptor.signing_key_cert.signing_key.filter(|key| descriptor.verify(key, Utc::now()).is_ok()));
+
+                match blinded_key {
+                    Some(key) => {
+                        self.onion.descriptors.lock().unwrap().insert(netdoc::encode_base64(&key), body);
+
+                        String::from("HTTP/1.0 200 OK\r\n\r\n")
+                    },
+                    None => String::from("HTTP/1.0 400 Bad descriptor\r\n\r\n"),
+                }
+            },
+            _ => String::from("HTTP/1.0 404 Not found\r\n\r\n"),
+        };
+
+        let mut stream = stream;
+
+        self.send_data(& mut stream, circuit_id, hop, stream_id, response.as_bytes())?;
+
+        self.send_relay(stream, circuit_id, hop, RelayCell::new(stream_id, Relay::End { end_reason: EndReason::Done }))
+    }
+
+    ///A server descriptor with the relay's keys, signed with its identity key
+    fn server_descriptor(& self) -> String {
+        let fingerprint: Vec<String> = self.identity.node_id.chunks(2).map(|pair| format!("{:02X}{:02X}", pair[0], pair[1])).collect();
+
+        //Clients use the address they reached the relay on, so the router line's is only a placeholder
+        let mut descriptor = format!("router mock 127.0.0.1 9001 0 0\nsigning-key\n{}\nfingerprint {}\nntor-onion-key {}\nrouter-signature\n",
+            netdoc::encode_object("RSA PUBLIC KEY", self.identity.signing_key.to_public_key().to_pkcs1_der().unwrap().as_bytes()),
+            fingerprint.join(" "),
+            netdoc::encode_base64(PublicKey::from(&self.identity.onion_secret).as_bytes()).trim_end_matches('='));
+
+        let signature = self.identity.signing_key.sign(Pkcs1v15Sign::new_unprefixed(), &Sha1::digest(descriptor.as_bytes())).unwrap();
+
+        descriptor.push_str(&netdoc::encode_object("SIGNATURE", &signature));
+        descriptor.push('\n');
+
+        descriptor
+    }
+
+    ///Connect to a loopback destination and start relaying whatever it sends back to the client. Like an exit, it refuses
+    ///address families the BEGIN flags rule out
+    fn begin<S: Write>(& mut self, stream: S, circuit_id: u32, hop: usize, stream_id: u16, target: &str, flags: BeginFlags) -> torserde::Result<()> {
+        let (host, port) = match target.rsplit_once(':') {
+            Some((host, port)) => (host.trim_start_matches('[').trim_end_matches(']'), port.parse::<u16>().ok()),
+            None => (target, None),
+        };
+
+        let is_loopback = host == "localhost" || host.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false);
+
+        let family_allowed = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => !flags.contains(BeginFlags::IPV4_NOT_OK),
+            Ok(IpAddr::V6(_)) => flags.contains(BeginFlags::IPV6_OK),
+            Err(_) => true,
+        };
+
+        if !is_loopback || !family_allowed {
+            let ip = host.parse::<Ipv4Addr>().unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+            return self.send_relay(stream, circuit_id, hop, RelayCell::new(stream_id, Relay::End { end_reason: EndReason::ExitPolicy { ip, ttl: 300 } }));
+        }
+
+        let target = match port.and_then(|port| TcpStream::connect((host, port)).ok()) {
+            Some(target) => target,
+            None => return self.send_relay(stream, circuit_id, hop, RelayCell::new(stream_id, Relay::End { end_reason: EndReason::ConnectRefused })),
+        };
+
+        let mut reader = target.try_clone()?;
+        let sender = self.event_sender.clone();
+
+        let address = match target.peer_addr().map(|address| address.ip()) {
+            Ok(IpAddr::V6(ip)) => ConnectedAddress::Ipv6 { ip, ttl: 300 },
+            _ => ConnectedAddress::Ipv4 { ip: Ipv4Addr::LOCALHOST, ttl: 300 },
+        };
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; 498];
+
+            loop {
+                match reader.read(& mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => {
+                        if sender.send(Event::Data(circuit_id, stream_id, Vec::from(&buffer[..read]))).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+
+            let _ = sender.send(Event::Closed(circuit_id, stream_id));
+        });
+
+        self.exits.insert((circuit_id, stream_id), ExitStream { hop, target });
+
+        self.send_relay(stream, circuit_id, hop, RelayCell::new(stream_id, Relay::Connected { address }))
+    }
+}
@@ -0,0 +1,103 @@
+//! Parsing of Tor's line based documents (dir-spec section 1.2): a keyword and arguments on each line, optionally
+//! followed by a base64 object between `-----BEGIN <TAG>-----` and `-----END <TAG>-----`.
+
+use crate::error::{ErrorKind, Result};
+
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub keyword: String,
+    pub arguments: Vec<String>,
+    ///The object's tag and decoded contents
+    pub object: Option<(String, Vec<u8>)>,
+}
+
+impl Item {
+    pub fn argument(& self, index: usize) -> Result<&str> {
+        self.arguments.get(index).map(|argument| argument.as_str()).ok_or_else(|| ErrorKind::InvalidDocument(format!("{} is missing argument {}", self.keyword, index + 1)))
+    }
+
+    pub fn object(& self) -> Result<&[u8]> {
+        self.object.as_ref().map(|(_, object)| object.as_slice()).ok_or_else(|| ErrorKind::InvalidDocument(format!("{} has no object", self.keyword)))
+    }
+}
+
+///Decode base64 with or without padding, ignoring whitespace
+pub fn decode_base64(encoded: &str) -> Result<Vec<u8>> {
+    let mut cleaned: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+
+    cleaned = String::from(cleaned.trim_end_matches('='));
+
+    base64::decode_config(&cleaned, base64::STANDARD_NO_PAD).map_err(|e| ErrorKind::InvalidDocument(format!("bad base64: {}", e)))
+}
+
+pub fn encode_base64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::STANDARD)
+}
+
+///Wrap `data` in a base64 object with 64 character lines
+pub fn encode_object(tag: &str, data: &[u8]) -> String {
+    let encoded = encode_base64(data);
+
+    let mut object = format!("-----BEGIN {}-----\n", tag);
+
+    for line in encoded.as_bytes().chunks(64) {
+        object.push_str(std::str::from_utf8(line).unwrap());
+        object.push('\n');
+    }
+
+    object.push_str(&format!("-----END {}-----", tag));
+
+    object
+}
+
+///Split a document into its items, in order
+pub fn parse_items(document: &str) -> Result<Vec<Item>> {
+    let mut items: Vec<Item> = Vec::new();
+
+    let mut lines = document.lines();
+
+    while let Some(line) = lines.next() {
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(tag) = line.strip_prefix("-----BEGIN ").and_then(|rest| rest.strip_suffix("-----")) {
+            let end = format!("-----END {}-----", tag);
+
+            let mut encoded = String::new();
+
+            loop {
+                match lines.next() {
+                    Some(line) if line.trim_end() == end => break,
+                    Some(line) => encoded.push_str(line.trim()),
+                    None => return Err(ErrorKind::InvalidDocument(format!("unterminated {} object", tag))),
+                }
+            }
+
+            let item = items.last_mut().ok_or_else(|| ErrorKind::InvalidDocument(String::from("object before any keyword")))?;
+
+            item.object = Some((String::from(tag), decode_base64(&encoded)?));
+
+            continue;
+        }
+
+        let mut words = line.split_whitespace().map(String::from);
+
+        let keyword = words.next().unwrap();
+
+        items.push(Item {
+            keyword,
+            arguments: words.collect(),
+            object: None,
+        });
+    }
+
+    Ok(items)
+}
+
+///The first item with `keyword`
+pub fn find<'a>(items: &'a [Item], keyword: &str) -> Result<&'a Item> {
+    items.iter().find(|item| item.keyword == keyword).ok_or_else(|| ErrorKind::InvalidDocument(format!("missing {}", keyword)))
+}
@@ -0,0 +1,148 @@
+use x25519_dalek::{StaticSecret, PublicKey};
+use ring::rand::SecureRandom;
+use std::convert::TryInto;
+
+use crate::cells::CSRNG;
+use crate::custom_crypto::{hmac_sha256, kdf_rfc5869, constant_time_eq};
+
+///The handshake type used in `Command::Create2` and `Relay::Extend2` for ntor
+pub const NTOR_HANDSHAKE_TYPE: u16 = 2;
+
+///The key material taken from an ntor handshake, Df | Db | Kf | Kb followed by the nonce KH (tor-spec section 5.2.2)
+pub const NTOR_KEY_MATERIAL_LENGTH: usize = 92;
+
+const PROTOID: &[u8] = b"ntor-curve25519-sha256-1";
+const T_MAC: &[u8] = b"ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"ntor-curve25519-sha256-1:verify";
+const M_EXPAND: &[u8] = b"ntor-curve25519-sha256-1:key_expand";
+
+pub fn generate_secret() -> StaticSecret {
+    let mut bytes = [0u8; 32];
+
+    CSRNG.fill(& mut bytes).unwrap();
+
+    StaticSecret::from(bytes)
+}
+
+///Diffie-Hellman that refuses to produce the all-zero output of a low order point
+fn exp(secret: &StaticSecret, public: &PublicKey) -> Option<[u8; 32]> {
+    let shared = *secret.diffie_hellman(public).as_bytes();
+
+    if shared.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(shared)
+    }
+}
+
+///Compute KEY_SEED and the AUTH value from the shared secrets, the same way on both sides of the handshake
+fn seed_and_auth(xy: &[u8; 32], xb: &[u8; 32], node_id: &[u8; 20], b: &PublicKey, x: &PublicKey, y: &PublicKey) -> ([u8; 32], [u8; 32]) {
+    let mut secret_input = Vec::with_capacity(32 * 5 + 20 + PROTOID.len());
+
+    secret_input.extend_from_slice(xy);
+    secret_input.extend_from_slice(xb);
+    secret_input.extend_from_slice(node_id);
+    secret_input.extend_from_slice(b.as_bytes());
+    secret_input.extend_from_slice(x.as_bytes());
+    secret_input.extend_from_slice(y.as_bytes());
+    secret_input.extend_from_slice(PROTOID);
+
+    let key_seed = hmac_sha256(T_KEY, &secret_input);
+    let verify = hmac_sha256(T_VERIFY, &secret_input);
+
+    let mut auth_input = Vec::from(verify);
+
+    auth_input.extend_from_slice(node_id);
+    auth_input.extend_from_slice(b.as_bytes());
+    auth_input.extend_from_slice(y.as_bytes());
+    auth_input.extend_from_slice(x.as_bytes());
+    auth_input.extend_from_slice(PROTOID);
+    auth_input.extend_from_slice(b"Server");
+
+    (key_seed, hmac_sha256(T_MAC, &auth_input))
+}
+
+///Client side of the ntor handshake (tor-spec section 5.1.4)
+pub struct NtorClient {
+    node_id: [u8; 20],
+    onion_key: PublicKey,
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl NtorClient {
+    pub fn new(node_id: &[u8; 20], onion_key: &[u8; 32]) -> Self {
+        let secret = generate_secret();
+        let public = PublicKey::from(&secret);
+
+        Self {
+            node_id: *node_id,
+            onion_key: PublicKey::from(*onion_key),
+            secret,
+            public,
+        }
+    }
+
+    ///The 84 byte onion skin, NODEID | KEYID | CLIENT_PK
+    pub fn onion_skin(& self) -> Vec<u8> {
+        let mut skin = Vec::with_capacity(84);
+
+        skin.extend_from_slice(&self.node_id);
+        skin.extend_from_slice(self.onion_key.as_bytes());
+        skin.extend_from_slice(self.public.as_bytes());
+
+        skin
+    }
+
+    ///Verify the server's reply and derive `length` bytes of key material, or None if the server could not be authenticated
+    pub fn complete(& self, handshake_data: &[u8], length: usize) -> Option<Vec<u8>> {
+        if handshake_data.len() < 64 {
+            return None;
+        }
+
+        let y: [u8; 32] = handshake_data[0..32].try_into().unwrap();
+        let y = PublicKey::from(y);
+
+        let xy = exp(&self.secret, &y)?;
+        let xb = exp(&self.secret, &self.onion_key)?;
+
+        let (key_seed, auth) = seed_and_auth(&xy, &xb, &self.node_id, &self.onion_key, &self.public, &y);
+
+        if !constant_time_eq(&auth, &handshake_data[32..64]) {
+            return None;
+        }
+
+        Some(kdf_rfc5869(&key_seed, M_EXPAND, length))
+    }
+}
+
+///Server side of the ntor handshake. Returns the reply (SERVER_PK | AUTH) and `length` bytes of key material
+pub fn server_handshake(node_id: &[u8; 20], onion_secret: &StaticSecret, onion_skin: &[u8], length: usize) -> Option<(Vec<u8>, Vec<u8>)> {
+    if onion_skin.len() < 84 {
+        return None;
+    }
+
+    let b = PublicKey::from(onion_secret);
+
+    if &onion_skin[0..20] != node_id || &onion_skin[20..52] != b.as_bytes() {
+        return None;
+    }
+
+    let x: [u8; 32] = onion_skin[52..84].try_into().unwrap();
+    let x = PublicKey::from(x);
+
+    let secret = generate_secret();
+    let y = PublicKey::from(&secret);
+
+    let xy = exp(&secret, &x)?;
+    let xb = exp(onion_secret, &x)?;
+
+    let (key_seed, auth) = seed_and_auth(&xy, &xb, node_id, &b, &x, &y);
+
+    let mut reply = Vec::from(*y.as_bytes());
+
+    reply.extend_from_slice(&auth);
+
+    Some((reply, kdf_rfc5869(&key_seed, M_EXPAND, length)))
+}
@@ -0,0 +1,838 @@
+//! The obfs4 pluggable transport, built in so obfs4 bridges can be used without an external managed proxy.
+//!
+//! obfs4 makes a connection look like uniformly random bytes. The handshake is an ntor handshake against the bridge's
+//! static key `B`, which the bridge line gives with its node ID in the `cert` argument. Both sides send their ephemeral
+//! key as an Elligator 2 representative, followed by random padding, a mark and a MAC, both HMAC-SHA256-128 keyed with
+//! `B | NODEID`, so the end of the padding can be found. After that, data travels in frames sealed with NaCl's
+//! secretbox, whose lengths are masked with a SipHash-2-4 keystream. Each frame holds one packet, of payload or of the
+//! seed for the distribution padding lengths are drawn from.
+//!
+//! `iat-mode=1` spreads writes out over MTU sized segments with random delays between them, and `iat-mode=2` also
+//! randomises the segment sizes. The padding and delay tables come from a seed through obfs4proxy's hash DRBG and the
+//! `math/rand` calls obfs4proxy makes, ported from Go. The DRBG is checked against Go's output, but the tables have only
+//! been checked against our own, as no Go toolchain was to hand.
+//!
+//! Both sides are here, so `MockRelay` can serve obfs4 for tests.
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hasher;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+use siphasher::sip::SipHasher24;
+use x25519_dalek::{StaticSecret, PublicKey};
+
+use crate::channel::ChannelTransport;
+use crate::custom_crypto::{hmac_sha256, kdf_rfc5869, constant_time_eq, secretbox_seal, secretbox_open, SECRETBOX_TAG_LENGTH};
+use crate::elligator;
+use crate::netdoc;
+use crate::ntor;
+use crate::error::{ErrorKind, Result};
+
+///The transport name in bridge lines
+pub const TRANSPORT_NAME: &str = "obfs4";
+
+const PROTOID: &[u8] = b"ntor-curve25519-sha256-1";
+const T_MAC: &[u8] = b"ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"ntor-curve25519-sha256-1:key_verify";
+const M_EXPAND: &[u8] = b"ntor-curve25519-sha256-1:key_expand";
+
+const MAX_HANDSHAKE_LENGTH: usize = 8192;
+const REPRESENTATIVE_LENGTH: usize = 32;
+const AUTH_LENGTH: usize = 32;
+const MARK_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 16;
+
+const CLIENT_MIN_HANDSHAKE_LENGTH: usize = REPRESENTATIVE_LENGTH + MARK_LENGTH + MAC_LENGTH;
+const SERVER_MIN_HANDSHAKE_LENGTH: usize = REPRESENTATIVE_LENGTH + AUTH_LENGTH + MARK_LENGTH + MAC_LENGTH;
+
+///The largest TCP segment obfs4 plans for, and so the largest frame
+const MAX_SEGMENT_LENGTH: usize = 1500 - 52;
+const LENGTH_LENGTH: usize = 2;
+const FRAME_OVERHEAD: usize = LENGTH_LENGTH + SECRETBOX_TAG_LENGTH;
+const MAX_FRAME_PAYLOAD: usize = MAX_SEGMENT_LENGTH - FRAME_OVERHEAD;
+const PACKET_OVERHEAD: usize = 3;
+const MAX_PACKET_PAYLOAD: usize = MAX_FRAME_PAYLOAD - PACKET_OVERHEAD;
+
+const SEED_LENGTH: usize = 24;
+const INLINE_SEED_FRAME_LENGTH: usize = FRAME_OVERHEAD + PACKET_OVERHEAD + SEED_LENGTH;
+
+///The client pads its handshake to at least the length of the server's shortest handshake and seed frame
+const CLIENT_MIN_PADDING: usize = SERVER_MIN_HANDSHAKE_LENGTH + INLINE_SEED_FRAME_LENGTH - CLIENT_MIN_HANDSHAKE_LENGTH;
+const CLIENT_MAX_PADDING: usize = MAX_HANDSHAKE_LENGTH - CLIENT_MIN_HANDSHAKE_LENGTH;
+const SERVER_MAX_PADDING: usize = MAX_HANDSHAKE_LENGTH - (SERVER_MIN_HANDSHAKE_LENGTH + INLINE_SEED_FRAME_LENGTH);
+
+const PACKET_PAYLOAD: u8 = 0;
+const PACKET_PRNG_SEED: u8 = 1;
+
+///A secretbox key, nonce prefix and length mask seed for each direction
+const FRAME_KEY_LENGTH: usize = 32 + 16 + SEED_LENGTH;
+
+///The longest delay between segments in IAT modes, in units of 100 microseconds
+const MAX_IAT_DELAY: usize = 100;
+
+///How long the handshake may take before the bridge is given up on
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn handshake_failed(reason: &str) -> ErrorKind {
+    ErrorKind::TransportFailed(format!("obfs4 handshake failed: {}", reason))
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("obfs4: {}", reason))
+}
+
+///Whether and how writes are split up and delayed to hide their timing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IatMode {
+    ///`iat-mode=0`, every write is sent at once
+    None,
+    ///`iat-mode=1`, writes are sent as MTU sized segments with random delays between them
+    Enabled,
+    ///`iat-mode=2`, writes are sent as randomly sized segments with random delays between them
+    Paranoid,
+}
+
+impl IatMode {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "0" => Some(IatMode::None),
+            "1" => Some(IatMode::Enabled),
+            "2" => Some(IatMode::Paranoid),
+            _ => None,
+        }
+    }
+}
+
+///What the client needs to know about an obfs4 bridge, from its bridge line's arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientArgs {
+    pub node_id: [u8; 20],
+    ///The bridge's static obfs4 key `B`
+    pub public_key: [u8; 32],
+    pub iat_mode: IatMode,
+}
+
+impl ClientArgs {
+    ///Read the `cert` and `iat-mode` arguments of an obfs4 bridge line
+    pub fn from_args(args: &[(String, String)]) -> Result<Self> {
+        let get = |key: &str| args.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_str());
+
+        let cert = get("cert").ok_or_else(|| ErrorKind::TransportFailed(String::from("obfs4 bridge line has no cert")))?;
+
+        let cert = netdoc::decode_base64(cert).ok().filter(|cert| cert.len() == 52)
+            .ok_or_else(|| ErrorKind::TransportFailed(format!("bad obfs4 cert {}", cert)))?;
+
+        let iat_mode = match get("iat-mode") {
+            Some(mode) => IatMode::from_arg(mode).ok_or_else(|| ErrorKind::TransportFailed(format!("bad obfs4 iat-mode {}", mode)))?,
+            None => IatMode::None,
+        };
+
+        Ok(Self {
+            node_id: cert[..20].try_into().unwrap(),
+            public_key: cert[20..].try_into().unwrap(),
+            iat_mode,
+        })
+    }
+}
+
+///The `cert` argument for a bridge with `node_id` and obfs4 key `public_key`
+pub fn encode_cert(node_id: &[u8; 20], public_key: &[u8; 32]) -> String {
+    let mut cert = node_id.to_vec();
+
+    cert.extend_from_slice(public_key);
+
+    netdoc::encode_base64(&cert).trim_end_matches('=').to_string()
+}
+
+///obfs4proxy's hash DRBG: SipHash-2-4 in output feedback mode, keyed with the first 16 bytes of the seed and fed the last
+///8 to start. The hash runs on over every block, as Go's streaming SipHash does, and blocks are its little-endian sums. It
+///masks frame lengths and, through Go's `math/rand`, picks the padding and delay tables
+pub struct HashDrbg {
+    hasher: SipHasher24,
+    ofb: [u8; 8],
+}
+
+impl HashDrbg {
+    pub fn new(seed: &[u8; SEED_LENGTH]) -> Self {
+        Self {
+            hasher: SipHasher24::new_with_key(seed[0..16].try_into().unwrap()),
+            ofb: seed[16..24].try_into().unwrap(),
+        }
+    }
+
+    pub fn next_block(& mut self) -> [u8; 8] {
+        self.hasher.write(&self.ofb);
+
+        self.ofb = self.hasher.finish().to_le_bytes();
+
+        self.ofb
+    }
+
+    ///The next block read big-endian with the top bit cleared, as `Int63` gives it to `math/rand`
+    pub fn int63(& mut self) -> u64 {
+        u64::from_be_bytes(self.next_block()) & (u64::MAX >> 1)
+    }
+
+    fn int31(& mut self) -> u32 {
+        (self.int63() >> 32) as u32
+    }
+
+    ///A uniform integer in [0, n) for n below 2^31, drawn as Go's `rand.Intn` draws it
+    fn intn(& mut self, n: usize) -> usize {
+        let n = n as u32;
+
+        if n & (n - 1) == 0 {
+            return (self.int31() & (n - 1)) as usize;
+        }
+
+        let max = (1 << 31) - 1 - (1 << 31) % n;
+
+        let mut value = self.int31();
+
+        while value > max {
+            value = self.int31();
+        }
+
+        (value % n) as usize
+    }
+
+    ///A permutation of [0, n), as Go's `rand.Perm` builds it
+    fn perm(& mut self, n: usize) -> Vec<usize> {
+        let mut permutation = vec![0; n];
+
+        for i in 0..n {
+            let j = self.intn(i + 1);
+
+            permutation[i] = permutation[j];
+            permutation[j] = i;
+        }
+
+        permutation
+    }
+
+    ///A uniform float in [0, 1), as Go's `rand.Float64` draws it
+    fn float64(& mut self) -> f64 {
+        loop {
+            let value = self.int63() as f64 / (1u64 << 63) as f64;
+
+            if value < 1.0 {
+                return value;
+            }
+        }
+    }
+}
+
+///Padding lengths or delays drawn from a weighted table of values that a seed picks, as obfs4proxy's `probdist` builds it
+///with the uniform weights it uses by default. Sampling uses fresh randomness, with Vose's alias method
+pub struct Distribution {
+    min: usize,
+    ///Offsets from `min`
+    values: Vec<usize>,
+    prob: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl Distribution {
+    ///The most values a table holds
+    const MAX_VALUES: usize = 100;
+
+    pub fn new(seed: &[u8; SEED_LENGTH], min: usize, max: usize) -> Self {
+        let mut drbg = HashDrbg::new(seed);
+
+        let count = max + 1 - min;
+
+        let mut values = drbg.perm(count);
+
+        values.truncate(drbg.intn(count.min(Self::MAX_VALUES)) + 1);
+
+        let weights: Vec<f64> = values.iter().map(|_| drbg.float64()).collect();
+
+        let (prob, alias) = Self::alias_tables(&weights);
+
+        Self {
+            min,
+            values,
+            prob,
+            alias,
+        }
+    }
+
+    ///The probability and alias tables for `weights`, worked through in the order obfs4proxy's lists give
+    fn alias_tables(weights: &[f64]) -> (Vec<f64>, Vec<usize>) {
+        let n = weights.len();
+        let sum: f64 = weights.iter().sum();
+
+        let mut prob = vec![0.0; n];
+        let mut alias = vec![0; n];
+
+        let mut scaled: Vec<f64> = weights.iter().map(|weight| weight * n as f64 / sum).collect();
+
+        let mut small: VecDeque<usize> = (0..n).filter(|i| scaled[*i] < 1.0).collect();
+        let mut large: VecDeque<usize> = (0..n).filter(|i| scaled[*i] >= 1.0).collect();
+
+        while !small.is_empty() && !large.is_empty() {
+            let l = small.pop_front().unwrap();
+            let g = large.pop_front().unwrap();
+
+            prob[l] = scaled[l];
+            alias[l] = g;
+
+            scaled[g] = (scaled[g] + scaled[l]) - 1.0;
+
+            if scaled[g] < 1.0 {
+                small.push_back(g);
+            } else {
+                large.push_back(g);
+            }
+        }
+
+        for i in large.into_iter().chain(small) {
+            prob[i] = 1.0;
+        }
+
+        (prob, alias)
+    }
+
+    ///The values in the table, in the order the seed picked them
+    pub fn values(& self) -> Vec<usize> {
+        self.values.iter().map(|value| self.min + value).collect()
+    }
+
+    pub fn sample(& self) -> usize {
+        let mut rng = rand::thread_rng();
+
+        let i = rng.gen_range(0..self.values.len());
+
+        let index = if rng.gen::<f64>() <= self.prob[i] {
+            i
+        } else {
+            self.alias[i]
+        };
+
+        self.min + self.values[index]
+    }
+}
+
+///The secretbox key, nonce and length mask for the frames going one way
+pub struct FrameCipher {
+    key: [u8; 32],
+    nonce_prefix: [u8; 16],
+    ///Starts at 1 and goes up with each frame
+    counter: u64,
+    lengths: HashDrbg,
+}
+
+impl FrameCipher {
+    pub fn new(material: &[u8]) -> Self {
+        Self {
+            key: material[0..32].try_into().unwrap(),
+            nonce_prefix: material[32..48].try_into().unwrap(),
+            counter: 1,
+            lengths: HashDrbg::new(material[48..72].try_into().unwrap()),
+        }
+    }
+
+    fn next_nonce(& mut self) -> io::Result<[u8; 24]> {
+        //Reusing a nonce would be fatal, so the connection ends first
+        if self.counter == u64::MAX {
+            return Err(invalid_data("frame counter wrapped"));
+        }
+
+        let mut nonce = [0u8; 24];
+
+        nonce[..16].copy_from_slice(&self.nonce_prefix);
+        nonce[16..].copy_from_slice(&self.counter.to_be_bytes());
+
+        self.counter += 1;
+
+        Ok(nonce)
+    }
+
+    fn length_mask(& mut self) -> u16 {
+        let block = self.lengths.next_block();
+
+        u16::from_be_bytes([block[0], block[1]])
+    }
+
+    pub fn seal(& mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+
+        let sealed = secretbox_seal(&self.key, &nonce, payload);
+
+        let length = sealed.len() as u16 ^ self.length_mask();
+
+        let mut frame = length.to_be_bytes().to_vec();
+
+        frame.extend_from_slice(&sealed);
+
+        Ok(frame)
+    }
+}
+
+///The ntor variant obfs4 uses, returning `KEY_SEED` and `AUTH`. It differs from Tor's ntor in what follows the two
+///exponentials, which is `B | B | X | Y | PROTOID | NODEID` here, as obfs4proxy builds it
+pub fn ntor(first: &[u8; 32], second: &[u8; 32], node_id: &[u8; 20], b: &[u8; 32], x: &[u8; 32], y: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut suffix = Vec::new();
+
+    suffix.extend_from_slice(b);
+    suffix.extend_from_slice(b);
+    suffix.extend_from_slice(x);
+    suffix.extend_from_slice(y);
+    suffix.extend_from_slice(PROTOID);
+    suffix.extend_from_slice(node_id);
+
+    let mut secret_input = first.to_vec();
+
+    secret_input.extend_from_slice(second);
+    secret_input.extend_from_slice(&suffix);
+
+    let key_seed = hmac_sha256(T_KEY, &secret_input);
+
+    let mut auth_input = hmac_sha256(T_VERIFY, &secret_input).to_vec();
+
+    auth_input.extend_from_slice(&suffix);
+    auth_input.extend_from_slice(b"Server");
+
+    (key_seed, hmac_sha256(T_MAC, &auth_input))
+}
+
+///The frame keys for both directions, the client's to the server first
+fn frame_keys(key_seed: &[u8; 32]) -> Vec<u8> {
+    kdf_rfc5869(&hmac_sha256(T_KEY, key_seed), M_EXPAND, FRAME_KEY_LENGTH * 2)
+}
+
+fn mark(key: &[u8], representative: &[u8]) -> [u8; MARK_LENGTH] {
+    hmac_sha256(key, representative)[..MARK_LENGTH].try_into().unwrap()
+}
+
+fn mac(key: &[u8], message: &[u8], epoch_hour: &str) -> [u8; MAC_LENGTH] {
+    let mut input = message.to_vec();
+
+    input.extend_from_slice(epoch_hour.as_bytes());
+
+    hmac_sha256(key, &input)[..MAC_LENGTH].try_into().unwrap()
+}
+
+fn epoch_hour(offset: i64) -> String {
+    (chrono::Utc::now().timestamp() / 3600 + offset).to_string()
+}
+
+fn random_bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+
+    rand::thread_rng().fill_bytes(& mut bytes);
+
+    bytes
+}
+
+///Read from `stream` until `received` holds `mark` at or after `start` followed by a whole MAC, returning where the
+///mark is. Gives up once the handshake would be longer than any valid one
+fn read_until_mark<S: Read>(stream: & mut S, received: & mut Vec<u8>, mark: impl Fn(&[u8]) -> [u8; MARK_LENGTH], start: usize) -> Result<usize> {
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        if received.len() >= start + MARK_LENGTH + MAC_LENGTH {
+            let mark = mark(received);
+
+            let found = (start..=received.len() - MARK_LENGTH - MAC_LENGTH).find(|position| received[*position..*position + MARK_LENGTH] == mark);
+
+            if let Some(position) = found {
+                return Ok(position);
+            }
+        }
+
+        if received.len() > MAX_HANDSHAKE_LENGTH {
+            return Err(handshake_failed("no mark"));
+        }
+
+        let read = stream.read(& mut chunk)?;
+
+        if read == 0 {
+            return Err(handshake_failed("connection closed"));
+        }
+
+        received.extend_from_slice(&chunk[..read]);
+    }
+}
+
+///Perform the client handshake over `stream`, which should have a read timeout such as `HANDSHAKE_TIMEOUT`
+pub fn connect<S: Read + Write>(mut stream: S, args: &ClientArgs) -> Result<Obfs4Stream<S>> {
+    let keypair = elligator::generate_representable();
+
+    let mut mac_key = args.public_key.to_vec();
+
+    mac_key.extend_from_slice(&args.node_id);
+
+    let hour = epoch_hour(0);
+
+    let mut request = keypair.representative.to_vec();
+
+    request.extend(random_bytes(rand::thread_rng().gen_range(CLIENT_MIN_PADDING..=CLIENT_MAX_PADDING)));
+    request.extend_from_slice(&mark(&mac_key, &keypair.representative));
+
+    let request_mac = mac(&mac_key, &request, &hour);
+
+    request.extend_from_slice(&request_mac);
+
+    stream.write_all(&request)?;
+
+    let mut received = Vec::new();
+
+    let position = read_until_mark(& mut stream, & mut received, |received| mark(&mac_key, &received[..REPRESENTATIVE_LENGTH]), REPRESENTATIVE_LENGTH + AUTH_LENGTH)?;
+
+    let end = position + MARK_LENGTH + MAC_LENGTH;
+
+    if !constant_time_eq(&mac(&mac_key, &received[..position + MARK_LENGTH], &hour), &received[position + MARK_LENGTH..end]) {
+        return Err(handshake_failed("bad server MAC"));
+    }
+
+    let y = elligator::representative_to_public(received[..REPRESENTATIVE_LENGTH].try_into().unwrap());
+
+    let exp_yx = keypair.secret.diffie_hellman(&PublicKey::from(y));
+    let exp_bx = keypair.secret.diffie_hellman(&PublicKey::from(args.public_key));
+
+    if exp_yx.as_bytes() == &[0u8; 32] || exp_bx.as_bytes() == &[0u8; 32] {
+        return Err(handshake_failed("degenerate key"));
+    }
+
+    let (key_seed, auth) = ntor(exp_yx.as_bytes(), exp_bx.as_bytes(), &args.node_id, &args.public_key, &keypair.public, &y);
+
+    if !constant_time_eq(&auth, &received[REPRESENTATIVE_LENGTH..REPRESENTATIVE_LENGTH + AUTH_LENGTH]) {
+        return Err(handshake_failed("bad AUTH"));
+    }
+
+    let keys = frame_keys(&key_seed);
+
+    //Until the server sends its seed, padding comes from one of our own
+    let seed = random_bytes(SEED_LENGTH).as_slice().try_into().unwrap();
+
+    let mut obfs4 = Obfs4Stream::new(stream, FrameCipher::new(&keys[..FRAME_KEY_LENGTH]), FrameCipher::new(&keys[FRAME_KEY_LENGTH..]), false, args.iat_mode, &seed);
+
+    obfs4.received = received.split_off(end);
+    obfs4.process_frames()?;
+
+    Ok(obfs4)
+}
+
+///Connect to an obfs4 bridge at `address` and perform the handshake
+pub fn dial(address: SocketAddr, args: &ClientArgs) -> Result<Obfs4Stream<TcpStream>> {
+    let stream = TcpStream::connect(address)?;
+
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let obfs4 = connect(stream, args)?;
+
+    obfs4.get_ref().set_read_timeout(None)?;
+
+    Ok(obfs4)
+}
+
+///A bridge's static obfs4 key, and the client MACs it has seen so replayed handshakes are refused
+pub struct ServerKeys {
+    node_id: [u8; 20],
+    secret: StaticSecret,
+    public: [u8; 32],
+    replays: Mutex<HashSet<[u8; MAC_LENGTH]>>,
+}
+
+impl ServerKeys {
+    pub fn generate(node_id: [u8; 20]) -> Self {
+        let secret = ntor::generate_secret();
+        let public = *PublicKey::from(&secret).as_bytes();
+
+        Self {
+            node_id,
+            secret,
+            public,
+            replays: Mutex::new(HashSet::new()),
+        }
+    }
+
+    ///The `cert` argument of our bridge line
+    pub fn cert(& self) -> String {
+        encode_cert(&self.node_id, &self.public)
+    }
+}
+
+///Perform the server handshake over `stream`, writing our frames with `iat_mode`
+pub fn accept<S: Read + Write>(mut stream: S, keys: &ServerKeys, iat_mode: IatMode) -> Result<Obfs4Stream<S>> {
+    let mut mac_key = keys.public.to_vec();
+
+    mac_key.extend_from_slice(&keys.node_id);
+
+    let mut received = Vec::new();
+
+    let position = read_until_mark(& mut stream, & mut received, |received| mark(&mac_key, &received[..REPRESENTATIVE_LENGTH]), REPRESENTATIVE_LENGTH + CLIENT_MIN_PADDING)?;
+
+    let end = position + MARK_LENGTH + MAC_LENGTH;
+
+    //Allow for the clocks being an hour apart either way
+    let hour = (-1..=1).map(epoch_hour)
+        .find(|hour| constant_time_eq(&mac(&mac_key, &received[..position + MARK_LENGTH], hour), &received[position + MARK_LENGTH..end]))
+        .ok_or_else(|| handshake_failed("bad client MAC"))?;
+
+    if !keys.replays.lock().unwrap().insert(received[position + MARK_LENGTH..end].try_into().unwrap()) {
+        return Err(handshake_failed("replayed handshake"));
+    }
+
+    let x = elligator::representative_to_public(received[..REPRESENTATIVE_LENGTH].try_into().unwrap());
+
+    let keypair = elligator::generate_representable();
+
+    let exp_xy = keypair.secret.diffie_hellman(&PublicKey::from(x));
+    let exp_xb = keys.secret.diffie_hellman(&PublicKey::from(x));
+
+    if exp_xy.as_bytes() == &[0u8; 32] || exp_xb.as_bytes() == &[0u8; 32] {
+        return Err(handshake_failed("degenerate key"));
+    }
+
+    let (key_seed, auth) = ntor(exp_xy.as_bytes(), exp_xb.as_bytes(), &keys.node_id, &keys.public, &x, &keypair.public);
+
+    let mut response = keypair.representative.to_vec();
+
+    response.extend_from_slice(&auth);
+    response.extend(random_bytes(rand::thread_rng().gen_range(0..=SERVER_MAX_PADDING)));
+    response.extend_from_slice(&mark(&mac_key, &keypair.representative));
+
+    let response_mac = mac(&mac_key, &response, &hour);
+
+    response.extend_from_slice(&response_mac);
+
+    let frame_keys = frame_keys(&key_seed);
+
+    let seed: [u8; SEED_LENGTH] = random_bytes(SEED_LENGTH).as_slice().try_into().unwrap();
+
+    let mut obfs4 = Obfs4Stream::new(stream, FrameCipher::new(&frame_keys[FRAME_KEY_LENGTH..]), FrameCipher::new(&frame_keys[..FRAME_KEY_LENGTH]), true, iat_mode, &seed);
+
+    //The seed for the client's padding goes out with the handshake
+    obfs4.make_packet(& mut response, PACKET_PRNG_SEED, &seed, 0)?;
+
+    obfs4.inner.write_all(&response)?;
+
+    obfs4.received = received.split_off(end);
+    obfs4.process_frames()?;
+
+    Ok(obfs4)
+}
+
+///A stream wrapped in obfs4 framing. Partly received frames are kept between reads, so reads that time out lose nothing
+pub struct Obfs4Stream<S> {
+    inner: S,
+    encoder: FrameCipher,
+    decoder: FrameCipher,
+    is_server: bool,
+    iat_mode: IatMode,
+    lengths: Distribution,
+    delays: Distribution,
+    ///Bytes read from `inner` that don't make a whole frame yet
+    received: Vec<u8>,
+    ///The unmasked length of the frame at the start of `received`, once its length field has been read
+    next_length: Option<usize>,
+    ///Payload that has been received but not read
+    readable: Vec<u8>,
+}
+
+impl<S: Read + Write> Obfs4Stream<S> {
+    fn new(inner: S, encoder: FrameCipher, decoder: FrameCipher, is_server: bool, iat_mode: IatMode, seed: &[u8; SEED_LENGTH]) -> Self {
+        let (lengths, delays) = Self::distributions(seed);
+
+        Self {
+            inner,
+            encoder,
+            decoder,
+            is_server,
+            iat_mode,
+            lengths,
+            delays,
+            received: Vec::new(),
+            next_length: None,
+            readable: Vec::new(),
+        }
+    }
+
+    ///The padding length and delay distributions for `seed`. The delays use a seed hashed from it
+    fn distributions(seed: &[u8; SEED_LENGTH]) -> (Distribution, Distribution) {
+        let delay_seed: [u8; SEED_LENGTH] = Sha256::digest(seed)[..SEED_LENGTH].try_into().unwrap();
+
+        (Distribution::new(seed, 0, MAX_SEGMENT_LENGTH), Distribution::new(&delay_seed, 0, MAX_IAT_DELAY))
+    }
+
+    pub fn get_ref(& self) -> &S {
+        &self.inner
+    }
+
+    ///Append a frame holding one packet of `kind` to `out`, padded with `padding` zeros
+    fn make_packet(& mut self, out: & mut Vec<u8>, kind: u8, payload: &[u8], padding: usize) -> io::Result<()> {
+        let mut packet = vec![kind];
+
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet.resize(packet.len() + padding, 0);
+
+        out.extend(self.encoder.seal(&packet)?);
+
+        Ok(())
+    }
+
+    ///Pad `burst` so its last segment is `pad_to` bytes long, adding a segment if there isn't room for a padding frame
+    fn pad_burst(& mut self, burst: & mut Vec<u8>, pad_to: usize) -> io::Result<()> {
+        let tail = burst.len() % MAX_SEGMENT_LENGTH;
+
+        let padding = if pad_to >= tail {
+            pad_to - tail
+        } else {
+            MAX_SEGMENT_LENGTH - tail + pad_to
+        };
+
+        let header = FRAME_OVERHEAD + PACKET_OVERHEAD;
+
+        if padding > header {
+            self.make_packet(burst, PACKET_PAYLOAD, &[], padding - header)?;
+        } else if padding > 0 {
+            self.make_packet(burst, PACKET_PAYLOAD, &[], MAX_PACKET_PAYLOAD)?;
+            self.make_packet(burst, PACKET_PAYLOAD, &[], padding)?;
+        }
+
+        Ok(())
+    }
+
+    ///Open every whole frame in `received`, keeping payload and acting on seeds
+    fn process_frames(& mut self) -> io::Result<()> {
+        loop {
+            let length = match self.next_length {
+                Some(length) => length,
+                None if self.received.len() >= LENGTH_LENGTH => {
+                    let length = (u16::from_be_bytes([self.received[0], self.received[1]]) ^ self.decoder.length_mask()) as usize;
+
+                    if !(SECRETBOX_TAG_LENGTH..=MAX_SEGMENT_LENGTH - LENGTH_LENGTH).contains(&length) {
+                        return Err(invalid_data("bad frame length"));
+                    }
+
+                    self.received.drain(..LENGTH_LENGTH);
+                    self.next_length = Some(length);
+
+                    length
+                },
+                None => return Ok(()),
+            };
+
+            if self.received.len() < length {
+                return Ok(());
+            }
+
+            let sealed: Vec<u8> = self.received.drain(..length).collect();
+
+            self.next_length = None;
+
+            let nonce = self.decoder.next_nonce()?;
+
+            let packet = secretbox_open(&self.decoder.key, &nonce, &sealed).ok_or_else(|| invalid_data("frame failed to authenticate"))?;
+
+            if packet.len() < PACKET_OVERHEAD {
+                return Err(invalid_data("short packet"));
+            }
+
+            let payload_length = u16::from_be_bytes([packet[1], packet[2]]) as usize;
+
+            let payload = packet.get(PACKET_OVERHEAD..PACKET_OVERHEAD + payload_length).ok_or_else(|| invalid_data("bad packet length"))?;
+
+            match packet[0] {
+                PACKET_PAYLOAD => self.readable.extend_from_slice(payload),
+                //Only the client takes the server's seed
+                PACKET_PRNG_SEED if payload.len() == SEED_LENGTH && !self.is_server => {
+                    let (lengths, delays) = Self::distributions(payload.try_into().unwrap());
+
+                    self.lengths = lengths;
+                    self.delays = delays;
+                },
+                //Unknown packets are ignored
+                _ => {},
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Read for Obfs4Stream<S> {
+    fn read(& mut self, buf: & mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+
+        while self.readable.is_empty() {
+            let read = self.inner.read(& mut chunk)?;
+
+            if read == 0 {
+                return Ok(0);
+            }
+
+            self.received.extend_from_slice(&chunk[..read]);
+
+            self.process_frames()?;
+        }
+
+        let length = buf.len().min(self.readable.len());
+
+        buf[..length].copy_from_slice(&self.readable[..length]);
+
+        self.readable.drain(..length);
+
+        Ok(length)
+    }
+}
+
+impl<S: Read + Write> Write for Obfs4Stream<S> {
+    fn write(& mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut burst = Vec::new();
+
+        for chunk in buf.chunks(MAX_PACKET_PAYLOAD) {
+            self.make_packet(& mut burst, PACKET_PAYLOAD, chunk, 0)?;
+        }
+
+        //Paranoid mode hides lengths by how it splits segments instead
+        if self.iat_mode != IatMode::Paranoid {
+            let pad_to = self.lengths.sample();
+
+            self.pad_burst(& mut burst, pad_to)?;
+        }
+
+        if self.iat_mode == IatMode::None {
+            self.inner.write_all(&burst)?;
+
+            return Ok(buf.len());
+        }
+
+        let mut rest = burst.as_slice();
+
+        while !rest.is_empty() {
+            let segment = match self.iat_mode {
+                IatMode::Paranoid => self.lengths.sample().max(1),
+                _ => MAX_SEGMENT_LENGTH,
+            }.min(rest.len());
+
+            thread::sleep(Duration::from_micros(100 * self.delays.sample() as u64));
+
+            self.inner.write_all(&rest[..segment])?;
+            self.inner.flush()?;
+
+            rest = &rest[segment..];
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(& mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: ChannelTransport> ChannelTransport for Obfs4Stream<S> {
+    fn set_poll_timeout(& self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_poll_timeout(timeout)
+    }
+}
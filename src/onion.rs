@@ -0,0 +1,716 @@
+//! Client side of version 3 onion services (rend-spec-v3).
+//!
+//! Connecting to `<address>.onion` takes several circuits: one to an HSDir responsible for the service's blinded key this
+//! time period to fetch its descriptor, one to a rendezvous point of our choosing, and one to an introduction point from
+//! the descriptor. The INTRODUCE1 cell carries the first half of the hs-ntor handshake to the service, which answers at
+//! the rendezvous point. The rendezvous circuit then gains a virtual hop to the service and streams are opened on it.
+//!
+//! Path selection and the consensus are not part of this module, so circuits and the HSDir ring come from an `OnionPaths`.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Timelike, Utc};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use ring::rand::SecureRandom;
+
+use crate::cells::{Relay, RelayCell, LinkSpecifier, HsExtension, CSRNG};
+use crate::cellcrypto::CellCrypto;
+use crate::circuit::{Circuit, TorStream};
+use crate::circuit_padding::PaddingMachine;
+use crate::client_auth::ClientAuthStore;
+use crate::custom_crypto::sha3_256;
+use crate::hs_descriptor::{self, HsDescriptor, IntroPoint, InnerLayer};
+use crate::hs_ntor::HsNtorClient;
+use crate::netdoc;
+use crate::pow::{self, PowConfig, PowSolution};
+use crate::error::{ErrorKind, Result};
+
+const ONION_VERSION: u8 = 3;
+
+const BLIND_STRING: &[u8] = b"Derive temporary signing key\0";
+
+///The ed25519 basepoint, as written in rend-spec-v3 section A.2
+const BASEPOINT_STRING: &[u8] = b"(15112221349535400772501151409588531511454012693041857206046113283949847762202, 46316835694926478169428394003475163141307993866256225615783033603165251855960)";
+
+///The default `hsdir_interval` consensus parameter, in minutes
+pub const DEFAULT_TIME_PERIOD_LENGTH: u64 = 1440;
+
+///The default `hsdir_n_replicas` consensus parameter
+pub const HSDIR_REPLICAS: u8 = 2;
+
+///The default `hsdir_spread_fetch` consensus parameter
+pub const HSDIR_SPREAD_FETCH: usize = 3;
+
+///The default `hsdir_spread_store` consensus parameter
+pub const HSDIR_SPREAD_STORE: usize = 4;
+
+///The most bytes of an INTRODUCE1 body, so every introduction has the same size whatever it contains
+pub(crate) const INTRODUCE1_LENGTH: usize = 498;
+
+///The `Relay::IntroduceAck` status for an introduction the intro point relayed
+pub const INTRODUCE_ACK_SUCCESS: u16 = 0;
+
+///A version 3 onion address, which is the service's ed25519 identity key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddress {
+    public_key: [u8; 32],
+}
+
+impl OnionAddress {
+    pub fn from_public_key(public_key: [u8; 32]) -> Self {
+        Self {
+            public_key,
+        }
+    }
+
+    pub fn public_key(& self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    fn checksum(public_key: &[u8; 32]) -> [u8; 2] {
+        let mut input = Vec::from(&b".onion checksum"[..]);
+
+        input.extend_from_slice(public_key);
+        input.push(ONION_VERSION);
+
+        sha3_256(&input)[0..2].try_into().unwrap()
+    }
+
+    ///The factor the identity key is multiplied by to give the blinded key for `period`, already clamped
+    pub(crate) fn blinding_factor(& self, period: TimePeriod) -> [u8; 32] {
+        let mut input = Vec::from(BLIND_STRING);
+
+        input.extend_from_slice(&self.public_key);
+        input.extend_from_slice(BASEPOINT_STRING);
+        input.extend_from_slice(b"key-blind");
+        input.extend_from_slice(&period.number.to_be_bytes());
+        input.extend_from_slice(&period.length.to_be_bytes());
+
+        let mut h = sha3_256(&input);
+
+        h[0] &= 248;
+        h[31] &= 63;
+        h[31] |= 64;
+
+        h
+    }
+
+    ///The blinded public key the service signs its descriptor with during `period` (rend-spec-v3 section A.2)
+    pub fn blinded_key(& self, period: TimePeriod) -> Result<[u8; 32]> {
+        let point = CompressedEdwardsY(self.public_key).decompress().ok_or_else(|| ErrorKind::InvalidOnionAddress(String::from("public key is not a curve point")))?;
+
+        let blinded = Scalar::from_bits(self.blinding_factor(period)) * point;
+
+        Ok(blinded.compress().to_bytes())
+    }
+
+    ///N_hs_subcred, which ties the descriptor and introduction to both the identity key and the blinded key
+    pub fn subcredential(& self, period: TimePeriod) -> Result<[u8; 32]> {
+        let mut credential = Vec::from(&b"credential"[..]);
+
+        credential.extend_from_slice(&self.public_key);
+
+        let mut input = Vec::from(&b"subcredential"[..]);
+
+        input.extend_from_slice(&sha3_256(&credential));
+        input.extend_from_slice(&self.blinded_key(period)?);
+
+        Ok(sha3_256(&input))
+    }
+}
+
+impl FromStr for OnionAddress {
+    type Err = ErrorKind;
+
+    ///Parse `[subdomain.]<56 characters>[.onion]`, case insensitively
+    fn from_str(address: &str) -> Result<Self> {
+        let address = address.trim_end_matches('.').to_ascii_lowercase();
+        let address = address.strip_suffix(".onion").unwrap_or(&address);
+        let label = address.rsplit('.').next().unwrap();
+
+        if label.len() != 56 {
+            return Err(ErrorKind::InvalidOnionAddress(format!("{} is not 56 characters", label)));
+        }
+
+        let decoded = base32::decode(base32::Alphabet::RFC4648 { padding: false }, label)
+            .ok_or_else(|| ErrorKind::InvalidOnionAddress(format!("{} is not base32", label)))?;
+
+        if decoded[34] != ONION_VERSION {
+            return Err(ErrorKind::InvalidOnionAddress(format!("unsupported version {}", decoded[34])));
+        }
+
+        let public_key: [u8; 32] = decoded[0..32].try_into().unwrap();
+
+        if Self::checksum(&public_key) != decoded[32..34] {
+            return Err(ErrorKind::InvalidOnionAddress(String::from("checksum mismatch")));
+        }
+
+        Ok(Self::from_public_key(public_key))
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut encoded = Vec::from(self.public_key);
+
+        encoded.extend_from_slice(&Self::checksum(&self.public_key));
+        encoded.push(ONION_VERSION);
+
+        write!(f, "{}.onion", base32::encode(base32::Alphabet::RFC4648 { padding: false }, &encoded).to_ascii_lowercase())
+    }
+}
+
+///A time period of the onion service protocol (rend-spec-v3 section 2.2.1). Periods start at 12:00 UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimePeriod {
+    pub number: u64,
+    ///Minutes
+    pub length: u64,
+}
+
+impl TimePeriod {
+    pub fn at(time: DateTime<Utc>, length: u64) -> Self {
+        let minutes = time.timestamp() as u64 / 60;
+
+        //Rotation happens 12 hours after midnight, so subtract that to line periods up with it
+        Self {
+            number: (minutes - 12 * 60) / length,
+            length,
+        }
+    }
+
+    pub fn current() -> Self {
+        Self::at(Utc::now(), DEFAULT_TIME_PERIOD_LENGTH)
+    }
+
+    ///The period after this one, which services publish descriptors for ahead of time
+    pub fn next(self) -> Self {
+        Self {
+            number: self.number + 1,
+            length: self.length,
+        }
+    }
+}
+
+///Which shared random value the hash ring uses (rend-spec-v3 section 2.2.4). A new value is agreed at 00:00 UTC but the
+///time period it belongs to only starts at 12:00, so until then the previous value is still the one for this period
+pub fn shared_random_value<'a>(time: DateTime<Utc>, current: &'a [u8; 32], previous: &'a [u8; 32]) -> &'a [u8; 32] {
+    if time.hour() < 12 {
+        previous
+    } else {
+        current
+    }
+}
+
+///How to reach a relay and extend a circuit to it
+#[derive(Debug, Clone)]
+pub struct RelayInfo {
+    ///The SHA1 digest of the relay's RSA identity key
+    pub node_id: [u8; 20],
+    pub ed25519_id: Option<[u8; 32]>,
+    pub ntor_onion_key: [u8; 32],
+    pub link_specifiers: Vec<LinkSpecifier>,
+}
+
+impl RelayInfo {
+    ///The relay of an introduction point, if it named its RSA identity
+    pub fn from_intro_point(intro_point: &IntroPoint) -> Option<Self> {
+        Some(Self {
+            node_id: intro_point.node_id()?,
+            ed25519_id: intro_point.ed25519_id(),
+            ntor_onion_key: intro_point.onion_key,
+            link_specifiers: intro_point.link_specifiers.clone(),
+        })
+    }
+
+    ///The relay described by link specifiers and an ntor key, such as a rendezvous point from INTRODUCE2
+    pub fn from_link_specifiers(link_specifiers: Vec<LinkSpecifier>, ntor_onion_key: [u8; 32]) -> Option<Self> {
+        let find = |spec_type: u8| link_specifiers.iter().find(|spec| spec.get_type() == spec_type).map(|spec| Vec::from(spec.get_spec()));
+
+        let node_id = find(2)?.as_slice().try_into().ok()?;
+        let ed25519_id = find(3).and_then(|spec| spec.as_slice().try_into().ok());
+
+        Some(Self {
+            node_id,
+            ed25519_id,
+            ntor_onion_key,
+            link_specifiers,
+        })
+    }
+}
+
+///The HSDirs of a time period, ordered by their index on the hash ring (rend-spec-v3 section 2.2.3)
+pub struct HsDirRing {
+    period: TimePeriod,
+    relays: Vec<([u8; 32], RelayInfo)>,
+}
+
+impl HsDirRing {
+    ///Place every relay with the HSDir flag and an ed25519 identity on the ring, using the shared random value for `period`
+    pub fn new(relays: Vec<RelayInfo>, shared_random: &[u8; 32], period: TimePeriod) -> Self {
+        let mut relays: Vec<([u8; 32], RelayInfo)> = relays.into_iter()
+            .filter_map(|relay| {
+                let mut input = Vec::from(&b"node-idx"[..]);
+
+                input.extend_from_slice(&relay.ed25519_id?);
+                input.extend_from_slice(shared_random);
+                input.extend_from_slice(&period.number.to_be_bytes());
+                input.extend_from_slice(&period.length.to_be_bytes());
+
+                Some((sha3_256(&input), relay))
+            })
+            .collect();
+
+        relays.sort_by_key(|(index, _)| *index);
+
+        Self {
+            period,
+            relays,
+        }
+    }
+
+    pub fn period(& self) -> TimePeriod {
+        self.period
+    }
+
+    ///The HSDirs that store the descriptor for `blinded_key`, in the order they should be tried
+    pub fn responsible(& self, blinded_key: &[u8; 32]) -> Vec<&RelayInfo> {
+        self.select(blinded_key, HSDIR_SPREAD_FETCH)
+    }
+
+    ///The HSDirs a service uploads its descriptor for `blinded_key` to
+    pub fn store_targets(& self, blinded_key: &[u8; 32]) -> Vec<&RelayInfo> {
+        self.select(blinded_key, HSDIR_SPREAD_STORE)
+    }
+
+    ///For each replica, the `spread` relays following the replica's index on the ring, skipping relays already chosen
+    fn select(& self, blinded_key: &[u8; 32], spread: usize) -> Vec<&RelayInfo> {
+        let mut chosen: Vec<usize> = Vec::new();
+
+        if self.relays.is_empty() {
+            return Vec::new();
+        }
+
+        for replica in 1..=HSDIR_REPLICAS as u64 {
+            let mut input = Vec::from(&b"store-at-idx"[..]);
+
+            input.extend_from_slice(blinded_key);
+            input.extend_from_slice(&replica.to_be_bytes());
+            input.extend_from_slice(&self.period.length.to_be_bytes());
+            input.extend_from_slice(&self.period.number.to_be_bytes());
+
+            let index = sha3_256(&input);
+
+            let start = self.relays.iter().position(|(relay_index, _)| *relay_index >= index).unwrap_or(0);
+
+            let mut taken = 0;
+
+            for offset in 0..self.relays.len() {
+                if taken == spread {
+                    break;
+                }
+
+                let position = (start + offset) % self.relays.len();
+
+                if !chosen.contains(&position) {
+                    chosen.push(position);
+                    taken += 1;
+                }
+            }
+        }
+
+        chosen.into_iter().map(|position| &self.relays[position].1).collect()
+    }
+}
+
+///Where the circuits of an onion service connection come from
+pub trait OnionPaths {
+    ///The HSDir ring for `period`, built from the current consensus
+    fn hsdir_ring(& self, period: TimePeriod) -> Result<HsDirRing>;
+
+    ///A circuit whose last hop is `relay`
+    fn circuit_to(& self, relay: &RelayInfo) -> Result<Circuit>;
+
+    ///A circuit to a rendezvous point we chose, and the relay it ends at
+    fn rendezvous_circuit(& self) -> Result<(Circuit, RelayInfo)>;
+}
+
+fn encode_extensions(extensions: &[HsExtension]) -> Vec<u8> {
+    let mut encoded = vec![extensions.len() as u8];
+
+    for extension in extensions {
+        encoded.push(extension.get_type());
+        encoded.push(extension.get_data().len() as u8);
+        encoded.extend_from_slice(extension.get_data());
+    }
+
+    encoded
+}
+
+///Parse N_EXTENSIONS | (EXT_FIELD_TYPE | EXT_FIELD_LEN | EXT_FIELD)*, returning the extensions and the bytes after them
+pub fn parse_extensions(encoded: &[u8]) -> Result<(Vec<HsExtension>, &[u8])> {
+    let truncated = || ErrorKind::InvalidDocument(String::from("truncated extensions"));
+
+    let count = *encoded.first().ok_or_else(truncated)?;
+
+    let mut extensions = Vec::new();
+
+    let mut offset = 1;
+
+    for _ in 0..count {
+        let header = encoded.get(offset..offset + 2).ok_or_else(truncated)?;
+        let data = encoded.get(offset + 2..offset + 2 + header[1] as usize).ok_or_else(truncated)?;
+
+        extensions.push(HsExtension::new(header[0], Vec::from(data)));
+
+        offset += 2 + header[1] as usize;
+    }
+
+    Ok((extensions, &encoded[offset..]))
+}
+
+///The body of INTRODUCE1, which the intro point relays to the service as INTRODUCE2 (rend-spec-v3 section 3.2.1)
+#[derive(Debug)]
+pub struct Introduce1 {
+    ///The service's authentication key for the intro point
+    pub auth_key: [u8; 32],
+    pub extensions: Vec<HsExtension>,
+    ///CLIENT_PK | ENCRYPTED_DATA | MAC from the hs-ntor handshake
+    pub encrypted: Vec<u8>,
+}
+
+impl Introduce1 {
+    ///LEGACY_KEY_ID | AUTH_KEY_TYPE | AUTH_KEY_LEN | AUTH_KEY | extensions, which the MAC of the encrypted part covers
+    pub fn header(auth_key: &[u8; 32], extensions: &[HsExtension]) -> Vec<u8> {
+        let mut header = vec![0u8; 20];
+
+        //AUTH_KEY_TYPE 2 is ed25519
+        header.push(2);
+        header.extend_from_slice(&32u16.to_be_bytes());
+        header.extend_from_slice(auth_key);
+        header.extend_from_slice(&encode_extensions(extensions));
+
+        header
+    }
+
+    pub fn to_bytes(& self) -> Vec<u8> {
+        let mut body = Self::header(&self.auth_key, &self.extensions);
+
+        body.extend_from_slice(&self.encrypted);
+
+        body
+    }
+
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let invalid = || ErrorKind::InvalidDocument(String::from("bad INTRODUCE1"));
+
+        if body.len() < 20 + 3 + 32 || body[20] != 2 || body[21..23] != 32u16.to_be_bytes() {
+            return Err(invalid());
+        }
+
+        let (extensions, encrypted) = parse_extensions(&body[55..])?;
+
+        Ok(Self {
+            auth_key: body[23..55].try_into().unwrap(),
+            extensions,
+            encrypted: Vec::from(encrypted),
+        })
+    }
+}
+
+///The plaintext of the encrypted part of INTRODUCE1
+#[derive(Debug)]
+pub struct IntroducePlaintext {
+    pub rendezvous_cookie: [u8; 20],
+    pub extensions: Vec<HsExtension>,
+    ///The rendezvous point's ntor onion key
+    pub onion_key: [u8; 32],
+    ///How to reach the rendezvous point
+    pub link_specifiers: Vec<LinkSpecifier>,
+}
+
+impl IntroducePlaintext {
+    ///Encode, padding with zeros so the whole INTRODUCE1 body fills a relay cell after a header of `header_length` bytes
+    pub fn to_bytes(& self, header_length: usize) -> Vec<u8> {
+        let mut plaintext = Vec::from(self.rendezvous_cookie);
+
+        plaintext.extend_from_slice(&encode_extensions(&self.extensions));
+
+        //ONION_KEY_TYPE 1 is ntor
+        plaintext.push(1);
+        plaintext.extend_from_slice(&32u16.to_be_bytes());
+        plaintext.extend_from_slice(&self.onion_key);
+        plaintext.extend_from_slice(&hs_descriptor::encode_link_specifiers(&self.link_specifiers));
+
+        //CLIENT_PK and MAC take 64 bytes of the body
+        let length = INTRODUCE1_LENGTH.saturating_sub(header_length + 64);
+
+        if plaintext.len() < length {
+            plaintext.resize(length, 0);
+        }
+
+        plaintext
+    }
+
+    pub fn parse(plaintext: &[u8]) -> Result<Self> {
+        let invalid = || ErrorKind::InvalidDocument(String::from("bad INTRODUCE2 plaintext"));
+
+        let rendezvous_cookie = plaintext.get(0..20).ok_or_else(invalid)?.try_into().unwrap();
+
+        let (extensions, rest) = parse_extensions(&plaintext[20..])?;
+
+        if rest.len() < 35 || rest[0] != 1 || rest[1..3] != 32u16.to_be_bytes() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            rendezvous_cookie,
+            extensions,
+            onion_key: rest[3..35].try_into().unwrap(),
+            link_specifiers: hs_descriptor::parse_link_specifiers(&rest[35..])?,
+        })
+    }
+}
+
+pub(crate) fn http_get(stream: & mut TorStream, path: &str) -> Result<Vec<u8>> {
+    http_request(stream, format!("GET {} HTTP/1.0\r\n\r\n", path).as_bytes())
+}
+
+pub(crate) fn http_post(stream: & mut TorStream, path: &str, body: &[u8]) -> Result<Vec<u8>> {
+    let mut request = Vec::from(format!("POST {} HTTP/1.0\r\nContent-Length: {}\r\n\r\n", path, body.len()));
+
+    request.extend_from_slice(body);
+
+    http_request(stream, &request)
+}
+
+///Send a request on a directory stream and read the whole HTTP/1.0 response, returning the body if the status was 200
+fn http_request(stream: & mut TorStream, request: &[u8]) -> Result<Vec<u8>> {
+    stream.write_all(request)?;
+
+    let mut response = Vec::new();
+
+    stream.read_to_end(& mut response)?;
+
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| ErrorKind::InvalidDocument(String::from("HTTP response has no body")))?;
+
+    let status = String::from_utf8_lossy(&response[..split]).lines().next().unwrap_or("").to_string();
+
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(ErrorKind::DescriptorUnavailable(status));
+    }
+
+    Ok(response.split_off(split + 4))
+}
+
+///Connects to onion services through circuits from an `OnionPaths`
+pub struct OnionClient<P: OnionPaths> {
+    paths: P,
+    client_auth: ClientAuthStore,
+    pow: PowConfig,
+}
+
+impl<P: OnionPaths> OnionClient<P> {
+    pub fn new(paths: P) -> Self {
+        Self::with_client_auth(paths, ClientAuthStore::new())
+    }
+
+    ///A client that uses the keys in `client_auth` for services that restrict discovery
+    pub fn with_client_auth(paths: P, client_auth: ClientAuthStore) -> Self {
+        Self {
+            paths,
+            client_auth,
+            pow: PowConfig::default(),
+        }
+    }
+
+    pub fn client_auth(& mut self) -> & mut ClientAuthStore {
+        & mut self.client_auth
+    }
+
+    ///How we solve the puzzles of services that ask for proof-of-work
+    pub fn pow_config(& mut self) -> & mut PowConfig {
+        & mut self.pow
+    }
+
+    ///Fetch the descriptor for `blinded_key` from the first responsible HSDir with a validly signed copy
+    pub fn fetch_descriptor(& self, blinded_key: &[u8; 32], period: TimePeriod) -> Result<HsDescriptor> {
+        let ring = self.paths.hsdir_ring(period)?;
+
+        let path = format!("/tor/hs/3/{}", netdoc::encode_base64(blinded_key));
+
+        let mut last_error = ErrorKind::DescriptorUnavailable(String::from("no responsible HSDirs"));
+
+        for hsdir in ring.responsible(blinded_key) {
+            let fetched = self.paths.circuit_to(hsdir)
+                .and_then(|circuit| http_get(& mut circuit.begin_dir()?, &path))
+                .and_then(|body| HsDescriptor::parse(&String::from_utf8_lossy(&body)))
+                .and_then(|descriptor| descriptor.verify(blinded_key, Utc::now()).map(|_| descriptor));
+
+            match fetched {
+                Ok(descriptor) => return Ok(descriptor),
+                Err(error) => last_error = error,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    ///The introduction points of the service at `address`. Fails with `ErrorKind::ClientAuthRequired` if the service
+    ///restricts discovery and we have no key for it, or `ErrorKind::ClientAuthRejected` if it didn't authorize our key
+    pub fn intro_points(& self, address: &OnionAddress, period: TimePeriod) -> Result<Vec<IntroPoint>> {
+        Ok(self.inner_layer(address, period)?.intro_points)
+    }
+
+    ///The introduction points of the service at `address` and the proof-of-work it asks for, failing as `intro_points` does
+    pub fn inner_layer(& self, address: &OnionAddress, period: TimePeriod) -> Result<InnerLayer> {
+        let blinded_key = address.blinded_key(period)?;
+
+        self.fetch_descriptor(&blinded_key, period)?.decrypt_inner(&blinded_key, &address.subcredential(period)?, self.client_auth.get(address))
+    }
+
+    ///Open a stream to `port` on the onion service at `address`. If the service asks for proof-of-work we solve its
+    ///puzzle, and introduce ourselves again with more effort if it doesn't meet us at the rendezvous point in time
+    pub fn connect(& self, address: &OnionAddress, port: u16) -> Result<TorStream> {
+        self.connect_in_period(address, port, TimePeriod::current())
+    }
+
+    ///`connect`, using the service's descriptor for `period`, as a client whose consensus is already in the next period would
+    pub fn connect_in_period(& self, address: &OnionAddress, port: u16, period: TimePeriod) -> Result<TorStream> {
+        let inner = self.inner_layer(address, period)?;
+
+        let subcredential = address.subcredential(period)?;
+
+        let pow_params = match inner.pow_params {
+            Some(params) if !params.expired_at(Utc::now()) => params,
+            _ => return self.introduce_and_rendezvous(&inner.intro_points, &subcredential, None, port, None),
+        };
+
+        let blinded_key = address.blinded_key(period)?;
+
+        let mut effort = self.pow.initial_effort(pow_params.suggested_effort);
+
+        let mut last_error = ErrorKind::IntroductionFailed;
+
+        for attempt in 0..self.pow.attempts.max(1) {
+            if attempt > 0 {
+                effort = pow::next_effort(effort, self.pow.max_effort);
+            }
+
+            //An effort of zero needs no proof
+            let solution = if effort > 0 {
+                Some(pow::solve(&pow_params, &blinded_key, effort, self.pow.threads, self.pow.solve_timeout)?)
+            } else {
+                None
+            };
+
+            match self.introduce_and_rendezvous(&inner.intro_points, &subcredential, solution, port, Some(self.pow.rendezvous_timeout)) {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = error,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    ///Set up a rendezvous point, introduce ourselves through the first intro point that relays it and open the stream
+    ///once the service arrives, giving up after `timeout` if there is one
+    fn introduce_and_rendezvous(& self, intro_points: &[IntroPoint], subcredential: &[u8; 32], solution: Option<PowSolution>, port: u16, timeout: Option<Duration>) -> Result<TorStream> {
+        let (rendezvous, rendezvous_point) = self.paths.rendezvous_circuit()?;
+
+        rendezvous.start_padding(PaddingMachine::client_rendezvous())?;
+
+        let mut rendezvous_cookie = [0u8; 20];
+
+        CSRNG.fill(& mut rendezvous_cookie).unwrap();
+
+        rendezvous.send_control(RelayCell::new(0, Relay::EstablishRendezvous { rendezvous_cookie }))?;
+
+        loop {
+            let reply = rendezvous.receive_control()?;
+
+            match reply.get_command() {
+                39 => break,
+                5 => {},
+                _ => return Err(ErrorKind::UnexpectedCell(format!("{:?}", reply.get_payload()))),
+            }
+        }
+
+        let plaintext = IntroducePlaintext {
+            rendezvous_cookie,
+            extensions: solution.iter().map(|solution| solution.to_extension()).collect(),
+            onion_key: rendezvous_point.ntor_onion_key,
+            link_specifiers: rendezvous_point.link_specifiers.clone(),
+        };
+
+        for intro_point in intro_points {
+            if let Some(ntor) = self.introduce(intro_point, &plaintext, subcredential) {
+                return self.rendezvous(&rendezvous, &ntor, port, timeout);
+            }
+        }
+
+        Err(ErrorKind::IntroductionFailed)
+    }
+
+    ///Send INTRODUCE1 through `intro_point`, returning the handshake to finish at the rendezvous point if it was relayed
+    fn introduce(& self, intro_point: &IntroPoint, plaintext: &IntroducePlaintext, subcredential: &[u8; 32]) -> Option<HsNtorClient> {
+        let relay = RelayInfo::from_intro_point(intro_point)?;
+
+        let circuit = self.paths.circuit_to(&relay).ok()?;
+
+        circuit.start_padding(PaddingMachine::client_intro()).ok()?;
+
+        let ntor = HsNtorClient::new(&intro_point.auth_key, &intro_point.enc_key, subcredential);
+
+        let header = Introduce1::header(&intro_point.auth_key, &[]);
+
+        let introduce1 = Introduce1 {
+            auth_key: intro_point.auth_key,
+            extensions: Vec::new(),
+            encrypted: ntor.encrypt(&header, &plaintext.to_bytes(header.len()))?,
+        };
+
+        circuit.send_control(RelayCell::new_raw(34, 0, introduce1.to_bytes())).ok()?;
+
+        loop {
+            let reply = circuit.receive_control().ok()?;
+
+            match reply.get_payload() {
+                Ok(Some(Relay::IntroduceAck { status, .. })) if status == INTRODUCE_ACK_SUCCESS => return Some(ntor),
+                Ok(Some(Relay::SendMe { .. })) => {},
+                _ => return None,
+            }
+        }
+    }
+
+    ///Wait for RENDEZVOUS2, add the service as a virtual hop and open the stream
+    fn rendezvous(& self, rendezvous: &Circuit, ntor: &HsNtorClient, port: u16, timeout: Option<Duration>) -> Result<TorStream> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let reply = match deadline {
+                Some(deadline) => rendezvous.receive_control_timeout(deadline.saturating_duration_since(Instant::now()))?,
+                None => rendezvous.receive_control()?,
+            };
+
+            match reply.get_payload()? {
+                Some(Relay::Rendezvous2 { handshake_info }) => {
+                    let materials = ntor.complete(&handshake_info).ok_or(ErrorKind::HandshakeFailed)?;
+
+                    rendezvous.add_virtual_hop(CellCrypto::onion_service(&materials));
+
+                    //Onion services ignore the address part of BEGIN
+                    return rendezvous.begin("", port);
+                },
+                Some(Relay::SendMe { .. }) => {},
+                payload => return Err(ErrorKind::UnexpectedCell(format!("{:?}", payload))),
+            }
+        }
+    }
+}
@@ -0,0 +1,899 @@
+//! Hosting version 3 onion services (rend-spec-v3).
+//!
+//! A service is an ed25519 identity key, from which its address and the blinded key of each time period are derived. On
+//! launch the service builds circuits to a few introduction points and registers an authentication key with each using
+//! ESTABLISH_INTRO, then publishes a descriptor listing them to the HSDirs responsible for its blinded key.
+//!
+//! A client's INTRODUCE1 reaches the service as INTRODUCE2 on an intro circuit. The service finishes the hs-ntor
+//! handshake, builds a circuit to the client's rendezvous point and sends RENDEZVOUS1, after which that circuit has a
+//! virtual hop to the client. Streams the client opens on it are handed to a `StreamHandler`.
+//!
+//! With `PowDefenses` the descriptor asks clients for proof-of-work. Introductions with invalid or replayed solutions
+//! are dropped and the rest wait in a queue, from which the service answers those with the most effort first.
+//!
+//! As with the client side, path selection and the consensus are not part of this module, so circuits and the HSDir ring
+//! come from a `ServicePaths`.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
+
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::ExpandedSecretKey;
+use sha2::{Digest, Sha512};
+use x25519_dalek::{StaticSecret, PublicKey};
+
+use crate::cells::{Relay, RelayCell, HsExtension, LinkSpecifier};
+use crate::cellcrypto::CellCrypto;
+use crate::circuit::{Circuit, TorStream};
+use crate::custom_crypto::{hs_mac, constant_time_eq, ed25519_verify};
+use crate::hs_descriptor::{self, Ed25519Keypair, IntroPoint, CERT_TYPE_INTRO_AUTH, CERT_TYPE_INTRO_ENC, DESCRIPTOR_CERT_LIFETIME};
+use crate::hs_ntor::{self, HS_KEY_MATERIAL_LENGTH};
+use crate::ntor::generate_secret;
+use crate::onion::{self, OnionAddress, OnionPaths, TimePeriod, RelayInfo, Introduce1, IntroducePlaintext};
+use crate::pow::{PowParams, PowSolution, PowVerifier, IntroQueue, EXTENSION_POW};
+use crate::error::{ErrorKind, Result};
+
+const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+const HOSTNAME_FILE: &str = "hostname";
+
+///Tor's key files start with a tag padded with NULs to 32 bytes
+const SECRET_KEY_TAG: &[u8] = b"== ed25519v1-secret: type0 ==";
+const PUBLIC_KEY_TAG: &[u8] = b"== ed25519v1-public: type0 ==";
+const KEY_FILE_HEADER_LENGTH: usize = 32;
+
+///Hashed with the identity key's hash prefix to give the blinded key's
+const BLIND_PREFIX_STRING: &[u8] = b"Derive temporary signing key hash input";
+
+///Prefixed to ESTABLISH_INTRO before it is signed
+const ESTABLISH_INTRO_SIG_PREFIX: &[u8] = b"Tor establish-intro cell v1";
+
+///The ESTABLISH_INTRO extension carrying the intro point's DoS defence parameters
+pub const EXTENSION_DOS_PARAMS: u8 = 1;
+
+///DoS parameter for the INTRODUCE2 cells per second the intro point lets through
+pub const DOS_PARAM_RATE_PER_SEC: u8 = 1;
+
+///DoS parameter for the burst of INTRODUCE2 cells the intro point lets through
+pub const DOS_PARAM_BURST_PER_SEC: u8 = 2;
+
+fn key_file_header(tag: &[u8]) -> Vec<u8> {
+    let mut header = Vec::from(tag);
+
+    header.resize(KEY_FILE_HEADER_LENGTH, 0);
+
+    header
+}
+
+///The identity key of an onion service, which is its address
+pub struct OnionServiceKeys {
+    identity: Ed25519Keypair,
+}
+
+impl OnionServiceKeys {
+    pub fn generate() -> Self {
+        Self {
+            identity: Ed25519Keypair::generate(),
+        }
+    }
+
+    ///Use an identity key in the 64 byte expanded form Tor stores
+    pub fn from_expanded_secret(secret: &[u8]) -> Result<Self> {
+        let secret = ExpandedSecretKey::from_bytes(secret).map_err(|_| ErrorKind::InvalidKeyFile(String::from("bad expanded ed25519 key")))?;
+
+        Ok(Self {
+            identity: Ed25519Keypair::from_expanded(secret),
+        })
+    }
+
+    ///Read the identity key from `hs_ed25519_secret_key` in a directory laid out like Tor's `HiddenServiceDir`
+    pub fn load(directory: &Path) -> Result<Self> {
+        let contents = fs::read(directory.join(SECRET_KEY_FILE))?;
+
+        if contents.len() != KEY_FILE_HEADER_LENGTH + 64 || contents[..KEY_FILE_HEADER_LENGTH] != key_file_header(SECRET_KEY_TAG)[..] {
+            return Err(ErrorKind::InvalidKeyFile(format!("{} is not an ed25519v1 secret key", SECRET_KEY_FILE)));
+        }
+
+        Self::from_expanded_secret(&contents[KEY_FILE_HEADER_LENGTH..])
+    }
+
+    ///Write the secret and public keys and the hostname to `directory` in Tor's formats, creating it if needed
+    pub fn save(& self, directory: &Path) -> Result<()> {
+        fs::create_dir_all(directory)?;
+
+        let mut secret = key_file_header(SECRET_KEY_TAG);
+
+        secret.extend_from_slice(&self.identity.secret.to_bytes());
+
+        let mut public = key_file_header(PUBLIC_KEY_TAG);
+
+        public.extend_from_slice(&self.identity.public_bytes());
+
+        fs::write(directory.join(SECRET_KEY_FILE), secret)?;
+        fs::write(directory.join(PUBLIC_KEY_FILE), public)?;
+        fs::write(directory.join(HOSTNAME_FILE), format!("{}\n", self.address()))?;
+
+        Ok(())
+    }
+
+    ///Load the keys in `directory`, or generate and save new ones if there are none yet
+    pub fn load_or_generate(directory: &Path) -> Result<Self> {
+        if directory.join(SECRET_KEY_FILE).exists() {
+            return Self::load(directory);
+        }
+
+        let keys = Self::generate();
+
+        keys.save(directory)?;
+
+        Ok(keys)
+    }
+
+    pub fn address(& self) -> OnionAddress {
+        OnionAddress::from_public_key(self.identity.public_bytes())
+    }
+
+    ///The blinded key for `period`, which can sign in place of the identity key (rend-spec-v3 section A.2)
+    pub fn blinded(& self, period: TimePeriod) -> Ed25519Keypair {
+        let expanded = self.identity.secret.to_bytes();
+
+        let factor = Scalar::from_bits(self.address().blinding_factor(period));
+        let scalar = Scalar::from_bits(expanded[0..32].try_into().unwrap());
+
+        let mut hasher = Sha512::new();
+
+        hasher.update(BLIND_PREFIX_STRING);
+        hasher.update(&expanded[32..64]);
+
+        let mut blinded = [0u8; 64];
+
+        blinded[0..32].copy_from_slice((factor * scalar).as_bytes());
+        blinded[32..64].copy_from_slice(&hasher.finalize()[0..32]);
+
+        Ed25519Keypair::from_expanded(ExpandedSecretKey::from_bytes(&blinded).unwrap())
+    }
+}
+
+///The INTRODUCE2 rate limit an intro point enforces for the service (rend-spec-v3 section 3.1.1.1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DosParams {
+    pub rate_per_sec: u64,
+    pub burst_per_sec: u64,
+}
+
+impl DosParams {
+    pub fn to_extension(self) -> HsExtension {
+        let mut data = vec![2];
+
+        data.push(DOS_PARAM_RATE_PER_SEC);
+        data.extend_from_slice(&self.rate_per_sec.to_be_bytes());
+        data.push(DOS_PARAM_BURST_PER_SEC);
+        data.extend_from_slice(&self.burst_per_sec.to_be_bytes());
+
+        HsExtension::new(EXTENSION_DOS_PARAMS, data)
+    }
+
+    ///Read the parameters from a DoS extension, returning `None` if either is missing
+    pub fn from_extension(extension: &HsExtension) -> Option<Self> {
+        let data = extension.get_data();
+
+        let count = *data.first()? as usize;
+
+        let mut rate_per_sec = None;
+        let mut burst_per_sec = None;
+
+        for param in data[1..].chunks(9).take(count) {
+            let value = u64::from_be_bytes(param.get(1..9)?.try_into().unwrap());
+
+            match param[0] {
+                DOS_PARAM_RATE_PER_SEC => rate_per_sec = Some(value),
+                DOS_PARAM_BURST_PER_SEC => burst_per_sec = Some(value),
+                _ => {},
+            }
+        }
+
+        Some(Self {
+            rate_per_sec: rate_per_sec?,
+            burst_per_sec: burst_per_sec?,
+        })
+    }
+}
+
+///The body of ESTABLISH_INTRO, which registers a service's authentication key with an intro point (rend-spec-v3 section 3.1.1)
+#[derive(Debug)]
+pub struct EstablishIntro {
+    pub auth_key: [u8; 32],
+    pub extensions: Vec<HsExtension>,
+    ///A MAC keyed with KH of the circuit, so the cell can't be replayed on another circuit
+    handshake_auth: [u8; 32],
+    signature: [u8; 64],
+}
+
+impl EstablishIntro {
+    ///AUTH_KEY_TYPE | AUTH_KEY_LEN | AUTH_KEY | extensions, which HANDSHAKE_AUTH covers
+    fn header(auth_key: &[u8; 32], extensions: &[HsExtension]) -> Vec<u8> {
+        //AUTH_KEY_TYPE 2 is ed25519
+        let mut header = vec![2];
+
+        header.extend_from_slice(&32u16.to_be_bytes());
+        header.extend_from_slice(auth_key);
+        header.push(extensions.len() as u8);
+
+        for extension in extensions {
+            header.push(extension.get_type());
+            header.push(extension.get_data().len() as u8);
+            header.extend_from_slice(extension.get_data());
+        }
+
+        header
+    }
+
+    ///Everything the signature covers, which is the cell up to and including SIG_LEN
+    fn signed(& self) -> Vec<u8> {
+        let mut signed = Self::header(&self.auth_key, &self.extensions);
+
+        signed.extend_from_slice(&self.handshake_auth);
+        signed.extend_from_slice(&64u16.to_be_bytes());
+
+        signed
+    }
+
+    ///Build the cell for a circuit whose last hop's KH is `nonce`
+    pub fn new(auth: &Ed25519Keypair, extensions: Vec<HsExtension>, nonce: &[u8; 20]) -> Self {
+        let auth_key = auth.public_bytes();
+
+        let mut establish = Self {
+            auth_key,
+            handshake_auth: hs_mac(nonce, &Self::header(&auth_key, &extensions)),
+            extensions,
+            signature: [0u8; 64],
+        };
+
+        let mut signed = Vec::from(ESTABLISH_INTRO_SIG_PREFIX);
+
+        signed.extend_from_slice(&establish.signed());
+
+        establish.signature = auth.sign(&signed);
+
+        establish
+    }
+
+    pub fn to_bytes(& self) -> Vec<u8> {
+        let mut body = self.signed();
+
+        body.extend_from_slice(&self.signature);
+
+        body
+    }
+
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let invalid = || ErrorKind::InvalidDocument(String::from("bad ESTABLISH_INTRO"));
+
+        if body.len() < 3 + 32 || body[0] != 2 || body[1..3] != 32u16.to_be_bytes() {
+            return Err(invalid());
+        }
+
+        let (extensions, rest) = onion::parse_extensions(&body[35..])?;
+
+        if rest.len() != 32 + 2 + 64 || rest[32..34] != 64u16.to_be_bytes() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            auth_key: body[3..35].try_into().unwrap(),
+            extensions,
+            handshake_auth: rest[0..32].try_into().unwrap(),
+            signature: rest[34..98].try_into().unwrap(),
+        })
+    }
+
+    ///Whether the cell was made for the circuit with KH `nonce` and signed by its authentication key
+    pub fn verify(& self, nonce: &[u8; 20]) -> bool {
+        let mut signed = Vec::from(ESTABLISH_INTRO_SIG_PREFIX);
+
+        signed.extend_from_slice(&self.signed());
+
+        constant_time_eq(&hs_mac(nonce, &Self::header(&self.auth_key, &self.extensions)), &self.handshake_auth)
+            && ed25519_verify(&self.auth_key, &signed, &self.signature)
+    }
+
+    ///The DoS parameters the service asked for, if any
+    pub fn dos_params(& self) -> Option<DosParams> {
+        self.extensions.iter().find(|extension| extension.get_type() == EXTENSION_DOS_PARAMS).and_then(DosParams::from_extension)
+    }
+}
+
+///Settings for an `OnionService`
+#[derive(Debug, Clone)]
+pub struct ServiceConfig {
+    ///How many introduction points to keep
+    pub intro_points: usize,
+    ///The rate limit to ask intro points to enforce, if any
+    pub dos_params: Option<DosParams>,
+    ///The x25519 keys of the clients allowed to read the descriptor. Anyone can read it if this is empty
+    pub authorized_clients: Vec<PublicKey>,
+    ///Ask clients for proof-of-work, if set
+    pub pow: Option<PowDefenses>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            intro_points: 3,
+            dos_params: None,
+            authorized_clients: Vec::new(),
+            pow: None,
+        }
+    }
+}
+
+///How a service asks for and ranks proof-of-work (prop 327)
+#[derive(Debug, Clone)]
+pub struct PowDefenses {
+    ///The effort the descriptor suggests. Introductions with less are still answered, after those with more
+    pub suggested_effort: u32,
+    ///How long each seed is used before a new one is published
+    pub seed_lifetime: Duration,
+    ///How many introductions can wait to be answered. When full, the one with the least effort is dropped
+    pub max_queued: usize,
+}
+
+impl Default for PowDefenses {
+    fn default() -> Self {
+        Self {
+            suggested_effort: 0,
+            seed_lifetime: Duration::from_secs(2 * 60 * 60),
+            max_queued: 2500,
+        }
+    }
+}
+
+///What to do with the streams clients open to the service
+#[derive(Clone)]
+pub enum StreamHandler {
+    ///Call a function with each stream and the port the client asked for. It is called on the rendezvous circuit's
+    ///thread, so it should hand long running work to another thread
+    Callback(Arc<dyn Fn(TorStream, u16) + Send + Sync>),
+    ///Connect each stream to a local TCP address, whatever port the client asked for
+    Forward(SocketAddr),
+}
+
+///Where the circuits of an onion service come from
+pub trait ServicePaths: OnionPaths {
+    ///A circuit to an introduction point we chose, and the relay it ends at
+    fn intro_circuit(& self) -> Result<(Circuit, RelayInfo)>;
+}
+
+///An introduction point the service has established
+struct ServiceIntroPoint {
+    circuit: Arc<Circuit>,
+    relay: RelayInfo,
+    auth: Ed25519Keypair,
+    enc_secret: StaticSecret,
+}
+
+///An introduction that passed its proof-of-work check, waiting for the service to meet the client
+struct QueuedIntroduction {
+    plaintext: IntroducePlaintext,
+    reply: [u8; 64],
+    materials: [u8; HS_KEY_MATERIAL_LENGTH],
+}
+
+///The proof-of-work state of a service with `PowDefenses`
+struct ServicePow {
+    defenses: PowDefenses,
+    verifier: Mutex<PowVerifier>,
+    ///The blinded keys of the periods descriptors are published for, one of which each solution is bound to
+    blinded_keys: Mutex<HashMap<u64, [u8; 32]>>,
+    queue: Mutex<IntroQueue<QueuedIntroduction>>,
+    queued: Condvar,
+    stopped: AtomicBool,
+}
+
+impl ServicePow {
+    fn new(defenses: PowDefenses) -> Self {
+        Self {
+            verifier: Mutex::new(PowVerifier::new(new_pow_params(&defenses))),
+            blinded_keys: Mutex::new(HashMap::new()),
+            queue: Mutex::new(IntroQueue::new(defenses.max_queued)),
+            queued: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            defenses,
+        }
+    }
+
+    ///The effort of an introduction, which is zero without a solution, or `None` if its solution is no good
+    fn effort(& self, plaintext: &IntroducePlaintext) -> Option<u32> {
+        let extension = match plaintext.extensions.iter().find(|extension| extension.get_type() == EXTENSION_POW) {
+            Some(extension) => extension,
+            None => return Some(0),
+        };
+
+        let solution = PowSolution::from_extension(extension)?;
+
+        let blinded_keys: Vec<[u8; 32]> = self.blinded_keys.lock().unwrap().values().copied().collect();
+
+        let mut verifier = self.verifier.lock().unwrap();
+
+        blinded_keys.iter().find_map(|blinded_key| verifier.check(&solution, blinded_key))
+    }
+
+    fn enqueue(& self, effort: u32, introduction: QueuedIntroduction) {
+        if self.queue.lock().unwrap().push(effort, introduction) {
+            self.queued.notify_one();
+        }
+    }
+
+    ///The introduction with the most effort, waiting for one if the queue is empty. `None` once the service stops
+    fn next(& self) -> Option<QueuedIntroduction> {
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            if let Some((_, introduction)) = queue.pop() {
+                return Some(introduction);
+            }
+
+            queue = self.queued.wait(queue).unwrap();
+        }
+    }
+
+    fn stop(& self) {
+        //Hold the queue lock so the dispatcher is either waiting or will see the flag
+        let _queue = self.queue.lock().unwrap();
+
+        self.stopped.store(true, Ordering::SeqCst);
+        self.queued.notify_all();
+    }
+}
+
+fn new_pow_params(defenses: &PowDefenses) -> PowParams {
+    PowParams::generate(defenses.suggested_effort, chrono::Duration::from_std(defenses.seed_lifetime).unwrap_or_else(|_| chrono::Duration::hours(2)))
+}
+
+struct ServiceShared<P> {
+    paths: P,
+    handler: StreamHandler,
+    ///The subcredentials of the periods descriptors are published for, by period number. INTRODUCE2 may use any of them
+    subcredentials: Mutex<HashMap<u64, [u8; 32]>>,
+    pow: Option<ServicePow>,
+}
+
+///A running onion service. Dropping it closes the introduction circuits, but streams already open keep going
+pub struct OnionService<P: ServicePaths + Send + Sync + 'static> {
+    keys: OnionServiceKeys,
+    config: ServiceConfig,
+    shared: Arc<ServiceShared<P>>,
+    intro_points: Mutex<Vec<ServiceIntroPoint>>,
+    authorized_clients: Mutex<Vec<PublicKey>>,
+    ///The current period when descriptors were last published
+    published: Mutex<Option<TimePeriod>>,
+}
+
+impl<P: ServicePaths + Send + Sync + 'static> OnionService<P> {
+    ///Establish the introduction points and publish the service's first descriptors
+    pub fn launch(keys: OnionServiceKeys, config: ServiceConfig, handler: StreamHandler, paths: P) -> Result<Self> {
+        let pow = config.pow.clone().map(ServicePow::new);
+
+        let service = Self {
+            keys,
+            authorized_clients: Mutex::new(config.authorized_clients.clone()),
+            config,
+            shared: Arc::new(ServiceShared {
+                paths,
+                handler,
+                subcredentials: Mutex::new(HashMap::new()),
+                pow,
+            }),
+            intro_points: Mutex::new(Vec::new()),
+            published: Mutex::new(None),
+        };
+
+        if service.shared.pow.is_some() {
+            let shared = service.shared.clone();
+
+            thread::spawn(move || dispatch_introductions(shared));
+        }
+
+        service.maintain()?;
+
+        Ok(service)
+    }
+
+    pub fn address(& self) -> OnionAddress {
+        self.keys.address()
+    }
+
+    ///Replace introduction points whose circuits closed, then publish new descriptors if they changed or a new time
+    ///period began. Call this every few minutes
+    pub fn maintain(& self) -> Result<()> {
+        let mut intro_points = self.intro_points.lock().unwrap();
+
+        let count = intro_points.len();
+
+        intro_points.retain(|intro_point| !intro_point.circuit.is_closed());
+
+        let mut changed = intro_points.len() != count;
+
+        let mut last_error = None;
+
+        //Give up after a few failures for each missing intro point, rather than building circuits forever
+        for _ in 0..(self.config.intro_points - intro_points.len()) * 3 {
+            if intro_points.len() == self.config.intro_points {
+                break;
+            }
+
+            match self.establish_intro() {
+                Ok(intro_point) => {
+                    intro_points.push(intro_point);
+                    changed = true;
+                },
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        if intro_points.is_empty() {
+            return Err(last_error.unwrap_or(ErrorKind::IntroductionFailed));
+        }
+
+        if let Some(pow) = &self.shared.pow {
+            let mut verifier = pow.verifier.lock().unwrap();
+
+            if verifier.params().expired_at(chrono::Utc::now()) {
+                verifier.rotate(new_pow_params(&pow.defenses));
+                changed = true;
+            }
+        }
+
+        if changed || *self.published.lock().unwrap() != Some(TimePeriod::current()) {
+            self.publish_periods(&intro_points)?;
+        }
+
+        Ok(())
+    }
+
+    ///Publish descriptors for the current and the next time period, each to the HSDirs that should store it, returning
+    ///how many accepted them. Clients whose consensus has moved on to the next period look for the second
+    pub fn publish(& self) -> Result<usize> {
+        let intro_points = self.intro_points.lock().unwrap();
+
+        self.publish_periods(&intro_points)
+    }
+
+    ///The proof-of-work parameters in the published descriptor, if the service asks for it
+    pub fn pow_params(& self) -> Option<PowParams> {
+        self.shared.pow.as_ref().map(|pow| pow.verifier.lock().unwrap().params().clone())
+    }
+
+    ///The keys of the clients allowed to read the descriptor
+    pub fn authorized_clients(& self) -> Vec<PublicKey> {
+        self.authorized_clients.lock().unwrap().clone()
+    }
+
+    ///Allow the client with `client_key` to read the descriptor, publishing a new one that includes it
+    pub fn authorize_client(& self, client_key: PublicKey) -> Result<usize> {
+        {
+            let mut clients = self.authorized_clients.lock().unwrap();
+
+            if !clients.contains(&client_key) {
+                clients.push(client_key);
+            }
+        }
+
+        self.publish()
+    }
+
+    ///Stop the client with `client_key` reading the descriptor, publishing a new one without it. Clients that already
+    ///have a descriptor can still use it until its intro points change. Revoking the last client lets anyone read it
+    pub fn revoke_client(& self, client_key: &PublicKey) -> Result<usize> {
+        self.authorized_clients.lock().unwrap().retain(|client| client != client_key);
+
+        self.publish()
+    }
+
+    ///Close the introduction circuits, so no new clients can reach the service
+    pub fn shutdown(& self) {
+        for intro_point in self.intro_points.lock().unwrap().drain(..) {
+            intro_point.circuit.destroy();
+        }
+
+        if let Some(pow) = &self.shared.pow {
+            pow.stop();
+        }
+    }
+
+    ///Build a circuit to an intro point, send ESTABLISH_INTRO and start answering its INTRODUCE2 cells
+    fn establish_intro(& self) -> Result<ServiceIntroPoint> {
+        let (circuit, relay) = self.shared.paths.intro_circuit()?;
+
+        let nonce = circuit.last_hop_nonce().ok_or(ErrorKind::HandshakeFailed)?;
+
+        let auth = Ed25519Keypair::generate();
+
+        let extensions = self.config.dos_params.iter().map(|params| params.to_extension()).collect();
+
+        circuit.send_control(RelayCell::new_raw(32, 0, EstablishIntro::new(&auth, extensions, &nonce).to_bytes()))?;
+
+        loop {
+            let reply = circuit.receive_control()?;
+
+            match reply.get_payload()? {
+                Some(Relay::IntroEstablished { .. }) => break,
+                Some(Relay::SendMe { .. }) => {},
+                payload => return Err(ErrorKind::UnexpectedCell(format!("{:?}", payload))),
+            }
+        }
+
+        let intro_point = ServiceIntroPoint {
+            circuit: Arc::new(circuit),
+            relay,
+            auth,
+            enc_secret: generate_secret(),
+        };
+
+        let shared = self.shared.clone();
+        let circuit = intro_point.circuit.clone();
+        let auth_key = intro_point.auth.public_bytes();
+        let enc_secret = intro_point.enc_secret.clone();
+
+        thread::spawn(move || serve_intro_point(shared, circuit, auth_key, enc_secret));
+
+        Ok(intro_point)
+    }
+
+    fn publish_periods(& self, intro_points: &[ServiceIntroPoint]) -> Result<usize> {
+        let period = TimePeriod::current();
+
+        let accepted = self.publish_with(intro_points, period)? + self.publish_with(intro_points, period.next())?;
+
+        *self.published.lock().unwrap() = Some(period);
+
+        Ok(accepted)
+    }
+
+    ///Publish a descriptor for `period` to every HSDir that should store it, returning how many accepted it
+    fn publish_with(& self, intro_points: &[ServiceIntroPoint], period: TimePeriod) -> Result<usize> {
+        let address = self.keys.address();
+
+        let blinded = self.keys.blinded(period);
+        let subcredential = address.subcredential(period)?;
+
+        let signing = Ed25519Keypair::generate();
+
+        let expiration = (chrono::Utc::now().timestamp() / 3600) as u32 + DESCRIPTOR_CERT_LIFETIME;
+
+        let entries: Vec<IntroPoint> = intro_points.iter()
+            .map(|intro_point| {
+                let enc_key = *PublicKey::from(&intro_point.enc_secret).as_bytes();
+
+                //The encryption key is certified in its ed25519 form, with the sign bit clear
+                let enc_ed25519 = MontgomeryPoint(enc_key).to_edwards(0).unwrap().compress().to_bytes();
+
+                IntroPoint {
+                    link_specifiers: intro_point.relay.link_specifiers.clone(),
+                    onion_key: intro_point.relay.ntor_onion_key,
+                    auth_key: intro_point.auth.public_bytes(),
+                    auth_key_cert: signing.certify(CERT_TYPE_INTRO_AUTH, expiration, &intro_point.auth.public_bytes()),
+                    enc_key,
+                    enc_key_cert: signing.certify(CERT_TYPE_INTRO_ENC, expiration, &enc_ed25519),
+                }
+            })
+            .collect();
+
+        //Seconds are fine grained enough that each upload has a higher revision counter than the last
+        let revision_counter = chrono::Utc::now().timestamp() as u64;
+
+        let authorized_clients = self.authorized_clients();
+
+        let pow_params = self.pow_params();
+
+        let document = hs_descriptor::encode_descriptor(&blinded, &signing, &subcredential, revision_counter, &entries, &authorized_clients, pow_params.as_ref());
+
+        let ring = self.shared.paths.hsdir_ring(period)?;
+
+        let blinded_key = blinded.public_bytes();
+
+        let mut accepted = 0;
+        let mut last_error = ErrorKind::DescriptorUnavailable(String::from("no HSDirs to upload to"));
+
+        for hsdir in ring.store_targets(&blinded_key) {
+            let uploaded = self.shared.paths.circuit_to(hsdir)
+                .and_then(|circuit| onion::http_post(& mut circuit.begin_dir()?, "/tor/hs/3/publish", document.as_bytes()));
+
+            match uploaded {
+                Ok(_) => accepted += 1,
+                Err(error) => last_error = error,
+            }
+        }
+
+        if accepted == 0 {
+            return Err(last_error);
+        }
+
+        //Nothing before the period ahead of `period` is still in use
+        let mut subcredentials = self.shared.subcredentials.lock().unwrap();
+
+        subcredentials.retain(|number, _| *number + 1 >= period.number);
+        subcredentials.insert(period.number, subcredential);
+
+        if let Some(pow) = &self.shared.pow {
+            let mut blinded_keys = pow.blinded_keys.lock().unwrap();
+
+            blinded_keys.retain(|number, _| *number + 1 >= period.number);
+            blinded_keys.insert(period.number, blinded_key);
+        }
+
+        Ok(accepted)
+    }
+}
+
+impl<P: ServicePaths + Send + Sync + 'static> Drop for OnionService<P> {
+    fn drop(& mut self) {
+        self.shutdown();
+    }
+}
+
+///Answer the INTRODUCE2 cells arriving on an intro circuit until it closes
+fn serve_intro_point<P: ServicePaths + Send + Sync + 'static>(shared: Arc<ServiceShared<P>>, circuit: Arc<Circuit>, auth_key: [u8; 32], enc_secret: StaticSecret) {
+    //CLIENT_PK is fresh for every introduction, so one we've seen before is a replay
+    let mut seen: HashSet<[u8; 32]> = HashSet::new();
+
+    while let Ok(cell) = circuit.receive_control() {
+        if cell.get_command() != 35 {
+            continue;
+        }
+
+        let body = cell.into_data();
+
+        let introduce = match Introduce1::parse(&body) {
+            Ok(introduce) if introduce.auth_key == auth_key && introduce.encrypted.len() >= 32 => introduce,
+            _ => continue,
+        };
+
+        if !seen.insert(introduce.encrypted[0..32].try_into().unwrap()) {
+            continue;
+        }
+
+        let header = &body[..body.len() - introduce.encrypted.len()];
+
+        //The client may have used the descriptor of either period we published for
+        let subcredentials: Vec<[u8; 32]> = shared.subcredentials.lock().unwrap().values().copied().collect();
+
+        let handshake = subcredentials.iter().find_map(|subcredential| hs_ntor::service_handshake(&auth_key, &enc_secret, subcredential, header, &introduce.encrypted));
+
+        if let Some((plaintext, reply, materials)) = handshake {
+            if let Ok(plaintext) = IntroducePlaintext::parse(&plaintext) {
+                match &shared.pow {
+                    Some(pow) => {
+                        if let Some(effort) = pow.effort(&plaintext) {
+                            pow.enqueue(effort, QueuedIntroduction { plaintext, reply, materials });
+                        }
+                    },
+                    None => {
+                        let shared = shared.clone();
+
+                        thread::spawn(move || {
+                            let _ = rendezvous(&shared, &plaintext, &reply, &materials);
+                        });
+                    },
+                }
+            }
+        }
+    }
+}
+
+///Answer queued introductions one at a time, highest effort first, until the service shuts down. Only meeting the client
+///waits its turn, as its streams are served on their own thread
+fn dispatch_introductions<P: ServicePaths + Send + Sync + 'static>(shared: Arc<ServiceShared<P>>) {
+    let pow = match &shared.pow {
+        Some(pow) => pow,
+        None => return,
+    };
+
+    while let Some(introduction) = pow.next() {
+        if let Ok(circuit) = meet(&shared, &introduction.plaintext, &introduction.reply, &introduction.materials) {
+            let shared = shared.clone();
+
+            thread::spawn(move || {
+                let _ = serve_streams(&shared, &circuit);
+            });
+        }
+    }
+}
+
+///Meet the client at its rendezvous point and serve the streams it opens
+fn rendezvous<P: ServicePaths>(shared: &ServiceShared<P>, plaintext: &IntroducePlaintext, reply: &[u8; 64], materials: &[u8; HS_KEY_MATERIAL_LENGTH]) -> Result<()> {
+    let circuit = meet(shared, plaintext, reply, materials)?;
+
+    serve_streams(shared, &circuit)
+}
+
+///Build a circuit to the client's rendezvous point and send RENDEZVOUS1, returning the circuit with its virtual hop
+fn meet<P: ServicePaths>(shared: &ServiceShared<P>, plaintext: &IntroducePlaintext, reply: &[u8; 64], materials: &[u8; HS_KEY_MATERIAL_LENGTH]) -> Result<Circuit> {
+    let link_specifiers: Vec<LinkSpecifier> = plaintext.link_specifiers.clone();
+
+    let relay = RelayInfo::from_link_specifiers(link_specifiers, plaintext.onion_key)
+        .ok_or_else(|| ErrorKind::InvalidDocument(String::from("rendezvous point has no RSA identity")))?;
+
+    let circuit = shared.paths.circuit_to(&relay)?;
+
+    circuit.send_control(RelayCell::new(0, Relay::Rendezvous1 { rendezvous_cookie: plaintext.rendezvous_cookie, handshake_info: *reply }))?;
+
+    //Our forward direction is the client's backward one, so the halves of the key material swap
+    let mut swapped = [0u8; HS_KEY_MATERIAL_LENGTH];
+
+    swapped[0..32].copy_from_slice(&materials[32..64]);
+    swapped[32..64].copy_from_slice(&materials[0..32]);
+    swapped[64..96].copy_from_slice(&materials[96..128]);
+    swapped[96..128].copy_from_slice(&materials[64..96]);
+
+    circuit.add_virtual_hop(CellCrypto::onion_service(&swapped));
+
+    Ok(circuit)
+}
+
+///Hand the streams the client opens on a rendezvous circuit to the `StreamHandler`, until the circuit closes
+fn serve_streams<P: ServicePaths>(shared: &ServiceShared<P>, circuit: &Circuit) -> Result<()> {
+    loop {
+        let cell = circuit.receive_control()?;
+
+        if cell.get_command() != 1 {
+            continue;
+        }
+
+        let (stream, target) = match circuit.accept(cell) {
+            Ok(accepted) => accepted,
+            Err(_) => continue,
+        };
+
+        let port = target.rsplit(':').next().and_then(|port| port.parse().ok()).unwrap_or(0);
+
+        match &shared.handler {
+            StreamHandler::Callback(callback) => callback(stream, port),
+            StreamHandler::Forward(address) => {
+                let address = *address;
+
+                thread::spawn(move || forward(stream, address));
+            },
+        }
+    }
+}
+
+///Copy a stream to and from a local TCP connection until both directions finish
+fn forward(mut stream: TorStream, address: SocketAddr) {
+    let mut local = match TcpStream::connect(address) {
+        Ok(local) => local,
+        Err(_) => return,
+    };
+
+    let mut local_reader = match local.try_clone() {
+        Ok(reader) => reader,
+        Err(_) => return,
+    };
+
+    let mut writer = stream.writer();
+
+    thread::spawn(move || {
+        let _ = io::copy(& mut local_reader, & mut writer);
+
+        writer.end();
+    });
+
+    let _ = io::copy(& mut stream, & mut local);
+
+    let _ = local.flush();
+    let _ = local.shutdown(Shutdown::Write);
+}
+
@@ -0,0 +1,405 @@
+//! Proof-of-work for onion service introductions (prop 327, the `v1` scheme).
+//!
+//! A service under attack puts a `pow-params` line in its descriptor with a random seed and the effort it suggests.
+//! Clients then solve an Equi-X puzzle over the challenge `P | ID | C | N | E`, where `ID` is the blinded key, `C` the
+//! seed, `N` a nonce of the client's choosing and `E` the effort. A solution `S` only counts if
+//! `E * BLAKE2b-32(challenge | S)` fits in 32 bits, so on average a client tries `E` solutions. The nonce, effort, seed
+//! head and solution go in an INTRODUCE1 extension, and the service answers introductions with the most effort first.
+//!
+//! Equi-X finds eight indices whose hashes sum to zero modulo 2^60, with their pairs and quads summing to zero modulo
+//! 2^15 and 2^30. The indices are hashed with HashX, a hash function generated from the challenge. Both come from the
+//! `equix` crate, which is Tor's own Rust port, and the test vectors are C tor's.
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use ring::rand::SecureRandom;
+
+use crate::cells::{HsExtension, CSRNG};
+use equix::EquiX;
+
+use crate::custom_crypto::blake2b;
+use crate::netdoc::{self, Item};
+use crate::error::{ErrorKind, Result};
+
+///The `pow-params` type of the Equi-X scheme
+pub const POW_TYPE_V1: &str = "v1";
+
+///The INTRODUCE1 extension carrying a proof-of-work
+pub const EXTENSION_POW: u8 = 2;
+
+///POW_VERSION of the `v1` scheme in the INTRODUCE1 extension
+const POW_VERSION_V1: u8 = 1;
+
+///P, which starts every challenge
+const CHALLENGE_PREFIX: &[u8] = b"Tor hs intro v1\0";
+
+const EXPIRATION_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+///Clients that had no luck at the suggested effort try again with at least this much
+pub const CLIENT_MIN_RETRY_EFFORT: u32 = 8;
+
+///Clients double their effort on each retry up to here, then increase it by half
+pub const CLIENT_EFFORT_DOUBLE_UNTIL: u32 = 1000;
+
+///The most effort clients put into an introduction by default
+pub const CLIENT_MAX_EFFORT: u32 = 10000;
+
+///The proof-of-work parameters from a descriptor
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowParams {
+    ///C, which the service replaces from time to time
+    pub seed: [u8; 32],
+    ///The effort the service suggests clients start with
+    pub suggested_effort: u32,
+    ///When the service stops accepting solutions for `seed`
+    pub expiration: DateTime<Utc>,
+}
+
+impl PowParams {
+    ///Parameters with a new random seed that expires after `lifetime`
+    pub fn generate(suggested_effort: u32, lifetime: chrono::Duration) -> Self {
+        let mut seed = [0u8; 32];
+
+        CSRNG.fill(& mut seed).unwrap();
+
+        Self {
+            seed,
+            suggested_effort,
+            //The descriptor only has whole seconds
+            expiration: Utc.timestamp_opt((Utc::now() + lifetime).timestamp(), 0).unwrap(),
+        }
+    }
+
+    ///The first four bytes of the seed, which tell the service which seed a solution is for
+    pub fn seed_head(& self) -> [u8; 4] {
+        self.seed[0..4].try_into().unwrap()
+    }
+
+    pub fn expired_at(& self, time: DateTime<Utc>) -> bool {
+        time >= self.expiration
+    }
+
+    ///The `pow-params` line for the inner layer of a descriptor
+    pub fn to_line(& self) -> String {
+        format!("pow-params {} {} {} {}\n", POW_TYPE_V1, netdoc::encode_base64(&self.seed).trim_end_matches('='), self.suggested_effort, self.expiration.format(EXPIRATION_FORMAT))
+    }
+
+    ///Parse a `pow-params` item, returning `None` for schemes other than `v1`
+    pub fn parse(item: &Item) -> Result<Option<Self>> {
+        let invalid = |reason: &str| ErrorKind::InvalidDocument(format!("bad pow-params: {}", reason));
+
+        if item.argument(0)? != POW_TYPE_V1 {
+            return Ok(None);
+        }
+
+        let seed = netdoc::decode_base64(item.argument(1)?)?;
+
+        let expiration = NaiveDateTime::parse_from_str(item.argument(3)?, EXPIRATION_FORMAT).map_err(|_| invalid("expiration time"))?;
+
+        Ok(Some(Self {
+            seed: seed.as_slice().try_into().map_err(|_| invalid("seed length"))?,
+            suggested_effort: item.argument(2)?.parse().map_err(|_| invalid("suggested effort"))?,
+            expiration: Utc.from_utc_datetime(&expiration),
+        }))
+    }
+}
+
+///The effort a client tries after failing to get in at `effort`, which is never more than `max_effort`
+pub fn next_effort(effort: u32, max_effort: u32) -> u32 {
+    let next = if effort < CLIENT_EFFORT_DOUBLE_UNTIL {
+        effort.saturating_mul(2)
+    } else {
+        effort.saturating_add(effort / 2)
+    };
+
+    next.max(CLIENT_MIN_RETRY_EFFORT).min(max_effort)
+}
+
+///P | ID | C | N | E, which Equi-X is solved for
+fn challenge(blinded_key: &[u8; 32], seed: &[u8; 32], nonce: &[u8; 16], effort: u32) -> Vec<u8> {
+    let mut challenge = Vec::from(CHALLENGE_PREFIX);
+
+    challenge.extend_from_slice(blinded_key);
+    challenge.extend_from_slice(seed);
+    challenge.extend_from_slice(nonce);
+    challenge.extend_from_slice(&effort.to_be_bytes());
+
+    challenge
+}
+
+///Whether `solution` to `challenge` is worth `effort`
+fn meets_effort(challenge: &[u8], solution: &[u8; 16], effort: u32) -> bool {
+    let mut input = Vec::from(challenge);
+
+    input.extend_from_slice(solution);
+
+    let r = u32::from_be_bytes(blake2b(&input, 4).as_slice().try_into().unwrap());
+
+    r.checked_mul(effort).is_some()
+}
+
+///The first solution to `challenge` that is worth `effort`, if any. Challenges HashX can't make a program for have no
+///solutions, as in Tor, so the caller moves on to the next nonce
+fn solve_challenge(challenge: &[u8], effort: u32) -> Option<[u8; 16]> {
+    let equix = EquiX::new(challenge).ok()?;
+
+    equix.solve().iter()
+        .map(|solution| solution.to_bytes())
+        .find(|solution| meets_effort(challenge, solution, effort))
+}
+
+///A solved puzzle, as carried in the INTRODUCE1 extension
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowSolution {
+    pub nonce: [u8; 16],
+    pub effort: u32,
+    pub seed_head: [u8; 4],
+    pub solution: [u8; 16],
+}
+
+impl PowSolution {
+    ///POW_VERSION | POW_NONCE | POW_EFFORT | POW_SEED | POW_SOLUTION
+    pub fn to_extension(& self) -> HsExtension {
+        let mut data = vec![POW_VERSION_V1];
+
+        data.extend_from_slice(&self.nonce);
+        data.extend_from_slice(&self.effort.to_be_bytes());
+        data.extend_from_slice(&self.seed_head);
+        data.extend_from_slice(&self.solution);
+
+        HsExtension::new(EXTENSION_POW, data)
+    }
+
+    ///Read a `v1` solution from a PoW extension
+    pub fn from_extension(extension: &HsExtension) -> Option<Self> {
+        let data = extension.get_data();
+
+        if extension.get_type() != EXTENSION_POW || data.len() != 41 || data[0] != POW_VERSION_V1 {
+            return None;
+        }
+
+        Some(Self {
+            nonce: data[1..17].try_into().unwrap(),
+            effort: u32::from_be_bytes(data[17..21].try_into().unwrap()),
+            seed_head: data[21..25].try_into().unwrap(),
+            solution: data[25..41].try_into().unwrap(),
+        })
+    }
+
+    ///Whether this solves the puzzle for `seed` and the blinded key `blinded_key` with the effort it claims
+    pub fn verify(& self, seed: &[u8; 32], blinded_key: &[u8; 32]) -> bool {
+        let challenge = challenge(blinded_key, seed, &self.nonce, self.effort);
+
+        seed[0..4] == self.seed_head
+            && meets_effort(&challenge, &self.solution, self.effort)
+            && equix::verify_bytes(&challenge, &self.solution).is_ok()
+    }
+}
+
+///Add one to a nonce as a little endian number
+fn increment(nonce: & mut [u8; 16]) {
+    *nonce = (u128::from_le_bytes(*nonce).wrapping_add(1)).to_le_bytes();
+}
+
+///Solve the puzzle from `params` for the service with blinded key `blinded_key` at `effort`, trying nonces on `threads`
+///threads until one of them finds a solution worth it. Fails with `Timeout` if none is found within `budget`
+pub fn solve(params: &PowParams, blinded_key: &[u8; 32], effort: u32, threads: usize, budget: Duration) -> Result<PowSolution> {
+    let found = Mutex::new(None);
+    let done = AtomicBool::new(false);
+    let deadline = Instant::now() + budget;
+
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| {
+                let mut nonce = [0u8; 16];
+
+                CSRNG.fill(& mut nonce).unwrap();
+
+                while !done.load(Ordering::Relaxed) {
+                    if Instant::now() >= deadline {
+                        done.store(true, Ordering::Relaxed);
+
+                        break;
+                    }
+
+                    if let Some(solution) = solve_challenge(&challenge(blinded_key, &params.seed, &nonce, effort), effort) {
+                        done.store(true, Ordering::Relaxed);
+
+                        found.lock().unwrap().get_or_insert(PowSolution {
+                            nonce,
+                            effort,
+                            seed_head: params.seed_head(),
+                            solution,
+                        });
+                    }
+
+                    increment(& mut nonce);
+                }
+            });
+        }
+    });
+
+    found.into_inner().unwrap().ok_or(ErrorKind::Timeout)
+}
+
+///Solve the puzzle for `seed` and the blinded key `blinded_key` at `effort` on this thread, trying nonces from `nonce`
+///up until one has a solution worth it. Tor's solver does the same, so this finds the nonce and solution it would
+pub fn solve_from(seed: &[u8; 32], blinded_key: &[u8; 32], effort: u32, nonce: [u8; 16]) -> PowSolution {
+    let mut nonce = nonce;
+
+    loop {
+        if let Some(solution) = solve_challenge(&challenge(blinded_key, seed, &nonce, effort), effort) {
+            return PowSolution {
+                nonce,
+                effort,
+                seed_head: seed[0..4].try_into().unwrap(),
+                solution,
+            };
+        }
+
+        increment(& mut nonce);
+    }
+}
+
+///How a client solves puzzles for services that ask for proof-of-work
+#[derive(Debug, Clone)]
+pub struct PowConfig {
+    ///How many threads to solve with
+    pub threads: usize,
+    ///The least effort to start with, if it is more than the service suggests
+    pub min_effort: u32,
+    ///The most effort to put into one introduction
+    pub max_effort: u32,
+    ///How many times to introduce ourselves, with more effort each time, before giving up
+    pub attempts: usize,
+    ///How long to spend solving one puzzle before giving up on the service
+    pub solve_timeout: Duration,
+    ///How long to wait at the rendezvous point for a service that wants proof-of-work, which may drop introductions
+    ///with too little effort
+    pub rendezvous_timeout: Duration,
+}
+
+impl Default for PowConfig {
+    fn default() -> Self {
+        Self {
+            threads: thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1),
+            min_effort: 0,
+            max_effort: CLIENT_MAX_EFFORT,
+            attempts: 4,
+            solve_timeout: Duration::from_secs(60),
+            rendezvous_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl PowConfig {
+    ///The effort to start with for a service that suggests `suggested_effort`
+    pub fn initial_effort(& self, suggested_effort: u32) -> u32 {
+        suggested_effort.max(self.min_effort).min(self.max_effort)
+    }
+}
+
+///The seeds a service accepts solutions for, and the solutions it has already seen
+pub struct PowVerifier {
+    params: PowParams,
+    ///The seed before the last rotation, which clients with an older descriptor still use
+    previous_seed: Option<[u8; 32]>,
+    seen: HashSet<([u8; 4], [u8; 16])>,
+}
+
+impl PowVerifier {
+    pub fn new(params: PowParams) -> Self {
+        Self {
+            params,
+            previous_seed: None,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn params(& self) -> &PowParams {
+        &self.params
+    }
+
+    ///Switch to `params`, still accepting solutions for the seed they replace
+    pub fn rotate(& mut self, params: PowParams) {
+        self.previous_seed = Some(self.params.seed);
+        self.params = params;
+
+        let heads = [self.params.seed_head(), self.previous_seed.unwrap()[0..4].try_into().unwrap()];
+
+        self.seen.retain(|(head, _)| heads.contains(head));
+    }
+
+    ///The effort of an introduction carrying `solution` for the blinded key `blinded_key`, or `None` if the solution
+    ///is invalid, for a seed we don't know or replayed
+    pub fn check(& mut self, solution: &PowSolution, blinded_key: &[u8; 32]) -> Option<u32> {
+        let seed = std::iter::once(self.params.seed).chain(self.previous_seed)
+            .find(|seed| seed[0..4] == solution.seed_head)?;
+
+        if self.seen.contains(&(solution.seed_head, solution.nonce)) || !solution.verify(&seed, blinded_key) {
+            return None;
+        }
+
+        self.seen.insert((solution.seed_head, solution.nonce));
+
+        Some(solution.effort)
+    }
+}
+
+///Introductions waiting for the service to answer, highest effort first and oldest first for the same effort. When
+///full, the introduction with the least effort is dropped
+pub struct IntroQueue<T> {
+    pending: BTreeMap<(u32, std::cmp::Reverse<u64>), T>,
+    capacity: usize,
+    next: u64,
+}
+
+impl<T> IntroQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            capacity,
+            next: 0,
+        }
+    }
+
+    ///Queue an introduction made with `effort`, returning false if it was dropped because the queue is full of
+    ///introductions with at least as much effort
+    pub fn push(& mut self, effort: u32, introduction: T) -> bool {
+        if self.pending.len() >= self.capacity {
+            match self.pending.keys().next().copied() {
+                Some(lowest) if lowest.0 < effort => {
+                    self.pending.remove(&lowest);
+                },
+                _ => return false,
+            }
+        }
+
+        self.pending.insert((effort, std::cmp::Reverse(self.next)), introduction);
+
+        self.next += 1;
+
+        true
+    }
+
+    ///The introduction with the most effort, and its effort
+    pub fn pop(& mut self) -> Option<(u32, T)> {
+        let key = *self.pending.keys().next_back()?;
+
+        self.pending.remove(&key).map(|introduction| (key.0, introduction))
+    }
+
+    pub fn len(& self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(& self) -> bool {
+        self.pending.is_empty()
+    }
+}
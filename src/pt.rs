@@ -0,0 +1,478 @@
+//! Client side pluggable transports, following version 1.0 of the PT specification.
+//!
+//! A transport such as obfs4 or snowflake runs as a separate "managed proxy" process configured with Tor's
+//! `ClientTransportPlugin name[,name...] exec path [args]` lines. The proxy is launched the first time one of its
+//! transports is needed, told what we want through `TOR_PT_*` environment variables, and answers on stdout with a
+//! `CMETHOD` line giving the SOCKS listener of each transport. Connections to a bridge then go through that listener,
+//! with the bridge line's `k=v` arguments passed in the SOCKS authentication fields.
+//!
+//! Proxies that are already running can be configured with `ClientTransportPlugin name socks5 address:port` instead.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{ErrorKind, Result};
+
+///The only version of the managed proxy protocol there is
+pub const PT_VERSION: &str = "1";
+
+///How long a managed proxy has to report its methods after being launched
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+///How long a managed proxy has to exit after its stdin is closed before it is killed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+///How long the SOCKS handshake with a transport may take
+const SOCKS_TIMEOUT: Duration = Duration::from_secs(30);
+
+///The longest argument string SOCKS5 can carry, split between the 255 byte username and password
+const MAX_SOCKS5_ARGS: usize = 255 * 2;
+
+fn failed(reason: String) -> ErrorKind {
+    ErrorKind::TransportFailed(reason)
+}
+
+///Which SOCKS version a transport's listener speaks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocksVersion {
+    Socks4,
+    Socks5,
+}
+
+///A transport's SOCKS listener, as announced by a `CMETHOD` line
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientMethod {
+    pub transport: String,
+    pub socks: SocksVersion,
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginKind {
+    ///A managed proxy we launch with `path` and `args`
+    Exec { path: PathBuf, args: Vec<String> },
+    ///A proxy that is already listening
+    Socks(SocksVersion, SocketAddr),
+}
+
+///One `ClientTransportPlugin` line
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransportPlugin {
+    pub transports: Vec<String>,
+    pub kind: PluginKind,
+}
+
+impl std::str::FromStr for TransportPlugin {
+    type Err = ErrorKind;
+
+    ///Parse a plugin line, with or without the `ClientTransportPlugin` keyword
+    fn from_str(line: &str) -> Result<Self> {
+        let invalid = |reason: &str| ErrorKind::InvalidDocument(format!("bad transport plugin line: {}", reason));
+
+        let mut words = line.split_whitespace().peekable();
+
+        if words.peek() == Some(&"ClientTransportPlugin") {
+            words.next();
+        }
+
+        let transports: Vec<String> = words.next().ok_or_else(|| invalid("missing transports"))?.split(',').map(String::from).collect();
+
+        if transports.iter().any(|transport| transport.is_empty() || !transport.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+            return Err(invalid("bad transport name"));
+        }
+
+        let kind = match (words.next(), words.next()) {
+            (Some("exec"), Some(path)) => PluginKind::Exec {
+                path: PathBuf::from(path),
+                args: words.map(String::from).collect(),
+            },
+            (Some(socks @ "socks4"), Some(address)) | (Some(socks @ "socks5"), Some(address)) => {
+                let version = if socks == "socks4" { SocksVersion::Socks4 } else { SocksVersion::Socks5 };
+
+                PluginKind::Socks(version, address.parse().map_err(|_| invalid(&format!("{} is not an address", address)))?)
+            },
+            _ => return Err(invalid("expected exec or a SOCKS proxy")),
+        };
+
+        Ok(Self {
+            transports,
+            kind,
+        })
+    }
+}
+
+///Join a bridge line's arguments as `k=v;k=v`, escaping `\`, `=` and `;` with a backslash
+pub fn encode_args(args: &[(String, String)]) -> String {
+    let escape = |text: &str| text.chars().fold(String::new(), |mut escaped, c| {
+        if c == '\\' || c == '=' || c == ';' {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+        escaped
+    });
+
+    args.iter().map(|(key, value)| format!("{}={}", escape(key), escape(value))).collect::<Vec<String>>().join(";")
+}
+
+///The username and password a SOCKS5 transport gets `args` in. The username holds up to 255 bytes and the password the
+///rest, or a single NUL when there is no rest since it can't be empty
+fn socks5_credentials(args: &str) -> Result<(&[u8], &[u8])> {
+    let bytes = args.as_bytes();
+
+    if bytes.len() > MAX_SOCKS5_ARGS {
+        return Err(failed(format!("{} bytes of arguments won't fit in SOCKS5 authentication", bytes.len())));
+    }
+
+    let (username, password) = bytes.split_at(bytes.len().min(255));
+
+    Ok((username, if password.is_empty() { &[0u8] } else { password }))
+}
+
+///Connect to `target` through a SOCKS5 listener, passing `args` as the username and password
+fn socks5_connect(stream: & mut TcpStream, target: SocketAddr, args: &str) -> Result<()> {
+    let reply = |stream: & mut TcpStream, length: usize| -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; length];
+
+        stream.read_exact(& mut buffer)?;
+
+        Ok(buffer)
+    };
+
+    let credentials = if args.is_empty() { None } else { Some(socks5_credentials(args)?) };
+
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+
+    stream.write_all(&[0x05, 0x01, method])?;
+
+    if reply(stream, 2)? != [0x05, method] {
+        return Err(failed(String::from("the transport refused our SOCKS5 authentication method")));
+    }
+
+    if let Some((username, password)) = credentials {
+        let mut request = vec![0x01, username.len() as u8];
+
+        request.extend_from_slice(username);
+        request.push(password.len() as u8);
+        request.extend_from_slice(password);
+
+        stream.write_all(&request)?;
+
+        if reply(stream, 2)?[1] != 0x00 {
+            return Err(failed(String::from("the transport rejected our arguments")));
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+
+    match target {
+        SocketAddr::V4(address) => {
+            request.push(0x01);
+            request.extend_from_slice(&address.ip().octets());
+        },
+        SocketAddr::V6(address) => {
+            request.push(0x04);
+            request.extend_from_slice(&address.ip().octets());
+        },
+    }
+
+    request.extend_from_slice(&target.port().to_be_bytes());
+
+    stream.write_all(&request)?;
+
+    let header = reply(stream, 4)?;
+
+    if header[1] != 0x00 {
+        return Err(failed(format!("the transport couldn't reach {} (SOCKS5 reply {})", target, header[1])));
+    }
+
+    //Skip the bound address, which we don't need
+    let address_length = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => reply(stream, 1)?[0] as usize,
+        kind => return Err(failed(format!("unknown SOCKS5 address type {}", kind))),
+    };
+
+    reply(stream, address_length + 2)?;
+
+    Ok(())
+}
+
+///Connect to `target` through a SOCKS4 listener, passing `args` as the user ID
+fn socks4_connect(stream: & mut TcpStream, target: SocketAddr, args: &str) -> Result<()> {
+    let target = match target {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => return Err(failed(String::from("SOCKS4 transports can't reach IPv6 bridges"))),
+    };
+
+    let mut request = vec![0x04, 0x01];
+
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request.extend_from_slice(&target.ip().octets());
+    request.extend_from_slice(args.as_bytes());
+    request.push(0);
+
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 8];
+
+    stream.read_exact(& mut reply)?;
+
+    if reply[1] != 0x5a {
+        return Err(failed(format!("the transport couldn't reach {} (SOCKS4 reply {})", target, reply[1])));
+    }
+
+    Ok(())
+}
+
+///A launched managed proxy. Its stdin is held open, since closing it asks the proxy to exit
+struct ManagedProxy {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    methods: Vec<ClientMethod>,
+    ///Transports the proxy reported a `CMETHOD-ERROR` for, and why
+    errors: HashMap<String, String>,
+}
+
+impl ManagedProxy {
+    fn launch(plugin: &TransportPlugin, path: &Path, args: &[String], state: &Path) -> Result<Self> {
+        fs::create_dir_all(state)?;
+
+        let mut child = Command::new(path)
+            .args(args)
+            .env("TOR_PT_MANAGED_TRANSPORT_VER", PT_VERSION)
+            .env("TOR_PT_STATE_LOCATION", state)
+            .env("TOR_PT_CLIENT_TRANSPORTS", plugin.transports.join(","))
+            .env("TOR_PT_EXIT_ON_STDIN_CLOSE", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|error| failed(format!("couldn't launch {}: {}", path.display(), error)))?;
+
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().unwrap();
+
+        //The reader keeps draining stdout after the methods are in, so the proxy never blocks writing its logs
+        let (sender, receiver) = channel();
+
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => { let _ = sender.send(line); },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let mut proxy = Self {
+            child,
+            stdin,
+            methods: Vec::new(),
+            errors: HashMap::new(),
+        };
+
+        let deadline = Instant::now() + LAUNCH_TIMEOUT;
+        let mut version = false;
+
+        loop {
+            let line = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    proxy.stop();
+                    return Err(ErrorKind::Timeout);
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    proxy.stop();
+                    return Err(failed(format!("{} exited before reporting its methods", path.display())));
+                },
+            };
+
+            let mut words = line.splitn(2, ' ');
+
+            let result = match (words.next().unwrap_or(""), words.next().unwrap_or("")) {
+                ("VERSION", PT_VERSION) => {
+                    version = true;
+                    Ok(())
+                },
+                ("VERSION", other) => Err(format!("unsupported version {}", other)),
+                ("VERSION-ERROR", message) => Err(format!("no common version: {}", message)),
+                ("ENV-ERROR", message) => Err(format!("bad environment: {}", message)),
+                ("CMETHOD", method) => proxy.add_method(method),
+                ("CMETHOD-ERROR", error) => {
+                    let (transport, message) = error.split_once(' ').unwrap_or((error, ""));
+
+                    proxy.errors.insert(String::from(transport), String::from(message));
+                    Ok(())
+                },
+                ("CMETHODS", "DONE") if version => return Ok(proxy),
+                ("CMETHODS", "DONE") => Err(String::from("methods reported without a version")),
+                //Everything else, like LOG and STATUS, is ignored as the specification asks
+                _ => Ok(()),
+            };
+
+            if let Err(reason) = result {
+                proxy.stop();
+                return Err(failed(format!("{}: {}", path.display(), reason)));
+            }
+        }
+    }
+
+    ///Record a `CMETHOD <transport> <socks4|socks5> <address>` line
+    fn add_method(& mut self, method: &str) -> std::result::Result<(), String> {
+        let words: Vec<&str> = method.split_whitespace().collect();
+
+        let (transport, socks, address) = match words.as_slice() {
+            [transport, socks, address, ..] => (transport, socks, address),
+            _ => return Err(format!("bad CMETHOD {}", method)),
+        };
+
+        let socks = match *socks {
+            "socks5" => SocksVersion::Socks5,
+            "socks4" => SocksVersion::Socks4,
+            other => {
+                self.errors.insert(String::from(*transport), format!("unsupported proxy type {}", other));
+                return Ok(());
+            },
+        };
+
+        let address = address.parse().map_err(|_| format!("bad CMETHOD address {}", address))?;
+
+        self.methods.push(ClientMethod {
+            transport: String::from(*transport),
+            socks,
+            address,
+        });
+
+        Ok(())
+    }
+
+    fn running(& mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    ///Close stdin so the proxy exits by itself, and kill it if it hasn't after `SHUTDOWN_GRACE`
+    fn stop(& mut self) {
+        self.stdin = None;
+
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+
+        while Instant::now() < deadline {
+            if !self.running() {
+                return;
+            }
+
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+///Launches managed proxies as their transports are needed and connects through them
+pub struct TransportManager {
+    plugins: Vec<TransportPlugin>,
+    ///Where managed proxies keep their state, in a `pt_state` directory as with Tor
+    state: PathBuf,
+    ///Running proxies by the index of their plugin
+    proxies: Mutex<HashMap<usize, ManagedProxy>>,
+}
+
+impl TransportManager {
+    pub fn new(plugins: Vec<TransportPlugin>, data_directory: &Path) -> Self {
+        Self {
+            plugins,
+            state: data_directory.join("pt_state"),
+            proxies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///The plugins in the `ClientTransportPlugin` lines of a torrc-style configuration, ignoring every other option
+    pub fn from_config(config: &str, data_directory: &Path) -> Result<Self> {
+        let plugins = config.lines()
+            .map(|line| line.trim())
+            .filter(|line| line.split_whitespace().next() == Some("ClientTransportPlugin"))
+            .map(|line| line.parse())
+            .collect::<Result<Vec<TransportPlugin>>>()?;
+
+        Ok(Self::new(plugins, data_directory))
+    }
+
+    ///Whether a plugin provides `transport`
+    pub fn supports(& self, transport: &str) -> bool {
+        self.plugins.iter().any(|plugin| plugin.transports.iter().any(|name| name == transport))
+    }
+
+    ///The SOCKS listener of `transport`, launching its managed proxy if it isn't running
+    pub fn method(& self, transport: &str) -> Result<ClientMethod> {
+        let (index, plugin) = self.plugins.iter().enumerate()
+            .find(|(_, plugin)| plugin.transports.iter().any(|name| name == transport))
+            .ok_or_else(|| ErrorKind::UnsupportedTransport(String::from(transport)))?;
+
+        let (path, args) = match &plugin.kind {
+            PluginKind::Socks(socks, address) => return Ok(ClientMethod {
+                transport: String::from(transport),
+                socks: *socks,
+                address: *address,
+            }),
+            PluginKind::Exec { path, args } => (path, args),
+        };
+
+        let mut proxies = self.proxies.lock().unwrap();
+
+        //A proxy that died is launched again
+        if !proxies.get_mut(&index).map(|proxy| proxy.running()).unwrap_or(false) {
+            proxies.remove(&index);
+            proxies.insert(index, ManagedProxy::launch(plugin, path, args, &self.state)?);
+        }
+
+        let proxy = &proxies[&index];
+
+        if let Some(method) = proxy.methods.iter().find(|method| method.transport == transport) {
+            return Ok(method.clone());
+        }
+
+        Err(failed(match proxy.errors.get(transport) {
+            Some(reason) => format!("{}: {}", transport, reason),
+            None => format!("{} didn't report a method for {}", path.display(), transport),
+        }))
+    }
+
+    ///Open a TCP connection to the bridge at `target` through `transport`, giving the transport the bridge's `args`
+    pub fn connect(& self, transport: &str, target: SocketAddr, args: &[(String, String)]) -> Result<TcpStream> {
+        let method = self.method(transport)?;
+
+        let args = encode_args(args);
+
+        let mut stream = TcpStream::connect(method.address)?;
+
+        stream.set_read_timeout(Some(SOCKS_TIMEOUT))?;
+
+        match method.socks {
+            SocksVersion::Socks5 => socks5_connect(& mut stream, target, &args)?,
+            SocksVersion::Socks4 => socks4_connect(& mut stream, target, &args)?,
+        }
+
+        stream.set_read_timeout(None)?;
+
+        Ok(stream)
+    }
+}
+
+impl Drop for TransportManager {
+    fn drop(& mut self) {
+        for (_, mut proxy) in self.proxies.lock().unwrap().drain() {
+            proxy.stop();
+        }
+    }
+}
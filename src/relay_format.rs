@@ -0,0 +1,179 @@
+//! Relay cell formats, from proposal 340.
+//!
+//! Format v0 is the layout every relay speaks: one relay message per cell, behind a command, `recognized` field, stream
+//! ID, digest and length. Format v1 gives the first 16 bytes of the cell body to the relay crypto, as tor1's recognized
+//! and digest fields in their v0 places or as CGO's tag, and fills the other 493 with messages. Each message is a
+//! command, a length, a stream ID for the commands that take one, then its data.
+//!
+//! Messages are packed one after another while they fit, and a message too long for the rest of a cell carries on at
+//! the start of the next. A command of zero, or too little room left for a header, ends a cell's messages. Each hop
+//! has its own format, which both ends settle before the hop's handshake.
+
+use std::convert::TryInto;
+
+use crate::cells::RelayCell;
+
+///The bytes at the start of a v1 cell body that belong to the relay crypto
+pub const V1_TAG_LEN: usize = 16;
+
+///The bytes of a v1 cell body that carry messages
+pub const V1_MESSAGE_AREA_LEN: usize = 509 - V1_TAG_LEN;
+
+///The most data a v1 message carries, since its length is 16 bits
+pub const V1_MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+
+///The most data a v1 DATA message carries without being fragmented
+pub const V1_DATA_LEN: usize = V1_MESSAGE_AREA_LEN - 5;
+
+///How a hop lays out the relay messages in a cell body
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RelayCellFormat {
+    ///One message per cell, as every relay supports
+    #[default]
+    V0,
+    ///Packed and fragmented messages with optional stream IDs
+    V1,
+}
+
+///Whether messages with `command` carry a stream ID in format v1
+pub fn has_stream_id(command: u8) -> bool {
+    matches!(command, 1..=5 | 11..=13 | 43 | 44)
+}
+
+///The length of a v1 message header for `command`
+fn header_len(command: u8) -> usize {
+    if has_stream_id(command) {
+        5
+    } else {
+        3
+    }
+}
+
+///Lay out `messages` in as few v1 cell bodies as they fit, leaving each tag zeroed for the relay crypto
+pub fn pack(messages: Vec<RelayCell>) -> torserde::Result<Vec<[u8; 509]>> {
+    let mut bodies = Vec::new();
+    let mut body = [0u8; 509];
+    let mut offset = V1_TAG_LEN;
+
+    for message in messages {
+        let command = message.get_command();
+        let stream_id = message.get_stream_id();
+        let data = message.into_data();
+
+        if data.len() > V1_MAX_MESSAGE_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} bytes of data do not fit in a relay message", data.len())).into());
+        }
+
+        //Headers aren't split, so a cell with no room for one is finished with padding
+        if 509 - offset < header_len(command) + 1 {
+            bodies.push(body);
+            body = [0u8; 509];
+            offset = V1_TAG_LEN;
+        }
+
+        body[offset] = command;
+        body[offset + 1..offset + 3].copy_from_slice(&(data.len() as u16).to_be_bytes());
+
+        if has_stream_id(command) {
+            body[offset + 3..offset + 5].copy_from_slice(&stream_id.to_be_bytes());
+        }
+
+        offset += header_len(command);
+
+        let mut data = &data[..];
+
+        loop {
+            let length = data.len().min(509 - offset);
+
+            body[offset..offset + length].copy_from_slice(&data[..length]);
+
+            offset += length;
+            data = &data[length..];
+
+            if data.is_empty() {
+                break;
+            }
+
+            bodies.push(body);
+            body = [0u8; 509];
+            offset = V1_TAG_LEN;
+        }
+    }
+
+    if offset > V1_TAG_LEN || bodies.is_empty() {
+        bodies.push(body);
+    }
+
+    Ok(bodies)
+}
+
+///A message that has arrived in part
+struct Fragment {
+    command: u8,
+    stream_id: u16,
+    length: usize,
+    data: Vec<u8>,
+}
+
+///Reads the messages out of a hop's v1 cell bodies, keeping hold of a message until its last fragment arrives
+#[derive(Default)]
+pub struct Reassembler {
+    fragment: Option<Fragment>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///The messages completed by a decrypted v1 cell body, in order
+    pub fn unpack(& mut self, body: &[u8; 509]) -> torserde::Result<Vec<RelayCell>> {
+        let mut messages = Vec::new();
+        let mut offset = V1_TAG_LEN;
+
+        if let Some(mut fragment) = self.fragment.take() {
+            let length = (fragment.length - fragment.data.len()).min(509 - offset);
+
+            fragment.data.extend_from_slice(&body[offset..offset + length]);
+
+            offset += length;
+
+            if fragment.data.len() < fragment.length {
+                self.fragment = Some(fragment);
+
+                return Ok(messages);
+            }
+
+            messages.push(RelayCell::new_raw(fragment.command, fragment.stream_id, fragment.data));
+        }
+
+        while offset < 509 && body[offset] != 0 && 509 - offset >= header_len(body[offset]) {
+            let command = body[offset];
+            let length = u16::from_be_bytes(body[offset + 1..offset + 3].try_into().unwrap()) as usize;
+
+            let stream_id = if has_stream_id(command) {
+                u16::from_be_bytes(body[offset + 3..offset + 5].try_into().unwrap())
+            } else {
+                0
+            };
+
+            offset += header_len(command);
+
+            let available = length.min(509 - offset);
+
+            let data = Vec::from(&body[offset..offset + available]);
+
+            offset += available;
+
+            if available < length {
+                self.fragment = Some(Fragment { command, stream_id, length, data });
+
+                break;
+            }
+
+            messages.push(RelayCell::new_raw(command, stream_id, data));
+        }
+
+        Ok(messages)
+    }
+}
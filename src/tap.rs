@@ -0,0 +1,371 @@
+//! The legacy TAP circuit handshake (tor-spec section 5.1.3), for relays and captures that predate ntor.
+//!
+//! The client sends g^x in the Oakley group 2 1024 bit Diffie-Hellman group, hybrid encrypted to the relay's RSA onion
+//! key: the first 70 bytes go under RSA-OAEP along with a fresh AES key, which encrypts the remaining 58 bytes. The
+//! relay answers with g^y and the KH derived from g^xy with `kdf_tor`. TAP is only as strong as RSA-1024 and DH-1024,
+//! which is why it is behind the `legacy-tap` feature.
+
+use std::convert::TryInto;
+
+use ctr::cipher::{NewCipher, StreamCipher};
+use num_bigint::BigUint;
+use ring::rand::SecureRandom;
+use sha1::{Digest, Sha1};
+
+use crate::cells::CSRNG;
+use crate::custom_crypto::{kdf_tor, constant_time_eq};
+use crate::error::{ErrorKind, Result};
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+///The length of the onion skin in CREATE
+pub const TAP_ONION_SKIN_LENGTH: usize = 186;
+
+///The length of g^y and KH in CREATED
+pub const TAP_REPLY_LENGTH: usize = 148;
+
+///The key material taken from a TAP handshake, KH | Df | Db | Kf | Kb, the same layout as CREATE_FAST's
+pub const TAP_KEY_MATERIAL_LENGTH: usize = 92;
+
+///The length of an RSA-1024 ciphertext, PK_ENC_LEN
+const RSA_LENGTH: usize = 128;
+
+///The bytes OAEP padding takes up, PK_PAD_LEN
+const OAEP_PADDING_LENGTH: usize = 42;
+
+///The length of the AES key in the hybrid encryption, KEY_LEN
+const SYMMETRIC_KEY_LENGTH: usize = 16;
+
+///The length of g^x and g^y, DH_LEN
+const DH_LENGTH: usize = 128;
+
+///Tor draws its DH exponents with 320 bits
+const DH_SECRET_LENGTH: usize = 40;
+
+///The prime of the 1024 bit MODP group from RFC 2409, with generator 2
+const DH_PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7EDEE386BFB5A899FA5AE9F24117C4B1FE649286651ECE65381FFFFFFFFFFFFFFFF";
+
+fn dh_prime() -> BigUint {
+    BigUint::parse_bytes(DH_PRIME.as_bytes(), 16).unwrap()
+}
+
+///Big endian bytes of `value`, left padded with zeros to `length`
+fn to_fixed_bytes(value: &BigUint, length: usize) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+
+    let mut padded = vec![0u8; length.saturating_sub(bytes.len())];
+
+    padded.extend_from_slice(&bytes);
+
+    padded
+}
+
+///Whether a DH public value is in 2..p-2, as tor requires of both sides
+fn valid_public(public: &BigUint) -> bool {
+    let two = BigUint::from(2u8);
+
+    public >= &two && public <= &(dh_prime() - &two)
+}
+
+///A DH key pair in the TAP group
+struct DhKeypair {
+    secret: BigUint,
+    public: BigUint,
+}
+
+impl DhKeypair {
+    fn generate() -> Self {
+        let mut secret = [0u8; DH_SECRET_LENGTH];
+
+        CSRNG.fill(& mut secret).unwrap();
+
+        let secret = BigUint::from_bytes_be(&secret);
+
+        let public = BigUint::from(2u8).modpow(&secret, &dh_prime());
+
+        Self {
+            secret,
+            public,
+        }
+    }
+
+    ///g^xy, or None if the other side's public value is out of range
+    fn shared_secret(& self, public: &[u8]) -> Option<Vec<u8>> {
+        let public = BigUint::from_bytes_be(public);
+
+        if !valid_public(&public) {
+            return None;
+        }
+
+        Some(to_fixed_bytes(&public.modpow(&self.secret, &dh_prime()), DH_LENGTH))
+    }
+}
+
+///MGF1 with SHA1, as OAEP uses it
+fn mgf1(seed: &[u8], length: usize) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(length + 20);
+
+    let mut counter: u32 = 0;
+
+    while mask.len() < length {
+        let mut hasher = Sha1::new();
+
+        hasher.update(seed);
+        hasher.update(counter.to_be_bytes());
+
+        mask.extend_from_slice(&hasher.finalize());
+
+        counter += 1;
+    }
+
+    mask.truncate(length);
+
+    mask
+}
+
+fn xor_into(target: & mut [u8], mask: &[u8]) {
+    for (byte, mask) in target.iter_mut().zip(mask.iter()) {
+        *byte ^= mask;
+    }
+}
+
+///Read a DER length, returning it and the bytes after it
+fn der_length(der: &[u8]) -> Option<(usize, &[u8])> {
+    let (first, rest) = der.split_first()?;
+
+    if first & 0x80 == 0 {
+        return Some((*first as usize, rest));
+    }
+
+    let count = (first & 0x7f) as usize;
+
+    if count == 0 || count > 4 || rest.len() < count {
+        return None;
+    }
+
+    let length = rest[..count].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+
+    Some((length, &rest[count..]))
+}
+
+///Read a DER element with `tag`, returning its contents and the bytes after it
+fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (first, rest) = der.split_first()?;
+
+    if *first != tag {
+        return None;
+    }
+
+    let (length, rest) = der_length(rest)?;
+
+    if rest.len() < length {
+        return None;
+    }
+
+    Some(rest.split_at(length))
+}
+
+///An RSA-1024 public key, such as a relay's onion key
+#[derive(Debug, Clone, PartialEq)]
+pub struct RsaPublicKey {
+    pub modulus: BigUint,
+    pub exponent: BigUint,
+}
+
+impl RsaPublicKey {
+    ///Parse a PKCS#1 `RSAPublicKey`, the contents of the `onion-key` object in a descriptor
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let invalid = || ErrorKind::InvalidDocument(String::from("malformed RSA public key"));
+
+        let (sequence, _) = der_element(der, 0x30).ok_or_else(invalid)?;
+        let (modulus, rest) = der_element(sequence, 0x02).ok_or_else(invalid)?;
+        let (exponent, _) = der_element(rest, 0x02).ok_or_else(invalid)?;
+
+        let key = Self {
+            modulus: BigUint::from_bytes_be(modulus),
+            exponent: BigUint::from_bytes_be(exponent),
+        };
+
+        if key.modulus.bits() != (RSA_LENGTH * 8) as u64 {
+            return Err(ErrorKind::InvalidDocument(format!("onion key has {} bits, not 1024", key.modulus.bits())));
+        }
+
+        Ok(key)
+    }
+
+    ///RSA-OAEP with SHA1, MGF1 and an empty label, for messages of up to 86 bytes
+    pub fn encrypt_oaep(& self, message: &[u8]) -> Vec<u8> {
+        assert!(message.len() <= RSA_LENGTH - OAEP_PADDING_LENGTH);
+
+        //DB = SHA1("") | zeros | 0x01 | M
+        let mut db = Vec::from(&Sha1::digest(b"")[..]);
+
+        db.resize(RSA_LENGTH - 21 - message.len() - 1, 0);
+        db.push(1);
+        db.extend_from_slice(message);
+
+        let mut seed = [0u8; 20];
+
+        CSRNG.fill(& mut seed).unwrap();
+
+        let db_mask = mgf1(&seed, db.len());
+
+        xor_into(& mut db, &db_mask);
+        xor_into(& mut seed, &mgf1(&db, 20));
+
+        let mut encoded = vec![0u8];
+
+        encoded.extend_from_slice(&seed);
+        encoded.extend_from_slice(&db);
+
+        to_fixed_bytes(&BigUint::from_bytes_be(&encoded).modpow(&self.exponent, &self.modulus), RSA_LENGTH)
+    }
+}
+
+///An RSA-1024 private key, enough to decrypt onion skins
+#[derive(Debug, Clone)]
+pub struct RsaPrivateKey {
+    pub public: RsaPublicKey,
+    pub private_exponent: BigUint,
+}
+
+impl RsaPrivateKey {
+    ///Undo `RsaPublicKey::encrypt_oaep`, or None if the padding is wrong
+    pub fn decrypt_oaep(& self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if ciphertext.len() != RSA_LENGTH {
+            return None;
+        }
+
+        let encoded = to_fixed_bytes(&BigUint::from_bytes_be(ciphertext).modpow(&self.private_exponent, &self.public.modulus), RSA_LENGTH);
+
+        if encoded[0] != 0 {
+            return None;
+        }
+
+        let mut seed: [u8; 20] = encoded[1..21].try_into().unwrap();
+        let mut db = Vec::from(&encoded[21..]);
+
+        xor_into(& mut seed, &mgf1(&db, 20));
+
+        let db_mask = mgf1(&seed, db.len());
+
+        xor_into(& mut db, &db_mask);
+
+        if !constant_time_eq(&db[..20], &Sha1::digest(b"")[..]) {
+            return None;
+        }
+
+        let separator = db[20..].iter().position(|byte| *byte != 0)? + 20;
+
+        if db[separator] != 1 {
+            return None;
+        }
+
+        Some(Vec::from(&db[separator + 1..]))
+    }
+}
+
+fn aes_ctr(key: &[u8], data: & mut [u8]) {
+    let iv = [0u8; 16];
+
+    Aes128Ctr::new(key.into(), iv.as_ref().into()).apply_keystream(data);
+}
+
+///Hybrid encrypt a message longer than RSA-OAEP can take, as tor-spec section 0.4 describes
+pub fn hybrid_encrypt(key: &RsaPublicKey, message: &[u8]) -> Vec<u8> {
+    let mut symmetric_key = [0u8; SYMMETRIC_KEY_LENGTH];
+
+    CSRNG.fill(& mut symmetric_key).unwrap();
+
+    let split = RSA_LENGTH - OAEP_PADDING_LENGTH - SYMMETRIC_KEY_LENGTH;
+
+    let mut first = Vec::from(symmetric_key);
+
+    first.extend_from_slice(&message[..split]);
+
+    let mut encrypted = key.encrypt_oaep(&first);
+
+    let mut rest = Vec::from(&message[split..]);
+
+    aes_ctr(&symmetric_key, & mut rest);
+
+    encrypted.extend_from_slice(&rest);
+
+    encrypted
+}
+
+///Undo `hybrid_encrypt`
+pub fn hybrid_decrypt(key: &RsaPrivateKey, ciphertext: &[u8]) -> Option<Vec<u8>> {
+    if ciphertext.len() < RSA_LENGTH {
+        return None;
+    }
+
+    let first = key.decrypt_oaep(&ciphertext[..RSA_LENGTH])?;
+
+    if first.len() != RSA_LENGTH - OAEP_PADDING_LENGTH {
+        return None;
+    }
+
+    let mut message = Vec::from(&first[SYMMETRIC_KEY_LENGTH..]);
+
+    let mut rest = Vec::from(&ciphertext[RSA_LENGTH..]);
+
+    aes_ctr(&first[..SYMMETRIC_KEY_LENGTH], & mut rest);
+
+    message.extend_from_slice(&rest);
+
+    Some(message)
+}
+
+///The client's half of a TAP handshake
+pub struct TapClient {
+    keypair: DhKeypair,
+}
+
+impl TapClient {
+    pub fn new() -> Self {
+        Self {
+            keypair: DhKeypair::generate(),
+        }
+    }
+
+    ///The onion skin for CREATE, g^x hybrid encrypted to `onion_key`
+    pub fn onion_skin(& self, onion_key: &RsaPublicKey) -> [u8; TAP_ONION_SKIN_LENGTH] {
+        hybrid_encrypt(onion_key, &to_fixed_bytes(&self.keypair.public, DH_LENGTH)).try_into().unwrap()
+    }
+
+    ///Check the relay's CREATED and derive the key material, or None if g^y is out of range or KH doesn't match
+    pub fn complete(& self, reply: &[u8; TAP_REPLY_LENGTH]) -> Option<Vec<u8>> {
+        let shared_secret = self.keypair.shared_secret(&reply[..DH_LENGTH])?;
+
+        let materials = kdf_tor(&shared_secret);
+
+        if !constant_time_eq(&materials[0..20], &reply[DH_LENGTH..]) {
+            return None;
+        }
+
+        Some(materials)
+    }
+}
+
+impl Default for TapClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///The relay's half of a TAP handshake. Returns the CREATED payload and the key material, or None if the onion skin
+///wasn't for `onion_key` or its g^x is out of range
+pub fn server_handshake(onion_key: &RsaPrivateKey, onion_skin: &[u8; TAP_ONION_SKIN_LENGTH]) -> Option<([u8; TAP_REPLY_LENGTH], Vec<u8>)> {
+    let client_public = hybrid_decrypt(onion_key, onion_skin)?;
+
+    let keypair = DhKeypair::generate();
+
+    let materials = kdf_tor(&keypair.shared_secret(&client_public)?);
+
+    let mut reply = to_fixed_bytes(&keypair.public, DH_LENGTH);
+
+    reply.extend_from_slice(&materials[0..20]);
+
+    Some((reply.try_into().unwrap(), materials))
+}
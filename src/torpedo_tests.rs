@@ -18,6 +18,10 @@ mod torpedo_tests {
     use rand::Rng;
     use std::io::{Read, Write};
 
+    use crate::cells::{Relay, BeginFlags};
+    use crate::mock_relay::{MockRelay, MockTransport, spawn_echo_server};
+    use crate::ntor::{NtorClient, NTOR_HANDSHAKE_TYPE};
+
     #[test]
     fn test_cells_coms() {
         let sample = [("148.251.23.114", "443"),
@@ -225,4 +229,3868 @@ mod torpedo_tests {
         assert_eq!(cell.data(), vec![87, 23, 72, 33, 38, 145, 234, 34, 62, 76, 129]);
     }
 
-}
\ No newline at end of file
+    ///Perform the link handshake against a `MockRelay` in the same way `test_cells_coms` does against a live relay
+    fn mock_link_handshake<S: Read + Write>(stream: & mut S) {
+        TorCell::new(0, Command::Versions {version_list: VersionsVector::from(vec![3, 4]) }).into_stream(& mut *stream, 3).unwrap();
+
+        let versions = TorCell::from_stream(& mut *stream, 3).unwrap();
+        assert!(matches!(versions.get_command(), Command::Versions { .. }));
+
+        let certs = TorCell::from_stream(& mut *stream, 4).unwrap();
+        assert!(matches!(certs.get_command(), Command::Certs { .. }));
+
+        let auth = TorCell::from_stream(& mut *stream, 4).unwrap();
+        assert!(matches!(auth.get_command(), Command::AuthChallenge { .. }));
+
+        let netinfo = TorCell::from_stream(& mut *stream, 4).unwrap();
+        assert!(matches!(netinfo.get_command(), Command::NetInfo { .. }));
+
+        TorCell::new(0, Command::NetInfo {
+            timestamp: Local::now(),
+            other_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            this_ips: NLengthVector::from(vec![IpAddr::V4(Ipv4Addr::from_str("0.0.0.0").unwrap())]) }).into_stream(& mut *stream, 4).unwrap();
+    }
+
+    fn send_mock_relay<S: Write>(stream: S, cell_crypto: & mut CellCrypto, circuit_id: u32, mut relay: RelayCell) {
+        cell_crypto.set_forward_digest(& mut relay).unwrap();
+
+        let contents = cell_crypto.encrypt(relay).unwrap();
+
+        TorCell::new(circuit_id, Command::Relay { contents }).into_stream(stream, 4).unwrap();
+    }
+
+    fn receive_mock_relay<S: Read>(stream: S, cell_crypto: & mut CellCrypto) -> RelayCell {
+        let cell = TorCell::from_stream(stream, 4).unwrap();
+
+        if let Command::Relay { contents } = cell.get_command() {
+            let mut relay = cell_crypto.decrypt(contents).unwrap();
+
+            cell_crypto.verify_backward_digest(& mut relay).unwrap();
+
+            relay
+        } else {
+            panic!("Expected a relay cell, got {:?}", cell);
+        }
+    }
+
+    #[test]
+    fn test_ntor_handshake() {
+        let node_id = [7u8; 20];
+        let onion_secret = crate::ntor::generate_secret();
+        let onion_key = *x25519_dalek::PublicKey::from(&onion_secret).as_bytes();
+
+        let client = NtorClient::new(&node_id, &onion_key);
+
+        let (reply, server_keys) = crate::ntor::server_handshake(&node_id, &onion_secret, &client.onion_skin(), 72).unwrap();
+
+        let client_keys = client.complete(&reply, 72).unwrap();
+
+        assert_eq!(client_keys, server_keys);
+
+        let mut tampered = reply.clone();
+        tampered[40] ^= 1;
+
+        assert!(client.complete(&tampered, 72).is_none());
+
+        assert!(crate::ntor::server_handshake(&[8u8; 20], &onion_secret, &client.onion_skin(), 72).is_none());
+    }
+
+    #[test]
+    fn test_mock_relay_begin_dir() {
+        let relay = MockRelay::spawn(MockTransport::Plain);
+
+        let mut stream = TcpStream::connect(relay.address()).unwrap();
+
+        mock_link_handshake(& mut stream);
+
+        TorCell::new(0x80000001, Command::CreateFast {onion_skin: [3u8; 20]}).into_stream(& mut stream, 4).unwrap();
+
+        let created_fast = TorCell::from_stream(& mut stream, 4).unwrap();
+
+        let handshake_data = match created_fast.get_command() {
+            Command::CreatedFast { handshake_data } => *handshake_data,
+            command => panic!("Expected CREATED_FAST, got {:?}", command),
+        };
+
+        let mut shared_secret = Vec::from([3u8; 20]);
+
+        shared_secret.extend_from_slice(&handshake_data[0..20]);
+
+        let materials = kdf_tor(&shared_secret);
+
+        assert_eq!(&materials[0..20], &handshake_data[20..40]);
+
+        let mut cell_crypto = CellCrypto::from(&materials[20..92].try_into().unwrap());
+
+        send_mock_relay(& mut stream, & mut cell_crypto, 0x80000001, RelayCell::new(1, Relay::BeginDir));
+
+        let connected = receive_mock_relay(& mut stream, & mut cell_crypto);
+
+        assert_eq!(connected.get_command(), 4);
+        assert_eq!(connected.get_stream_id(), 1);
+    }
+
+    #[test]
+    fn test_mock_relay_ntor_exit() {
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_hostnames(true)
+            .danger_accept_invalid_certs(true)
+            .use_sni(false)
+            .build().unwrap();
+
+        let stream = TcpStream::connect(relay.address()).unwrap();
+        let mut stream = connector.connect("", stream).unwrap();
+
+        mock_link_handshake(& mut stream);
+
+        let ntor = NtorClient::new(&relay.node_id(), &relay.onion_key());
+
+        TorCell::new(0x80000001, Command::Create2 { handshake_type: NTOR_HANDSHAKE_TYPE, onion_skin: NLengthVector::from(ntor.onion_skin()) }).into_stream(& mut stream, 4).unwrap();
+
+        let created2 = TorCell::from_stream(& mut stream, 4).unwrap();
+
+        let materials = match created2.get_command() {
+            Command::Created2 { handshake_data } => ntor.complete(&handshake_data.0, 72).unwrap(),
+            command => panic!("Expected CREATED2, got {:?}", command),
+        };
+
+        let mut cell_crypto = CellCrypto::from(&materials[..].try_into().unwrap());
+
+        send_mock_relay(& mut stream, & mut cell_crypto, 0x80000001, RelayCell::new(1, Relay::Begin { addr_and_port: format!("127.0.0.1:{}", echo.port()), flags: BeginFlags::empty() }));
+
+        let connected = receive_mock_relay(& mut stream, & mut cell_crypto);
+
+        assert!(matches!(connected.get_payload().unwrap(), Some(Relay::Connected { .. })));
+
+        send_mock_relay(& mut stream, & mut cell_crypto, 0x80000001, RelayCell::new_data(1, b"hello torpedo"));
+
+        let data = receive_mock_relay(& mut stream, & mut cell_crypto);
+
+        assert_eq!(data.get_command(), 2);
+        assert_eq!(data.into_data(), b"hello torpedo".to_vec());
+    }
+
+    #[test]
+    fn test_channel_demultiplexes_circuits() {
+        use crate::channel::Channel;
+        use crate::cells::LinkSpecifier;
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::connect(relay.address()).unwrap();
+
+        assert_eq!(channel.link_version(), 5);
+
+        let first = channel.create_fast().unwrap();
+        let second = channel.create_ntor(&relay.node_id(), &relay.onion_key()).unwrap();
+
+        second.extend_ntor(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key()).unwrap();
+
+        assert_eq!(channel.circuit_count(), 2);
+        assert_ne!(first.circuit_id(), second.circuit_id());
+        assert_ne!(first.circuit_id() & 0x80000000, 0);
+        assert_ne!(second.circuit_id() & 0x80000000, 0);
+        assert_eq!(second.hop_count(), 2);
+
+        let mut first_stream = first.begin("127.0.0.1", echo.port()).unwrap();
+        let mut second_stream = second.begin("127.0.0.1", echo.port()).unwrap();
+
+        //Interleave the two circuits so each must pick its own cells out of the channel
+        first_stream.write_all(&[0x11u8; 3000]).unwrap();
+        second_stream.write_all(&[0x22u8; 3000]).unwrap();
+
+        let mut echoed = vec![0u8; 3000];
+
+        second_stream.read_exact(& mut echoed).unwrap();
+        assert!(echoed.iter().all(|b| *b == 0x22));
+
+        first_stream.read_exact(& mut echoed).unwrap();
+        assert!(echoed.iter().all(|b| *b == 0x11));
+
+        drop(first_stream);
+        drop(first);
+
+        assert_eq!(channel.circuit_count(), 1);
+    }
+
+    #[test]
+    fn test_channel_closes_on_eof() {
+        use crate::channel::Channel;
+        use crate::error::ErrorKind;
+
+        let relay = MockRelay::spawn(MockTransport::Plain);
+        let echo = spawn_echo_server();
+
+        let tcp = TcpStream::connect(relay.address()).unwrap();
+
+        let channel = Channel::handshake(tcp, relay.address().ip()).unwrap();
+
+        let circuit = channel.create_fast().unwrap();
+
+        let mut stream = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+        relay.disconnect_all();
+
+        let mut buffer = [0u8; 16];
+
+        assert!(stream.read(& mut buffer).is_err());
+        assert!(circuit.is_closed());
+        assert_eq!(circuit.teardown(), Some(crate::error::Teardown::Destroyed(crate::cells::DestroyReason::OrConnClosed)));
+        assert!(channel.is_closed());
+        assert_eq!(channel.circuit_count(), 0);
+
+        match channel.create_fast() {
+            Err(ErrorKind::ChannelClosed) => {},
+            other => panic!("expected a closed channel, got {:?}", other.map(|c| c.circuit_id())),
+        }
+    }
+
+    fn teardown_of(error: &std::io::Error) -> Option<crate::error::Teardown> {
+        error.get_ref().and_then(|inner| inner.downcast_ref::<crate::error::Teardown>()).copied()
+    }
+
+    #[test]
+    fn test_circuit_destroyed_by_relay() {
+        use crate::channel::Channel;
+        use crate::cells::DestroyReason;
+        use crate::error::Teardown;
+
+        let relay = MockRelay::spawn(MockTransport::Plain);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::handshake(TcpStream::connect(relay.address()).unwrap(), relay.address().ip()).unwrap();
+
+        let circuit = channel.create_fast().unwrap();
+
+        let mut stream = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+        relay.destroy_circuits(DestroyReason::ResourceLimit);
+
+        let error = stream.read(& mut [0u8; 16]).unwrap_err();
+
+        assert_eq!(teardown_of(&error), Some(Teardown::Destroyed(DestroyReason::ResourceLimit)));
+        assert_eq!(circuit.teardown(), Some(Teardown::Destroyed(DestroyReason::ResourceLimit)));
+        assert_eq!(channel.circuit_count(), 0);
+        assert!(!channel.is_closed());
+
+        //The channel outlives its circuits
+        let replacement = channel.create_fast().unwrap();
+
+        replacement.destroy();
+
+        assert_eq!(replacement.teardown(), Some(Teardown::Closed));
+        assert_eq!(channel.circuit_count(), 0);
+        assert!(replacement.begin("127.0.0.1", echo.port()).is_err());
+    }
+
+    #[test]
+    fn test_circuit_truncate() {
+        use crate::channel::Channel;
+        use crate::cells::{DestroyReason, LinkSpecifier};
+        use crate::error::{ErrorKind, Teardown};
+
+        let relay = MockRelay::spawn(MockTransport::Plain);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::handshake(TcpStream::connect(relay.address()).unwrap(), relay.address().ip()).unwrap();
+
+        let circuit = channel.create_fast().unwrap();
+
+        let mut near = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+        //The relay can't complete a handshake for another identity, so it answers with TRUNCATED and the circuit survives
+        match circuit.extend_ntor(vec![LinkSpecifier::legacy_id([0u8; 20])], &[0u8; 20], &relay.onion_key()) {
+            Err(ErrorKind::CircuitTruncated(DestroyReason::ConnectFailed)) => {},
+            other => panic!("expected TRUNCATED, got {:?}", other),
+        }
+
+        assert_eq!(circuit.hop_count(), 1);
+
+        circuit.extend_ntor(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key()).unwrap();
+
+        let mut far = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+        circuit.truncate(1).unwrap();
+
+        assert_eq!(circuit.hop_count(), 1);
+        assert!(!circuit.is_closed());
+
+        let error = far.read(& mut [0u8; 16]).unwrap_err();
+
+        assert_eq!(teardown_of(&error), Some(Teardown::Truncated(DestroyReason::Requested)));
+
+        let error = far.write(b"lost").unwrap_err();
+
+        assert_eq!(teardown_of(&error), Some(Teardown::Truncated(DestroyReason::Requested)));
+
+        near.write_all(b"still here").unwrap();
+
+        let mut echoed = [0u8; 10];
+
+        near.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"still here");
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_circuit_teardown() {
+        use crate::async_channel::AsyncChannel;
+        use crate::cells::{DestroyReason, LinkSpecifier};
+        use crate::error::{ErrorKind, Teardown};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let relay = MockRelay::spawn(MockTransport::Plain);
+        let echo = spawn_echo_server();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let tcp = tokio::net::TcpStream::connect(relay.address()).await.unwrap();
+
+            let channel = AsyncChannel::handshake(tcp, relay.address().ip()).await.unwrap();
+
+            let circuit = channel.create_fast().await.unwrap();
+
+            match circuit.extend_ntor(vec![LinkSpecifier::legacy_id([0u8; 20])], &[0u8; 20], &relay.onion_key()).await {
+                Err(ErrorKind::CircuitTruncated(DestroyReason::ConnectFailed)) => {},
+                other => panic!("expected TRUNCATED, got {:?}", other),
+            }
+
+            let mut near = circuit.begin("127.0.0.1", echo.port()).await.unwrap();
+
+            circuit.extend_ntor(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key()).await.unwrap();
+
+            let mut far = circuit.begin("127.0.0.1", echo.port()).await.unwrap();
+
+            circuit.truncate(1).await.unwrap();
+
+            assert_eq!(circuit.hop_count(), 1);
+
+            let error = far.read(& mut [0u8; 16]).await.unwrap_err();
+
+            assert_eq!(teardown_of(&error), Some(Teardown::Truncated(DestroyReason::Requested)));
+
+            near.write_all(b"ping").await.unwrap();
+
+            let mut echoed = [0u8; 4];
+
+            near.read_exact(& mut echoed).await.unwrap();
+
+            assert_eq!(&echoed, b"ping");
+
+            relay.destroy_circuits(DestroyReason::Finished);
+
+            let error = near.read(& mut [0u8; 16]).await.unwrap_err();
+
+            assert_eq!(teardown_of(&error), Some(Teardown::Destroyed(DestroyReason::Finished)));
+            assert_eq!(circuit.teardown(), Some(Teardown::Destroyed(DestroyReason::Finished)));
+            assert_eq!(channel.circuit_count(), 0);
+        });
+    }
+
+    struct FakeCircuit {
+        ports: Vec<u16>,
+        closed: std::sync::atomic::AtomicBool,
+    }
+
+    impl crate::circuit_pool::PooledCircuit for FakeCircuit {
+        fn is_closed(& self) -> bool {
+            self.closed.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeBuilder {
+        fail: std::sync::atomic::AtomicBool,
+    }
+
+    impl crate::circuit_pool::CircuitBuilder for FakeBuilder {
+        type Circuit = FakeCircuit;
+
+        fn build(& self, ports: &[u16]) -> crate::error::Result<FakeCircuit> {
+            if self.fail.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(crate::error::ErrorKind::HandshakeFailed);
+            }
+
+            Ok(FakeCircuit { ports: Vec::from(ports), closed: std::sync::atomic::AtomicBool::new(false) })
+        }
+
+        fn exit_allows(& self, circuit: &FakeCircuit, port: u16) -> bool {
+            circuit.ports.is_empty() || circuit.ports.contains(&port)
+        }
+    }
+
+    #[test]
+    fn test_pool_reuse_and_isolation() {
+        use crate::circuit_pool::{CircuitPool, PoolConfig};
+        use crate::isolation::{IsolationKey, IsolationToken};
+        use std::sync::Arc;
+
+        let pool = CircuitPool::new(FakeBuilder::default(), PoolConfig::default());
+
+        assert_eq!(pool.predicted_ports(), vec![80]);
+
+        pool.maintain();
+
+        assert_eq!(pool.clean_count(), 2);
+        assert_eq!(pool.metrics().builds_succeeded, 2);
+
+        let shared = IsolationKey::default();
+
+        let first = pool.get(80, &shared).unwrap();
+        let second = pool.get(80, &shared).unwrap();
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(pool.clean_count(), 1);
+
+        //An isolated stream takes the remaining clean circuit rather than sharing
+        let isolated = pool.get(80, &IsolationToken::new().into()).unwrap();
+
+        assert!(!Arc::ptr_eq(&first, &isolated));
+        assert_eq!(pool.clean_count(), 0);
+
+        //No circuit's exit allows 443, so one is built while we wait
+        let https = pool.get(443, &shared).unwrap();
+
+        assert_eq!(https.ports, vec![443]);
+        assert_eq!(pool.metrics().built_on_demand, 1);
+        assert_eq!(pool.metrics().circuits_reused, 3);
+
+        pool.maintain();
+
+        assert_eq!(pool.predicted_ports(), vec![80, 443]);
+        assert_eq!(pool.clean_count(), 2);
+        assert!(pool.get(443, &IsolationToken::new().into()).unwrap().ports.contains(&80));
+        assert_eq!(pool.metrics().builds_succeeded, 5);
+        assert!(pool.metrics().mean_build_time().is_some());
+    }
+
+    #[test]
+    fn test_pool_retires_circuits() {
+        use crate::circuit_pool::{CircuitPool, PoolConfig};
+        use crate::isolation::IsolationKey;
+        use std::sync::Arc;
+        use std::sync::atomic::Ordering;
+        use std::time::Duration;
+
+        let config = PoolConfig {
+            clean_circuits: 0,
+            max_circuit_dirtiness: Duration::from_secs(0),
+            initial_ports: vec![],
+            ..PoolConfig::default()
+        };
+
+        let pool = CircuitPool::new(FakeBuilder::default(), config);
+
+        pool.maintain();
+
+        assert!(pool.is_empty());
+
+        let first = pool.get(22, &IsolationKey::default()).unwrap();
+
+        //With no dirtiness allowed, a circuit is never handed out twice
+        let second = pool.get(22, &IsolationKey::default()).unwrap();
+
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(pool.metrics().circuits_retired, 1);
+
+        let config = PoolConfig {
+            initial_ports: vec![],
+            ..PoolConfig::default()
+        };
+
+        let pool = CircuitPool::new(FakeBuilder::default(), config);
+
+        pool.maintain();
+
+        let clean = pool.get(22, &IsolationKey::default()).unwrap();
+
+        assert!(clean.ports.is_empty());
+
+        clean.closed.store(true, Ordering::SeqCst);
+
+        pool.maintain();
+
+        assert_eq!(pool.metrics().circuits_retired, 1);
+        assert_eq!(pool.clean_count(), 2);
+
+        pool.builder().fail.store(true, Ordering::SeqCst);
+
+        assert!(pool.get(25, &IsolationKey::default()).is_err());
+        assert_eq!(pool.metrics().builds_failed, 1);
+    }
+
+    #[test]
+    fn test_isolation_keys() {
+        use crate::isolation::{IsolationFlags, StreamRequest, IsolationToken};
+
+        let flags = IsolationFlags::default();
+
+        let alice = StreamRequest::new("example.com", 80).socks_auth(b"alice", b"x");
+        let bob = StreamRequest::new("example.com", 80).socks_auth(b"bob", b"x");
+        let anonymous = StreamRequest::new("example.com", 80);
+
+        //IsolateSOCKSAuth: different credentials, or credentials against none, never match
+        assert_eq!(flags.key(&alice), flags.key(&StreamRequest::new("example.net", 443).socks_auth(b"alice", b"x")));
+        assert_ne!(flags.key(&alice), flags.key(&bob));
+        assert_ne!(flags.key(&alice), flags.key(&anonymous));
+        assert_ne!(flags.key(&alice), flags.key(&alice.clone().socks_auth(b"alice", b"y")));
+        assert_eq!(IsolationFlags::none().key(&alice), IsolationFlags::none().key(&bob));
+
+        //IsolateDestAddr compares addresses without case and ignores ports
+        let by_address = IsolationFlags { dest_addr: true, ..IsolationFlags::none() };
+
+        assert_eq!(by_address.key(&anonymous), by_address.key(&StreamRequest::new("EXAMPLE.com", 443)));
+        assert_ne!(by_address.key(&anonymous), by_address.key(&StreamRequest::new("example.org", 80)));
+
+        let by_port = IsolationFlags { dest_port: true, ..IsolationFlags::none() };
+
+        assert_ne!(by_port.key(&anonymous), by_port.key(&StreamRequest::new("example.com", 443)));
+
+        let local = "127.0.0.1".parse().unwrap();
+        let remote = "10.0.0.2".parse().unwrap();
+
+        assert_ne!(flags.key(&anonymous.clone().client_addr(local)), flags.key(&anonymous.clone().client_addr(remote)));
+        assert_ne!(flags.key(&anonymous), flags.key(&anonymous.clone().token(IsolationToken::new())));
+
+        //Front-ends with different flags are separate session groups
+        assert_ne!(flags.key(&anonymous), IsolationFlags::none().key(&anonymous));
+    }
+
+    #[test]
+    fn test_pool_isolates_tenants() {
+        use crate::circuit_pool::{CircuitPool, PoolConfig};
+        use crate::isolation::{IsolationFlags, StreamRequest};
+        use std::sync::Arc;
+
+        let pool = CircuitPool::new(FakeBuilder::default(), PoolConfig::default());
+
+        pool.maintain();
+
+        let flags = IsolationFlags::default();
+
+        let first = pool.get_for(&StreamRequest::new("example.com", 80).socks_auth(b"tenant-a", b""), &flags).unwrap();
+        let second = pool.get_for(&StreamRequest::new("example.org", 80).socks_auth(b"tenant-b", b""), &flags).unwrap();
+        let third = pool.get_for(&StreamRequest::new("example.net", 80).socks_auth(b"tenant-a", b""), &flags).unwrap();
+
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&first, &third));
+
+        //A third tenant finds no clean circuit left and gets a new one rather than sharing
+        let fourth = pool.get_for(&StreamRequest::new("example.com", 80).socks_auth(b"tenant-c", b""), &flags).unwrap();
+
+        assert!(!Arc::ptr_eq(&fourth, &first) && !Arc::ptr_eq(&fourth, &second));
+        assert_eq!(pool.metrics().built_on_demand, 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_mock_relay_exit() {
+        use crate::async_channel::AsyncChannel;
+        use crate::cells::LinkSpecifier;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let channel = AsyncChannel::connect(relay.address()).await.unwrap();
+
+            assert_eq!(channel.link_version(), 5);
+
+            let circuit = channel.create_fast().await.unwrap();
+
+            circuit.extend_ntor(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key()).await.unwrap();
+
+            assert_eq!(circuit.hop_count(), 2);
+            assert_ne!(circuit.circuit_id() & 0x80000000, 0);
+
+            let mut stream = circuit.begin("127.0.0.1", echo.port()).await.unwrap();
+
+            let message = vec![0x5au8; 2000];
+
+            stream.write_all(&message).await.unwrap();
+
+            let mut echoed = vec![0u8; 2000];
+
+            stream.read_exact(& mut echoed).await.unwrap();
+
+            assert_eq!(echoed, message);
+        });
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_cancelled_build() {
+        use crate::async_channel::AsyncChannel;
+
+        let relay = MockRelay::spawn(MockTransport::Plain);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let tcp = tokio::net::TcpStream::connect(relay.address()).await.unwrap();
+
+            let channel = AsyncChannel::handshake(tcp, relay.address().ip()).await.unwrap();
+
+            //The build is polled once, which sends CREATE_FAST, then dropped in favour of the ready branch
+            tokio::select! {
+                biased;
+                _ = channel.create_fast() => panic!("The build should not have completed"),
+                _ = async {} => {},
+            }
+
+            assert_eq!(channel.circuit_count(), 0);
+
+            let circuit = channel.create_fast().await.unwrap();
+
+            assert_eq!(channel.circuit_count(), 1);
+            assert_eq!(circuit.hop_count(), 1);
+        });
+    }
+
+    #[test]
+    fn test_onion_address() {
+        use crate::onion::OnionAddress;
+        use std::str::FromStr;
+
+        let text = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+        let address = OnionAddress::from_str(text).unwrap();
+
+        assert_eq!(address.to_string(), text);
+        assert_eq!(OnionAddress::from_str(&text.to_ascii_uppercase()).unwrap(), address);
+        assert_eq!(OnionAddress::from_str(&format!("www.{}", text)).unwrap(), address);
+        assert_eq!(OnionAddress::from_public_key(*address.public_key()).to_string(), text);
+
+        //Changing one character breaks the checksum
+        assert!(OnionAddress::from_str("duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczae.onion").is_err());
+        assert!(OnionAddress::from_str("duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzcza.onion").is_err());
+    }
+
+    #[test]
+    fn test_onion_time_period_and_blinding() {
+        use crate::onion::{OnionAddress, TimePeriod};
+        use chrono::{DateTime, Utc};
+        use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+        use curve25519_dalek::scalar::Scalar;
+
+        //The example from rend-spec-v3 section 2.2.1
+        let period = TimePeriod::at("2016-04-13T11:00:00Z".parse::<DateTime<Utc>>().unwrap(), 1440);
+
+        assert_eq!(period.number, 16903);
+        assert_eq!(TimePeriod::at("2016-04-13T12:00:00Z".parse::<DateTime<Utc>>().unwrap(), 1440).number, 16904);
+
+        //The blinded key is the identity key multiplied by the blinding factor, so it is also h * a * B
+        let secret = Scalar::from_bits([7u8; 32]);
+
+        let address = OnionAddress::from_public_key((secret * ED25519_BASEPOINT_POINT).compress().to_bytes());
+
+        let blinded = address.blinded_key(period).unwrap();
+
+        assert_ne!(&blinded, address.public_key());
+        assert_ne!(blinded, address.blinded_key(TimePeriod { number: 16904, length: 1440 }).unwrap());
+        assert_ne!(address.subcredential(period).unwrap(), address.subcredential(TimePeriod { number: 16904, length: 1440 }).unwrap());
+
+        let reparsed: OnionAddress = address.to_string().parse().unwrap();
+
+        assert_eq!(reparsed.blinded_key(period).unwrap(), blinded);
+    }
+
+    #[test]
+    fn test_hsdir_ring() {
+        use crate::onion::{HsDirRing, RelayInfo, TimePeriod};
+
+        let relays = |count: u8| (0..count).map(|i| RelayInfo {
+            node_id: [i; 20],
+            ed25519_id: if i == 0 { None } else { Some([i; 32]) },
+            ntor_onion_key: [0u8; 32],
+            link_specifiers: vec![],
+        }).collect::<Vec<_>>();
+
+        let period = TimePeriod { number: 16903, length: 1440 };
+
+        let ring = HsDirRing::new(relays(21), &[1u8; 32], period);
+
+        let responsible = ring.responsible(&[2u8; 32]);
+
+        assert_eq!(responsible.len(), 6);
+
+        let mut ids: Vec<[u8; 20]> = responsible.iter().map(|relay| relay.node_id).collect();
+
+        //The same ring gives the same answer, and never chooses a relay twice or one without an ed25519 identity
+        assert_eq!(ring.responsible(&[2u8; 32]).iter().map(|relay| relay.node_id).collect::<Vec<_>>(), ids);
+
+        ids.sort();
+        ids.dedup();
+
+        assert_eq!(ids.len(), 6);
+        assert!(!ids.contains(&[0u8; 20]));
+
+        //A different shared random value moves the relays around the ring
+        let moved = HsDirRing::new(relays(21), &[3u8; 32], period);
+
+        assert_ne!(moved.responsible(&[2u8; 32]).iter().map(|relay| relay.node_id).collect::<Vec<_>>(), responsible.iter().map(|relay| relay.node_id).collect::<Vec<_>>());
+
+        //With fewer relays than replicas need, each is used once
+        assert_eq!(HsDirRing::new(relays(5), &[1u8; 32], period).responsible(&[2u8; 32]).len(), 4);
+    }
+
+    ///An ed25519 key from a fixed seed, as its expanded secret and public halves
+    fn test_ed25519_key(seed: u8) -> (ed25519_dalek::ExpandedSecretKey, ed25519_dalek::PublicKey) {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[seed; 32]).unwrap();
+
+        (ed25519_dalek::ExpandedSecretKey::from(&secret), ed25519_dalek::PublicKey::from(&secret))
+    }
+
+    ///A signed descriptor with two introduction points, the second of which has a certificate from the wrong key.
+    ///`auth_lines` go in the middle layer and `descriptor_cookie` keys the inner layer
+    fn test_hs_descriptor(blinded: u8, subcredential: &[u8; 32], auth_lines: &str, descriptor_cookie: Option<&[u8; 16]>) -> String {
+        use crate::hs_descriptor::{Ed25519Cert, encrypt_superencrypted, encrypt_inner, encode_link_specifiers};
+        use crate::netdoc::{encode_base64, encode_object};
+        use crate::cells::LinkSpecifier;
+        use std::net::SocketAddrV4;
+
+        let (blinded_secret, blinded_key) = test_ed25519_key(blinded);
+        let (signing_secret, signing_key) = test_ed25519_key(30);
+        let (wrong_secret, wrong_key) = test_ed25519_key(31);
+
+        //Expires in the year 2100
+        let expiration = 1141000;
+
+        let mut inner = String::from("create2-formats 2\n");
+
+        for (i, (secret, key)) in [(&signing_secret, &signing_key), (&wrong_secret, &wrong_key)].iter().enumerate() {
+            let link_specifiers = vec![LinkSpecifier::ipv4(SocketAddrV4::new([127, 0, 0, 1].into(), 9001 + i as u16)), LinkSpecifier::legacy_id([13u8; 20]), LinkSpecifier::ed25519_id([32u8; 32])];
+
+            inner.push_str(&format!("introduction-point {}\nonion-key ntor {}\nauth-key\n{}\nenc-key ntor {}\nenc-key-cert\n{}\n",
+                encode_base64(&encode_link_specifiers(&link_specifiers)),
+                encode_base64(&[14u8; 32]),
+                encode_object("ED25519 CERT", &Ed25519Cert::encode(9, expiration, &[15u8; 32], &signing_secret, &signing_key)),
+                encode_base64(&[16u8; 32]),
+                encode_object("ED25519 CERT", &Ed25519Cert::encode(11, expiration, &[17u8; 32], secret, key))));
+        }
+
+        let middle = format!("desc-auth-type x25519\n{}encrypted\n{}\n",
+            auth_lines,
+            encode_object("MESSAGE", &encrypt_inner(inner.as_bytes(), blinded_key.as_bytes(), descriptor_cookie, subcredential, 42)));
+
+        let outer = format!("hs-descriptor 3\ndescriptor-lifetime 180\ndescriptor-signing-key-cert\n{}\nrevision-counter 42\nsuperencrypted\n{}\n",
+            encode_object("ED25519 CERT", &Ed25519Cert::encode(8, expiration, signing_key.as_bytes(), &blinded_secret, &blinded_key)),
+            encode_object("MESSAGE", &encrypt_superencrypted(middle.as_bytes(), blinded_key.as_bytes(), subcredential, 42)));
+
+        let mut signed = Vec::from(&b"Tor onion service descriptor sig v3"[..]);
+        signed.extend_from_slice(outer.as_bytes());
+
+        format!("{}signature {}\n", outer, encode_base64(&signing_secret.sign(&signed, &signing_key).to_bytes()))
+    }
+
+    #[test]
+    fn test_hs_descriptor_layers() {
+        use crate::hs_descriptor::HsDescriptor;
+        use crate::netdoc::encode_base64;
+        use chrono::Utc;
+
+        let blinded = *test_ed25519_key(33).1.as_bytes();
+        let subcredential = [12u8; 32];
+
+        let auth_lines = format!("desc-auth-ephemeral-key {}\nauth-client AAAAAAAAAAA= AAAAAAAAAAAAAAAAAAAAAA== {}\n", encode_base64(&[18u8; 32]), encode_base64(&[0u8; 16]));
+
+        let document = test_hs_descriptor(33, &subcredential, &auth_lines, None);
+
+        let descriptor = HsDescriptor::parse(&document).unwrap();
+
+        assert_eq!(descriptor.lifetime, 180);
+        assert_eq!(descriptor.revision_counter, 42);
+        assert_eq!(descriptor.signing_key_cert.certified_key, *test_ed25519_key(30).1.as_bytes());
+
+        descriptor.verify(&blinded, Utc::now()).unwrap();
+
+        //The intro point whose certificate came from the wrong key is dropped
+        let intro_points = descriptor.decrypt(&blinded, &subcredential, None).unwrap();
+
+        assert_eq!(intro_points.len(), 1);
+        assert_eq!(intro_points[0].onion_key, [14u8; 32]);
+        assert_eq!(intro_points[0].auth_key, [15u8; 32]);
+        assert_eq!(intro_points[0].enc_key, [16u8; 32]);
+        assert_eq!(intro_points[0].node_id(), Some([13u8; 20]));
+        assert_eq!(intro_points[0].ed25519_id(), Some([32u8; 32]));
+        assert_eq!(intro_points[0].link_specifiers.len(), 3);
+
+        //The wrong subcredential fails the MAC of the first layer
+        assert!(descriptor.decrypt(&blinded, &[0u8; 32], None).is_err());
+
+        //A descriptor signing key certified by another key, or a changed document, is rejected
+        assert!(HsDescriptor::parse(&test_hs_descriptor(34, &subcredential, &auth_lines, None)).unwrap().verify(&blinded, Utc::now()).is_err());
+        assert!(HsDescriptor::parse(&document.replace("revision-counter 42", "revision-counter 43")).unwrap().verify(&blinded, Utc::now()).is_err());
+
+        //So is an expired certificate
+        assert!(descriptor.verify(&blinded, "2101-01-01T00:00:00Z".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_hs_descriptor_client_auth() {
+        use crate::hs_descriptor::{HsDescriptor, AuthClient};
+        use crate::netdoc::encode_base64;
+        use crate::ntor::generate_secret;
+        use crate::error::ErrorKind;
+        use x25519_dalek::PublicKey;
+
+        let blinded = *test_ed25519_key(33).1.as_bytes();
+        let subcredential = [12u8; 32];
+        let descriptor_cookie = [19u8; 16];
+
+        let ephemeral = generate_secret();
+        let client = generate_secret();
+        let stranger = generate_secret();
+
+        let auth_lines = format!("desc-auth-ephemeral-key {}\n{}\n{}\n",
+            encode_base64(PublicKey::from(&ephemeral).as_bytes()),
+            AuthClient::fake().to_line(),
+            AuthClient::new(&ephemeral, &PublicKey::from(&client), &subcredential, &descriptor_cookie).to_line());
+
+        let descriptor = HsDescriptor::parse(&test_hs_descriptor(33, &subcredential, &auth_lines, Some(&descriptor_cookie))).unwrap();
+
+        assert_eq!(descriptor.decrypt(&blinded, &subcredential, Some(&client)).unwrap().len(), 1);
+
+        match descriptor.decrypt(&blinded, &subcredential, Some(&stranger)) {
+            Err(ErrorKind::ClientAuthRejected(reason)) => assert!(reason.contains("not authorized")),
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        match descriptor.decrypt(&blinded, &subcredential, None) {
+            Err(ErrorKind::ClientAuthRequired) => {},
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hs_vectors() {
+        use crate::custom_crypto::{sha3_256, shake256, ed25519_verify};
+        use crate::onion::OnionAddress;
+        use std::convert::TryInto;
+
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+
+        //FIPS 202 empty message digests
+        assert_eq!(sha3_256(b"")[..], hex("a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a")[..]);
+        assert_eq!(shake256(b"", 32), hex("46b9dd2b0ba88d13233b3feb743eeb243fcd52ea62b81b82b50c27646ed5762f"));
+
+        //RFC 8032 section 7.1, test 1
+        let public_key: [u8; 32] = hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a").as_slice().try_into().unwrap();
+        let signature: [u8; 64] = hex("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b").as_slice().try_into().unwrap();
+
+        assert!(ed25519_verify(&public_key, b"", &signature));
+        assert!(!ed25519_verify(&public_key, b"x", &signature));
+
+        //The same key as an onion address, from Tor's test_hs_common.c
+        assert_eq!(OnionAddress::from_public_key(public_key).to_string(), "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion");
+    }
+
+    #[test]
+    fn test_hs_spec_derivations() {
+        use crate::onion::TimePeriod;
+        use crate::onion_service::OnionServiceKeys;
+        use crate::hs_descriptor::{decrypt_layer, encrypt_superencrypted, encrypt_inner};
+        use crate::custom_crypto::ed25519_verify;
+        use curve25519_dalek::edwards::CompressedEdwardsY;
+        use curve25519_dalek::scalar::Scalar;
+        use ctr::cipher::{NewCipher, StreamCipher};
+        use sha2::Sha512;
+        use sha3::{Sha3_256, Shake256};
+        use sha3::digest::{Update, ExtendableOutput, XofReader};
+
+        //Each value is worked out here from the formulas of rend-spec-v3, with the hashes and ciphers called directly
+        let sha3 = |parts: &[&[u8]]| -> [u8; 32] {
+            let mut hasher = Sha3_256::default();
+
+            for part in parts {
+                Update::update(& mut hasher, part);
+            }
+
+            sha3::Digest::finalize(hasher).into()
+        };
+
+        let shake = |parts: &[&[u8]], length: usize| -> Vec<u8> {
+            let mut hasher = Shake256::default();
+
+            for part in parts {
+                Update::update(& mut hasher, part);
+            }
+
+            let mut output = vec![0u8; length];
+
+            XofReader::read(& mut hasher.finalize_xof(), & mut output);
+
+            output
+        };
+
+        //RFC 8032 section 7.1, test 1, whose public key is d75a9801...
+        let seed = ed25519_dalek::SecretKey::from_bytes(&[0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c, 0xc4, 0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60]).unwrap();
+        let expanded = ed25519_dalek::ExpandedSecretKey::from(&seed).to_bytes();
+
+        let keys = OnionServiceKeys::from_expanded_secret(&expanded).unwrap();
+        let address = keys.address();
+
+        assert_eq!(address.to_string(), "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion");
+
+        let period = TimePeriod { number: 16903, length: 1440 };
+
+        //A.2: h = H(BLIND_STRING | A | s | B | N), N = "key-blind" | INT_8(period number) | INT_8(period length), clamped
+        let mut h = sha3(&[
+            b"Derive temporary signing key\0",
+            address.public_key(),
+            b"(15112221349535400772501151409588531511454012693041857206046113283949847762202, 46316835694926478169428394003475163141307993866256225615783033603165251855960)",
+            b"key-blind",
+            &16903u64.to_be_bytes(),
+            &1440u64.to_be_bytes(),
+        ]);
+
+        h[0] &= 248;
+        h[31] &= 63;
+        h[31] |= 64;
+
+        let identity = CompressedEdwardsY(*address.public_key()).decompress().unwrap();
+        let blinded_public = (Scalar::from_bits(h) * identity).compress().to_bytes();
+
+        assert_eq!(address.blinded_key(period).unwrap(), blinded_public);
+
+        //The blinded secret is h * a with prefix SHA-512("Derive temporary signing key hash input" | RH)[:32]
+        let blinded_secret = Scalar::from_bits(h) * Scalar::from_bits(expanded[0..32].try_into().unwrap());
+
+        let mut prefix = sha2::Digest::chain(Sha512::default(), &b"Derive temporary signing key hash input"[..]);
+        prefix = sha2::Digest::chain(prefix, &expanded[32..64]);
+
+        let mut blinded_expanded = [0u8; 64];
+
+        blinded_expanded[0..32].copy_from_slice(blinded_secret.as_bytes());
+        blinded_expanded[32..64].copy_from_slice(&sha2::Digest::finalize(prefix)[0..32]);
+
+        let blinded = keys.blinded(period);
+
+        assert_eq!(blinded.public_bytes(), blinded_public);
+        assert_eq!(blinded.sign(b"descriptor")[..], ed25519_dalek::ExpandedSecretKey::from_bytes(&blinded_expanded).unwrap().sign(b"descriptor", &ed25519_dalek::PublicKey::from_bytes(&blinded_public).unwrap()).to_bytes()[..]);
+        assert!(ed25519_verify(&blinded_public, b"descriptor", &blinded.sign(b"descriptor")));
+
+        //2.1: N_hs_subcred = H("subcredential" | H("credential" | public-identity-key) | blinded-public-key)
+        let credential = sha3(&[b"credential", address.public_key()]);
+        let subcredential = sha3(&[b"subcredential", &credential, &blinded_public]);
+
+        assert_eq!(address.subcredential(period).unwrap(), subcredential);
+
+        //2.5.3: keys = SHAKE256(SECRET_DATA | N_hs_subcred | INT_8(revision counter) | SALT | STRING_CONSTANT), 32 + 16 + 32
+        //bytes, and D_MAC = H(INT_8(len(MAC_KEY)) | MAC_KEY | INT_8(len(SALT)) | SALT | ENCRYPTED)
+        let layer = |secret_data: &[u8], salt: &[u8; 16], constant: &[u8], plaintext: &[u8]| -> Vec<u8> {
+            let keys = shake(&[secret_data, &subcredential, &42u64.to_be_bytes(), salt, constant], 80);
+
+            let mut encrypted = Vec::from(plaintext);
+
+            ctr::Ctr128BE::<aes::Aes256>::new(keys[0..32].into(), keys[32..48].into()).apply_keystream(& mut encrypted);
+
+            let mac = sha3(&[&32u64.to_be_bytes(), &keys[48..80], &16u64.to_be_bytes(), salt, &encrypted]);
+
+            [&salt[..], &encrypted, &mac].concat()
+        };
+
+        let unlayer = |secret_data: &[u8], constant: &[u8], blob: &[u8]| -> Vec<u8> {
+            let salt = &blob[0..16];
+            let keys = shake(&[secret_data, &subcredential, &42u64.to_be_bytes(), salt, constant], 80);
+            let encrypted = &blob[16..blob.len() - 32];
+
+            assert_eq!(blob[blob.len() - 32..], sha3(&[&32u64.to_be_bytes(), &keys[48..80], &16u64.to_be_bytes(), salt, encrypted]));
+
+            let mut plaintext = Vec::from(encrypted);
+
+            ctr::Ctr128BE::<aes::Aes256>::new(keys[0..32].into(), keys[32..48].into()).apply_keystream(& mut plaintext);
+
+            plaintext
+        };
+
+        let plaintext = b"desc-auth-type x25519\n";
+        let cookie = [19u8; 16];
+        let inner_secret = [&blinded_public[..], &cookie].concat();
+
+        //The outer layer uses SECRET_DATA = blinded-public-key and the constant "hsdir-superencrypted-data"
+        let outer = layer(&blinded_public, &[20u8; 16], b"hsdir-superencrypted-data", plaintext);
+
+        assert_eq!(decrypt_layer(&outer, &blinded_public, &subcredential, 42, b"hsdir-superencrypted-data").unwrap(), plaintext);
+
+        let sealed = encrypt_superencrypted(plaintext, &blinded_public, &subcredential, 42);
+        let opened = unlayer(&blinded_public, b"hsdir-superencrypted-data", &sealed);
+
+        assert_eq!(&opened[..plaintext.len()], plaintext);
+        assert_eq!(opened.len(), 10000);
+        assert!(opened[plaintext.len()..].iter().all(|byte| *byte == 0));
+
+        //The inner layer uses SECRET_DATA = blinded-public-key | descriptor_cookie and "hsdir-encrypted-data"
+        let inner = layer(&inner_secret, &[21u8; 16], b"hsdir-encrypted-data", plaintext);
+
+        assert_eq!(decrypt_layer(&inner, &inner_secret, &subcredential, 42, b"hsdir-encrypted-data").unwrap(), plaintext);
+
+        let sealed = encrypt_inner(plaintext, &blinded_public, Some(&cookie), &subcredential, 42);
+
+        assert_eq!(&unlayer(&inner_secret, b"hsdir-encrypted-data", &sealed)[..plaintext.len()], plaintext);
+
+        let sealed = encrypt_inner(plaintext, &blinded_public, None, &subcredential, 42);
+
+        assert_eq!(&unlayer(&blinded_public, b"hsdir-encrypted-data", &sealed)[..plaintext.len()], plaintext);
+
+        //C tor's key blinding vector, as arti's tor-hscrypto checks it
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+        let array = |text: &str| -> [u8; 32] { hex(text).try_into().unwrap() };
+
+        let expanded = hex("D8C7FF0E31295B66540D789AF3E3DF992038A9592EEA01D8B7CBA06D6E66D1594D6167696320576F7264733A20737065697373636F62616C742062697669756D");
+
+        let keys = OnionServiceKeys::from_expanded_secret(&expanded).unwrap();
+        let address = keys.address();
+        let period = TimePeriod { number: 1234, length: 1440 };
+
+        assert_eq!(address.public_key(), &array("833990B085C1A688C1D4C8B1F6B56AFAF5A2ECA674449E1D704F83765CCB7BC6"));
+
+        let h = sha3(&[
+            b"Derive temporary signing key\0",
+            address.public_key(),
+            b"(15112221349535400772501151409588531511454012693041857206046113283949847762202, 46316835694926478169428394003475163141307993866256225615783033603165251855960)",
+            b"key-blind",
+            &1234u64.to_be_bytes(),
+            &1440u64.to_be_bytes(),
+        ]);
+
+        assert_eq!(h, array("379E50DB31FEE6775ABD0AF6FB7C371E060308F4F847DB09FE4CFE13AF602287"));
+
+        let blinded_public = array("3A50BF210E8F9EE955AE0014F7A6917FB65EBF098A86305ABB508D1A7291B6D5");
+        let blinded_secret = ed25519_dalek::ExpandedSecretKey::from_bytes(&hex("A958DC83AC885F6814C67035DE817A2C604D5D2F715282079448F789B656350B4540FE1F80AA3F7E91306B7BF7A8E367293352B14A29FDCC8C19F3558075524B")).unwrap();
+
+        assert_eq!(address.blinded_key(period).unwrap(), blinded_public);
+        assert_eq!(address.subcredential(period).unwrap(), array("635D55907816E8D76398A675A50B1C2F3E36B42A5CA77BA3A0441285161AE07D"));
+        assert_eq!(keys.blinded(period).public_bytes(), blinded_public);
+        assert_eq!(keys.blinded(period).sign(b"descriptor")[..], blinded_secret.sign(b"descriptor", &ed25519_dalek::PublicKey::from_bytes(&blinded_public).unwrap()).to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_hs_descriptor_vectors() {
+        use crate::hs_descriptor::HsDescriptor;
+        use crate::onion::{OnionAddress, TimePeriod};
+        use crate::error::ErrorKind;
+        use chrono::{TimeZone, Utc};
+        use x25519_dalek::StaticSecret;
+
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+        let array = |text: &str| -> [u8; 32] { hex(text).try_into().unwrap() };
+
+        //Descriptors published by C tor services, with the keys arti's tor-netdoc decrypts them with
+        let descriptor = HsDescriptor::parse(include_str!("../testdata/hsdesc1.txt")).unwrap();
+
+        let blinded_key = array("43cc0d62fc6252f578705ca645a46109e265290343b1137e90189744b20b3f2d");
+        let subcredential = array("78210A0D2C72BB7A0CAF606BCD938B9A3696894FDDDBC3B87D424753A7E3DF37");
+
+        assert_eq!(descriptor.lifetime, 180);
+        assert_eq!(descriptor.revision_counter, 19655750);
+
+        descriptor.verify(&blinded_key, Utc.with_ymd_and_hms(2023, 1, 23, 15, 0, 0).unwrap()).unwrap();
+
+        assert!(descriptor.verify(&[12u8; 32], Utc.with_ymd_and_hms(2023, 1, 23, 15, 0, 0).unwrap()).is_err());
+        assert!(descriptor.verify(&blinded_key, Utc.with_ymd_and_hms(2023, 1, 27, 0, 0, 0).unwrap()).is_err());
+
+        let intro_points = descriptor.decrypt(&blinded_key, &subcredential, None).unwrap();
+
+        assert_eq!(intro_points.len(), 3);
+        assert_eq!(intro_points[0].onion_key, array("553BF9F9E1979D6F5D5D7D20BB3FE7272E32E22B6E86E35C76A7CA8A377E402F"));
+
+        //The second needs client authorization
+        let address = OnionAddress::from_public_key(array("781D978CE6CE9CAA8BCA306F53E82D2C993E5C91346625F1C151DCFC56D753D3"));
+        let period = TimePeriod { number: 19397, length: 1440 };
+
+        let blinded_key = address.blinded_key(period).unwrap();
+        let subcredential = address.subcredential(period).unwrap();
+
+        assert_eq!(blinded_key, array("706628758208395D461AA0F460A5E76E7B828C66B5E794768592B451302E961D"));
+        assert_eq!(subcredential, array("24A133E905102BDA9A6AFE57F901366A1B8281865A91F1FE0853E4B50CC8B070"));
+
+        let descriptor = HsDescriptor::parse(include_str!("../testdata/hsdesc2.txt")).unwrap();
+
+        descriptor.verify(&blinded_key, Utc.with_ymd_and_hms(2023, 2, 9, 12, 0, 0).unwrap()).unwrap();
+
+        assert!(matches!(descriptor.decrypt(&blinded_key, &subcredential, None), Err(ErrorKind::ClientAuthRequired)));
+        assert!(matches!(descriptor.decrypt(&blinded_key, &subcredential, Some(&StaticSecret::from([7u8; 32]))), Err(ErrorKind::ClientAuthRejected(_))));
+
+        let client_secret = StaticSecret::from(array("90F2D60F917F2423F0989CE9979639435A5B01CF7AB3E0A1B900BEB892BAE443"));
+
+        assert_eq!(x25519_dalek::PublicKey::from(&client_secret).as_bytes(), &array("900467008E194C2C635A6E335E7724915E558CCB606C50094476F9731C129019"));
+        assert_eq!(descriptor.decrypt(&blinded_key, &subcredential, Some(&client_secret)).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_introduce1_hs_ntor() {
+        use crate::onion::{Introduce1, IntroducePlaintext};
+        use crate::hs_ntor::{HsNtorClient, service_handshake};
+        use crate::ntor::generate_secret;
+        use crate::cells::LinkSpecifier;
+        use x25519_dalek::PublicKey;
+
+        let enc_secret = generate_secret();
+        let auth_key = [21u8; 32];
+        let subcredential = [22u8; 32];
+
+        let client = HsNtorClient::new(&auth_key, PublicKey::from(&enc_secret).as_bytes(), &subcredential);
+
+        let plaintext = IntroducePlaintext {
+            rendezvous_cookie: [23u8; 20],
+            extensions: vec![],
+            onion_key: [24u8; 32],
+            link_specifiers: vec![LinkSpecifier::legacy_id([25u8; 20])],
+        };
+
+        let header = Introduce1::header(&auth_key, &[]);
+
+        let introduce1 = Introduce1 {
+            auth_key,
+            extensions: vec![],
+            encrypted: client.encrypt(&header, &plaintext.to_bytes(header.len())).unwrap(),
+        };
+
+        let body = introduce1.to_bytes();
+
+        assert_eq!(body.len(), 498);
+
+        //The service sees the same cell as INTRODUCE2
+        let received = Introduce1::parse(&body).unwrap();
+
+        assert_eq!(received.auth_key, auth_key);
+
+        let (decrypted, reply, service_keys) = service_handshake(&auth_key, &enc_secret, &subcredential, &Introduce1::header(&received.auth_key, &received.extensions), &received.encrypted).unwrap();
+
+        let decrypted = IntroducePlaintext::parse(&decrypted).unwrap();
+
+        assert_eq!(decrypted.rendezvous_cookie, [23u8; 20]);
+        assert_eq!(decrypted.onion_key, [24u8; 32]);
+        assert_eq!(decrypted.link_specifiers[0].get_spec(), &[25u8; 20]);
+
+        assert_eq!(client.complete(&reply).unwrap()[..], service_keys[..]);
+
+        //A tampered cell fails the MAC
+        let mut tampered = received.encrypted.clone();
+        tampered[40] ^= 1;
+
+        assert!(service_handshake(&auth_key, &enc_secret, &subcredential, &header, &tampered).is_none());
+    }
+
+    ///Paths for onion service tests in which the mock relay plays every part, each circuit a single hop on a new channel
+    #[derive(Clone)]
+    struct MockOnionPaths {
+        address: std::net::SocketAddr,
+        relay: crate::onion::RelayInfo,
+    }
+
+    impl MockOnionPaths {
+        fn new(relay: &MockRelay) -> Self {
+            use crate::cells::LinkSpecifier;
+            use std::net::SocketAddrV4;
+
+            let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, relay.address().port());
+
+            Self {
+                address: relay.address(),
+                relay: crate::onion::RelayInfo {
+                    node_id: relay.node_id(),
+                    ed25519_id: Some([26u8; 32]),
+                    ntor_onion_key: relay.onion_key(),
+                    link_specifiers: vec![LinkSpecifier::ipv4(address), LinkSpecifier::legacy_id(relay.node_id()), LinkSpecifier::ed25519_id([26u8; 32])],
+                },
+            }
+        }
+    }
+
+    impl crate::onion::OnionPaths for MockOnionPaths {
+        fn hsdir_ring(& self, period: crate::onion::TimePeriod) -> crate::error::Result<crate::onion::HsDirRing> {
+            Ok(crate::onion::HsDirRing::new(vec![self.relay.clone()], &[0u8; 32], period))
+        }
+
+        fn circuit_to(& self, relay: &crate::onion::RelayInfo) -> crate::error::Result<crate::circuit::Circuit> {
+            crate::channel::Channel::connect(self.address)?.create_ntor(&relay.node_id, &relay.ntor_onion_key)
+        }
+
+        fn rendezvous_circuit(& self) -> crate::error::Result<(crate::circuit::Circuit, crate::onion::RelayInfo)> {
+            Ok((crate::onion::OnionPaths::circuit_to(self, &self.relay)?, self.relay.clone()))
+        }
+    }
+
+    impl crate::onion_service::ServicePaths for MockOnionPaths {
+        fn intro_circuit(& self) -> crate::error::Result<(crate::circuit::Circuit, crate::onion::RelayInfo)> {
+            Ok((crate::onion::OnionPaths::circuit_to(self, &self.relay)?, self.relay.clone()))
+        }
+    }
+
+    #[test]
+    fn test_onion_service_keys() {
+        use crate::onion_service::{OnionServiceKeys, EstablishIntro, DosParams};
+        use crate::hs_descriptor::Ed25519Keypair;
+        use crate::custom_crypto::ed25519_verify;
+        use crate::onion::TimePeriod;
+
+        let directory = std::env::temp_dir().join(format!("torpedo-hs-{}", rand::thread_rng().gen::<u64>()));
+
+        let keys = OnionServiceKeys::load_or_generate(&directory).unwrap();
+        let loaded = OnionServiceKeys::load_or_generate(&directory).unwrap();
+
+        assert_eq!(keys.address(), loaded.address());
+        assert_eq!(std::fs::read_to_string(directory.join("hostname")).unwrap(), format!("{}\n", keys.address()));
+
+        let public = std::fs::read(directory.join("hs_ed25519_public_key")).unwrap();
+
+        assert_eq!(&public[..29], b"== ed25519v1-public: type0 ==");
+        assert_eq!(&public[32..], keys.address().public_key());
+
+        std::fs::write(directory.join("hs_ed25519_secret_key"), b"not a key").unwrap();
+
+        assert!(OnionServiceKeys::load(&directory).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        //The blinded secret signs for the blinded public key clients derive from the address
+        let period = TimePeriod::current();
+        let blinded = loaded.blinded(period);
+
+        assert_eq!(blinded.public_bytes(), loaded.address().blinded_key(period).unwrap());
+        assert!(ed25519_verify(&blinded.public_bytes(), b"descriptor", &blinded.sign(b"descriptor")));
+
+        //ESTABLISH_INTRO is bound to the circuit's KH
+        let auth = Ed25519Keypair::generate();
+        let params = DosParams { rate_per_sec: 25, burst_per_sec: 200 };
+
+        let establish = EstablishIntro::parse(&EstablishIntro::new(&auth, vec![params.to_extension()], &[27u8; 20]).to_bytes()).unwrap();
+
+        assert_eq!(establish.auth_key, auth.public_bytes());
+        assert_eq!(establish.dos_params(), Some(params));
+        assert!(establish.verify(&[27u8; 20]));
+        assert!(!establish.verify(&[28u8; 20]));
+    }
+
+    #[test]
+    fn test_onion_service_rendezvous() {
+        use crate::onion::{OnionClient, TimePeriod};
+        use crate::onion_service::{OnionService, OnionServiceKeys, ServiceConfig, StreamHandler, DosParams};
+        use crate::circuit_padding::{CIRCPAD_COMMAND_START, MACHINE_CLIENT_INTRO, MACHINE_CLIENT_RENDEZVOUS};
+        use std::sync::Arc;
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+        let paths = MockOnionPaths::new(&relay);
+
+        let params = DosParams { rate_per_sec: 25, burst_per_sec: 200 };
+        let config = ServiceConfig { intro_points: 2, dos_params: Some(params), ..ServiceConfig::default() };
+
+        let forwarding = OnionService::launch(OnionServiceKeys::generate(), config, StreamHandler::Forward(echo), paths.clone()).unwrap();
+
+        //One descriptor for the current time period and one for the next
+        assert_eq!(relay.descriptor_count(), 2);
+        assert_eq!(relay.intro_dos_params(), vec![params; 2]);
+
+        let client = OnionClient::new(paths.clone());
+
+        let mut stream = client.connect(&forwarding.address(), 80).unwrap();
+
+        stream.write_all(&[0x33u8; 2000]).unwrap();
+
+        let mut echoed = vec![0u8; 2000];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert!(echoed.iter().all(|b| *b == 0x33));
+
+        //A client already in the next period introduces itself with that period's subcredential
+        let mut stream = client.connect_in_period(&forwarding.address(), 80, TimePeriod::current().next()).unwrap();
+
+        stream.write_all(b"next").unwrap();
+
+        let mut echoed = [0u8; 4];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"next");
+
+        //The client padded both its intro and rendezvous circuits
+        let machines = relay.padding_machines();
+
+        assert!(machines.iter().any(|(command, machine_type, _)| *command == CIRCPAD_COMMAND_START && *machine_type == MACHINE_CLIENT_INTRO));
+        assert!(machines.iter().any(|(command, machine_type, _)| *command == CIRCPAD_COMMAND_START && *machine_type == MACHINE_CLIENT_RENDEZVOUS));
+        assert!(relay.drops_received() >= 1);
+
+        //A callback gets the stream and the port the client asked for
+        let callback = StreamHandler::Callback(Arc::new(|mut stream: crate::circuit::TorStream, port: u16| {
+            let _ = stream.write_all(format!("port {}", port).as_bytes());
+        }));
+
+        let answering = OnionService::launch(OnionServiceKeys::generate(), ServiceConfig::default(), callback, paths).unwrap();
+
+        assert_eq!(relay.descriptor_count(), 4);
+
+        let mut stream = client.connect(&answering.address(), 8080).unwrap();
+
+        let mut answer = String::new();
+
+        stream.read_to_string(& mut answer).unwrap();
+
+        assert_eq!(answer, "port 8080");
+
+        //Once the intro circuits close the service can't be reached
+        answering.shutdown();
+
+        assert!(client.connect(&answering.address(), 8080).is_err());
+    }
+
+    #[test]
+    fn test_client_auth_files() {
+        use crate::client_auth::{ClientAuthKey, ClientAuthStore, load_authorized_clients, save_authorized_client, parse_authorized_client};
+        use crate::onion::OnionAddress;
+
+        let address: OnionAddress = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion".parse().unwrap();
+
+        //A line as Tor's documentation writes it
+        let line = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid:descriptor:x25519:NBYRHCRTCA33UV3NGVG46NM52HQQDX6N2REKUVGL6C4YWKZNOBUA";
+
+        let key: ClientAuthKey = line.parse().unwrap();
+
+        assert_eq!(key.address, address);
+        assert_eq!(key.to_line(), line);
+        assert_eq!(parse_authorized_client(&key.service_line()).unwrap(), key.public_key());
+
+        assert!("25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid:descriptor:ed25519:NBYRHCRTCA33UV3NGVG46NM52HQQDX6N2REKUVGL6C4YWKZNOBUA".parse::<ClientAuthKey>().is_err());
+        assert!("descriptor:x25519:tooshort".parse::<ClientAuthKey>().is_err());
+
+        let directory = std::env::temp_dir().join(format!("torpedo-auth-{}", rand::thread_rng().gen::<u64>()));
+
+        ClientAuthStore::save(&directory, &key).unwrap();
+
+        let store = ClientAuthStore::load(&directory).unwrap();
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(&address).unwrap().to_bytes(), key.secret.to_bytes());
+
+        assert!(load_authorized_clients(&directory).unwrap().is_empty());
+
+        save_authorized_client(&directory, "alice", &key.public_key()).unwrap();
+
+        assert_eq!(load_authorized_clients(&directory).unwrap(), vec![key.public_key()]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_onion_service_client_auth() {
+        use crate::onion::{OnionClient, TimePeriod};
+        use crate::onion_service::{OnionService, OnionServiceKeys, ServiceConfig, StreamHandler};
+        use crate::client_auth::{ClientAuthKey, ClientAuthStore};
+        use crate::error::ErrorKind;
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+        let paths = MockOnionPaths::new(&relay);
+
+        let keys = OnionServiceKeys::generate();
+        let address = keys.address();
+
+        let alice = ClientAuthKey::generate(address);
+        let mallory = ClientAuthKey::generate(address);
+
+        let config = ServiceConfig { intro_points: 1, authorized_clients: vec![alice.public_key()], ..ServiceConfig::default() };
+
+        let service = OnionService::launch(keys, config, StreamHandler::Forward(echo), paths.clone()).unwrap();
+
+        let mut store = ClientAuthStore::new();
+
+        store.insert(alice);
+
+        let mut stream = OnionClient::with_client_auth(paths.clone(), store).connect(&address, 80).unwrap();
+
+        stream.write_all(b"authorized").unwrap();
+
+        let mut echoed = [0u8; 10];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"authorized");
+
+        let period = TimePeriod::current();
+
+        let mut client = OnionClient::new(paths);
+
+        assert!(matches!(client.intro_points(&address, period), Err(ErrorKind::ClientAuthRequired)));
+
+        client.client_auth().insert(ClientAuthKey { address, secret: mallory.secret.clone() });
+
+        assert!(matches!(client.intro_points(&address, period), Err(ErrorKind::ClientAuthRejected(_))));
+
+        service.authorize_client(mallory.public_key()).unwrap();
+
+        assert_eq!(client.intro_points(&address, period).unwrap().len(), 1);
+
+        //Once every client is revoked the descriptor is readable by anyone again
+        service.revoke_client(&service.authorized_clients()[0]).unwrap();
+        service.revoke_client(&mallory.public_key()).unwrap();
+
+        client.client_auth().remove(&address);
+
+        assert_eq!(client.intro_points(&address, period).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_blake2b() {
+        use crate::custom_crypto::blake2b;
+
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+
+        //RFC 7693 appendix A
+        assert_eq!(blake2b(b"abc", 64), hex("ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d17d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"));
+
+        //Short digests, an empty message, exactly one block and several blocks
+        assert_eq!(blake2b(b"", 4), hex("1271cf25"));
+        assert_eq!(blake2b(&(0..128).map(|i| i as u8).collect::<Vec<u8>>(), 32), hex("c3582f71ebb2be66fa5dd750f80baae97554f3b015663c8be377cfcb2488c1d1"));
+        assert_eq!(blake2b(&(0..300).map(|i| i as u8).collect::<Vec<u8>>(), 16), hex("15f53a13900056879b859fc1bcea544d"));
+    }
+
+    #[test]
+    fn test_pow_vectors() {
+        use crate::pow::{PowSolution, solve_from};
+
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+        let array = |text: &str| -> [u8; 32] { hex(text).try_into().unwrap() };
+        let short = |text: &str| -> [u8; 16] { hex(text).try_into().unwrap() };
+
+        //C tor's v1 vectors: the blinded key, seed, effort, first nonce tried, and the nonce and solution it finds
+        let vectors = [
+            ("1111111111111111111111111111111111111111111111111111111111111111", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 0, "55555555555555555555555555555555", "55555555555555555555555555555555", "4312f87ceab844c78e1c793a913812d7"),
+            ("1111111111111111111111111111111111111111111111111111111111111111", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 1, "55555555555555555555555555555555", "55555555555555555555555555555555", "84355542ab2b3f79532ef055144ac5ab"),
+            ("1111111111111111111111111111111111111111111111111111111111111110", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 1, "55555555555555555555555555555555", "55555555555555555555555555555555", "115e4b70da858792fc205030b8c83af9"),
+            ("1111111111111111111111111111111111111111111111111111111111111111", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 10, "ffffffffffffffffffffffffffffffff", "01000000000000000000000000000000", "203af985537fadb23f3ed5873b4c81ce"),
+            ("4111111111111111111111111111111111111111111111111111111111111111", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 1337, "feffffffffffffffffffffffffffffff", "01000000000000000000000000000000", "31c377cb72796ed80ae77df6ac1d6bfd"),
+            ("1111111111111111111111111111111111111111111111111111111111111111", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 1000000, "58217255555555555555555555555555", "59217255555555555555555555555555", "0f3db97b9cac20c1771680a1a34848d3"),
+            ("bfd298428562e530c52bdb36d81a0e293ef4a0e94d787f0f8c0c611f4f9e78ed", "c52be1f8a5e6cc3b8fb71cfdbe272cbc91d4d035400f2f94fb0d0074794e0a07", 1, "d0aec1669384bfe5ed39cd724d6c7954", "d1aec1669384bfe5ed39cd724d6c7954", "462606e5f8c2f3f844127b8bfdd6b4ff"),
+            ("bec632eb76123956f99a06d394fcbee8f135b8ed01f2e90aabe404cb0346744a", "9dfbd06d86fed8e12de3ab214e1a63ea61f46253fe08346a20378da70c4a327d", 1, "b4d0e611e6935750fcf9406aae131f62", "b4d0e611e6935750fcf9406aae131f62", "161baa7490356292d020065fdbe55ffc"),
+            ("bfd298428562e530c52bdb36d81a0e293ef4a0e94d787f0f8c0c611f4f9e78ed", "86fb0acf4932cda44dbb451282f415479462dd10cb97ff5e7e8e2a53c3767a7f", 100000, "2cff9fdbc34326d9d2f18ed277469c63", "2eff9fdbc34326d9d2f18ed277469c63", "400cb091139f86b352119f6e131802d6"),
+            ("bfd298428562e530c52bdb36d81a0e293ef4a0e94d787f0f8c0c611f4f9e78ed", "86fb0acf4932cda44dbb451282f415479462dd10cb97ff5e7e8e2a53c3767a7f", 1000000, "5243b3dbc34326d9d2f18ed277469c63", "5543b3dbc34326d9d2f18ed277469c63", "b47c718b56315e9697173a6bac1feaa4"),
+        ];
+
+        for (blinded_key, seed, effort, first_nonce, nonce, solution) in vectors.iter() {
+            let (blinded_key, seed) = (array(blinded_key), array(seed));
+
+            let expected = PowSolution {
+                nonce: short(nonce),
+                effort: *effort,
+                seed_head: seed[0..4].try_into().unwrap(),
+                solution: short(solution),
+            };
+
+            assert!(expected.verify(&seed, &blinded_key));
+            assert_eq!(solve_from(&seed, &blinded_key, *effort, short(first_nonce)), expected);
+        }
+
+        //Solutions C tor rejects
+        let service = array("bfd298428562e530c52bdb36d81a0e293ef4a0e94d787f0f8c0c611f4f9e78ed");
+        let seed = array("86fb0acf4932cda44dbb451282f415479462dd10cb97ff5e7e8e2a53c3767a7f");
+
+        let good = PowSolution {
+            nonce: short("2eff9fdbc34326d9d2f18ed277469c63"),
+            effort: 100000,
+            seed_head: seed[0..4].try_into().unwrap(),
+            solution: short("400cb091139f86b352119f6e131802d6"),
+        };
+
+        assert!(good.verify(&seed, &service));
+        assert!(!PowSolution { effort: 99999, ..good.clone() }.verify(&seed, &service));
+        assert!(!PowSolution { nonce: short("2eff9fdbc34326d9a2f18ed277469c63"), ..good.clone() }.verify(&seed, &service));
+
+        let zero = PowSolution { nonce: [0u8; 16], effort: 1, seed_head: [0u8; 4], solution: [0u8; 16] };
+
+        assert!(!zero.verify(&[0u8; 32], &array("1111111111111111111111111111111111111111111111111111111111111111")));
+        assert!(!PowSolution { seed_head: [0, 0, 0, 1], ..zero }.verify(&[0u8; 32], &array("1111111111111111111111111111111111111111111111111111111111111111")));
+
+        //Only the canonical order of a solution is accepted
+        let mut swapped = good.clone();
+
+        swapped.solution.swap(0, 2);
+        swapped.solution.swap(1, 3);
+
+        assert!(!swapped.verify(&seed, &service));
+    }
+
+    #[test]
+    fn test_pow_solution() {
+        use crate::pow::{PowParams, PowSolution, PowVerifier, IntroQueue, next_effort, solve};
+        use crate::error::ErrorKind;
+        use std::time::{Duration, Instant};
+        use crate::netdoc::parse_items;
+
+        let params = PowParams::generate(8, chrono::Duration::hours(2));
+
+        assert_eq!(PowParams::parse(&parse_items(&params.to_line()).unwrap()[0]).unwrap(), Some(params.clone()));
+        assert_eq!(PowParams::parse(&parse_items("pow-params v2 whatever").unwrap()[0]).unwrap(), None);
+        assert!(PowParams::parse(&parse_items("pow-params v1 AAAA 8 2024-01-01T00:00:00").unwrap()[0]).is_err());
+
+        let blinded_key = [29u8; 32];
+
+        let solution = solve(&params, &blinded_key, 8, 2, Duration::from_secs(60)).unwrap();
+
+        assert_eq!(solution.effort, 8);
+        assert_eq!(PowSolution::from_extension(&solution.to_extension()), Some(solution.clone()));
+
+        assert!(solution.verify(&params.seed, &blinded_key));
+        assert!(!solution.verify(&params.seed, &[30u8; 32]));
+        assert!(!PowSolution { effort: 9, ..solution.clone() }.verify(&params.seed, &blinded_key));
+
+        //Each nonce counts once, and only for a seed the service published
+        let mut verifier = PowVerifier::new(params.clone());
+
+        assert_eq!(verifier.check(&solution, &blinded_key), Some(8));
+        assert_eq!(verifier.check(&solution, &blinded_key), None);
+
+        let rotated = PowParams::generate(8, chrono::Duration::hours(2));
+
+        verifier.rotate(rotated.clone());
+
+        assert_eq!(verifier.check(&solution, &blinded_key), None);
+        assert_eq!(verifier.check(&PowSolution { nonce: [0u8; 16], ..solution.clone() }, &blinded_key), None);
+
+        verifier.rotate(PowParams::generate(8, chrono::Duration::hours(2)));
+
+        assert_eq!(verifier.check(&solve(&params, &blinded_key, 1, 1, Duration::from_secs(60)).unwrap(), &blinded_key), None);
+        assert_eq!(verifier.check(&solve(&rotated, &blinded_key, 1, 1, Duration::from_secs(60)).unwrap(), &blinded_key), Some(1));
+
+        //A puzzle that can't be solved in time gives up instead of spinning forever
+        let started = Instant::now();
+
+        assert!(matches!(solve(&params, &blinded_key, u32::MAX, 2, Duration::from_millis(200)), Err(ErrorKind::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(next_effort(0, 10000), 8);
+        assert_eq!(next_effort(600, 10000), 1200);
+        assert_eq!(next_effort(2000, 10000), 3000);
+        assert_eq!(next_effort(8000, 10000), 10000);
+
+        //Most effort first, then first come first served, dropping the least effort when full
+        let mut queue = IntroQueue::new(3);
+
+        assert!(queue.push(5, "a"));
+        assert!(queue.push(10, "b"));
+        assert!(queue.push(5, "c"));
+        assert!(!queue.push(1, "d"));
+        assert!(queue.push(7, "e"));
+
+        assert_eq!(queue.pop(), Some((10, "b")));
+        assert_eq!(queue.pop(), Some((7, "e")));
+        assert_eq!(queue.pop(), Some((5, "a")));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_onion_service_pow() {
+        use crate::onion::{OnionClient, TimePeriod};
+        use crate::onion_service::{OnionService, OnionServiceKeys, ServiceConfig, StreamHandler, PowDefenses};
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+        let paths = MockOnionPaths::new(&relay);
+
+        let defenses = PowDefenses { suggested_effort: 4, ..PowDefenses::default() };
+        let config = ServiceConfig { intro_points: 1, pow: Some(defenses), ..ServiceConfig::default() };
+
+        let service = OnionService::launch(OnionServiceKeys::generate(), config, StreamHandler::Forward(echo), paths.clone()).unwrap();
+
+        let mut client = OnionClient::new(paths);
+
+        client.pow_config().threads = 2;
+
+        let inner = client.inner_layer(&service.address(), TimePeriod::current()).unwrap();
+
+        assert_eq!(inner.pow_params, service.pow_params());
+        assert_eq!(inner.pow_params.unwrap().suggested_effort, 4);
+
+        let mut stream = client.connect(&service.address(), 80).unwrap();
+
+        stream.write_all(b"worked for it").unwrap();
+
+        let mut echoed = [0u8; 13];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"worked for it");
+    }
+
+    #[test]
+    fn test_bridge_lines() {
+        use crate::bridge::BridgeLine;
+
+        let line: BridgeLine = "Bridge obfs4 192.0.2.3:443 4352E58420E68F5E40BF7C74FADDCCD9D1349413 cert=ssH+9rP8dG2NLDN2XuFw63hIO/9MNNinLmxQDpVa+7kTOa9/m+tGWT1SmSYpQ9uTBGa6Hw iat-mode=0".parse().unwrap();
+
+        assert_eq!(line.transport.as_deref(), Some("obfs4"));
+        assert_eq!(line.address, "192.0.2.3:443".parse().unwrap());
+        assert_eq!(line.fingerprint.unwrap()[0..2], [0x43, 0x52]);
+        assert_eq!(line.args[1], (String::from("iat-mode"), String::from("0")));
+        assert_eq!(line.to_string().parse::<BridgeLine>().unwrap(), line);
+
+        let direct: BridgeLine = "[2001:db8::1]:9001".parse().unwrap();
+
+        assert_eq!(direct.transport, None);
+        assert_eq!(direct.fingerprint, None);
+        assert_eq!(direct.to_string(), "[2001:db8::1]:9001");
+
+        assert!("Bridge".parse::<BridgeLine>().is_err());
+        assert!("Bridge obfs4".parse::<BridgeLine>().is_err());
+        assert!("Bridge 0.0.0.0:443".parse::<BridgeLine>().is_err());
+        assert!("Bridge 192.0.2.3:443 NOTAFINGERPRINT".parse::<BridgeLine>().is_err());
+        assert!("Bridge 192.0.2.3:443 cert=abc 4352E58420E68F5E40BF7C74FADDCCD9D1349413".parse::<BridgeLine>().is_err());
+    }
+
+    #[test]
+    fn test_bridges() {
+        use crate::bridge::{Bridges, BridgeLine, Reachability, encode_fingerprint};
+        use crate::error::ErrorKind;
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        //Nothing listens on a port we just let go of
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let config = format!("UseBridges 1\nBridge {}\nBridge {} {}\n", closed, relay.address(), encode_fingerprint(&relay.node_id()));
+
+        let bridges = Bridges::from_config(&config).unwrap();
+
+        assert_eq!(bridges.lines().len(), 2);
+        assert_eq!(bridges.reachability(&relay.address()), Some(Reachability::Unknown));
+
+        assert_eq!(bridges.fetch_descriptors(), 1);
+        assert_eq!(bridges.reachability(&closed), Some(Reachability::Unreachable(1)));
+        assert_eq!(bridges.reachability(&relay.address()), Some(Reachability::Reachable));
+
+        let descriptor = bridges.descriptor(&relay.address()).unwrap();
+
+        assert_eq!(descriptor.node_id, relay.node_id());
+        assert_eq!(descriptor.ntor_onion_key, relay.onion_key());
+
+        //The unreachable bridge waits out its backoff, so the circuit goes through the other one
+        let circuit = bridges.build_circuit(&[descriptor]).unwrap();
+
+        assert_eq!(circuit.hop_count(), 2);
+        assert_eq!(bridges.reachability(&closed), Some(Reachability::Unreachable(1)));
+
+        let mut stream = circuit.begin(&echo.ip().to_string(), echo.port()).unwrap();
+
+        stream.write_all(b"over a bridge").unwrap();
+
+        let mut echoed = [0u8; 13];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"over a bridge");
+
+        //Reachability survives a restart
+        let state = std::env::temp_dir().join(format!("torpedo-bridges-{}", rand::thread_rng().gen::<u64>()));
+
+        bridges.save_state(&state).unwrap();
+
+        let restarted = Bridges::from_config(&config).unwrap();
+
+        restarted.load_state(&state).unwrap();
+
+        assert_eq!(restarted.reachability(&closed), Some(Reachability::Unreachable(1)));
+        assert_eq!(restarted.reachability(&relay.address()), Some(Reachability::Reachable));
+
+        std::fs::remove_file(&state).unwrap();
+
+        //A bridge that isn't the relay its line names is not used
+        let impostor = BridgeLine { fingerprint: Some([0u8; 20]), ..format!("{}", relay.address()).parse().unwrap() };
+
+        assert!(matches!(Bridges::new(vec![impostor]).build_circuit(&[]), Err(ErrorKind::BridgeMismatch(_))));
+
+        let transport: BridgeLine = format!("snowflake {} url=https://snowflake.example/", relay.address()).parse().unwrap();
+
+        assert!(matches!(Bridges::new(vec![transport]).build_circuit(&[]), Err(ErrorKind::UnsupportedTransport(_))));
+    }
+
+    #[test]
+    fn test_bridge_descriptor_signature() {
+        use crate::bridge::{parse_bridge_descriptor, BRIDGE_DESCRIPTOR_PATH};
+        use crate::channel::Channel;
+        use crate::error::ErrorKind;
+        use crate::onion::http_get;
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+
+        let circuit = Channel::connect(relay.address()).unwrap().create_fast().unwrap();
+        let body = http_get(& mut circuit.begin_dir().unwrap(), BRIDGE_DESCRIPTOR_PATH).unwrap();
+        let document = String::from_utf8(body).unwrap();
+
+        assert_eq!(parse_bridge_descriptor(&document, relay.address(), None).unwrap().node_id, relay.node_id());
+        assert_eq!(parse_bridge_descriptor(&document, relay.address(), Some(relay.node_id())).unwrap().node_id, relay.node_id());
+
+        assert!(matches!(parse_bridge_descriptor(&document, relay.address(), Some([0u8; 20])), Err(ErrorKind::BridgeMismatch(_))));
+
+        //Any change to the signed part, such as a different onion key, breaks the signature
+        let onion_key = document.lines().find(|line| line.starts_with("ntor-onion-key ")).unwrap();
+        let tampered = document.replace(onion_key, "ntor-onion-key AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+
+        assert!(matches!(parse_bridge_descriptor(&tampered, relay.address(), None), Err(ErrorKind::InvalidDocument(_))));
+
+        let unsigned = &document[..document.find("router-signature").unwrap()];
+
+        assert!(matches!(parse_bridge_descriptor(unsigned, relay.address(), None), Err(ErrorKind::InvalidDocument(_))));
+
+        //Another relay's signature doesn't verify with this one's key
+        let other = MockRelay::spawn(MockTransport::Tls);
+
+        let circuit = Channel::connect(other.address()).unwrap().create_fast().unwrap();
+        let other_document = String::from_utf8(http_get(& mut circuit.begin_dir().unwrap(), BRIDGE_DESCRIPTOR_PATH).unwrap()).unwrap();
+
+        let mixed = format!("{}{}", unsigned, &other_document[other_document.find("router-signature").unwrap()..]);
+
+        assert!(matches!(parse_bridge_descriptor(&mixed, relay.address(), None), Err(ErrorKind::InvalidDocument(_))));
+    }
+
+    #[test]
+    fn test_transport_plugins() {
+        use crate::pt::{TransportPlugin, PluginKind, SocksVersion, encode_args};
+
+        let plugin: TransportPlugin = "ClientTransportPlugin obfs4,meek_lite exec /usr/bin/lyrebird -enableLogging".parse().unwrap();
+
+        assert_eq!(plugin.transports, vec![String::from("obfs4"), String::from("meek_lite")]);
+        assert_eq!(plugin.kind, PluginKind::Exec { path: "/usr/bin/lyrebird".into(), args: vec![String::from("-enableLogging")] });
+
+        let plugin: TransportPlugin = "snowflake socks5 127.0.0.1:1080".parse().unwrap();
+
+        assert_eq!(plugin.kind, PluginKind::Socks(SocksVersion::Socks5, "127.0.0.1:1080".parse().unwrap()));
+
+        assert!("ClientTransportPlugin obfs4".parse::<TransportPlugin>().is_err());
+        assert!("ClientTransportPlugin obfs4 socks5 nowhere".parse::<TransportPlugin>().is_err());
+        assert!("ClientTransportPlugin obfs-4 exec /bin/true".parse::<TransportPlugin>().is_err());
+
+        let args = vec![(String::from("cert"), String::from("a;b=c\\d")), (String::from("iat-mode"), String::from("0"))];
+
+        assert_eq!(encode_args(&args), r"cert=a\;b\=c\\d;iat-mode=0");
+        assert_eq!(encode_args(&[]), "");
+    }
+
+    #[test]
+    fn test_managed_transport() {
+        use crate::bridge::{Bridges, BridgeLine, Reachability, encode_fingerprint};
+        use crate::pt::{TransportManager, SocksVersion, encode_args};
+        use crate::error::ErrorKind;
+        use std::sync::Arc;
+
+        //A managed proxy whose transport passes connections straight through, logging the arguments it was given
+        const PASSTHROUGH: &str = r#"
+import os, socket, sys, threading
+
+def exact(connection, length):
+    data = b''
+    while len(data) < length:
+        chunk = connection.recv(length - len(data))
+        if not chunk:
+            raise OSError('closed')
+        data += chunk
+    return data
+
+def pipe(source, destination):
+    try:
+        while True:
+            data = source.recv(65536)
+            if not data:
+                break
+            destination.sendall(data)
+    except OSError:
+        pass
+    for connection in (source, destination):
+        try:
+            connection.shutdown(socket.SHUT_RDWR)
+        except OSError:
+            pass
+
+def serve(client):
+    try:
+        _, count = exact(client, 2)
+        args = b''
+        if 2 in exact(client, count):
+            client.sendall(b'\x05\x02')
+            exact(client, 1)
+            username = exact(client, exact(client, 1)[0])
+            password = exact(client, exact(client, 1)[0])
+            args = username + (b'' if password == b'\x00' else password)
+            client.sendall(b'\x01\x00')
+        else:
+            client.sendall(b'\x05\x00')
+        kind = exact(client, 4)[3]
+        if kind == 1:
+            host = socket.inet_ntop(socket.AF_INET, exact(client, 4))
+        else:
+            host = socket.inet_ntop(socket.AF_INET6, exact(client, 16))
+        port = int.from_bytes(exact(client, 2), 'big')
+        with open(os.path.join(os.environ['TOR_PT_STATE_LOCATION'], 'args'), 'ab') as log:
+            log.write(args + b'\n')
+        upstream = socket.create_connection((host, port))
+        client.sendall(b'\x05\x00\x00\x01\x00\x00\x00\x00\x00\x00')
+        threading.Thread(target=pipe, args=(client, upstream), daemon=True).start()
+        pipe(upstream, client)
+    except OSError:
+        client.close()
+
+if '1' not in os.environ.get('TOR_PT_MANAGED_TRANSPORT_VER', '').split(','):
+    print('VERSION-ERROR no-version', flush=True)
+    sys.exit(1)
+
+listener = socket.socket()
+listener.bind(('127.0.0.1', 0))
+listener.listen()
+
+print('VERSION 1')
+print('LOG SEVERITY=notice MESSAGE="starting"')
+for name in os.environ['TOR_PT_CLIENT_TRANSPORTS'].split(','):
+    if name == 'passthrough':
+        print('CMETHOD passthrough socks5 127.0.0.1:%d' % listener.getsockname()[1])
+    else:
+        print('CMETHOD-ERROR %s no such transport' % name)
+print('CMETHODS DONE', flush=True)
+
+def exit_on_stdin_close():
+    sys.stdin.read()
+    os._exit(0)
+
+threading.Thread(target=exit_on_stdin_close, daemon=True).start()
+
+while True:
+    client, _ = listener.accept()
+    threading.Thread(target=serve, args=(client,), daemon=True).start()
+"#;
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let directory = std::env::temp_dir().join(format!("torpedo-pt-{}", rand::thread_rng().gen::<u64>()));
+
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let script = directory.join("passthrough.py");
+
+        std::fs::write(&script, PASSTHROUGH).unwrap();
+
+        let config = format!("ClientTransportPlugin passthrough,meek exec python3 {}\nClientTransportPlugin snowflake socks4 127.0.0.1:9\n", script.display());
+
+        let manager = Arc::new(TransportManager::from_config(&config, &directory).unwrap());
+
+        let method = manager.method("passthrough").unwrap();
+
+        assert_eq!(method.socks, SocksVersion::Socks5);
+        assert_eq!(manager.method("passthrough").unwrap(), method);
+        assert_eq!(manager.method("snowflake").unwrap().socks, SocksVersion::Socks4);
+
+        assert!(matches!(manager.method("meek"), Err(ErrorKind::TransportFailed(_))));
+        assert!(matches!(manager.method("obfs4"), Err(ErrorKind::UnsupportedTransport(_))));
+
+        //Long enough that the arguments are split between the SOCKS5 username and password
+        let line: BridgeLine = format!("passthrough {} {} cert=a;b=c iat-mode=0 padding={}", relay.address(), encode_fingerprint(&relay.node_id()), "x".repeat(300)).parse().unwrap();
+
+        let bridges = Bridges::with_transports(vec![line.clone()], manager.clone());
+
+        assert_eq!(bridges.fetch_descriptors(), 1);
+        assert_eq!(bridges.reachability(&relay.address()), Some(Reachability::Reachable));
+
+        let circuit = bridges.build_circuit(&[bridges.descriptor(&relay.address()).unwrap()]).unwrap();
+
+        let mut stream = circuit.begin(&echo.ip().to_string(), echo.port()).unwrap();
+
+        stream.write_all(b"through a transport").unwrap();
+
+        let mut echoed = [0u8; 19];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"through a transport");
+
+        let logged = std::fs::read_to_string(directory.join("pt_state").join("args")).unwrap();
+
+        assert_eq!(logged.lines().next(), Some(encode_args(&line.args).as_str()));
+
+        //Arguments past what SOCKS5 can carry are refused before connecting
+        let long: BridgeLine = format!("passthrough {} padding={}", relay.address(), "x".repeat(600)).parse().unwrap();
+
+        assert!(matches!(Bridges::with_transports(vec![long], manager.clone()).build_circuit(&[]), Err(ErrorKind::TransportFailed(_))));
+
+        drop(bridges);
+        drop(circuit);
+        drop(stream);
+        drop(manager);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_secretbox_elligator() {
+        use crate::custom_crypto::{secretbox_seal, secretbox_open};
+        use crate::elligator::{representative_to_public, generate_representable};
+        use x25519_dalek::PublicKey;
+
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+
+        //The secretbox vector from NaCl's tests
+        let key: [u8; 32] = hex("1b27556473e985d462cd51197a9a46c76009549eac6474f206c4ee0844f68389").try_into().unwrap();
+        let nonce: [u8; 24] = hex("69696ee955b62b73cd62bda875fc73d68219e0036b7a0b37").try_into().unwrap();
+        let message = hex("be075fc53c81f2d5cf141316ebeb0c7b5228c52a4c62cbd44b66849b64244ffce5ecbaaf33bd751a1ac728d45e6c61296cdc3c01233561f41db66cce314adb310e3be8250c46f06dceea3a7fa1348057e2f6556ad6b1318a024a838f21af1fde048977eb48f59ffd4924ca1c60902e52f0a089bc76897040e082f937763848645e0705");
+
+        let sealed = secretbox_seal(&key, &nonce, &message);
+
+        assert_eq!(sealed, hex("f3ffc7703f9400e52a7dfb4b3d3305d98e993b9f48681273c29650ba32fc76ce48332ea7164d96a4476fb8c531a1186ac0dfc17c98dce87b4da7f011ec48c97271d2c20f9b928fe2270d6fb863d51738b48eeee314a7cc8ab932164548e526ae90224368517acfeabd6bb3732bc0e9da99832b61ca01b6de56244a9e88d5f9b37973f622a43d14a6599b1f654cb45a74e355a5"));
+        assert_eq!(secretbox_open(&key, &nonce, &sealed), Some(message));
+
+        let mut tampered = sealed.clone();
+
+        tampered[40] ^= 1;
+
+        assert_eq!(secretbox_open(&key, &nonce, &tampered), None);
+        assert_eq!(secretbox_open(&key, &[0u8; 24], &sealed), None);
+
+        //The top two bits of a representative are padding
+        assert_eq!(representative_to_public(&hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").try_into().unwrap()).to_vec(),
+                   hex("5f3520001c6c9936a31206afe7c7ac224e8861619bf98872444915899d95f46e"));
+        assert_eq!(representative_to_public(&[0xff; 32]).to_vec(), hex("80e5132b658f7f451b2b658f7f451b2b658f7f451b2b658f7f451b2b658f7f45"));
+
+        let alice = generate_representable();
+        let bob = generate_representable();
+
+        assert_eq!(representative_to_public(&alice.representative), alice.public);
+        assert_eq!(representative_to_public(&bob.representative), bob.public);
+
+        //The low order component of each public key drops out of the shared secret
+        assert_eq!(alice.secret.diffie_hellman(&PublicKey::from(bob.public)).as_bytes(), bob.secret.diffie_hellman(&PublicKey::from(alice.public)).as_bytes());
+    }
+
+    #[test]
+    fn test_obfs4() {
+        use crate::obfs4::{self, ClientArgs, IatMode, ServerKeys, encode_cert};
+        use crate::bridge::{Bridges, BridgeLine, Reachability, encode_fingerprint};
+        use std::net::TcpListener;
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let keys = Arc::new(ServerKeys::generate([7u8; 20]));
+
+        let args = ClientArgs::from_args(&[(String::from("cert"), keys.cert()), (String::from("iat-mode"), String::from("2"))]).unwrap();
+
+        assert_eq!(args.node_id, [7u8; 20]);
+        assert_eq!(encode_cert(&args.node_id, &args.public_key), keys.cert());
+        assert_eq!(args.iat_mode, IatMode::Paranoid);
+
+        assert!(ClientArgs::from_args(&[(String::from("cert"), String::from("AAAA"))]).is_err());
+        assert!(ClientArgs::from_args(&[(String::from("cert"), keys.cert()), (String::from("iat-mode"), String::from("3"))]).is_err());
+
+        //An echo server behind obfs4, talked to in every IAT mode
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server_keys = keys.clone();
+
+        thread::spawn(move || {
+            for tcp in listener.incoming() {
+                let keys = server_keys.clone();
+
+                thread::spawn(move || {
+                    let tcp = tcp.unwrap();
+
+                    //Hang up on clients that can't complete the handshake rather than leave them waiting
+                    tcp.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+                    let mut stream = match obfs4::accept(tcp, &keys, IatMode::Enabled) {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+
+                    stream.get_ref().set_read_timeout(None).unwrap();
+
+                    let mut buffer = [0u8; 4096];
+
+                    loop {
+                        match stream.read(& mut buffer) {
+                            Ok(0) | Err(_) => break,
+                            Ok(read) => stream.write_all(&buffer[..read]).unwrap(),
+                        }
+                    }
+                });
+            }
+        });
+
+        let message: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+
+        for mode in &[IatMode::None, IatMode::Enabled, IatMode::Paranoid] {
+            let mut stream = obfs4::dial(address, &ClientArgs { iat_mode: *mode, ..args.clone() }).unwrap();
+
+            stream.write_all(&message).unwrap();
+
+            let mut echoed = vec![0u8; message.len()];
+
+            stream.read_exact(& mut echoed).unwrap();
+
+            assert_eq!(echoed, message);
+        }
+
+        //A client with the wrong key can't complete the handshake
+        let impostor = ServerKeys::generate([7u8; 20]);
+
+        assert!(obfs4::dial(address, &ClientArgs::from_args(&[(String::from("cert"), impostor.cert())]).unwrap()).is_err());
+
+        //A relay that is an obfs4 bridge, used through the built in transport
+        let relay = MockRelay::spawn(MockTransport::Obfs4);
+        let echo = spawn_echo_server();
+
+        let line: BridgeLine = format!("obfs4 {} {} cert={} iat-mode=0", relay.address(), encode_fingerprint(&relay.node_id()), relay.obfs4_cert().unwrap()).parse().unwrap();
+
+        let bridges = Bridges::new(vec![line]);
+
+        assert_eq!(bridges.fetch_descriptors(), 1);
+
+        let circuit = bridges.build_circuit(&[bridges.descriptor(&relay.address()).unwrap()]).unwrap();
+
+        let mut stream = circuit.begin(&echo.ip().to_string(), echo.port()).unwrap();
+
+        stream.write_all(b"obfuscated").unwrap();
+
+        let mut echoed = [0u8; 10];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"obfuscated");
+
+        let wrong: BridgeLine = format!("obfs4 {} cert={}", relay.address(), impostor.cert()).parse().unwrap();
+
+        let bridges = Bridges::new(vec![wrong]);
+
+        assert_eq!(bridges.fetch_descriptors(), 0);
+        assert_eq!(bridges.reachability(&relay.address()), Some(Reachability::Unreachable(1)));
+    }
+
+    #[test]
+    fn test_obfs4_vectors() {
+        use crate::custom_crypto::secretbox_open;
+        use crate::obfs4::{self, HashDrbg, Distribution, FrameCipher};
+        use x25519_dalek::{StaticSecret, PublicKey};
+
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+
+        //Int63 outputs of obfs4proxy's Go hash DRBG, as the obfs4 crate checks its own against them
+        let drbg_vectors = [
+            ("000000000000000000000000000000000000000000000000", [7432626515892259304, 5773523046280711756, 4537542203639783680]),
+            ("0c10867722204c856e78315d669449dcb6e66f2fe5247a80", [9059004827137905928, 6853924365612632173, 1485252377529977150]),
+            ("ddbb886aefbe2a65c2509dfc3bb0932c5e881965afca80a0", [3952461850862704951, 6715353867928838006, 5560038622741453571]),
+            ("e691b1eaa81018e8b16bbf84d71f3ba0c5f965bace2da7cc", [8251725530906761037, 5718043109939568014, 7585544303175018394]),
+        ];
+
+        for (seed, outputs) in drbg_vectors.iter() {
+            let mut drbg = HashDrbg::new(hex(seed).as_slice().try_into().unwrap());
+
+            for output in outputs.iter() {
+                assert_eq!(drbg.int63(), *output);
+            }
+        }
+
+        //KEY_SEED and AUTH for fixed keys, from the obfs4 crate's test vector for compatibility with obfs4proxy
+        let secret = |text: &str| -> StaticSecret {
+            let bytes: [u8; 32] = hex(text).as_slice().try_into().unwrap();
+
+            StaticSecret::from(bytes)
+        };
+
+        let b = secret("a83fdd04eb9ed77a2b38d86092a09a1cecfb93a7bdec0da35e542775b2e7af6e");
+        let x = secret("308ff4f3a0ebe8c1a93bcd40d67e3eec6b856aa5c07ef6d5a3d3cedf13dcf150");
+        let y = secret("881f9ad60e0833a627f0c47f5aafbdcb0b5471800eaeaa1e678291b947e4295c");
+
+        let node_id: [u8; 20] = hex("000102030405060708090a0b0c0d0e0f10111213").as_slice().try_into().unwrap();
+
+        let (b_public, x_public, y_public) = (PublicKey::from(&b), PublicKey::from(&x), PublicKey::from(&y));
+
+        let (key_seed, auth) = obfs4::ntor(x.diffie_hellman(&y_public).as_bytes(), x.diffie_hellman(&b_public).as_bytes(), &node_id, b_public.as_bytes(), x_public.as_bytes(), y_public.as_bytes());
+
+        assert_eq!(key_seed.to_vec(), hex("05b858d18df21a01566c74d39a5b091b4415f103c05851e77e79b274132dc5b5"));
+        assert_eq!(auth.to_vec(), hex("dc71f8ded2e56f829f1b944c1e94357fa8b7987f10211a017e2d1f2455092917"));
+
+        //The first frame's length is masked with the first DRBG block, whose low 63 bits the vectors above give, and sealed
+        //under the nonce prefix and a counter of 1
+        let mut material = vec![1u8; 32];
+
+        material.extend_from_slice(&[2u8; 16]);
+        material.extend_from_slice(&hex(drbg_vectors[1].0));
+
+        let frame = FrameCipher::new(&material).seal(b"obfs4").unwrap();
+
+        assert_eq!((u16::from_be_bytes([frame[0], frame[1]]) ^ 21) & 0x7fff, (drbg_vectors[1].1[0] >> 48) as u16 & 0x7fff);
+
+        let mut nonce = [2u8; 24];
+
+        nonce[16..].copy_from_slice(&1u64.to_be_bytes());
+
+        assert_eq!(secretbox_open(&[1u8; 32], &nonce, &frame[2..]).unwrap(), b"obfs4".to_vec());
+
+        //The table a seed picks through Go's math/rand. No Go toolchain was to hand, so this one comes from this port
+        //rather than obfs4proxy, and only guards against changes
+        let distribution = Distribution::new(hex(drbg_vectors[1].0).as_slice().try_into().unwrap(), 0, 100);
+
+        let values = vec![23, 32, 16, 10, 42, 62, 56, 37, 27, 81, 1, 3, 92, 25, 61, 94, 13, 98, 29, 59, 50, 68, 39, 82, 78, 51, 43, 53, 90, 89, 6, 77, 80, 30, 49, 74, 5, 57, 11, 46, 64, 48, 85, 73, 70];
+
+        assert_eq!(distribution.values(), values);
+
+        assert!((0..1000).all(|_| values.contains(&distribution.sample())));
+
+        //Tables are offset by their minimum and never hold more than 100 values
+        let distribution = Distribution::new(&[0u8; 24], 1000, 2448);
+
+        assert!(distribution.values().len() <= 100);
+        assert!(distribution.values().iter().all(|value| (1000..=2448).contains(value)));
+    }
+
+    #[test]
+    fn test_channel_padding() {
+        use crate::channel::Channel;
+        use crate::channel_padding::{PaddingParams, PaddingConfig, PaddingMode, PaddingTimer, sample_timeout, PADDING_COMMAND_START, PADDING_COMMAND_STOP};
+        use crate::circuit_padding::VirtualClock;
+        use crate::error::ErrorKind;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        //Consensus parameters, with defaults for the ones left out and clamping for the ones out of range
+        assert_eq!(PaddingParams::from_consensus(""), PaddingParams::default());
+
+        let params = PaddingParams::from_consensus("params CircuitPriorityHalflifeMsec=30000 nf_ito_low=2000 nf_ito_high=100000 nf_ito_low_reduced=bad");
+
+        assert_eq!(params.ito_low_ms, 2000);
+        assert_eq!(params.ito_high_ms, 60000);
+        assert_eq!(params.ito_low_reduced_ms, 9000);
+        assert_eq!(params.ito_high_reduced_ms, 14000);
+
+        let params = PaddingParams::from_consensus("nf_ito_low=5000 nf_ito_high=1000 nf_ito_low_reduced=-5");
+
+        assert_eq!(params.timeouts(PaddingMode::Normal), Some((5000, 5000)));
+        assert_eq!(params.ito_low_reduced_ms, 0);
+        assert_eq!(params.timeouts(PaddingMode::Disabled), None);
+        assert_eq!(PaddingParams::from_consensus("nf_ito_low=0 nf_ito_high=0").timeouts(PaddingMode::Normal), None);
+
+        //The max of two draws stays in range and leans towards the high end
+        let samples: Vec<Duration> = (0..2000).map(|_| sample_timeout(1000, 2000)).collect();
+
+        assert!(samples.iter().all(|sample| *sample >= Duration::from_millis(1000) && *sample <= Duration::from_millis(2000)));
+        assert!(samples.iter().sum::<Duration>() / samples.len() as u32 > Duration::from_millis(1550));
+
+        //The timer waits from the last cell, redraws its wait when the timeouts change and never fires without them
+        let start = Instant::now();
+        let mut timer = PaddingTimer::default();
+
+        assert!(!timer.due(start, Some((100, 100))));
+        assert!(!timer.due(start + Duration::from_millis(99), Some((100, 100))));
+        assert!(timer.due(start + Duration::from_millis(100), Some((100, 100))));
+
+        timer.activity(start + Duration::from_millis(100));
+
+        assert!(!timer.due(start + Duration::from_millis(199), Some((100, 100))));
+        assert!(!timer.due(start + Duration::from_millis(299), Some((200, 200))));
+        assert!(timer.due(start + Duration::from_millis(300), Some((200, 200))));
+        assert!(!timer.due(start + Duration::from_secs(3600), None));
+
+        let relay = MockRelay::spawn(MockTransport::Tls);
+
+        let channel = Channel::connect(relay.address()).unwrap();
+        let clock = VirtualClock::new();
+
+        channel.set_padding_clock(Arc::new(clock.clone()));
+
+        assert_eq!(channel.link_version(), 5);
+
+        //A channel only starts padding once it has a circuit
+        assert_eq!(channel.padding(), None);
+
+        let _circuit = channel.create_fast().unwrap();
+
+        assert_eq!(channel.padding(), PaddingConfig::default().timeouts());
+
+        let fast = PaddingParams {
+            ito_low_ms: 100,
+            ito_high_ms: 100,
+            ito_low_reduced_ms: 200,
+            ito_high_reduced_ms: 200,
+        };
+
+        //Normal padding needs no negotiation, and an idle channel pads each time the timeout passes
+        channel.set_padding(&PaddingConfig { mode: PaddingMode::Normal, params: fast }).unwrap();
+
+        clock.advance(Duration::from_millis(100));
+        relay.wait_for_link_padding(1, 0);
+
+        assert_eq!(relay.padding_received(), 1);
+
+        clock.advance(Duration::from_millis(100));
+        relay.wait_for_link_padding(2, 0);
+
+        assert_eq!(relay.padding_received(), 2);
+        assert!(relay.padding_negotiations().is_empty());
+
+        //Reduced padding tells the relay the timeouts it should use, and uses them
+        channel.set_padding(&PaddingConfig { mode: PaddingMode::Reduced, params: fast }).unwrap();
+
+        relay.wait_for_link_padding(2, 1);
+
+        clock.advance(Duration::from_millis(200));
+        relay.wait_for_link_padding(3, 1);
+
+        assert_eq!(relay.padding_received(), 3);
+        assert_eq!(relay.padding_negotiations(), vec![(PADDING_COMMAND_START, 200, 200)]);
+
+        //Disabled padding tells the relay to stop, and stops ours
+        channel.set_padding(&PaddingConfig { mode: PaddingMode::Disabled, params: fast }).unwrap();
+
+        relay.wait_for_link_padding(3, 2);
+
+        assert_eq!(channel.padding(), None);
+        assert_eq!(relay.padding_negotiations()[1], (PADDING_COMMAND_STOP, 0, 0));
+
+        //Before link protocol 5 there is no PADDING_NEGOTIATE, so only normal padding can be had
+        let old = MockRelay::spawn(MockTransport::Tls);
+
+        old.limit_link_version(4);
+
+        let channel = Channel::connect(old.address()).unwrap();
+
+        assert_eq!(channel.link_version(), 4);
+        assert!(matches!(channel.set_padding(&PaddingConfig { mode: PaddingMode::Reduced, params: fast }), Err(ErrorKind::NoCommonVersion)));
+        assert_eq!(channel.padding(), None);
+
+        channel.set_padding(&PaddingConfig { mode: PaddingMode::Normal, params: fast }).unwrap();
+
+        assert_eq!(channel.padding(), Some((100, 100)));
+    }
+
+    #[test]
+    fn test_circuit_padding() {
+        use crate::channel::Channel;
+        use crate::cells::LinkSpecifier;
+        use crate::circuit_padding::*;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let clock = VirtualClock::new();
+
+        //Run a machine to the end, advancing the clock a millisecond at a time, and count its padding
+        let run = |runtime: & mut PaddingRuntime| {
+            let mut sent = 0;
+
+            for _ in 0..1000 {
+                if runtime.is_ended() {
+                    break;
+                }
+
+                if runtime.poll() {
+                    sent += 1;
+                }
+
+                clock.advance(Duration::from_millis(1));
+            }
+
+            sent
+        };
+
+        //A histogram of two tokens between 10ms and 20ms, which ends the machine once both are used up
+        let machine = PaddingMachine {
+            name: "test",
+            machine_type: 9,
+            target_hop: 1,
+            negotiate_end: false,
+            states: vec![
+                PaddingState {
+                    histogram: Histogram::never(),
+                    length: None,
+                    transitions: vec![(PaddingEvent::NonPaddingSent, Transition::State(1))],
+                },
+                PaddingState {
+                    histogram: Histogram {
+                        edges: vec![Duration::from_millis(10), Duration::from_millis(20)],
+                        tokens: vec![2],
+                        infinity: 0,
+                        remove_tokens: true,
+                    },
+                    length: None,
+                    transitions: vec![
+                        (PaddingEvent::PaddingReceived, Transition::Cancel),
+                        (PaddingEvent::NonPaddingReceived, Transition::State(1)),
+                        (PaddingEvent::BinsEmpty, Transition::End),
+                    ],
+                },
+            ],
+        };
+
+        let mut runtime = PaddingRuntime::new(machine.clone(), Arc::new(clock.clone()));
+
+        assert_eq!(runtime.state(), Some(STATE_START));
+        assert_eq!(runtime.deadline(), None);
+
+        runtime.event(PaddingEvent::NonPaddingSent);
+
+        let deadline = runtime.deadline().unwrap();
+
+        assert_eq!(runtime.state(), Some(1));
+        assert!(deadline >= clock.now() + Duration::from_millis(10) && deadline < clock.now() + Duration::from_millis(20));
+        assert!(!runtime.poll());
+
+        clock.advance(Duration::from_millis(9));
+
+        assert!(!runtime.poll());
+
+        //Padding from the relay cancels ours until something else happens
+        runtime.event(PaddingEvent::PaddingReceived);
+
+        assert_eq!(runtime.deadline(), None);
+
+        clock.advance(Duration::from_millis(100));
+
+        assert!(!runtime.poll());
+
+        runtime.event(PaddingEvent::NonPaddingReceived);
+
+        assert_eq!(run(& mut runtime), 2);
+        assert!(runtime.is_ended());
+        assert_eq!(runtime.padding_sent(), 2);
+
+        //The intro machine pads 7 to 10 times after INTRODUCE1, unless the intro point answers first
+        for _ in 0..20 {
+            let mut runtime = PaddingRuntime::new(PaddingMachine::client_intro(), Arc::new(clock.clone()));
+
+            assert_eq!(run(& mut runtime), 0);
+
+            runtime.event(PaddingEvent::NonPaddingSent);
+
+            let sent = run(& mut runtime);
+
+            assert!((7..=10).contains(&sent));
+        }
+
+        let mut runtime = PaddingRuntime::new(PaddingMachine::client_intro(), Arc::new(clock.clone()));
+
+        runtime.event(PaddingEvent::NonPaddingSent);
+
+        clock.advance(Duration::from_millis(1));
+
+        assert!(runtime.poll());
+
+        runtime.event(PaddingEvent::NonPaddingReceived);
+
+        assert!(runtime.is_ended());
+        assert!(!runtime.poll());
+
+        //The rendezvous machine pads once
+        let mut runtime = PaddingRuntime::new(PaddingMachine::client_rendezvous(), Arc::new(clock.clone()));
+
+        runtime.event(PaddingEvent::NonPaddingSent);
+
+        assert_eq!(run(& mut runtime), 1);
+
+        //On a real circuit the machine is negotiated with its target hop, runs on the clock it is given and says when it
+        //stops. The rendezvous machine's one padding cell is due within a microsecond of the stream opening
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::connect(relay.address()).unwrap();
+        let circuit = channel.create_ntor(&relay.node_id(), &relay.onion_key()).unwrap();
+
+        circuit.extend_ntor(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key()).unwrap();
+
+        circuit.start_padding_with_clock(PaddingMachine::client_rendezvous(), Arc::new(clock.clone())).unwrap();
+
+        assert_eq!(circuit.padding_machine(), Some("client_rp_circ"));
+
+        let mut stream = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+        stream.write_all(b"padded").unwrap();
+
+        let mut echoed = [0u8; 6];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"padded");
+
+        clock.advance(Duration::from_micros(1));
+        relay.wait_for_circuit_padding(1, 2);
+
+        assert_eq!(relay.drops_received(), 1);
+        assert_eq!(circuit.padding_machine(), None);
+        assert_eq!(relay.padding_machines(), vec![(CIRCPAD_COMMAND_START, MACHINE_CLIENT_RENDEZVOUS, 1), (CIRCPAD_COMMAND_STOP, MACHINE_CLIENT_RENDEZVOUS, 1)]);
+
+        //A machine with delays only pads as its clock moves on, however long it waits in real time
+        circuit.start_padding_with_clock(PaddingMachine { negotiate_end: true, ..machine.clone() }, Arc::new(clock.clone())).unwrap();
+
+        stream.write_all(b"timed").unwrap();
+
+        let mut echoed = [0u8; 5];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        relay.wait_for_circuit_padding(1, 3);
+
+        assert_eq!(relay.drops_received(), 1);
+        assert_eq!(relay.padding_machines()[2], (CIRCPAD_COMMAND_START, 9, 2));
+
+        clock.advance(Duration::from_millis(20));
+        relay.wait_for_circuit_padding(2, 3);
+
+        assert_eq!(relay.drops_received(), 2);
+        assert_eq!(circuit.padding_machine(), Some("test"));
+
+        //The second padding cell uses up the histogram, which ends the machine
+        clock.advance(Duration::from_millis(20));
+        relay.wait_for_circuit_padding(3, 4);
+
+        assert_eq!(relay.drops_received(), 3);
+        assert_eq!(circuit.padding_machine(), None);
+        assert_eq!(relay.padding_machines()[3], (CIRCPAD_COMMAND_STOP, 9, 2));
+
+        //A relay that doesn't know a machine refuses it, and we stop it without telling the relay
+        relay.refuse_padding_machines();
+
+        let idle = PaddingMachine {
+            negotiate_end: true,
+            states: vec![PaddingState { histogram: Histogram::never(), length: None, transitions: vec![] }],
+            ..machine
+        };
+
+        circuit.start_padding(idle).unwrap();
+
+        stream.write_all(b"again").unwrap();
+
+        let mut echoed = [0u8; 5];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(circuit.padding_machine(), None);
+        assert_eq!(relay.padding_machines().len(), 5);
+        assert_eq!(relay.padding_machines()[4], (CIRCPAD_COMMAND_START, 9, 3));
+    }
+
+    #[test]
+    fn test_vpadding_authenticate() {
+        use crate::channel::Channel;
+        use crate::cells::{TorCell, Command, CellReader};
+        use crate::hs_descriptor::Ed25519Keypair;
+        use crate::link_auth::{Auth0003, AUTH0003_LENGTH, AUTH_METHOD_ED25519_SHA256_RFC5705};
+        use torserde::NLengthVector;
+        use std::time::Duration;
+        use std::thread;
+
+        //Variable length cells go out with their own length and come back the same
+        let roundtrip = |cell: TorCell| {
+            let mut bytes = Vec::new();
+
+            cell.into_stream(& mut bytes, 4).unwrap();
+
+            let length = bytes.len();
+
+            (CellReader::new().poll(&bytes[..], 4).unwrap().unwrap(), length)
+        };
+
+        let (cell, length) = roundtrip(TorCell::new(0, Command::VPadding { padding: NLengthVector::from(vec![7u8; 100]) }));
+
+        assert_eq!(length, 4 + 1 + 2 + 100);
+        assert!(matches!(cell.get_command(), Command::VPadding { padding } if padding.0 == vec![7u8; 100]));
+
+        let (cell, _) = roundtrip(TorCell::new(0, Command::Authorize { payload: NLengthVector::from(vec![]) }));
+
+        assert!(matches!(cell.get_command(), Command::Authorize { payload } if payload.0.is_empty()));
+
+        //AUTHENTICATE with a signed AUTH0003 body
+        let key = Ed25519Keypair::generate();
+
+        let mut auth = Auth0003 {
+            cid: [1u8; 32],
+            sid: [2u8; 32],
+            cid_ed: [3u8; 32],
+            sid_ed: [0u8; 32],
+            slog: [5u8; 32],
+            clog: [6u8; 32],
+            scert: [7u8; 32],
+            tls_secrets: [8u8; 32],
+            rand: [0u8; 24],
+            signature: [0u8; 64],
+        };
+
+        auth.sign(&key);
+
+        assert_ne!(auth.rand, [0u8; 24]);
+        assert!(auth.verify(&key.public_bytes()));
+        assert!(!auth.verify(&Ed25519Keypair::generate().public_bytes()));
+
+        let (cell, length) = roundtrip(TorCell::new(0, auth.to_command()));
+
+        assert_eq!(length, 4 + 1 + 2 + 4 + AUTH0003_LENGTH);
+        assert!(matches!(cell.get_command(), Command::Authenticate { auth_type, .. } if *auth_type == AUTH_METHOD_ED25519_SHA256_RFC5705));
+
+        let parsed = Auth0003::from_command(cell.get_command()).unwrap();
+
+        assert_eq!(parsed, auth);
+        assert!(parsed.verify(&key.public_bytes()));
+
+        let mut tampered = parsed.to_bytes();
+
+        tampered[200] ^= 1;
+
+        assert!(!Auth0003::parse(&tampered).unwrap().verify(&key.public_bytes()));
+        assert!(Auth0003::parse(&tampered[1..]).is_err());
+        assert!(Auth0003::from_command(&Command::Authenticate { length: 4, auth_type: 1, authentication: NLengthVector::from(vec![]) }).is_err());
+
+        //VPADDING both ways over a channel, which carries on working
+        let relay = MockRelay::spawn(MockTransport::Tls);
+
+        let channel = Channel::connect(relay.address()).unwrap();
+
+        channel.send_vpadding(300).unwrap();
+        channel.send_vpadding(0).unwrap();
+
+        relay.send_vpadding(1000);
+
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(relay.vpadding_received(), vec![300, 0]);
+
+        let circuit = channel.create_fast().unwrap();
+
+        assert_eq!(circuit.hop_count(), 1);
+    }
+
+    #[cfg(feature = "legacy-tap")]
+    #[test]
+    fn test_tap_handshake() {
+        use crate::channel::Channel;
+        use crate::cells::LinkSpecifier;
+        use crate::mock_relay::tap_onion_key;
+        use crate::netdoc;
+        use crate::tap::{self, RsaPublicKey, TapClient, TAP_REPLY_LENGTH};
+
+        let hex = |s: &str| -> Vec<u8> { (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect() };
+
+        let onion_key = tap_onion_key();
+
+        //The onion key as a descriptor carries it
+        let items = netdoc::parse_items("onion-key\n-----BEGIN RSA PUBLIC KEY-----\nMIGJAoGBAMJuXxR1KwPNOkBXCrj9bttdFgkr3GCfa05RBq+mx52Zugn8rCcZaF8C\n64GfAurX/M4Pw/TyKJOS0YN9JuwLKc1/5EkbdUyEJr9G2oAw++CfpP6IkO2V2caA\nw2Us3bdLIcnXxTavO3Y2ZI43ZrzUlB9rbyufktsgZ9PSfZ3f6FABAgMBAAE=\n-----END RSA PUBLIC KEY-----\n").unwrap();
+
+        let parsed = RsaPublicKey::from_der(netdoc::find(&items, "onion-key").unwrap().object().unwrap()).unwrap();
+
+        assert_eq!(parsed, onion_key.public);
+        assert!(RsaPublicKey::from_der(&[0x30, 0x03, 0x02, 0x01, 0x05]).is_err());
+
+        //RSA-OAEP with SHA1 from another implementation
+        let ciphertext = hex("bcfa5f8a3ddd37bfcf0c0fba3b9b2ed6bb8a7d8db2dbe1b5e67bcc9ca663b5dc09af4225d9bd2b7b55839ead46936de2fc211cd5596eb6d75c41aac574c4bab7a4283039915337e41ae4c22dec3ee499bb09628b3ad7d21c89380865470ff2813f7f3db8803ce735701c82eb339806ddb282e1310255e669eb61b78648b56ffa");
+
+        assert_eq!(onion_key.decrypt_oaep(&ciphertext).unwrap(), (0..86).collect::<Vec<u8>>());
+
+        let encrypted = onion_key.public.encrypt_oaep(b"short");
+
+        assert_eq!(onion_key.decrypt_oaep(&encrypted).unwrap(), b"short");
+
+        let mut tampered = encrypted.clone();
+
+        tampered[64] ^= 1;
+
+        assert!(onion_key.decrypt_oaep(&tampered).is_none());
+
+        //Both halves of the handshake agree, and a wrong KH or g^y is refused
+        let client = TapClient::new();
+
+        let (reply, server_materials) = tap::server_handshake(&onion_key, &client.onion_skin(&onion_key.public)).unwrap();
+
+        assert_eq!(client.complete(&reply).unwrap(), server_materials);
+
+        let mut wrong_kh = reply;
+
+        wrong_kh[TAP_REPLY_LENGTH - 1] ^= 1;
+
+        assert!(client.complete(&wrong_kh).is_none());
+
+        let mut one = [0u8; TAP_REPLY_LENGTH];
+
+        one[127] = 1;
+
+        assert!(client.complete(&one).is_none());
+
+        let mut skin = client.onion_skin(&onion_key.public);
+
+        skin[10] ^= 1;
+
+        assert!(tap::server_handshake(&onion_key, &skin).is_none());
+        assert!(tap::server_handshake(&onion_key, &[0u8; 186]).is_none());
+
+        //A TAP circuit through a relay carries streams like any other
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::connect(relay.address()).unwrap();
+        let circuit = channel.create_tap(&onion_key.public).unwrap();
+
+        circuit.extend_ntor(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key()).unwrap();
+
+        let mut stream = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+        stream.write_all(b"legacy").unwrap();
+
+        let mut echoed = [0u8; 6];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"legacy");
+    }
+
+    #[test]
+    fn test_exit_policy() {
+        use std::net::{IpAddr, Ipv4Addr};
+
+        use crate::cells::EndReason;
+        use crate::channel::Channel;
+        use crate::error::ErrorKind;
+        use crate::exit_policy::{AddressPattern, ExitPolicy, PortRange};
+        use crate::mock_relay::{MockRelay, MockTransport};
+        use crate::netdoc;
+
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+
+        assert_eq!(PortRange::parse("*").unwrap(), PortRange::ALL);
+        assert_eq!(PortRange::parse("80-88").unwrap(), PortRange { low: 80, high: 88 });
+        assert!(PortRange::parse("0").is_err());
+        assert!(PortRange::parse("90-80").is_err());
+
+        assert_eq!(AddressPattern::parse("10.0.0.0/255.0.0.0").unwrap(), AddressPattern::parse("10.0.0.0/8").unwrap());
+        assert!(AddressPattern::parse("10.0.0.0/255.0.255.0").is_err());
+        assert!(AddressPattern::parse("10.0.0.0/33").is_err());
+        assert!(AddressPattern::parse("::1").is_err());
+
+        //A torrc-style policy, with the lines around it skipped and the first match deciding
+        let policy = ExitPolicy::parse("router exit 198.51.100.7 9001 0 0
+reject private:*
+reject 198.51.100.0/24:*
+accept 203.0.113.5:22
+reject *:22
+accept *4:80-443
+reject6 [2001:db8::]/32:*
+accept6 *:80
+accept6 192.0.2.1:25
+reject *:*
+").unwrap();
+
+        assert!(policy.allows(ip("93.184.216.34"), 80));
+        assert!(policy.allows(ip("93.184.216.34"), 443));
+        assert!(!policy.allows(ip("93.184.216.34"), 444));
+        assert!(!policy.allows(ip("192.168.1.1"), 80));
+        assert!(!policy.allows(ip("127.0.0.1"), 80));
+        assert!(!policy.allows(ip("::1"), 80));
+        assert!(!policy.allows(ip("198.51.100.200"), 80));
+        assert!(policy.allows(ip("203.0.113.5"), 22));
+        assert!(!policy.allows(ip("203.0.113.6"), 22));
+        assert!(policy.allows(ip("2606:2800::1"), 80));
+        assert!(!policy.allows(ip("2606:2800::1"), 443));
+        assert!(!policy.allows(ip("2001:db8::1"), 80));
+
+        //accept6 with an IPv4 address is ignored rather than accepting port 25
+        assert!(!policy.allows(ip("192.0.2.1"), 25));
+
+        //Without an address, rules that only reject some addresses are passed over
+        assert!(policy.allows_port(80));
+        assert!(policy.allows_port(22));
+        assert!(!policy.allows_port(25));
+
+        assert!(ExitPolicy::parse("").unwrap().allows(ip("192.0.2.1"), 25));
+        assert!(ExitPolicy::parse("accept 1.2.3.4").is_err());
+        assert!(ExitPolicy::parse("reject *:0").is_err());
+
+        //A server descriptor as relays publish it: its accept and reject lines are the IPv4 policy, and ipv6-policy
+        //summarises the IPv6 one
+        let descriptor = netdoc::parse_items("router exit 198.51.100.7 9001 0 0
+or-address [2001:db8:7::1]:9001
+platform Tor 0.4.8.12 on Linux
+proto Cons=1-2 Desc=1-2 DirCache=2 FlowCtrl=1-2 HSDir=2 HSIntro=4-5 HSRend=1-2 Link=1-5 LinkAuth=1,3 Microdesc=1-2 Padding=2 Relay=1-4
+published 2024-05-01 12:00:00
+fingerprint 8C7F 0D7E 5A3B 19F1 E4C2 6A55 0B37 9E21 D4F6 A0C3
+uptime 864000
+bandwidth 10485760 20971520 9437184
+onion-key
+-----BEGIN RSA PUBLIC KEY-----
+4SIigNLaMoLcqpwmRX3IaYLk8Ln30xfQ8qUqucfY26TN+q1+XEeWhO4poI8mcTfx
+VREiQCIzet1FFVueeEfzd4WIsnFugmmypjEyLa4+LAUoM6cmjVtHfhcAToc74o0O
+6Uuuw1PmFUg+Xx8dtl0C0/eABf/lSFdTe/2ckaITCbLGniQ48d/fblpxuPg=
+-----END RSA PUBLIC KEY-----
+signing-key
+-----BEGIN RSA PUBLIC KEY-----
+rf/FlcaB5sQLfxVYbSjekIgnYQ/M/nMs++jXtmG0M3rbaPyabpA7+WE1P3HPck8W
+aCt9WFXrEwgOz/IyaC01STZr4h3k3PYNl4YFCmhQcOsRlRstwysIf2itajLf8dHi
+6daPJ+WUcWYWFsDJRuur/ujnxs3uA9cWavSZ+kiXCRS0GhqhEpdje336wBo=
+-----END RSA PUBLIC KEY-----
+hidden-service-dir
+contact exit operator <abuse AT example DOT org>
+ntor-onion-key L71GybuxBShjeD4RxAbV1jAVLDP2TauoiQT5C0j54C8
+reject 0.0.0.0/8:*
+reject 169.254.0.0/16:*
+reject 127.0.0.0/8:*
+reject 192.168.0.0/16:*
+reject 10.0.0.0/8:*
+reject 172.16.0.0/12:*
+reject 198.51.100.7:*
+reject *:25
+reject *:119
+reject *:135-139
+reject *:445
+reject *:563
+reject *:1214
+reject *:4661-4666
+reject *:6346-6429
+reject *:6699
+reject *:6881-6999
+accept *:*
+ipv6-policy accept 22,53,80,443,993,995
+router-sig-ed25519 L71GybuxBShjeD4RxAbV1jAVLDP2TauoiQT5C0j54C8
+router-signature
+-----BEGIN SIGNATURE-----
+3nvAVZiWiBRkRxp+flae54qxpQAz6lFKabp2dIw2ToePJv0HyKQUVbpnfE71/k43
+nQAMH3jDnkBt+8pfLKOSbtGGDsEgnLyQzexmtsvDXFpiGwUtzA0zucoGsD+HaWUf
+ThmQFps59qgd3s8oSePNpeap7IOSueIjwTfGEEHX9d4=
+-----END SIGNATURE-----
+").unwrap();
+
+        let exit = ExitPolicy::from_descriptor(&descriptor).unwrap();
+
+        assert!(exit.allows(ip("93.184.216.34"), 8080));
+        assert!(!exit.allows(ip("93.184.216.34"), 25));
+        assert!(!exit.allows(ip("10.1.2.3"), 80));
+        assert!(!exit.allows(ip("198.51.100.7"), 443));
+        assert!(exit.allows(ip("2606:2800::1"), 443));
+        assert!(!exit.allows(ip("2606:2800::1"), 8080));
+        assert!(exit.allows_port(8080));
+        assert!(!exit.allows_port(6900));
+
+        //Without ipv6-policy the exit takes no IPv6 streams, even though accept *:* matches every address
+        let ipv4_exit = ExitPolicy::from_descriptor(&netdoc::parse_items("router exit 198.51.100.7 9001 0 0\naccept *:*\n").unwrap()).unwrap();
+
+        assert!(ipv4_exit.allows(ip("93.184.216.34"), 443));
+        assert!(!ipv4_exit.allows(ip("2606:2800::1"), 443));
+
+        //Microdescriptor summaries
+        let microdesc = netdoc::parse_items("onion-key\nntor-onion-key AAAA\np accept 80,443,6660-6669\np6 reject 1-1024\n").unwrap();
+
+        let summary = ExitPolicy::from_microdesc(&microdesc).unwrap();
+
+        assert!(summary.allows(ip("192.0.2.1"), 443));
+        assert!(summary.allows(ip("192.0.2.1"), 6667));
+        assert!(!summary.allows(ip("192.0.2.1"), 22));
+        assert!(!summary.allows(ip("2001:db8::1"), 443));
+        assert!(summary.allows(ip("2001:db8::1"), 8080));
+        assert!(summary.allows_port(80));
+        assert!(!summary.allows_port(8080));
+
+        let ipv4_only = ExitPolicy::from_microdesc(&netdoc::parse_items("p reject 25\n").unwrap()).unwrap();
+
+        assert!(ipv4_only.allows(ip("192.0.2.1"), 80));
+        assert!(!ipv4_only.allows(ip("192.0.2.1"), 25));
+        assert!(!ipv4_only.allows(ip("2001:db8::1"), 80));
+
+        assert!(ExitPolicy::from_microdesc(&netdoc::parse_items("p allow 80\n").unwrap()).is_err());
+
+        //Refusals from the exit are remembered until their TTL runs out
+        let mut learning = ExitPolicy::parse("accept *:*").unwrap();
+
+        learning.learn(&EndReason::ExitPolicy { ip: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 }, 80);
+        learning.learn(&EndReason::ConnectRefused, 443);
+        learning.learn(&EndReason::ExitPolicy { ip: Ipv4Addr::new(192, 0, 2, 2), ttl: 0 }, 80);
+
+        assert!(!learning.allows(ip("192.0.2.1"), 80));
+        assert!(learning.allows(ip("192.0.2.1"), 443));
+        assert!(learning.allows(ip("192.0.2.3"), 80));
+        assert!(learning.allows(ip("192.0.2.2"), 80));
+        assert!(learning.allows_port(80));
+
+        learning.learn(&EndReason::ExitPolicy { ip: Ipv4Addr::UNSPECIFIED, ttl: 300 }, 8080);
+
+        assert!(!learning.allows_port(8080));
+        assert!(!learning.allows(ip("192.0.2.3"), 8080));
+
+        //An exit that refuses a stream teaches the policy not to try it again
+        let relay = MockRelay::spawn(MockTransport::Tls);
+
+        let channel = Channel::connect(relay.address()).unwrap();
+        let circuit = channel.create_fast().unwrap();
+
+        let mut policy = ExitPolicy::parse("accept *:*").unwrap();
+
+        assert!(policy.allows(ip("192.0.2.9"), 80));
+
+        match circuit.begin("192.0.2.9", 80) {
+            Err(ErrorKind::StreamRefused(end_reason)) => policy.learn(&end_reason, 80),
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+
+        assert!(!policy.allows(ip("192.0.2.9"), 80));
+        assert!(policy.allows(ip("192.0.2.10"), 80));
+    }
+
+    #[test]
+    fn test_begin_flags_and_connected() {
+        use std::net::{Ipv4Addr, Ipv6Addr};
+
+        use crate::cells::{ConnectedAddress, EndReason};
+        use crate::channel::Channel;
+        use crate::circuit::addr_and_port;
+        use crate::error::ErrorKind;
+        use crate::mock_relay::{MockRelay, MockTransport};
+
+        //BEGIN flags keep their wire bits, including ones we don't know
+        let flags = BeginFlags::IPV6_OK | BeginFlags::IPV6_PREFERRED;
+
+        assert_eq!(flags.bits(), 5);
+        assert!(flags.contains(BeginFlags::IPV6_OK));
+        assert!(!flags.contains(BeginFlags::IPV4_NOT_OK));
+        assert!(flags.contains(BeginFlags::empty()));
+
+        let begin = RelayCell::new(3, Relay::Begin { addr_and_port: addr_and_port("2001:db8::1", 443), flags: flags | BeginFlags::from_bits(0x100) });
+
+        assert_eq!(&begin.into_data()[..], &b"[2001:db8::1]:443\0\0\0\x01\x05"[..]);
+
+        match RelayCell::new(3, Relay::Begin { addr_and_port: addr_and_port("example.com", 80), flags: BeginFlags::IPV4_NOT_OK }).get_payload().unwrap() {
+            Some(Relay::Begin { addr_and_port, flags }) => {
+                assert_eq!(addr_and_port, "example.com:80");
+                assert_eq!(flags, BeginFlags::IPV4_NOT_OK);
+            },
+            payload => panic!("expected BEGIN, got {:?}", payload),
+        }
+
+        //Each form of CONNECTED survives a trip through a relay cell
+        let forms = [
+            (ConnectedAddress::None, 0),
+            (ConnectedAddress::Ipv4 { ip: Ipv4Addr::new(192, 0, 2, 1), ttl: 3600 }, 8),
+            (ConnectedAddress::Ipv6 { ip: "2001:db8::1".parse::<Ipv6Addr>().unwrap(), ttl: 60 }, 25),
+        ];
+
+        for (address, length) in forms.iter() {
+            let cell = RelayCell::new(3, Relay::Connected { address: *address });
+
+            assert_eq!(cell.get_command(), 4);
+
+            let body = cell.into_data();
+
+            assert_eq!(body.len(), *length);
+
+            match RelayCell::new_raw(4, 3, body).get_payload().unwrap() {
+                Some(Relay::Connected { address: parsed }) => assert_eq!(parsed, *address),
+                payload => panic!("expected CONNECTED, got {:?}", payload),
+            }
+        }
+
+        let ipv6 = RelayCell::new(3, Relay::Connected { address: forms[2].0 }).into_data();
+
+        assert_eq!(&ipv6[..5], &[0, 0, 0, 0, 6]);
+        assert_eq!(forms[2].0.ip(), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(forms[2].0.ttl(), Some(60));
+        assert_eq!(ConnectedAddress::None.ip(), None);
+
+        //Bodies of any other length are malformed
+        assert!(RelayCell::new_raw(4, 3, vec![0; 7]).get_payload().is_err());
+        assert!(RelayCell::new_raw(4, 3, vec![0; 8]).get_payload().is_err());
+
+        //An exit refuses address families the flags rule out
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::connect(relay.address()).unwrap();
+        let circuit = channel.create_fast().unwrap();
+
+        match circuit.begin_with_flags("127.0.0.1", echo.port(), BeginFlags::IPV4_NOT_OK) {
+            Err(ErrorKind::StreamRefused(EndReason::ExitPolicy { .. })) => {},
+            other => panic!("expected an exit policy refusal, got {:?}", other.map(|_| ())),
+        }
+
+        match circuit.begin("::1", echo.port()) {
+            Err(ErrorKind::StreamRefused(EndReason::ExitPolicy { .. })) => {},
+            other => panic!("expected an exit policy refusal, got {:?}", other.map(|_| ())),
+        }
+
+        let mut stream = circuit.begin_with_flags("127.0.0.1", echo.port(), BeginFlags::IPV6_OK).unwrap();
+
+        stream.write_all(b"flags").unwrap();
+
+        let mut echoed = [0u8; 5];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"flags");
+    }
+
+    #[test]
+    fn test_dns_cache() {
+        use std::net::{IpAddr, Ipv4Addr};
+        use std::time::Duration;
+
+        use crate::cells::ConnectedAddress;
+        use crate::channel::Channel;
+        use crate::circuit_pool::{CircuitPool, PoolConfig};
+        use crate::dns_cache::{clip_dns_ttl, DnsCache, DnsCacheConfig, MAX_DNS_TTL, MIN_DNS_TTL};
+        use crate::isolation::{IsolationKey, IsolationToken};
+        use crate::mock_relay::{MockRelay, MockTransport};
+
+        //TTLs fall into one of two buckets
+        assert_eq!(clip_dns_ttl(0), MIN_DNS_TTL);
+        assert_eq!(clip_dns_ttl(299), MIN_DNS_TTL);
+        assert_eq!(clip_dns_ttl(300), MAX_DNS_TTL);
+        assert_eq!(clip_dns_ttl(86400), MAX_DNS_TTL);
+
+        let shared = IsolationKey::default();
+        let isolated: IsolationKey = IsolationToken::new().into();
+
+        let answer = ConnectedAddress::Ipv4 { ip: Ipv4Addr::new(192, 0, 2, 1), ttl: 60 };
+        let ipv6_answer = ConnectedAddress::Ipv6 { ip: "2001:db8::1".parse().unwrap(), ttl: 60 };
+
+        //Off by default, so nothing is remembered
+        let disabled = DnsCache::default();
+
+        assert!(!disabled.config().enabled());
+
+        disabled.record(&shared, "example.com", &answer);
+
+        assert!(disabled.is_empty());
+        assert_eq!(disabled.rewrite(&shared, "example.com", BeginFlags::empty()), "example.com");
+
+        let cache = DnsCache::new(DnsCacheConfig { cache_ipv4: true, cache_ipv6: false });
+
+        cache.record(&shared, "Example.COM", &answer);
+        cache.record(&shared, "example.net", &ipv6_answer);
+        cache.record(&shared, "example.org", &ConnectedAddress::None);
+        cache.record(&shared, "192.0.2.7", &answer);
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.lookup(&shared, "example.com", BeginFlags::empty()), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+        assert_eq!(cache.rewrite(&shared, "EXAMPLE.com", BeginFlags::empty()), "192.0.2.1");
+        assert_eq!(cache.rewrite(&shared, "example.net", BeginFlags::empty()), "example.net");
+
+        //Answers are kept apart for each isolation group
+        assert_eq!(cache.lookup(&isolated, "example.com", BeginFlags::empty()), None);
+
+        cache.record(&isolated, "example.com", &ConnectedAddress::Ipv4 { ip: Ipv4Addr::new(192, 0, 2, 2), ttl: 60 });
+
+        assert_eq!(cache.rewrite(&isolated, "example.com", BeginFlags::empty()), "192.0.2.2");
+        assert_eq!(cache.rewrite(&shared, "example.com", BeginFlags::empty()), "192.0.2.1");
+
+        cache.flush(&isolated);
+
+        assert_eq!(cache.lookup(&isolated, "example.com", BeginFlags::empty()), None);
+        assert_eq!(cache.len(), 1);
+
+        cache.clear();
+
+        assert!(cache.is_empty());
+
+        //Cached addresses are only used for streams whose flags allow their family
+        let both = DnsCache::new(DnsCacheConfig { cache_ipv4: true, cache_ipv6: true });
+
+        both.record(&shared, "example.com", &answer);
+        both.record(&shared, "example.com", &ipv6_answer);
+        both.record(&shared, "example.net", &ipv6_answer);
+
+        assert_eq!(both.len(), 3);
+        assert_eq!(both.rewrite(&shared, "example.com", BeginFlags::empty()), "192.0.2.1");
+        assert_eq!(both.rewrite(&shared, "example.com", BeginFlags::IPV6_OK), "192.0.2.1");
+        assert_eq!(both.rewrite(&shared, "example.com", BeginFlags::IPV6_OK | BeginFlags::IPV6_PREFERRED), "2001:db8::1");
+        assert_eq!(both.rewrite(&shared, "example.com", BeginFlags::IPV6_OK | BeginFlags::IPV4_NOT_OK), "2001:db8::1");
+        assert_eq!(both.rewrite(&shared, "example.com", BeginFlags::IPV4_NOT_OK), "example.com");
+        assert_eq!(both.rewrite(&shared, "example.net", BeginFlags::empty()), "example.net");
+        assert_eq!(both.rewrite(&shared, "example.net", BeginFlags::IPV6_OK), "2001:db8::1");
+
+        //A new answer replaces the old one of its family only
+        both.record(&shared, "example.com", &ConnectedAddress::Ipv4 { ip: Ipv4Addr::new(192, 0, 2, 3), ttl: 60 });
+
+        assert_eq!(both.len(), 3);
+        assert_eq!(both.rewrite(&shared, "example.com", BeginFlags::empty()), "192.0.2.3");
+        assert_eq!(both.rewrite(&shared, "example.com", BeginFlags::IPV6_OK | BeginFlags::IPV4_NOT_OK), "2001:db8::1");
+
+        //Streams through the cache record the exit's answer and reuse it
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::connect(relay.address()).unwrap();
+        let circuit = channel.create_fast().unwrap();
+
+        let mut stream = cache.begin(&circuit, &shared, "localhost", echo.port(), BeginFlags::empty()).unwrap();
+
+        assert_eq!(stream.connected_address(), ConnectedAddress::Ipv4 { ip: Ipv4Addr::LOCALHOST, ttl: 300 });
+        assert_eq!(cache.rewrite(&shared, "localhost", BeginFlags::empty()), "127.0.0.1");
+
+        stream.end();
+
+        let mut stream = cache.begin(&circuit, &shared, "localhost", echo.port(), BeginFlags::empty()).unwrap();
+
+        stream.write_all(b"cached").unwrap();
+
+        let mut echoed = [0u8; 6];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"cached");
+
+        //A pool flushes a group's answers when it retires one of the group's circuits
+        let config = PoolConfig {
+            clean_circuits: 0,
+            initial_ports: vec![],
+            max_circuit_dirtiness: Duration::from_secs(0),
+            dns_cache: DnsCacheConfig { cache_ipv4: true, cache_ipv6: true },
+            ..PoolConfig::default()
+        };
+
+        let pool = CircuitPool::new(FakeBuilder::default(), config);
+
+        pool.get(80, &shared).unwrap();
+
+        pool.dns_cache().record(&shared, "example.com", &answer);
+        pool.dns_cache().record(&isolated, "example.com", &ipv6_answer);
+
+        assert_eq!(pool.dns_cache().len(), 2);
+
+        pool.maintain();
+
+        assert_eq!(pool.dns_cache().lookup(&shared, "example.com", BeginFlags::empty()), None);
+        assert_eq!(pool.dns_cache().rewrite(&isolated, "example.com", BeginFlags::IPV6_OK), "2001:db8::1");
+    }
+
+    #[test]
+    fn test_circuit_build_timeout() {
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        use crate::build_timeout::{CircuitBuildTimes, CBT_TIMEOUT_INITIAL_VALUE, CBT_STATE_FILE, CBT_NCIRCUITS_TO_OBSERVE};
+        use crate::channel::Channel;
+        use crate::cells::LinkSpecifier;
+        use crate::error::ErrorKind;
+        use crate::mock_relay::{MockRelay, MockTransport};
+        use crate::onion::RelayInfo;
+
+        let initial = Duration::from_millis(CBT_TIMEOUT_INITIAL_VALUE as u64);
+
+        let times = CircuitBuildTimes::new();
+
+        assert_eq!(times.timeout(), initial);
+        assert_eq!(times.close_timeout(), initial);
+
+        //Pareto distributed build times with Xm = 500ms and alpha = 2, whose 80th percentile is 1118ms
+        let samples = |count: usize| (0..count).map(move |i| {
+            let u = (i as f64 + 0.5) / count as f64;
+
+            Duration::from_millis((500.0 / (1.0 - u).powf(0.5)) as u64)
+        });
+
+        for time in samples(99) {
+            times.add_time(time);
+        }
+
+        assert_eq!(times.timeout(), initial);
+        assert!(times.pareto().is_none());
+
+        for time in samples(400) {
+            times.add_time(time);
+        }
+
+        let (xm, alpha) = times.pareto().unwrap();
+
+        assert!(xm > 480.0 && xm < 560.0, "xm {}", xm);
+        assert!(alpha > 1.6 && alpha < 2.8, "alpha {}", alpha);
+        assert!(times.timeout() > Duration::from_millis(900) && times.timeout() < Duration::from_millis(1400), "timeout {:?}", times.timeout());
+        assert!(times.close_timeout() > times.timeout());
+
+        //Abandoned circuits push the timeout out
+        let learned = times.timeout();
+
+        for _ in 0..100 {
+            times.add_abandoned();
+        }
+
+        assert!(times.timeout() > learned);
+
+        //The state file keeps the histogram, so a restart learns the same timeout
+        let directory = std::env::temp_dir().join(format!("torpedo_cbt_{}", std::process::id()));
+
+        times.save(&directory).unwrap();
+
+        let restored = CircuitBuildTimes::load(&directory).unwrap();
+
+        assert_eq!(restored.len(), times.len());
+        assert_eq!(restored.to_state(), times.to_state());
+        assert!(restored.timeout().as_millis().abs_diff(times.timeout().as_millis()) < 50);
+        assert!(std::fs::read_to_string(directory.join(CBT_STATE_FILE)).unwrap().contains("CircuitBuildAbandonedCount 100\n"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(CircuitBuildTimes::load(&directory).unwrap().is_empty());
+        assert!(CircuitBuildTimes::from_state("CircuitBuildTimeBin 105").is_err());
+        assert!(CircuitBuildTimes::from_state(&format!("CircuitBuildTimeBin {},1", u32::MAX)).is_err());
+
+        //Counts from the file can't grow the times past what is kept
+        let huge = CircuitBuildTimes::from_state(&format!("CircuitBuildAbandonedCount {}\nCircuitBuildTimeBin 105,{}", usize::MAX, usize::MAX)).unwrap();
+
+        assert_eq!(huge.len(), CBT_NCIRCUITS_TO_OBSERVE);
+        assert!(huge.to_state().starts_with("TotalBuildTimes 1000\nCircuitBuildAbandonedCount 0\n"));
+
+        //Timeouts with no sign of the network put the timeout back to its initial value until the network returns
+        let started = Instant::now();
+
+        for _ in 0..3 {
+            assert!(!times.network_timeout(started, false));
+        }
+
+        assert!(times.network_is_down());
+        assert_eq!(times.timeout(), initial);
+
+        times.network_is_live();
+
+        assert!(!times.network_is_down());
+        assert!(times.timeout() < initial);
+
+        //Nearly every recent circuit timing out after its first hop throws everything away
+        let started = Instant::now();
+
+        times.network_is_live();
+
+        for _ in 0..17 {
+            assert!(times.network_timeout(started, true));
+        }
+
+        assert!(!times.is_empty());
+
+        times.network_succeeded();
+
+        assert!(!times.is_empty());
+
+        times.network_timeout(started, true);
+
+        assert!(times.is_empty());
+        assert!(times.timeout() >= initial);
+
+        //Building through a relay records the time
+        let relay = MockRelay::spawn(MockTransport::Tls);
+
+        let hop = RelayInfo {
+            node_id: relay.node_id(),
+            ed25519_id: None,
+            ntor_onion_key: relay.onion_key(),
+            link_specifiers: vec![LinkSpecifier::legacy_id(relay.node_id())],
+        };
+
+        let channel = Arc::new(Channel::connect(relay.address()).unwrap());
+
+        let times = CircuitBuildTimes::new();
+
+        let circuit = times.build(&channel, &hop, &[hop.clone(), hop.clone()]).unwrap();
+
+        assert_eq!(circuit.hop_count(), 3);
+        assert_eq!(times.len(), 1);
+        assert!(times.to_state().contains("CircuitBuildTimeBin"));
+
+        //A circuit slower than the timeout fails the caller then, but keeps building until the close timeout so its time
+        //is still learned. Xm = 200ms and alpha = 2 give a timeout near 450ms and a close timeout well past a second
+        let learned = CircuitBuildTimes::new();
+
+        for i in 0..500 {
+            learned.add_time(Duration::from_millis((200.0 / (1.0 - (i as f64 + 0.5) / 500.0).powf(0.5)) as u64));
+        }
+
+        let delay = (learned.timeout() + learned.close_timeout()) / 2;
+
+        assert!(delay > learned.timeout() + Duration::from_millis(200), "timeout {:?}, close timeout {:?}", learned.timeout(), learned.close_timeout());
+
+        relay.delay_extends(delay);
+
+        let started = Instant::now();
+
+        match learned.build(&channel, &hop, std::slice::from_ref(&hop)) {
+            Err(ErrorKind::Timeout) => {},
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+
+        assert!(started.elapsed() < delay, "gave up after {:?}", started.elapsed());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while learned.len() == 500 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(learned.len(), 501);
+
+        relay.delay_extends(Duration::from_millis(0));
+
+        //A hop that doesn't answer by the deadline fails the build
+        match channel.create_ntor_until(&hop.node_id, &hop.ntor_onion_key, Some(Instant::now())) {
+            Err(ErrorKind::Timeout) => {},
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+
+        match circuit.extend_ntor_until(hop.link_specifiers.clone(), &hop.node_id, &hop.ntor_onion_key, Some(Instant::now())) {
+            Err(ErrorKind::Timeout) => {},
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_conflux() {
+        use std::time::{Duration, Instant};
+
+        use crate::channel::Channel;
+        use crate::conflux::{ConfluxSet, ReorderQueue, Scheduler, Sequencer, is_multiplexed};
+
+        //Items come out in sequence order whatever order they went in
+        let mut queue = ReorderQueue::new();
+
+        assert!(queue.push(2, "second"));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.push(1, "first"));
+        assert!(!queue.push(1, "again"));
+        assert_eq!(queue.pop(), Some("first"));
+        assert_eq!(queue.pop(), Some("second"));
+        assert!(!queue.push(2, "late"));
+        assert!(queue.is_empty());
+
+        //Moving to another leg needs a SWITCH saying how far the numbering moved on, and the receiver counts the same way
+        let mut sender = Sequencer::new(2);
+        let mut receiver = Sequencer::new(2);
+
+        assert_eq!(sender.send(0), None);
+        assert_eq!(sender.send(0), None);
+        assert_eq!(sender.send(1), Some(2));
+        assert_eq!(sender.send(0), Some(1));
+        assert_eq!(sender.last_sent(), 4);
+
+        assert_eq!(receiver.receive(0), 1);
+        assert_eq!(receiver.receive(0), 2);
+
+        receiver.switch(1, 2);
+
+        assert_eq!(receiver.receive(1), 3);
+
+        receiver.switch(0, 1);
+
+        assert_eq!(receiver.receive(0), 4);
+
+        assert!(is_multiplexed(2));
+        assert!(!is_multiplexed(5));
+        assert!(!is_multiplexed(22));
+
+        let fast = Some(Duration::from_millis(10));
+        let slow = Some(Duration::from_millis(50));
+
+        assert_eq!(Scheduler::MinRtt.choose(&[slow, fast], 0, 1), Some(1));
+        assert_eq!(Scheduler::MinRtt.choose(&[fast, fast], 1, 1), Some(1));
+        assert_eq!(Scheduler::MinRtt.choose(&[None, slow], 0, 1), Some(1));
+        assert_eq!(Scheduler::MinRtt.choose(&[None, None], 0, 1), None);
+
+        //The low-memory scheduler only moves to a faster leg after a run of cells, or when its leg closes
+        assert_eq!(Scheduler::LowMemory.choose(&[slow, fast], 0, 1), Some(0));
+        assert_eq!(Scheduler::LowMemory.choose(&[slow, fast], 0, 100), Some(1));
+        assert_eq!(Scheduler::LowMemory.choose(&[None, slow], 0, 1), Some(1));
+
+        //The mock exit splits everything it relays over both legs and sends the second half first
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        let channel = Channel::connect(relay.address()).unwrap();
+
+        let set = ConfluxSet::link(channel.create_fast().unwrap(), channel.create_fast().unwrap(), Scheduler::MinRtt).unwrap();
+
+        assert_eq!(set.rtts().len(), 2);
+        assert_eq!(set.scheduler(), Scheduler::MinRtt);
+
+        let mut stream = set.begin("localhost", echo.port()).unwrap();
+
+        let sent: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+
+        stream.write_all(&sent).unwrap();
+
+        let mut echoed = vec![0u8; sent.len()];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert!(echoed == sent);
+        assert!(set.out_of_order() > 0);
+
+        //A second stream is numbered after the first
+        let mut stream = set.begin("localhost", echo.port()).unwrap();
+
+        stream.write_all(b"conflux").unwrap();
+
+        let mut echoed = [0u8; 7];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(&echoed, b"conflux");
+
+        //Once a leg has carried a SENDME increment of DATA, its round trip is the one its circuit SENDME took
+        let linked = set.rtts();
+
+        let sent = vec![7u8; 60000];
+
+        stream.write_all(&sent).unwrap();
+
+        let mut echoed = vec![0u8; sent.len()];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert!(echoed == sent);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while set.rtts() == linked && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_ne!(set.rtts(), linked);
+
+        //A leg waiting for its window to open doesn't hold up the set, so the cells that bring its SENDME are still taken
+        relay.hold_sendmes(true);
+
+        let received = relay.data_received();
+
+        let writers: Vec<_> = (0..6).map(|_| {
+            let mut stream = set.begin("localhost", echo.port()).unwrap();
+
+            std::thread::spawn(move || {
+                let sent = vec![3u8; 400 * 498];
+
+                stream.write_all(&sent).unwrap();
+
+                let mut echoed = vec![0u8; sent.len()];
+
+                stream.read_exact(& mut echoed).unwrap();
+
+                echoed == sent
+            })
+        }).collect();
+
+        //Six streams of 400 cells are more than a leg's window takes, so sending stalls until the SENDMEs come
+        let mut stalled = received;
+
+        loop {
+            std::thread::sleep(Duration::from_millis(200));
+
+            if relay.data_received() == stalled && stalled > received {
+                break;
+            }
+
+            stalled = relay.data_received();
+        }
+
+        assert!(stalled < received + 6 * 400);
+
+        relay.hold_sendmes(false);
+
+        for writer in writers {
+            assert!(writer.join().unwrap());
+        }
+
+        //Dropping the set stops the threads driving its legs, so the second leg is destroyed
+        drop(set);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while channel.circuit_count() > 1 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(channel.circuit_count(), 1);
+    }
+
+    #[test]
+    fn test_cgo() {
+        use crate::cells::RelayCell;
+        use crate::cgo::{self, CgoCrypto, CgoDirection, Et, Prf, Uiv, CGO_DATA_LEN, CGO_KEY_MATERIAL_LENGTH};
+        use crate::relay_format;
+
+        let hex = |text: &str| (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+
+        //Each line of a vector file is one vector, its fields separated by spaces
+        let vectors = |text: &'static str| text.lines().filter(|line| !line.starts_with('#')).map(|line| line.split(' ').collect::<Vec<&str>>()).collect::<Vec<_>>();
+
+        //The POLYVAL test vector from RFC 8452 appendix A
+        let key = [0x25, 0x62, 0x93, 0x47, 0x58, 0x92, 0x42, 0x76, 0x1d, 0x31, 0xf8, 0x26, 0xba, 0x4b, 0x75, 0x7b];
+
+        let data = [
+            0x4f, 0x4f, 0x95, 0x66, 0x8c, 0x83, 0xdf, 0xb6, 0x40, 0x17, 0x62, 0xbb, 0x2d, 0x01, 0xa2, 0x62,
+            0xd1, 0xa2, 0x4d, 0xdd, 0x27, 0x21, 0xd0, 0x06, 0xbb, 0xe4, 0x5f, 0x20, 0xd3, 0xc9, 0xf3, 0x62,
+        ];
+
+        assert_eq!(cgo::polyval(&key, &data), [0xf7, 0xa3, 0xb4, 0x7b, 0x84, 0x61, 0x19, 0xfa, 0xe5, 0xb7, 0x86, 0x6c, 0xf5, 0xe5, 0xb7, 0x7e]);
+
+        //Proposal 359's vectors for each layer of the construction, from its Python reference implementation by way of arti
+        for vector in vectors(include_str!("../testdata/cgo_et.txt")) {
+            let et = Et::new(hex(vector[1]).as_slice().try_into().unwrap());
+
+            let tweak = hex(vector[2]);
+
+            let mut block: [u8; 16] = hex(vector[3]).as_slice().try_into().unwrap();
+
+            if vector[0] == "1" {
+                et.encrypt(tweak[..16].try_into().unwrap(), tweak[16], &tweak[17..], & mut block);
+            } else {
+                et.decrypt(tweak[..16].try_into().unwrap(), tweak[16], &tweak[17..], & mut block);
+            }
+
+            assert_eq!(block.to_vec(), hex(vector[4]));
+        }
+
+        for vector in vectors(include_str!("../testdata/cgo_prf.txt")) {
+            let prf = Prf::new(hex(vector[0]).as_slice().try_into().unwrap());
+
+            let tweak: [u8; 16] = hex(vector[2]).as_slice().try_into().unwrap();
+
+            let expected = hex(vector[3]);
+
+            if vector[1] == "0" {
+                let mut data = vec![0u8; expected.len()];
+
+                prf.apply_n0(&tweak, & mut data);
+
+                assert_eq!(data, expected);
+            } else {
+                assert_eq!(prf.n1(&tweak, expected.len()), expected);
+            }
+        }
+
+        for vector in vectors(include_str!("../testdata/cgo_uiv.txt")) {
+            let uiv = Uiv::new(hex(vector[1]).as_slice().try_into().unwrap());
+
+            let tweak = hex(vector[2]);
+
+            let mut payload: [u8; 509] = [hex(vector[3]), hex(vector[4])].concat().as_slice().try_into().unwrap();
+
+            if vector[0] == "1" {
+                uiv.encrypt(tweak[..16].try_into().unwrap(), tweak[16], & mut payload);
+            } else {
+                uiv.decrypt(tweak[..16].try_into().unwrap(), tweak[16], & mut payload);
+            }
+
+            assert_eq!(payload.to_vec(), [hex(vector[5]), hex(vector[6])].concat());
+        }
+
+        for vector in vectors(include_str!("../testdata/cgo_uiv_update.txt")) {
+            let mut uiv = Uiv::new(hex(vector[0]).as_slice().try_into().unwrap());
+
+            let mut nonce: [u8; 16] = hex(vector[1]).as_slice().try_into().unwrap();
+
+            uiv.update(& mut nonce);
+
+            assert_eq!(uiv.keys().to_vec(), hex(vector[2]));
+            assert_eq!(nonce.to_vec(), hex(vector[3]));
+        }
+
+        //A direction from K | N, carrying on from T'
+        let direction = |keys: &str, nonce: &str, tag: &str| CgoDirection::new([hex(keys), hex(nonce)].concat().as_slice().try_into().unwrap()).with_tag(hex(tag).as_slice().try_into().unwrap());
+
+        let assert_state = |direction: &CgoDirection, keys: &str, nonce: &str, tag: &str| {
+            assert_eq!(direction.keys().to_vec(), hex(keys));
+            assert_eq!(direction.nonce().to_vec(), hex(nonce));
+            assert_eq!(direction.tag().to_vec(), hex(tag));
+        };
+
+        //A relay adding its layer going back to the client, or removing the client's going out and maybe recognising it
+        for vector in vectors(include_str!("../testdata/cgo_relay.txt")) {
+            let mut relay = direction(vector[1], vector[2], vector[3]);
+
+            let mut payload: [u8; 509] = [hex(vector[5]), hex(vector[6])].concat().as_slice().try_into().unwrap();
+
+            let command = hex(vector[4])[0];
+
+            if vector[0] == "1" {
+                relay.relay_encrypt(command, & mut payload);
+            } else {
+                relay.relay_decrypt(command, & mut payload);
+            }
+
+            assert_eq!(payload.to_vec(), [hex(vector[10]), hex(vector[11])].concat());
+
+            assert_state(&relay, vector[7], vector[8], vector[9]);
+        }
+
+        for vector in vectors(include_str!("../testdata/cgo_relay_originate.txt")) {
+            let mut relay = direction(vector[0], vector[1], vector[2]);
+
+            let mut payload = [0u8; 509];
+
+            payload[16..].copy_from_slice(&hex(vector[4]));
+
+            relay.relay_originate(hex(vector[3])[0], & mut payload);
+
+            assert_eq!(payload.to_vec(), [hex(vector[8]), hex(vector[9])].concat());
+
+            assert_state(&relay, vector[5], vector[6], vector[7]);
+        }
+
+        //A client originating a cell for the first, second or third hop of a circuit and adding the layers of the hops before it
+        for vector in vectors(include_str!("../testdata/cgo_client.txt")) {
+            let mut hops: Vec<CgoDirection> = (0..3).map(|hop| direction(vector[3 * hop], vector[3 * hop + 1], vector[3 * hop + 2])).collect();
+
+            let target: usize = vector[9].parse().unwrap();
+
+            let command = hex(vector[10])[0];
+
+            let mut payload = [0u8; 509];
+
+            payload[16..].copy_from_slice(&hex(vector[11]));
+
+            hops[target - 1].client_originate(command, & mut payload);
+
+            for hop in hops[..target - 1].iter_mut().rev() {
+                hop.client_encrypt(command, & mut payload);
+            }
+
+            assert_eq!(payload.to_vec(), [hex(vector[21]), hex(vector[22])].concat());
+
+            for (hop, direction) in hops.iter().enumerate() {
+                assert_state(direction, vector[12 + 3 * hop], vector[13 + 3 * hop], vector[14 + 3 * hop]);
+            }
+        }
+
+        //Cells the client originates are recognised at the relay, and cells the relay originates at the client, with each
+        //side's SENDME tag matching the other's as the keys move on
+        let keys: Vec<u8> = (0..CGO_KEY_MATERIAL_LENGTH + 20).map(|i| i as u8).collect();
+
+        let mut client = CgoCrypto::from(&keys[..CGO_KEY_MATERIAL_LENGTH].try_into().unwrap()).with_binding(&keys[CGO_KEY_MATERIAL_LENGTH..]);
+
+        assert_eq!(client.binding().unwrap().to_vec(), keys[CGO_KEY_MATERIAL_LENGTH..].to_vec());
+
+        let mut forward = CgoDirection::new(keys[..80].try_into().unwrap());
+        let mut backward = CgoDirection::new(keys[80..CGO_KEY_MATERIAL_LENGTH].try_into().unwrap());
+
+        for i in 0..3u8 {
+            let (mut payload, tag) = client.originate(3, RelayCell::new_data(1, &[i; 100])).unwrap();
+
+            assert_eq!(forward.relay_decrypt(3, & mut payload), Some(tag));
+
+            let relay_cell = cgo::decode_message(&payload).unwrap();
+
+            assert_eq!(relay_cell.get_stream_id(), 1);
+            assert_eq!(relay_cell.into_data(), vec![i; 100]);
+
+            let mut payload = cgo::encode_message(RelayCell::new_data(1, &[i; 10])).unwrap();
+
+            let tag = backward.relay_originate(3, & mut payload);
+
+            assert_eq!(client.decrypt_layer(3, & mut payload), Some(tag));
+            assert_eq!(cgo::decode_message(&payload).unwrap().into_data(), vec![i; 10]);
+        }
+
+        assert!(cgo::encode_message(RelayCell::new_data(1, &[0u8; CGO_DATA_LEN + 1])).is_err());
+
+        //Messages are laid out in format v1: command, length, then a stream ID only for the commands that take one
+        let encoded = cgo::encode_message(RelayCell::new_data(1, b"v1")).unwrap();
+
+        assert_eq!(&encoded[16..23], &[2, 0, 2, 0, 1, b'v', b'1']);
+        assert_eq!(encoded, relay_format::pack(vec![RelayCell::new_data(1, b"v1")]).unwrap()[0]);
+
+        let encoded = cgo::encode_message(RelayCell::new_raw(10, 0, vec![9])).unwrap();
+
+        assert_eq!(&encoded[16..20], &[10, 0, 1, 9]);
+        assert_eq!(cgo::decode_message(&encoded).unwrap().get_command(), 10);
+
+        //Flipping one bit garbles the cell so the relay doesn't recognise it, and every cell after it. So does sending a
+        //cell as RELAY_EARLY that was encrypted as RELAY
+        let (mut payload, _) = client.originate(3, RelayCell::new_data(1, b"tagged")).unwrap();
+
+        payload[300] ^= 1;
+
+        assert_eq!(forward.relay_decrypt(3, & mut payload), None);
+
+        let (mut payload, _) = client.originate(3, RelayCell::new_data(1, b"later")).unwrap();
+
+        assert_eq!(forward.relay_decrypt(3, & mut payload), None);
+
+        let mut client = CgoCrypto::from(&keys[..CGO_KEY_MATERIAL_LENGTH].try_into().unwrap());
+        let mut forward = CgoDirection::new(keys[..80].try_into().unwrap());
+
+        let (mut payload, _) = client.originate(3, RelayCell::new_data(1, b"early")).unwrap();
+
+        assert_eq!(forward.relay_decrypt(9, & mut payload), None);
+    }
+
+    #[test]
+    fn test_relay_cell_format() {
+        use crate::cellcrypto::{HopProtocol, RelayCryptoKind};
+        use crate::cells::{LinkSpecifier, RelayCell};
+        use crate::channel::Channel;
+        use crate::relay_format::{self, RelayCellFormat, Reassembler, V1_DATA_LEN};
+
+        //Small messages share a cell, and only stream commands carry a stream ID
+        let bodies = relay_format::pack(vec![RelayCell::new_data(5, b"hello"), RelayCell::new_raw(10, 0, vec![1, 2, 3]), RelayCell::new_raw(5, 0, vec![])]).unwrap();
+
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(&bodies[0][16..26], &[2, 0, 5, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+        assert_eq!(&bodies[0][26..32], &[10, 0, 3, 1, 2, 3]);
+        assert_eq!(&bodies[0][32..37], &[5, 0, 0, 0, 0]);
+
+        let messages = Reassembler::new().unpack(&bodies[0]).unwrap();
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].get_stream_id(), 5);
+        assert_eq!(messages[1].get_command(), 10);
+        assert_eq!(messages[2].get_command(), 5);
+
+        let data: Vec<_> = messages.into_iter().map(|message| message.into_data()).collect();
+
+        assert_eq!(data, vec![b"hello".to_vec(), vec![1, 2, 3], vec![]]);
+
+        //A full DATA message takes a whole cell
+        assert_eq!(relay_format::pack(vec![RelayCell::new_data(1, &[0u8; V1_DATA_LEN]), RelayCell::new_data(1, &[0u8; V1_DATA_LEN])]).unwrap().len(), 2);
+
+        //A long message carries on into the next cells and is only handed over once it is complete
+        let long: Vec<u8> = (0..1200u32).map(|i| (i % 256) as u8).collect();
+
+        let bodies = relay_format::pack(vec![RelayCell::new_raw(10, 0, long.clone()), RelayCell::new_data(7, b"after")]).unwrap();
+
+        assert_eq!(bodies.len(), 3);
+
+        let mut reassembler = Reassembler::new();
+
+        assert!(reassembler.unpack(&bodies[0]).unwrap().is_empty());
+        assert!(reassembler.unpack(&bodies[1]).unwrap().is_empty());
+
+        let messages = reassembler.unpack(&bodies[2]).unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].get_stream_id(), 7);
+
+        let data: Vec<_> = messages.into_iter().map(|message| message.into_data()).collect();
+
+        assert_eq!(data, vec![long.clone(), b"after".to_vec()]);
+
+        assert!(relay_format::pack(vec![RelayCell::new_raw(10, 0, vec![0u8; 70000])]).is_err());
+
+        //Circuits with v1 hops, whether every hop or only the last uses v1
+        let relay = MockRelay::spawn(MockTransport::Tls);
+        let echo = spawn_echo_server();
+
+        relay.use_cell_format(RelayCellFormat::V1);
+
+        let v1 = HopProtocol { crypto: RelayCryptoKind::Tor1, format: RelayCellFormat::V1 };
+
+        let channel = Channel::connect(relay.address()).unwrap();
+
+        let v1_circuit = channel.create_ntor_with(&relay.node_id(), &relay.onion_key(), v1, None).unwrap();
+
+        v1_circuit.extend_ntor_with(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key(), v1, None).unwrap();
+
+        let mixed_circuit = channel.create_fast().unwrap();
+
+        //A message longer than a cell doesn't fit format v0
+        assert!(mixed_circuit.send_control(RelayCell::new_raw(10, 0, long.clone())).is_err());
+
+        mixed_circuit.extend_ntor_with(vec![LinkSpecifier::legacy_id(relay.node_id())], &relay.node_id(), &relay.onion_key(), v1, None).unwrap();
+
+        for (sent, circuit) in [v1_circuit, mixed_circuit].iter().enumerate() {
+            //The relay only counts the DROP once every fragment has arrived
+            circuit.send_control(RelayCell::new_raw(10, 0, long.clone())).unwrap();
+
+            let mut stream = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+            let data: Vec<u8> = (0..5000u32).map(|i| (i % 249) as u8).collect();
+
+            stream.write_all(&data).unwrap();
+
+            let mut echoed = vec![0u8; data.len()];
+
+            stream.read_exact(& mut echoed).unwrap();
+
+            assert!(echoed == data);
+            assert_eq!(relay.drops_received(), sent as u64 + 1);
+        }
+    }
+
+    #[test]
+    fn test_package_windows() {
+        use crate::channel::Channel;
+        use crate::circuit::STREAM_WINDOW_START;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        let relay = Arc::new(MockRelay::spawn(MockTransport::Plain));
+        let echo = spawn_echo_server();
+
+        let tcp = TcpStream::connect(relay.address()).unwrap();
+
+        let channel = Channel::handshake(tcp, relay.address().ip()).unwrap();
+
+        let circuit = channel.create_fast().unwrap();
+
+        let mut stream = circuit.begin("127.0.0.1", echo.port()).unwrap();
+
+        relay.hold_sendmes(true);
+
+        let releasing = {
+            let relay = relay.clone();
+
+            std::thread::spawn(move || {
+                let deadline = Instant::now() + Duration::from_secs(10);
+
+                while relay.data_received() < STREAM_WINDOW_START as u64 && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+
+                let received = relay.data_received();
+
+                relay.hold_sendmes(false);
+
+                received
+            })
+        };
+
+        //1200 cells runs through the stream window twice and the circuit window once. Without its SENDMEs the stream
+        //can't send a cell past the first 500, which the relay would count as an overrun
+        let message: Vec<u8> = (0..1200 * 498).map(|i| (i % 251) as u8).collect();
+
+        stream.write_all(&message).unwrap();
+
+        assert_eq!(releasing.join().unwrap(), STREAM_WINDOW_START as u64);
+
+        let mut echoed = vec![0u8; message.len()];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        assert_eq!(echoed, message);
+        assert_eq!(relay.data_received(), 1200);
+
+        //An empty write sends no DATA cell
+        assert_eq!(stream.write(&[]).unwrap(), 0);
+        assert_eq!(relay.data_received(), 1200);
+
+        //A writer on another thread shares the stream's window
+        relay.hold_sendmes(true);
+
+        let mut writer = stream.writer();
+
+        assert_eq!(writer.write(&[]).unwrap(), 0);
+        assert_eq!(relay.data_received(), 1200);
+
+        let sent = message[..600 * 498].to_vec();
+
+        let writing = std::thread::spawn(move || writer.write_all(&sent));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while relay.data_received() < 1200 + STREAM_WINDOW_START as u64 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(relay.data_received(), 1200 + STREAM_WINDOW_START as u64);
+
+        relay.hold_sendmes(false);
+
+        let mut echoed = vec![0u8; 600 * 498];
+
+        stream.read_exact(& mut echoed).unwrap();
+
+        writing.join().unwrap().unwrap();
+
+        assert_eq!(echoed, message[..600 * 498]);
+        assert_eq!(relay.window_overruns(), 0);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_package_windows() {
+        use crate::async_channel::AsyncChannel;
+        use crate::circuit::STREAM_WINDOW_START;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let relay = Arc::new(MockRelay::spawn(MockTransport::Tls));
+        let echo = spawn_echo_server();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let channel = AsyncChannel::connect(relay.address()).await.unwrap();
+
+            let circuit = channel.create_fast().await.unwrap();
+
+            let mut stream = circuit.begin("127.0.0.1", echo.port()).await.unwrap();
+
+            relay.hold_sendmes(true);
+
+            let releasing = {
+                let relay = relay.clone();
+
+                std::thread::spawn(move || {
+                    let deadline = Instant::now() + Duration::from_secs(10);
+
+                    while relay.data_received() < STREAM_WINDOW_START as u64 && Instant::now() < deadline {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+
+                    let received = relay.data_received();
+
+                    relay.hold_sendmes(false);
+
+                    received
+                })
+            };
+
+            //Past the stream window the writes stay pending until the relay's SENDMEs arrive
+            let message: Vec<u8> = (0..1200 * 498).map(|i| (i % 251) as u8).collect();
+
+            stream.write_all(&message).await.unwrap();
+
+            assert_eq!(releasing.join().unwrap(), STREAM_WINDOW_START as u64);
+
+            let mut echoed = vec![0u8; message.len()];
+
+            stream.read_exact(& mut echoed).await.unwrap();
+
+            assert_eq!(echoed, message);
+        });
+
+        assert_eq!(relay.data_received(), 1200);
+        assert_eq!(relay.window_overruns(), 0);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_stream_outlives_circuit() {
+        use crate::async_channel::AsyncChannel;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let relay = MockRelay::spawn(MockTransport::Plain);
+        let echo = spawn_echo_server();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let tcp = tokio::net::TcpStream::connect(relay.address()).await.unwrap();
+
+            let channel = AsyncChannel::handshake(tcp, relay.address().ip()).await.unwrap();
+
+            let circuit = channel.create_fast().await.unwrap();
+
+            let mut stream = circuit.begin("127.0.0.1", echo.port()).await.unwrap();
+
+            drop(circuit);
+
+            //The stream still holds the circuit, so it isn't destroyed yet
+            assert_eq!(channel.circuit_count(), 1);
+
+            stream.write_all(b"ping").await.unwrap();
+
+            let mut echoed = [0u8; 4];
+
+            stream.read_exact(& mut echoed).await.unwrap();
+
+            assert_eq!(&echoed, b"ping");
+
+            drop(stream);
+
+            assert_eq!(channel.circuit_count(), 0);
+        });
+    }
+}